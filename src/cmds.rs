@@ -1,4 +1,6 @@
-use crate::{eval::Value, scope::Scope, utils::copy_vars_to_command_env};
+use crate::eval::{Function, Value};
+use crate::job::{Job, Process};
+use crate::{scope::Scope, stdio, utils::copy_vars_to_command_env};
 use colored::Colorize;
 use std::any::Any;
 use std::borrow::Cow;
@@ -6,6 +8,7 @@ use std::collections::HashMap;
 use std::ffi::OsStr;
 use std::fmt::Debug;
 use std::path::{Path, PathBuf};
+use std::process::Stdio;
 use std::sync::{Arc, LazyLock, Mutex};
 use std::{fs, io};
 use which::which;
@@ -14,6 +17,7 @@ mod flags;
 use flags::CommandFlags;
 // Built-in commands
 mod alias;
+mod argparse;
 mod basename;
 mod cat;
 mod cd;
@@ -28,11 +32,13 @@ mod df;
 mod diff;
 mod du;
 mod echo;
+mod errors;
 mod evalargs;
 mod exit;
 mod find;
 mod grep;
 mod help;
+mod jobs;
 mod less;
 mod ln;
 mod ls;
@@ -49,13 +55,16 @@ mod sort;
 mod strings;
 #[cfg(windows)]
 mod sudo;
+mod throw;
+mod time;
 mod touch;
+mod trap;
 mod vars;
 mod wc;
 #[cfg(windows)]
 mod whois;
 
-pub trait Exec {
+pub trait Exec: Send + Sync {
     fn as_any(&self) -> Option<&dyn Any> {
         None
     }
@@ -93,10 +102,29 @@ pub struct ShellCommand {
 }
 
 impl ShellCommand {
+    pub fn new(name: String, inner: Arc<dyn Exec>) -> Self {
+        Self { name, inner }
+    }
+
     pub fn name(&self) -> &String {
         &self.name
     }
 
+    /// Clone the command under another name.
+    pub fn with_name(&self, name: String) -> Self {
+        Self {
+            name,
+            inner: Arc::clone(&self.inner),
+        }
+    }
+
+    pub fn as_function(&self) -> Option<&Function> {
+        self.inner
+            .as_ref()
+            .as_any()
+            .and_then(|any| any.downcast_ref::<Function>())
+    }
+
     fn get_alias_def(&self) -> Option<String> {
         self.inner.as_ref().as_any().and_then(|any| {
             any.downcast_ref::<alias::AliasRunner>()
@@ -108,17 +136,40 @@ impl ShellCommand {
         self.inner
             .as_ref()
             .as_any()
-            .map(|any| any.downcast_ref::<alias::AliasRunner>())
+            .and_then(|any| any.downcast_ref::<alias::AliasRunner>())
             .is_some()
     }
 
-    fn is_external(&self) -> bool {
+    pub fn is_external(&self) -> bool {
         self.inner
             .as_ref()
             .as_any()
             .and_then(|any| any.downcast_ref::<External>())
             .is_some()
     }
+
+    /// Start an external command with the given standard input and output, without waiting
+    /// for it to complete. Used by pipelines, where builtin commands are evaluated in-process.
+    pub fn spawn(
+        &self,
+        args: &[String],
+        scope: &Arc<Scope>,
+        stdin: Stdio,
+        stdout: Stdio,
+    ) -> io::Result<Process> {
+        match self
+            .inner
+            .as_ref()
+            .as_any()
+            .and_then(|any| any.downcast_ref::<External>())
+        {
+            Some(external) => external.spawn(args, scope, stdin, stdout),
+            None => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{}: not an external command", self.name),
+            )),
+        }
+    }
 }
 
 impl Debug for ShellCommand {
@@ -149,8 +200,6 @@ impl Exec for ShellCommand {
     }
 }
 
-unsafe impl Send for ShellCommand {}
-
 static COMMAND_REGISTRY: LazyLock<Mutex<HashMap<String, ShellCommand>>> =
     LazyLock::new(|| Mutex::new(HashMap::new()));
 
@@ -257,6 +306,23 @@ impl External {
         }
     }
 
+    fn spawn(
+        &self,
+        args: &[String],
+        scope: &Arc<Scope>,
+        stdin: Stdio,
+        stdout: Stdio,
+    ) -> io::Result<Process> {
+        let path = self.which_path();
+
+        let mut job = Job::new(scope, &path, args, false);
+        let command = job.command_mut().unwrap();
+        copy_vars_to_command_env(command, scope);
+        command.stdin(stdin).stdout(stdout);
+
+        job.spawn()
+    }
+
     /// Run hooks upon successful execution of an external command.
     /// # TODO: Possible design refinements:
     /// * call hooks before and after executing commands?
@@ -289,13 +355,14 @@ impl Exec for External {
     }
 
     fn exec(&self, _name: &str, args: &Vec<String>, scope: &Arc<Scope>) -> Result<Value, String> {
-        use crate::job::*;
-
         // Resolve the path on each execution, because $PATH may have changed.
         let path = self.which_path();
 
         let mut job = Job::new(scope, &path, &args, false);
-        copy_vars_to_command_env(job.command_mut().unwrap(), &scope);
+        let command = job.command_mut().unwrap();
+        copy_vars_to_command_env(command, &scope);
+        // The standard input or output of the thread may be redirected, e.g. in pipelines.
+        stdio::connect(command).map_err(|e| e.to_string())?;
 
         let args = job.args().unwrap_or_default();
 
@@ -389,6 +456,8 @@ impl Exec for Which {
                 if !extern_only && !cmd.is_external() {
                     if cmd.is_alias() {
                         my_println!("{}: alias", command)?;
+                    } else if cmd.as_function().is_some() {
+                        my_println!("{}: function", command)?;
                     } else {
                         my_println!("{}: built-in", command)?;
                     }
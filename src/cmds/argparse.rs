@@ -0,0 +1,367 @@
+use super::{flags::CommandFlags, register_command, Exec, Flag, ShellCommand};
+use crate::{eval::Value, profiler, scope::Scope, traps};
+use std::process;
+use std::sync::Arc;
+
+struct ArgParse {
+    flags: CommandFlags,
+}
+
+impl ArgParse {
+    fn new() -> Self {
+        let mut flags = CommandFlags::with_help();
+        flags.add_value(
+            'n',
+            "name",
+            "NAME",
+            "Name of the script, for the usage line (default: script)",
+        );
+        flags.add_value(
+            'd',
+            "description",
+            "TEXT",
+            "Description of the script, for the generated help",
+        );
+
+        Self { flags }
+    }
+}
+
+/// Positional argument, declared as <name>, [name], <name>... or [name]...
+struct Positional {
+    spec: String,
+    name: String,
+    required: bool,
+    variadic: bool,
+    help: String,
+}
+
+/// The flags and positional arguments declared by a script.
+struct Spec {
+    name: String,
+    description: Option<String>,
+    flags: CommandFlags,
+    positionals: Vec<Positional>,
+}
+
+/// Split a declaration into the spec word and the help text that follows it.
+fn split_help(decl: &str) -> (&str, String) {
+    let decl = decl.trim();
+    match decl.split_once(char::is_whitespace) {
+        Some((spec, help)) => (spec, help.trim().to_string()),
+        None => (decl, String::new()),
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Name of the variable that a flag or positional argument is bound to.
+fn var_name(name: &str) -> String {
+    name.replace('-', "_")
+}
+
+impl Spec {
+    fn new(name: String, description: Option<String>, decls: &[String]) -> Result<Self, String> {
+        let mut spec = Self {
+            name,
+            description,
+            flags: CommandFlags::with_help(),
+            positionals: Vec::new(),
+        };
+
+        for decl in decls {
+            if decl.trim_start().starts_with(['<', '[']) {
+                spec.add_positional(decl)?;
+            } else {
+                spec.add_flag(decl)?;
+            }
+        }
+        Ok(spec)
+    }
+
+    /// Declare a flag: [SHORT,]LONG[=NAME][:DEFAULT] [HELP]
+    fn add_flag(&mut self, decl: &str) -> Result<(), String> {
+        let invalid = || format!("Invalid flag spec: {}", decl);
+        let (word, help) = split_help(decl);
+
+        let (word, default) = match word.split_once(':') {
+            Some((word, default)) => (word, Some(default)),
+            None => (word, None),
+        };
+        let (word, value_name) = match word.split_once('=') {
+            Some((word, name)) if !name.is_empty() => (word, Some(name.to_string())),
+            Some(_) => return Err(invalid()),
+            None => (word, None),
+        };
+        let (short, long) = match word.split_once(',') {
+            Some((short, long)) => {
+                let mut chars = short.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) if c.is_ascii_alphanumeric() => (Some(c), long),
+                    _ => return Err(invalid()),
+                }
+            }
+            None => (None, word),
+        };
+        if !is_valid_name(long) {
+            return Err(invalid());
+        }
+        // Boolean flags are off unless declared with :true, and can be negated with --no-LONG.
+        let default = match (&value_name, default) {
+            (None, Some("true")) => Some("true"),
+            (None, Some("false")) | (_, None) => None,
+            (None, Some(_)) => return Err(invalid()),
+            (Some(_), default) => default,
+        };
+
+        if self.flags.iter().any(|f| f.long == long)
+            || short.is_some() && self.flags.iter().any(|f| f.short == short)
+        {
+            return Err(format!("Duplicate flag: {}", decl));
+        }
+        self.flags
+            .add_with_default(short, long, value_name, &help, default);
+        Ok(())
+    }
+
+    /// Declare a positional argument: <name> is required, [name] is optional,
+    /// and a trailing ... collects the remaining arguments into a list.
+    fn add_positional(&mut self, decl: &str) -> Result<(), String> {
+        let invalid = || format!("Invalid argument spec: {}", decl);
+        let (word, help) = split_help(decl);
+
+        let (inner, variadic) = match word.strip_suffix("...") {
+            Some(inner) => (inner, true),
+            None => (word, false),
+        };
+        let (name, required) = if let Some(name) = inner.strip_prefix('<') {
+            (name.strip_suffix('>').ok_or_else(invalid)?, true)
+        } else if let Some(name) = inner.strip_prefix('[') {
+            (name.strip_suffix(']').ok_or_else(invalid)?, false)
+        } else {
+            return Err(invalid());
+        };
+        if !is_valid_name(name) {
+            return Err(invalid());
+        }
+
+        if let Some(last) = self.positionals.last() {
+            if last.variadic {
+                return Err(format!("Argument {} follows {}", word, last.spec));
+            }
+            if required && !last.required {
+                return Err(format!(
+                    "Required argument {} follows optional {}",
+                    word, last.spec
+                ));
+            }
+        }
+        if self.positionals.iter().any(|p| p.name == name) {
+            return Err(format!("Duplicate argument: {}", decl));
+        }
+
+        self.positionals.push(Positional {
+            spec: word.to_string(),
+            name: name.to_string(),
+            required,
+            variadic,
+            help,
+        });
+        Ok(())
+    }
+
+    fn usage(&self) -> String {
+        let mut usage = format!("Usage: {} [OPTIONS]", self.name);
+        for positional in &self.positionals {
+            usage.push(' ');
+            usage.push_str(&positional.spec);
+        }
+        usage
+    }
+
+    fn print_help(&self) {
+        println!("{}", self.usage());
+        if let Some(description) = &self.description {
+            println!("{}", description);
+        }
+        println!("\nOptions:");
+        print!("{}", self.flags.help());
+        if !self.positionals.is_empty() {
+            println!("\nArguments:");
+            for positional in &self.positionals {
+                println!("    {:22} {}", positional.spec, positional.help);
+            }
+        }
+        println!();
+    }
+}
+
+/// Stop the script after the generated help was shown, like `exit 0` would; the
+/// interactive shell is not exited, the command that called argparse fails instead.
+fn exit_after_help(scope: &Arc<Scope>) -> Result<Value, String> {
+    if matches!(scope.lookup_value("__interactive"), Some(Value::Int(0))) {
+        traps::run(traps::Condition::Exit);
+        profiler::report();
+        process::exit(0);
+    }
+    Err("Stopped after displaying help".to_string())
+}
+
+/// Bind a variable in the scope of the caller, unless it is read-only.
+fn bind(scope: &Arc<Scope>, name: &str, value: Value) -> Result<(), String> {
+    if scope.lookup(name).is_some_and(|var| var.is_readonly()) {
+        return Err(format!("Cannot assign read-only variable: ${}", name));
+    }
+    scope.insert(name.to_string(), value);
+    Ok(())
+}
+
+/// The arguments of the enclosing script or function. Scripts keep their arguments in the
+/// $__args list, which leaves out the script path and the interpreter options, and keeps the
+/// arguments that contain whitespace intact; functions have only $@.
+fn caller_args(scope: &Arc<Scope>) -> Vec<String> {
+    let mut current = Some(scope);
+    while let Some(scope) = current {
+        if let Some(all) = scope.lookup_local("@") {
+            if let Some(args) = scope.lookup_local("__args") {
+                if let Value::List(items) = &*args.value() {
+                    return items.iter().map(|arg| arg.to_string()).collect();
+                }
+            }
+            return all
+                .value()
+                .as_str()
+                .split_whitespace()
+                .map(String::from)
+                .collect();
+        }
+        current = scope.parent.as_ref();
+    }
+    Vec::new()
+}
+
+impl Exec for ArgParse {
+    fn cli_flags(&self) -> Box<dyn Iterator<Item = &Flag> + '_> {
+        Box::new(self.flags.iter())
+    }
+
+    fn exec(&self, _name: &str, args: &Vec<String>, scope: &Arc<Scope>) -> Result<Value, String> {
+        // The arguments to parse follow --; without it, parse the arguments of the caller
+        let (decls, script_args, offset) = match args.iter().position(|arg| arg == "--") {
+            Some(pos) => (&args[..pos], args[pos + 1..].to_vec(), Some(pos + 1)),
+            None => (&args[..], caller_args(scope), None),
+        };
+
+        let mut flags = self.flags.clone();
+        let decls = flags.parse(scope, decls)?;
+
+        if flags.is_present("help") {
+            println!("Usage: argparse [OPTIONS] SPEC... [-- ARGS...]");
+            println!("Declare the flags and arguments of a script or function, parse ARGS (or the arguments of");
+            println!(
+                "the script or function, when -- is not given), and bind the results to variables."
+            );
+            println!("\nOptions:");
+            print!("{}", flags.help());
+            println!();
+            println!("Specs:");
+            println!("    [SHORT,]LONG[=NAME][:DEFAULT] [HELP]   Flag; a boolean flag is bound to 1 or 0, and");
+            println!("                                          is negated with --no-LONG (:true enables it)");
+            println!("    <name> [HELP]                         Required argument");
+            println!("    [name] [HELP]                         Optional argument");
+            println!(
+                "    <name>... or [name]...                The remaining arguments, as a list"
+            );
+            println!();
+            println!("Dashes in names are bound as underscores. --help shows the generated help and exits.");
+            println!("Example:");
+            println!("    argparse -n backup \"v,verbose Verbose output\" \"o,out=DIR:/tmp Destination\" \"<src>...\"");
+            println!();
+            return Ok(Value::success());
+        }
+
+        let name = flags.value("name").unwrap_or("script").to_string();
+        let description = flags.value("description").map(String::from);
+        let spec = Spec::new(name, description, &decls)?;
+
+        let usage_error = |index: Option<usize>, message: String| {
+            if let (Some(offset), Some(index)) = (offset, index) {
+                scope.set_err_arg(offset + index);
+            }
+            format!("{}\n{}", message, spec.usage())
+        };
+
+        // Options end at --, everything after it is positional.
+        let (options, rest) = match script_args.iter().position(|arg| arg == "--") {
+            Some(pos) => (&script_args[..pos], &script_args[pos + 1..]),
+            None => (&script_args[..], &script_args[..0]),
+        };
+
+        // Parse in a scratch scope, to map the index of the offending argument
+        // to the arguments of argparse.
+        let parse_scope = Scope::new();
+        let mut script_flags = spec.flags.clone();
+        let mut values = script_flags
+            .parse(&parse_scope, options)
+            .map_err(|e| usage_error(parse_scope.err_arg().checked_sub(1), e))?;
+        values.extend(rest.iter().cloned());
+
+        if script_flags.is_present("help") {
+            spec.print_help();
+            return exit_after_help(scope);
+        }
+
+        let mut values = values.into_iter().peekable();
+        let mut bindings = Vec::new();
+        for positional in &spec.positionals {
+            if positional.required && values.peek().is_none() {
+                return Err(usage_error(
+                    None,
+                    format!("Missing argument: {}", positional.spec),
+                ));
+            }
+            let value = if positional.variadic {
+                let items = values.by_ref().map(|v| Value::from(v.as_str())).collect();
+                Value::new_list(items)
+            } else {
+                Value::from(values.next().unwrap_or_default().as_str())
+            };
+            bindings.push((var_name(&positional.name), value));
+        }
+        if let Some(extra) = values.next() {
+            let index = script_args.iter().rposition(|arg| *arg == extra);
+            return Err(usage_error(
+                index,
+                format!("Unexpected argument: {}", scope.err_str(&extra)),
+            ));
+        }
+
+        for flag in script_flags.iter().filter(|f| f.long != "help") {
+            let value = match &flag.takes_value {
+                Some(_) => Value::from(script_flags.value(&flag.long).unwrap_or_default()),
+                None => Value::Int(script_flags.is_present(&flag.long) as _),
+            };
+            bindings.push((var_name(&flag.long), value));
+        }
+
+        for (name, value) in bindings {
+            bind(scope, &name, value)?;
+        }
+
+        Ok(Value::success())
+    }
+}
+
+#[ctor::ctor]
+fn register() {
+    register_command(ShellCommand {
+        name: "argparse".to_string(),
+        inner: Arc::new(ArgParse::new()),
+    });
+}
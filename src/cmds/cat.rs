@@ -1,10 +1,11 @@
 use super::{register_command, Exec, Flag, ShellCommand};
 use crate::{
-    cmds::flags::CommandFlags, eval::Value, scope::Scope, symlnk::SymLink, utils::format_error,
+    cmds::flags::CommandFlags, eval::Value, scope::Scope, stdio, symlnk::SymLink,
+    utils::format_error,
 };
 use std::collections::VecDeque;
 use std::fs::File;
-use std::io::{self, BufRead, BufReader};
+use std::io::{self, BufRead, BufReader, ErrorKind, Write};
 use std::path::Path;
 use std::sync::Arc;
 
@@ -78,7 +79,7 @@ impl Exec for CatHeadTail {
             scope.show_eof_hint();
 
             let mode = self.mode.clone();
-            let mut stdin = BufReader::new(io::stdin());
+            let mut stdin = stdio::stdin();
             process_input(&mut stdin, mode, line_num, text_out, lines)
         } else {
             let mut result = Ok(());
@@ -113,6 +114,14 @@ fn process_input<R: BufRead>(
     text_out: bool,
     lines: usize,
 ) -> Result<(), String> {
+    if !line_numbers && !text_out {
+        match mode {
+            Mode::Cat => return copy_input(reader, None),
+            Mode::Head => return copy_input(reader, Some(lines)),
+            Mode::Tail => {}
+        }
+    }
+
     let mut i = 0;
     let mut tail = VecDeque::new();
 
@@ -171,6 +180,77 @@ fn process_input<R: BufRead>(
     Ok(())
 }
 
+/// Copy the input to stdout (up to the given number of lines), without collecting whole
+/// lines, which can be arbitrarily long, e.g. cat /dev/zero. Like the line by line output,
+/// invalid UTF-8 is replaced, and the output ends with a newline. Stop without an error
+/// when the reader of stdout is gone.
+fn copy_input<R: BufRead>(reader: &mut R, lines: Option<usize>) -> Result<(), String> {
+    let mut remaining = lines.unwrap_or(usize::MAX);
+    let mut pending = Vec::new();
+    let mut last = b'\n';
+
+    let write = |bytes: &[u8], last: &mut u8| -> io::Result<()> {
+        let text = String::from_utf8_lossy(bytes);
+        if let Some(&byte) = text.as_bytes().last() {
+            *last = byte;
+        }
+        stdio::stdout().write_all(text.as_bytes())
+    };
+
+    let result = loop {
+        if remaining == 0 || Scope::is_interrupted() {
+            break Ok(());
+        }
+        let buffer = reader
+            .fill_buf()
+            .map_err(|e| format!("Error reading input: {}", e))?;
+        if buffer.is_empty() {
+            break Ok(());
+        }
+        // Up to and including the last newline wanted, or the whole buffer.
+        let mut len = buffer.len();
+        for (pos, _) in buffer.iter().enumerate().filter(|(_, &b)| b == b'\n') {
+            remaining -= 1;
+            if remaining == 0 {
+                len = pos + 1;
+                break;
+            }
+        }
+        pending.extend_from_slice(&buffer[..len]);
+        reader.consume(len);
+
+        // Keep a character cut off at the end of the buffer for the next round.
+        let complete = pending.len() - incomplete_utf8(&pending);
+        if let Err(e) = write(&pending[..complete], &mut last) {
+            break Err(e);
+        }
+        pending.drain(..complete);
+    };
+
+    match result.and_then(|_| write(&pending, &mut last)) {
+        Err(e) if e.kind() == ErrorKind::BrokenPipe => Ok(()),
+        Err(e) => Err(e.to_string()),
+        Ok(_) if last != b'\n' => my_println!(),
+        Ok(_) => Ok(()),
+    }
+}
+
+/// The number of bytes at the end, that start a UTF-8 sequence and are not complete.
+fn incomplete_utf8(bytes: &[u8]) -> usize {
+    for (i, &byte) in bytes.iter().rev().take(3).enumerate() {
+        if byte & 0xC0 != 0x80 {
+            let width = match byte {
+                0xC0..=0xDF => 2,
+                0xE0..=0xEF => 3,
+                0xF0..=0xF7 => 4,
+                _ => 1,
+            };
+            return if width > i + 1 { i + 1 } else { 0 };
+        }
+    }
+    0
+}
+
 #[ctor::ctor]
 fn register() {
     register_command(ShellCommand {
@@ -1,10 +1,10 @@
 use super::{flags::CommandFlags, register_command, Exec, Flag, ShellCommand};
 use crate::{current_dir, eval::Value, scope::Scope, symlnk::SymLink};
-use std::cell::RefCell;
-use std::{env, path::Path, sync::Arc};
+use std::sync::{Arc, Mutex};
+use std::{env, path::Path};
 
 struct ChangeDir {
-    stack: RefCell<Vec<String>>,
+    stack: Mutex<Vec<String>>,
     flags: CommandFlags,
 }
 
@@ -16,7 +16,7 @@ impl ChangeDir {
     fn new() -> Self {
         let flags = CommandFlags::with_help();
         Self {
-            stack: RefCell::new(Vec::new()), // pushd / popd stack
+            stack: Mutex::new(Vec::new()), // pushd / popd stack
             flags,
         }
     }
@@ -78,14 +78,14 @@ impl ChangeDir {
                 } else {
                     parsed_args.join(" ")
                 };
-                self.stack.borrow_mut().push(current_dir()?);
+                self.stack.lock().unwrap().push(current_dir()?);
                 self.do_chdir(scope, &new_dir)?
             }
             "popd" => {
-                if self.stack.borrow().is_empty() {
-                    return Err("popd: directory stack empty".to_string());
-                }
-                let old_dir = self.stack.borrow_mut().pop().unwrap();
+                let old_dir = match self.stack.lock().unwrap().pop() {
+                    Some(dir) => dir,
+                    None => return Err("popd: directory stack empty".to_string()),
+                };
                 self.do_chdir(scope, &old_dir)?
             }
             _ => unreachable!(),
@@ -3,6 +3,7 @@ use crate::{
     eval::Value,
     prompt::{confirm, Answer},
     scope::Scope,
+    stdio,
     symlnk::SymLink,
     utils::format_error,
 };
@@ -112,7 +113,7 @@ impl<'a> FileCopier<'a> {
             recursive: flags.is_present("recursive"),
             // Progress indicator
             progress: if flags.is_present("progress") {
-                let template = if scope.use_colors(&std::io::stdout()) {
+                let template = if scope.use_colors(&stdio::stdout()) {
                     "{spinner:.green} [{elapsed_precise}] {msg:>30.cyan.bright} {total_bytes}"
                 } else {
                     "{spinner} [{elapsed_precise}] {msg:>30} {total_bytes}"
@@ -360,7 +361,7 @@ impl<'a> FileCopier<'a> {
     }
 
     fn reset_progress_indicator(&mut self, size: u64) {
-        let template = if self.scope.use_colors(&std::io::stdout()) {
+        let template = if self.scope.use_colors(&stdio::stdout()) {
             "{spinner:.green} [{elapsed_precise}] {msg:>30.cyan.bright} [{bar:45.green/}] {bytes}/{total_bytes} ({eta})"
         } else {
             "{spinner:} [{elapsed_precise}] {msg:>30} [{bar:45}] {bytes}/{total_bytes} ({eta})"
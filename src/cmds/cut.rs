@@ -1,10 +1,11 @@
 use super::{register_command, Exec, Flag, ShellCommand};
 use crate::{
-    cmds::flags::CommandFlags, eval::Value, scope::Scope, symlnk::SymLink, utils::format_error,
+    cmds::flags::CommandFlags, eval::Value, scope::Scope, stdio, symlnk::SymLink,
+    utils::format_error,
 };
 use regex::Regex;
 use std::fs::File;
-use std::io::{self, BufRead, BufReader};
+use std::io::{BufRead, BufReader};
 use std::path::Path;
 use std::sync::Arc;
 
@@ -72,7 +73,7 @@ impl Exec for CutCommand {
 
         if filenames.is_empty() {
             scope.show_eof_hint();
-            let mut stdin = BufReader::new(io::stdin());
+            let mut stdin = stdio::stdin();
             process_cut(&mut stdin, &regex_delimiter, &fields)?;
         } else {
             for filename in &filenames {
@@ -1,5 +1,5 @@
 use super::{flags::CommandFlags, register_command, Exec, Flag, ShellCommand};
-use crate::{eval::Value, scope::Scope, symlnk::SymLink, utils::format_error};
+use crate::{eval::Value, scope::Scope, stdio, symlnk::SymLink, utils::format_error};
 use colored::*;
 use std::collections::VecDeque;
 use std::fs::File;
@@ -55,7 +55,7 @@ impl Exec for Diff {
         let mut grid = Grid::new();
         diff(&files[0], &files[1], &mut grid);
 
-        let color = flags.is_present("color") && scope.use_colors(&std::io::stdout());
+        let color = flags.is_present("color") && scope.use_colors(&stdio::stdout());
 
         // Unified view, no context lines.
         print(&grid, &files[0], &files[1], &fnames[0], &fnames[1], color)?;
@@ -0,0 +1,175 @@
+use super::{flags::CommandFlags, register_command, Exec, Flag, ShellCommand};
+use crate::{eval::Value, scope::Ident, scope::Scope};
+use regex::Regex;
+use std::sync::Arc;
+
+struct Errors {
+    flags: CommandFlags,
+}
+
+impl Errors {
+    fn new() -> Self {
+        let mut flags = CommandFlags::with_help();
+        flags.add_value(
+            'c',
+            "command",
+            "NAME",
+            "Show the errors of the given command only",
+        );
+        flags.add_value(
+            'm',
+            "match",
+            "REGEX",
+            "Show the errors with messages that match the regular expression",
+        );
+        flags.add_value('n', "last", "N", "Show the last N errors");
+        flags.add_flag(
+            'j',
+            "json",
+            "Print the errors as JSON objects, one per line",
+        );
+        flags.add(None, "clear", None, "Clear the errors");
+
+        Self { flags }
+    }
+
+    /// Remove $__errors from the current scope and the enclosing scopes.
+    fn clear(scope: &Arc<Scope>) {
+        let mut current = Some(scope);
+        while let Some(scope) = current {
+            scope.vars_mut().remove(&Ident::from("__errors"));
+            current = scope.parent.as_ref();
+        }
+    }
+}
+
+/// Get the value of a field of an error entry, as a string.
+fn field(entry: &Value, key: &str) -> String {
+    match entry {
+        Value::Map(map) => map
+            .get(key)
+            .map(|value| value.as_str().to_string())
+            .unwrap_or_default(),
+        _ => String::default(),
+    }
+}
+
+fn to_json(value: &Value) -> String {
+    match value {
+        Value::Int(i) => i.to_string(),
+        Value::Real(r) if r.is_finite() => r.to_string(),
+        Value::Real(_) => "null".to_string(),
+        Value::Str(_) | Value::Stat(_) => json_str(&value.as_str()),
+        Value::List(items) => {
+            let items: Vec<String> = items.iter().map(to_json).collect();
+            format!("[{}]", items.join(","))
+        }
+        Value::Map(map) => {
+            let entries: Vec<String> = map
+                .iter()
+                .map(|(key, value)| format!("{}:{}", json_str(key), to_json(value)))
+                .collect();
+            format!("{{{}}}", entries.join(","))
+        }
+    }
+}
+
+fn json_str(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c.is_control() => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+impl Exec for Errors {
+    fn cli_flags(&self) -> Box<dyn Iterator<Item = &Flag> + '_> {
+        Box::new(self.flags.iter())
+    }
+
+    fn exec(&self, _name: &str, args: &Vec<String>, scope: &Arc<Scope>) -> Result<Value, String> {
+        let mut flags = self.flags.clone();
+        let args = flags.parse(scope, args)?;
+
+        if flags.is_present("help") {
+            println!("Usage: errors [OPTIONS]");
+            println!("List the errors of failed commands whose status was evaluated, from the $__errors variable.");
+            println!("\nOptions:");
+            print!("{}", flags.help());
+            println!();
+            println!("Each entry in $__errors is a map with the keys: command, args, exit_code, signal, message, file, line and col.");
+            println!();
+            return Ok(Value::success());
+        }
+
+        if !args.is_empty() {
+            return Err(format!("Unexpected argument: {}", scope.err_str(&args[0])));
+        }
+
+        if flags.is_present("clear") {
+            Self::clear(scope);
+            return Ok(Value::success());
+        }
+
+        let pattern = match flags.value("match") {
+            Some(pattern) => {
+                Some(Regex::new(pattern).map_err(|e| format!("Invalid regex: {}", e))?)
+            }
+            None => None,
+        };
+        let last = match flags.value("last") {
+            Some(n) => Some(
+                n.parse::<usize>()
+                    .map_err(|_| format!("Invalid number of errors: {}", scope.err_str(n)))?,
+            ),
+            None => None,
+        };
+
+        let errors = match scope.lookup_value("__errors") {
+            Some(Value::List(errors)) => errors,
+            _ => return Ok(Value::success()),
+        };
+
+        let entries: Vec<&Value> = errors
+            .iter()
+            .filter(|entry| match flags.value("command") {
+                Some(name) => field(entry, "command").split_whitespace().next() == Some(name),
+                None => true,
+            })
+            .filter(|entry| match &pattern {
+                Some(re) => re.is_match(&field(entry, "message")),
+                None => true,
+            })
+            .collect();
+
+        let skip = last.map_or(0, |n| entries.len().saturating_sub(n));
+
+        for entry in &entries[skip..] {
+            if flags.is_present("json") {
+                my_println!("{}", to_json(entry))?;
+            } else {
+                my_println!("{}: {}", field(entry, "command"), field(entry, "message"))?;
+            }
+        }
+
+        Ok(Value::success())
+    }
+}
+
+#[ctor::ctor]
+fn register() {
+    register_command(ShellCommand {
+        name: "errors".to_string(),
+        inner: Arc::new(Errors::new()),
+    });
+}
@@ -93,6 +93,9 @@ impl Exec for Evaluate {
                 }
                 scope.insert("#".to_string(), Value::Int(n));
                 scope.insert("@".to_string(), Value::from(cmd_args.join(" ").as_str()));
+                // The arguments of the script, without the script itself, see argparse.
+                let script_args = cmd_args[1..].iter().map(|arg| Value::from(arg.as_str()));
+                scope.insert("__args".to_string(), Value::new_list(script_args.collect()));
 
                 script
             } else {
@@ -127,7 +130,9 @@ impl Exec for Evaluate {
                         // Export variables from the eval scope to the global scope
                         for (key, var) in eval_scope.vars().iter() {
                             if !key.is_special_var() {
-                                global_scope.vars_mut().insert(key.clone(), var.clone());
+                                let var = var.clone();
+                                var.set_exported();
+                                global_scope.vars_mut().insert(key.clone(), var);
                             }
                         }
                     } else if !command && !flags.is_present("quiet") {
@@ -1,5 +1,5 @@
 use super::{register_command, Exec, ShellCommand};
-use crate::{eval::Value, scope::Scope};
+use crate::{eval::Value, profiler, scope::Scope, traps};
 use std::process;
 use std::sync::Arc;
 
@@ -15,6 +15,8 @@ impl Exec for Exit {
             0
         };
 
+        traps::run(traps::Condition::Exit);
+        profiler::report();
         process::exit(exit_code);
     }
 }
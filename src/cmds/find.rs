@@ -38,7 +38,7 @@ impl Find {
 
         // Check if the current directory or file matches the pattern
         if regex.is_match(&file_name.to_string_lossy()) {
-            println!("{}", path.display());
+            my_println!("{}", path.display())?;
         }
 
         if search_path.is_dir() {
@@ -1,10 +1,10 @@
 use super::{flags::CommandFlags, register_command, Exec, Flag, ShellCommand};
-use crate::{eval::Value, scope::Scope, symlnk::SymLink};
+use crate::{eval::Value, scope::Scope, stdio, stdio::Terminal, symlnk::SymLink};
 use colored::Colorize;
 use regex::Regex;
 use std::collections::HashSet;
 use std::fs::{self, File};
-use std::io::{self, BufRead, BufReader, IsTerminal};
+use std::io::{BufRead, BufReader};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use url::Url;
@@ -183,7 +183,7 @@ impl Grep {
         use_color: bool,
         use_hyperlink: bool,
         invert_match: bool,
-    ) {
+    ) -> Result<(), String> {
         let line_to_check = if ignore_case {
             line.to_lowercase()
         } else {
@@ -233,8 +233,9 @@ impl Grep {
                 output.push_str(line);
             }
 
-            println!("{}", output);
+            my_println!("{}", output)?;
         }
+        Ok(())
     }
 }
 
@@ -269,7 +270,7 @@ impl Exec for Grep {
         let no_filename = flags.is_present("no-filename");
         let recursive = flags.is_present("recursive");
         let silent = !flags.is_present("messages");
-        let use_color = scope.lookup("NO_COLOR").is_none() && std::io::stdout().is_terminal();
+        let use_color = scope.lookup("NO_COLOR").is_none() && stdio::stdout().is_terminal();
         let use_filename = flags.is_present("with-filename");
         let use_hyperlink = flags.is_present("hyperlink");
 
@@ -284,7 +285,7 @@ impl Exec for Grep {
         if files.is_empty() {
             // Read from stdin if no files are provided
             scope.show_eof_hint();
-            let reader = io::stdin().lock();
+            let reader = stdio::stdin();
             for (line_number, line) in reader.lines().enumerate() {
                 if Scope::is_interrupted() {
                     break;
@@ -302,7 +303,7 @@ impl Exec for Grep {
                     use_color,
                     use_hyperlink,
                     invert_match,
-                );
+                )?;
             }
         } else {
             let mut visited = HashSet::new();
@@ -349,7 +350,7 @@ impl Exec for Grep {
                                     use_color,
                                     use_hyperlink,
                                     invert_match,
-                                ),
+                                )?,
                                 Err(e) => {
                                     if !silent {
                                         my_warning!(scope, "{}: {}", scope.err_path(path), e);
@@ -6,6 +6,7 @@ use super::{
 };
 use crate::{
     eval::Value,
+    funcs,
     scope::Scope,
     utils::{self, executable},
 };
@@ -73,31 +74,86 @@ impl Help {
         println!("EXPRESSIONS");
         println!("    Variable Assignment and Evaluation");
         println!("        Example: i = 5; echo $i");
+        println!("        Example: local i = 0; readonly MAX = 10; export EDITOR = vim  # const is the same as readonly");
         println!("    Conditional Statements");
         println!("        Example: if ($i > 0) (echo \"i is positive\") else (echo \"i is not positive\")");
+        println!("    Match");
+        println!("        Example: match $x (case start stop (echo $x); case *.log (echo log); case r\"(^v(\\d+))\" (echo $__match[1]); case _ (echo other))");
         println!("    Loops");
         println!("        Example: while ($i > 0) (echo $i; $i = $i - 1)");
         println!("        Example: for f in *.rs; (echo $f; ls -l $f)");
+        println!("        Example: for i in {{1..100..10}}; (echo $i); echo file{{1,2,3}}.txt {{a..e}}");
+        println!("        Example: for k, v in $map; (echo \"$k = $v\"); for i, x in enumerate($list); (echo $i $x)");
+        println!("        Example: find . \".*\\\\.txt\" | for f[] in -; (echo $f)  # Iterate over lines");
+        println!("    Lists");
+        println!("        Example: x = [a \"b c\" (1 + 2)]; echo ${{#x}} $x[0] $x[-1] $x[1:3]; x = $x + d");
+        println!("        Example: ls | lines[]; for line in $lines; (echo $line)");
+        println!("    Maps");
+        println!("        Example: m = [host: localhost, port: 8080]; $m[port] = 9090; echo $m[host] ${{#m}}");
+        println!("        Example: for key in $m; (echo \"$key = $m[$key]\"); if (host in $m) (echo found)");
+        println!("    Functions");
+        println!("        Example: def greet (name) (echo \"Hello, $name\"); greet World");
+        println!("        Example: def square (x) (return $x * $x); echo (square 5)");
+        println!("    Modules");
+        println!("        Example: import util; util.greet World; echo ${{util.VERSION}}  # util.my, in $SHMYPATH or ~/.shmy/lib");
         println!();
         println!("    Arithmetic Operators");
         println!("        '+': add, '-': subtract, '/': divide, '//': divide integers, '%': modulo, '*': multiply, '^': exponent");
         println!("    Logical Operators");
         println!("        '||': or, '&&': and");
         println!();
+        println!("BUILT-IN FUNCTIONS");
+        println!("    Called with no whitespace before the arguments: upper($x), substr($s, 0, 3)");
+        for func in funcs::functions() {
+            println!("    {:<32} {}", func.usage, func.help);
+        }
+        println!();
+        println!("PARAMETER EXPANSION");
+        println!("    ${{X:-word}}           X if set and not empty, otherwise word");
+        println!("    ${{X:+word}}           word if X is set and not empty, otherwise nothing");
+        println!("    ${{X:?message}}        X if set and not empty, otherwise fail with message");
+        println!("    ${{#X}}                Length of X (characters, list items or map entries)");
+        println!("    ${{X:N}}, ${{X:N:L}}     Substring from offset N, of length L (negative values count from the end)");
+        println!("    ${{X#pat}}, ${{X##pat}}  Remove shortest, longest prefix matching glob pattern");
+        println!("    ${{X%pat}}, ${{X%%pat}}  Remove shortest, longest suffix matching glob pattern");
+        println!("    ${{X^}}, ${{X^^}}        Convert first, all characters to uppercase");
+        println!("    ${{X,}}, ${{X,,}}        Convert first, all characters to lowercase");
+        println!("    ${{X/regex/repl}}      Replace regex matches, captures are referenced as \\1, \\2...");
+        println!();
         println!("COMMAND EXECUTION");
         println!("    A command evaluates to true if it succeeds (exit code 0) and false otherwise.");
         println!("    Errors from failed commands are stored in the special variable $__errors.");
+        println!("    $__errors is a list of maps (command, args, exit_code, message, file, line...), listed by: errors");
+        println!("    The exit code of the last command is stored in $__status; for processes killed by a signal,");
+        println!("    it is 128 + the signal number, and $__signal holds the signal name (e.g. SIGKILL).");
         println!("    If a command fails and its status is not evaluated, execution stops.");
+        println!("    Errors can be caught with: try (<body>) catch e (<handler>) finally (<cleanup>)");
+        println!("    $e is a map with: message, command, exit_code, signal, file, line, col. Raise errors with: throw <message>");
+        println!("    Cleanup on exit, unhandled error or Ctrl+C: trap <expr> [EXIT|ERR|INT]; at the end of a block: defer (<body>)");
+        println!("    Scripts and functions declare their flags and arguments with: argparse <spec>... -- $@");
+        println!("    A trailing & runs an expression in the background: <expression> &");
+        println!("    Background jobs are managed with: jobs, fg [%N], bg [%N], wait [%N], kill %N");
+        println!("    Scripts can be checked for errors without running them: shmy --check <script>");
+        println!("    Scripts can be printed with consistent formatting: shmy --fmt <script>");
+        println!("    Scripts can be run step by step, with breakpoints: shmy --debug <script>");
+        println!("    Expressions can be timed with: time <expression>, scripts profiled with: shmy --profile <script>");
         println!();
         println!("REDIRECTS");
         println!("    Output to file: <command> => <file path>");
         println!("    Append to file: <command> =>> <file path>");
+        println!("    Input from file: <command> =< <file path>");
+        println!("    Here-string: <command> <<< <expression>");
+        println!("    Here-document: <command> <<MARKER (text lines) MARKER, <<'MARKER' does not expand variables");
         println!();
         println!("PIPES");
         println!("    Pipe output between commands:");
         println!("        <expression> | <expression>");
         println!("    Example:");
         println!("        ls -al | (echo \"\\t\\tHEADER\"; cat; echo \"\\t\\tFOOTER\")");
+        println!("    The status of each stage is saved in $__pipestatus (0 for success, or the error).");
+        println!("    A stage killed by SIGPIPE, when a later stage stops reading (e.g. yes | head), does not fail.");
+        println!("    Substitute the output of commands in arguments and strings:");
+        println!("        echo \"Running on $(uname)\"");
         println!();
         println!("COMMAND HISTORY");
         println!("    The command history is saved in ~/.shmy/history.txt");
@@ -130,6 +186,7 @@ impl Help {
         println!("        __stderr = log.txt; ls -al");
         println!("        __stderr = __stdout; ls -al /");
         println!("        __stdout = some/path/file.txt; __stderr = 1; ls -al");
+        println!("    Trace commands and their status to stderr: __trace = 1 (or start the shell with -x)");
         println!();
         Self::print_hooks_help();
         Self::print_available_commands(4, 4);
@@ -174,6 +231,8 @@ impl Help {
 
                     if let Some(alias_def) = cmd.get_alias_def() {
                         eprintln!("{} is an alias for: \"{}\"", highlited_cmd, alias_def)
+                    } else if let Some(func) = cmd.as_function() {
+                        eprintln!("{} is a function: {}", highlited_cmd, func)
                     } else if cmd.is_external() {
                         #[cfg(windows)]
                         let help = "/? (or -h, --help)";
@@ -0,0 +1,184 @@
+use super::{flags::CommandFlags, register_command, Exec, Flag, ShellCommand};
+use crate::job::{self, Signal};
+use crate::{eval::Value, scope::Scope};
+use std::sync::Arc;
+
+/// Parse a job number: %N
+fn parse_job(spec: &str) -> Result<usize, String> {
+    spec.strip_prefix('%')
+        .and_then(|n| n.parse::<usize>().ok())
+        .ok_or_else(|| format!("Invalid job specification: {}", spec))
+}
+
+/// Parse an optional job number argument; the default is the most recent job.
+fn parse_optional_job(args: &[String]) -> Result<Option<usize>, String> {
+    match args {
+        [] => Ok(None),
+        [spec] => parse_job(spec).map(Some),
+        _ => Err("Too many arguments".to_string()),
+    }
+}
+
+struct JobControl {
+    flags: CommandFlags,
+}
+
+impl JobControl {
+    fn new() -> Self {
+        let flags = CommandFlags::with_help();
+        Self { flags }
+    }
+
+    fn print_help(&self, name: &str, flags: &CommandFlags) {
+        match name {
+            "jobs" => {
+                println!("Usage: jobs");
+                println!("List the jobs started in the background with the & operator.");
+            }
+            "fg" => {
+                println!("Usage: fg [%N]");
+                println!("Bring a background job to the foreground, and wait for it to complete.");
+            }
+            "bg" => {
+                println!("Usage: bg [%N]");
+                println!("Resume a stopped background job.");
+            }
+            "wait" => {
+                println!("Usage: wait [%N]...");
+                println!("Wait for background jobs to complete (all jobs, if none is specified).");
+            }
+            _ => unreachable!(),
+        }
+        println!("\nOptions:");
+        print!("{}", flags.help());
+    }
+}
+
+impl Exec for JobControl {
+    fn cli_flags(&self) -> Box<dyn Iterator<Item = &Flag> + '_> {
+        Box::new(self.flags.iter())
+    }
+
+    fn exec(&self, name: &str, args: &Vec<String>, scope: &Arc<Scope>) -> Result<Value, String> {
+        let mut flags = self.flags.clone();
+        let args = flags.parse(scope, args)?;
+
+        if flags.is_present("help") {
+            self.print_help(name, &flags);
+            return Ok(Value::success());
+        }
+
+        match name {
+            "jobs" => {
+                for job in job::list_background_jobs() {
+                    my_println!("{}", job)?;
+                }
+            }
+            "fg" => job::wait_background_job(parse_optional_job(&args)?, true)?,
+            "bg" => {
+                let job = job::resume_background_job(parse_optional_job(&args)?)?;
+                println!("{}", job);
+            }
+            "wait" => {
+                let ids = if args.is_empty() {
+                    job::background_job_ids()
+                } else {
+                    args.iter()
+                        .map(|spec| parse_job(spec))
+                        .collect::<Result<_, _>>()?
+                };
+
+                let mut result = Ok(());
+                for id in ids {
+                    let job_result = job::wait_background_job(Some(id), false);
+                    if result.is_ok() {
+                        result = job_result;
+                    }
+                    if Scope::is_interrupted() {
+                        break;
+                    }
+                }
+                result?
+            }
+            _ => unreachable!(),
+        }
+
+        Ok(Value::success())
+    }
+}
+
+struct Kill {
+    flags: CommandFlags,
+}
+
+impl Kill {
+    fn new() -> Self {
+        let mut flags = CommandFlags::with_help();
+        flags.add_value(
+            's',
+            "signal",
+            "SIGNAL",
+            "Signal to send (HUP, INT, KILL, TERM, STOP, CONT, or number)",
+        );
+        Self { flags }
+    }
+}
+
+impl Exec for Kill {
+    fn cli_flags(&self) -> Box<dyn Iterator<Item = &Flag> + '_> {
+        Box::new(self.flags.iter())
+    }
+
+    fn exec(&self, _name: &str, args: &Vec<String>, scope: &Arc<Scope>) -> Result<Value, String> {
+        let mut flags = self.flags.clone();
+        let args = flags.parse(scope, args)?;
+
+        if flags.is_present("help") {
+            println!("Usage: kill [OPTIONS] %N|PID...");
+            println!("Send a signal (TERM by default) to background jobs or processes.");
+            println!("\nOptions:");
+            print!("{}", flags.help());
+            return Ok(Value::success());
+        }
+
+        if args.is_empty() {
+            return Err("Missing job or process id".to_string());
+        }
+
+        let signal = match flags.value("signal") {
+            Some(signal) => signal.parse::<Signal>()?,
+            None => Signal::Terminate,
+        };
+
+        for arg in &args {
+            if arg.starts_with('%') {
+                job::signal_background_job(Some(parse_job(arg)?), signal)?;
+            } else {
+                let pid = arg
+                    .parse::<u32>()
+                    .map_err(|_| format!("Invalid process id: {}", scope.err_str(arg)))?;
+
+                job::kill(pid, signal).map_err(|e| format!("{}: {}", pid, e))?;
+            }
+        }
+
+        Ok(Value::success())
+    }
+}
+
+#[ctor::ctor]
+fn register() {
+    let job_control = Arc::new(JobControl::new());
+
+    for name in ["jobs", "fg", "bg", "wait"] {
+        register_command(ShellCommand {
+            name: name.to_string(),
+            inner: Arc::clone(&job_control) as Arc<dyn Exec>,
+        });
+    }
+
+    register_command(ShellCommand {
+        name: "kill".to_string(),
+        inner: Arc::new(Kill::new()),
+    });
+}
@@ -4,6 +4,7 @@ use crate::{
     eval::Value,
     prompt,
     scope::Scope,
+    stdio,
     symlnk::SymLink,
     utils::{self, format_error},
 };
@@ -149,7 +150,7 @@ fn create_file_content(
         }
     } else {
         scope.show_eof_hint();
-        Ok(Box::new(InMemoryContent::new(io::stdin().lock())?))
+        Ok(Box::new(InMemoryContent::new(stdio::stdin())?))
     }
 }
 
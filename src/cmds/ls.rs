@@ -1,6 +1,6 @@
 use super::{flags::CommandFlags, register_command, Exec, Flag, ShellCommand};
 use crate::utils::{self, format_size, read_symlink, MAX_USER_DISPLAY_LEN};
-use crate::{eval::Value, scope::Scope, symlnk::SymLink};
+use crate::{eval::Value, scope::Scope, stdio, symlnk::SymLink};
 use chrono::{DateTime, Local, Utc};
 use colored::*;
 use core::fmt;
@@ -17,7 +17,7 @@ struct ColorScheme {
 impl ColorScheme {
     fn with_scope(scope: &Arc<Scope>) -> Self {
         Self {
-            use_colors: scope.use_colors(&std::io::stdout()),
+            use_colors: scope.use_colors(&stdio::stdout()),
             scope: Arc::clone(&scope),
         }
     }
@@ -161,7 +161,7 @@ impl Exec for Dir {
             self.print_help(name);
             return Ok(Value::success());
         }
-        let mut stdout = std::io::stdout();
+        let mut stdout = stdio::stdout();
         let _disable_wrap = utils::DisableLineWrap::new(&mut stdout).map_err(|e| e.to_string())?;
 
         list_entries(scope, &mut opts, &args)
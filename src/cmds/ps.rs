@@ -2,6 +2,7 @@ use super::{flags::CommandFlags, register_command, Exec, Flag, ShellCommand};
 use crate::{
     eval::Value,
     scope::Scope,
+    stdio,
     utils::{format_error, MAX_USER_DISPLAY_LEN},
 };
 use colored::Colorize;
@@ -11,7 +12,7 @@ use std::{
     cmp::{Ord, Ordering, PartialOrd},
     collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     ffi::{OsStr, OsString},
-    fmt, io,
+    fmt,
     sync::Arc,
 };
 
@@ -398,7 +399,7 @@ impl View {
 
     /// Display a list of running processes.
     fn process_list(&self, scope: &Arc<Scope>) -> Result<(), String> {
-        let use_color = scope.use_colors(&io::stdout());
+        let use_color = scope.use_colors(&stdio::stdout());
         let mut header = String::new();
 
         for col in &self.columns {
@@ -728,7 +729,7 @@ fn print_tree(
             "├───┬"
         }
     };
-    let use_color = scope.use_colors(&io::stdout());
+    let use_color = scope.use_colors(&stdio::stdout());
     my_println!(
         "{}{} {} ({}) {} {}",
         if use_color {
@@ -861,7 +862,7 @@ impl Exec for ProcStatus {
             view.filters.push(Box::new(UserProc::new(&view.system)));
         }
 
-        let mut stdout = std::io::stdout();
+        let mut stdout = stdio::stdout();
         let _disable_wrap =
             crate::utils::DisableLineWrap::new(&mut stdout).map_err(|e| e.to_string())?;
 
@@ -1,8 +1,8 @@
 use super::{flags::CommandFlags, register_command, Exec, Flag, ShellCommand};
-use crate::{eval::Value, scope::Scope, symlnk::SymLink, utils::format_error};
+use crate::{eval::Value, scope::Scope, stdio, symlnk::SymLink, utils::format_error};
 use std::collections::HashSet;
 use std::fs::File;
-use std::io::{self, BufRead, BufReader};
+use std::io::{BufRead, BufReader};
 use std::path::Path;
 use std::sync::Arc;
 
@@ -84,7 +84,7 @@ impl Exec for Sort {
         if args.is_empty() {
             // Read from stdin if no files are provided
             scope.show_eof_hint();
-            let reader = io::stdin().lock();
+            let reader = stdio::stdin();
             for line in reader.lines() {
                 if Scope::is_interrupted() {
                     break;
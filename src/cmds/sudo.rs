@@ -1,6 +1,6 @@
 use super::{flags::CommandFlags, get_command, register_command, Exec, Flag, ShellCommand};
+use crate::stdio::{self, Terminal};
 use crate::{eval::Value, job::Job, scope::Scope, utils::executable};
-use std::io::IsTerminal;
 use std::path::Path;
 use std::sync::Arc;
 
@@ -43,11 +43,11 @@ impl Exec for Sudo {
         }
 
         if cfg!(not(debug_assertions)) {
-            if !std::io::stdin().is_terminal() {
+            if !stdio::stdin().is_terminal() {
                 return Err("Cannot pipe or redirect input to elevated command".to_string());
             }
 
-            if !std::io::stdout().is_terminal() || !std::io::stderr().is_terminal() {
+            if !stdio::stdout().is_terminal() || !std::io::stderr().is_terminal() {
                 return Err("Cannot pipe or redirect output from elevated command".to_string());
             }
         }
@@ -0,0 +1,26 @@
+use super::{register_command, Exec, ShellCommand};
+use crate::{eval::Value, scope::Scope};
+use std::sync::Arc;
+
+struct Throw;
+
+impl Exec for Throw {
+    fn exec(&self, _name: &str, args: &Vec<String>, scope: &Arc<Scope>) -> Result<Value, String> {
+        if !args.is_empty() {
+            return Err(args.join(" "));
+        }
+        // Without arguments, re-raise the error caught by the enclosing CATCH block.
+        match scope.lookup_value("__caught") {
+            Some(caught) => Ok(caught),
+            None => Err("Missing error message (no caught error to re-raise)".to_string()),
+        }
+    }
+}
+
+#[ctor::ctor]
+fn register() {
+    register_command(ShellCommand {
+        name: "throw".to_string(),
+        inner: Arc::new(Throw),
+    });
+}
@@ -0,0 +1,152 @@
+use super::{flags::CommandFlags, register_command, Exec, Flag, ShellCommand};
+use crate::{eval::Interp, eval::Value, scope::Scope};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+struct Time {
+    flags: CommandFlags,
+}
+
+impl Time {
+    fn new() -> Self {
+        let mut flags = CommandFlags::with_help();
+        flags.add_flag(
+            'q',
+            "quiet",
+            "Do not print the times, only store them in variables",
+        );
+
+        Self { flags }
+    }
+}
+
+/// User and system CPU time used so far by the shell process (builtin commands and
+/// pipeline threads run in-process), and by the child processes that have been waited for.
+#[cfg(unix)]
+fn cpu_times() -> (Duration, Duration) {
+    use nix::sys::resource::{getrusage, UsageWho};
+    use nix::sys::time::TimeVal;
+
+    fn duration(time: TimeVal) -> Duration {
+        Duration::new(time.tv_sec() as u64, time.tv_usec() as u32 * 1000)
+    }
+
+    let mut user = Duration::ZERO;
+    let mut sys = Duration::ZERO;
+
+    for who in [UsageWho::RUSAGE_SELF, UsageWho::RUSAGE_CHILDREN] {
+        if let Ok(usage) = getrusage(who) {
+            user += duration(usage.user_time());
+            sys += duration(usage.system_time());
+        }
+    }
+    (user, sys)
+}
+
+/// User and system (kernel) CPU time used so far by the shell process;
+/// the times of the child processes are not available.
+#[cfg(windows)]
+fn cpu_times() -> (Duration, Duration) {
+    use windows::Win32::Foundation::FILETIME;
+    use windows::Win32::System::Threading::{GetCurrentProcess, GetProcessTimes};
+
+    fn duration(time: FILETIME) -> Duration {
+        // FILETIME counts 100-nanosecond intervals
+        let ticks = ((time.dwHighDateTime as u64) << 32) | time.dwLowDateTime as u64;
+        Duration::from_nanos(ticks * 100)
+    }
+
+    let mut creation = FILETIME::default();
+    let mut exit = FILETIME::default();
+    let mut kernel = FILETIME::default();
+    let mut user = FILETIME::default();
+
+    unsafe {
+        match GetProcessTimes(
+            GetCurrentProcess(),
+            &mut creation,
+            &mut exit,
+            &mut kernel,
+            &mut user,
+        ) {
+            Ok(_) => (duration(user), duration(kernel)),
+            Err(_) => (Duration::ZERO, Duration::ZERO),
+        }
+    }
+}
+
+impl Exec for Time {
+    fn cli_flags(&self) -> Box<dyn Iterator<Item = &Flag> + '_> {
+        Box::new(self.flags.iter())
+    }
+
+    fn exec(&self, _name: &str, args: &Vec<String>, scope: &Arc<Scope>) -> Result<Value, String> {
+        // Options of the time command come first, the rest (including the
+        // options of the timed command, if any) is the expression to evaluate.
+        let count = args.iter().take_while(|arg| arg.starts_with('-')).count();
+        let mut flags = self.flags.clone();
+        flags.parse(scope, &args[..count])?;
+        let args = &args[count..];
+
+        if flags.is_present("help") {
+            println!("Usage: time [OPTIONS] EXPR...");
+            println!("Evaluate the expression and report the elapsed real (wall clock), user and system time.");
+            println!("\nOptions:");
+            println!("{}", flags.help());
+            println!("The arguments are joined with spaces into one expression; quote non-trivial expressions, e.g.");
+            println!("    time \"ls -R | wc -l\"");
+            println!();
+            println!("The times, in seconds, are also stored in the $__time_real, $__time_user and $__time_sys variables.");
+            println!();
+            return Ok(Value::success());
+        }
+
+        if args.is_empty() {
+            return Err("Missing expression to time".to_string());
+        }
+        let input = args.join(" ");
+
+        let eval_scope = Scope::with_parent_and_hooks(Some(scope.clone()), None);
+        let mut interp = Interp::new(scope.clone());
+
+        let (user_start, sys_start) = cpu_times();
+        let start = Instant::now();
+
+        let result = interp.eval(&input, Some(eval_scope));
+
+        let real = start.elapsed();
+        let (user_end, sys_end) = cpu_times();
+        let user = user_end.saturating_sub(user_start);
+        let sys = sys_end.saturating_sub(sys_start);
+
+        for (name, time) in [("real", real), ("user", user), ("sys", sys)] {
+            scope.insert(format!("__time_{}", name), Value::Real(time.as_secs_f64()));
+            if !flags.is_present("quiet") {
+                eprintln!("{:<6}{:.3}s", name, time.as_secs_f64());
+            }
+        }
+
+        match result {
+            Err(e) => {
+                e.show(scope, &input);
+                Err(format!("Error evaluating '{}'", input))
+            }
+            Ok(Value::Stat(mut status)) => match status.err() {
+                Some(e) => Err(e.to_string()),
+                None => Ok(Value::success()),
+            },
+            Ok(value) => {
+                my_println!("{}", value)?;
+                Ok(Value::success())
+            }
+        }
+    }
+}
+
+#[ctor::ctor]
+fn register() {
+    register_command(ShellCommand {
+        name: "time".to_string(),
+        inner: Arc::new(Time::new()),
+    });
+}
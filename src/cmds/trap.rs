@@ -0,0 +1,74 @@
+use super::{flags::CommandFlags, register_command, Exec, Flag, ShellCommand};
+use crate::traps::{self, Condition};
+use crate::{eval::Value, scope::Scope};
+use std::sync::Arc;
+
+struct Trap {
+    flags: CommandFlags,
+}
+
+impl Trap {
+    fn new() -> Self {
+        let mut flags = CommandFlags::with_help();
+        flags.add_flag('l', "list", "List the traps, in the order they run");
+        flags.add(None, "clear", None, "Remove all traps");
+
+        Self { flags }
+    }
+}
+
+impl Exec for Trap {
+    fn cli_flags(&self) -> Box<dyn Iterator<Item = &Flag> + '_> {
+        Box::new(self.flags.iter())
+    }
+
+    fn exec(&self, _name: &str, args: &Vec<String>, scope: &Arc<Scope>) -> Result<Value, String> {
+        let mut flags = self.flags.clone();
+        let args = flags.parse(scope, args)?;
+
+        if flags.is_present("help") {
+            println!("Usage: trap [OPTIONS] EXPR [EXIT|ERR|INT]...");
+            println!("Register an expression to run when the script exits (EXIT, the default), when an error");
+            println!("is not handled (ERR), or when the script is interrupted with Ctrl+C (INT).");
+            println!("\nOptions:");
+            print!("{}", flags.help());
+            println!();
+            println!("Traps run in reverse order of registration; EXIT traps also run after ERR and INT traps,");
+            println!("when the script stops because of the error or interruption. Example:");
+            println!("    tmp = $(mktemp -d); trap \"rm -rf $tmp\" EXIT");
+            println!();
+            return Ok(Value::success());
+        }
+
+        if flags.is_present("clear") {
+            traps::clear();
+            return Ok(Value::success());
+        }
+
+        if args.is_empty() || flags.is_present("list") {
+            for trap in traps::list() {
+                my_println!("{}", trap)?;
+            }
+            return Ok(Value::success());
+        }
+
+        let mut conditions = args[1..]
+            .iter()
+            .map(|arg| arg.parse::<Condition>())
+            .collect::<Result<Vec<_>, _>>()?;
+        if conditions.is_empty() {
+            conditions.push(Condition::Exit);
+        }
+
+        traps::add(args[0].clone(), conditions, scope);
+        Ok(Value::success())
+    }
+}
+
+#[ctor::ctor]
+fn register() {
+    register_command(ShellCommand {
+        name: "trap".to_string(),
+        inner: Arc::new(Trap::new()),
+    });
+}
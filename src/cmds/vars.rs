@@ -2,7 +2,6 @@ use super::{flags::CommandFlags, register_command, Exec, Flag, ShellCommand};
 use crate::{eval::Value, scope::Ident, scope::Scope, scope::Variable};
 use std::borrow::Cow;
 use std::collections::BTreeMap;
-use std::env;
 use std::sync::Arc;
 
 struct Vars {
@@ -78,15 +77,12 @@ impl Exec for Vars {
         let local_only = flags.is_present("local");
 
         if !local_only && name == "env" {
-            // Print the environment directly.
-            let vars: Vec<String> = env::vars().map(|(key, _)| key).collect();
-
-            for key in vars {
-                my_println!(
-                    "{}={}",
-                    key,
-                    env::var(&key).map_err(|e| e.to_string())?.escape(quote)
-                )?;
+            // Print the exported variables, i.e. the environment of external commands.
+            let vars = Self::collect_vars(scope, false);
+            for (key, var) in vars {
+                if var.is_exported() && !key.is_special_var() && !var.value().is_map() {
+                    my_println!("{}={}", key, var.value().as_str().escape(quote))?;
+                }
             }
         } else {
             let vars = Self::collect_vars(scope, local_only);
@@ -1,6 +1,6 @@
 use super::{flags::CommandFlags, register_command, Exec, Flag, ShellCommand};
 use crate::utils::format_error;
-use crate::{eval::Value, scope::Scope, symlnk::SymLink};
+use crate::{eval::Value, scope::Scope, stdio, symlnk::SymLink};
 use std::fs::{self, File};
 use std::io::{self, BufRead, BufReader};
 #[cfg(windows)]
@@ -62,7 +62,7 @@ impl WordCount {
 
     fn count_stdin(scope: &Arc<Scope>) -> io::Result<CountResult> {
         scope.show_eof_hint();
-        let reader = io::stdin().lock();
+        let reader = stdio::stdin();
         let mut result = CountResult {
             lines: 0,
             words: 0,
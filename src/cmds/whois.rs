@@ -1,5 +1,5 @@
 use super::{flags::CommandFlags, register_command, Exec, Flag, ShellCommand};
-use crate::{eval::Value, scope::Scope, utils::format_error};
+use crate::{eval::Value, scope::Scope, stdio, utils::format_error};
 use std::io::{BufRead, BufReader, Write};
 use std::sync::Arc;
 use std::time::Duration;
@@ -94,7 +94,7 @@ impl Exec for Whois {
             .parse::<u64>()
             .map_err(|e| format!("Error parsing timeout value: {}", e))?;
 
-        let mut stdout = std::io::stdout();
+        let mut stdout = stdio::stdout();
         let _disable_wrap =
             crate::utils::DisableLineWrap::new(&mut stdout).map_err(|e| e.to_string())?;
 
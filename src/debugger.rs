@@ -0,0 +1,330 @@
+use crate::eval::{Location, Value};
+use crate::scope::Scope;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Read a line with the given prompt; None at the end of input, or when interrupted.
+pub type ReadLine = Box<dyn FnMut(&str) -> Option<String>>;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Step,        // Stop at the next statement, including in functions, aliases and sourced files
+    Next(usize), // Stop at the next statement, at the given depth of nested commands or above
+    Out(usize),  // Stop at the next statement above the given depth
+    Continue,    // Stop at breakpoints only
+}
+
+///
+/// Interactive script debugger, enabled with the --debug command line flag.
+///
+/// The interpreter calls before_statement ahead of evaluating each statement in a block;
+/// the debugger decides whether to stop there, and if so, reads commands from the user with
+/// the line reader installed by main.rs (the rustyline editor used in interactive mode).
+///
+struct Debugger {
+    read_line: ReadLine,
+    mode: Mode,
+    breakpoints: BTreeSet<(String, u32)>,
+    sources: HashMap<Arc<String>, Vec<String>>, // Lines of the files being debugged
+    file: Option<Arc<String>>,                  // File of the current statement
+    line: u32,                                  // Line of the current statement
+    line_entry: usize,                          // First statement run on the current line
+    last_command: String,
+}
+
+thread_local! {
+    static DEBUGGER: RefCell<Option<Debugger>> = const { RefCell::new(None) };
+}
+
+const HELP: &str = "\
+Debugger commands:
+  s, step                  Run the statement, stepping into functions, aliases and sourced files
+  n, next                  Run the statement, stepping over functions, aliases and sourced files
+  o, out                   Run until the current function, alias or sourced file returns
+  c, continue              Run until a breakpoint is reached
+  b, break [FILE:]LINE     Set a breakpoint
+  d, delete [[FILE:]LINE]  Delete a breakpoint, or all breakpoints
+  i, info                  List the breakpoints
+  l, list [LINE]           List the source code around the current (or the given) line
+  p, print NAME...         Print the values of variables
+  set NAME VALUE           Assign a value to a variable
+  v, vars                  List the variables in scope (not including the environment)
+  q, quit                  Quit the interpreter
+  h, help                  Show this help
+An empty line repeats the last command.";
+
+/// Start debugging; stop at the first statement.
+pub fn start(read_line: ReadLine) {
+    eprintln!("Type h for help on debugger commands.");
+
+    DEBUGGER.with(|debugger| {
+        *debugger.borrow_mut() = Some(Debugger {
+            read_line,
+            mode: Mode::Step,
+            breakpoints: BTreeSet::new(),
+            sources: HashMap::new(),
+            file: None,
+            line: 0,
+            line_entry: 0,
+            last_command: String::new(),
+        })
+    });
+}
+
+pub fn is_active() -> bool {
+    DEBUGGER.with(|debugger| debugger.borrow().is_some())
+}
+
+/// Called before evaluating a statement; depth is the number of nested commands
+/// (functions, aliases, eval --source) that are running.
+pub fn before_statement(
+    loc: &Location,
+    statement: &dyn fmt::Display,
+    scope: &Arc<Scope>,
+    depth: usize,
+) {
+    DEBUGGER.with(|debugger| {
+        if let Some(debugger) = debugger.borrow_mut().as_mut() {
+            debugger.before_statement(loc, statement, scope, depth);
+        }
+    });
+}
+
+impl Debugger {
+    fn before_statement(
+        &mut self,
+        loc: &Location,
+        statement: &dyn fmt::Display,
+        scope: &Arc<Scope>,
+        depth: usize,
+    ) {
+        let id = statement as *const dyn fmt::Display as *const () as usize;
+        let file = loc.file.clone();
+        let new_file = self.file != file;
+        self.file = file;
+
+        let line = self.statement_line(loc);
+        // The line is also entered again when a loop repeats its first statement.
+        let new_line = new_file || self.line != line || self.line_entry == id;
+        if new_file || self.line != line {
+            self.line_entry = id;
+        }
+        self.line = line;
+
+        let stepping = match self.mode {
+            Mode::Step => true,
+            Mode::Next(max_depth) => depth <= max_depth,
+            Mode::Out(max_depth) => depth < max_depth,
+            Mode::Continue => false,
+        };
+
+        // Stop at breakpoints once per line, not at each statement on the line.
+        if !(stepping || new_line && self.is_breakpoint()) {
+            return;
+        }
+
+        match &self.file {
+            Some(file) => eprint!("{}:{}: ", file, line),
+            None => eprint!("{}: ", line),
+        }
+        match self.source_line(line) {
+            Some(text) => eprintln!("{}", text.trim()),
+            None => eprintln!("{}", statement),
+        }
+
+        self.prompt(scope, depth);
+    }
+
+    /// Read and execute debugger commands, until one that resumes the execution.
+    fn prompt(&mut self, scope: &Arc<Scope>, depth: usize) {
+        loop {
+            let input = match (self.read_line)("(debug) ") {
+                Some(input) => input,
+                None => std::process::exit(1),
+            };
+            let input = if input.trim().is_empty() {
+                self.last_command.clone()
+            } else {
+                input.trim().to_string()
+            };
+            self.last_command = input.clone();
+
+            let mut words = input.split_whitespace();
+            let command = words.next().unwrap_or_default();
+            let args: Vec<&str> = words.collect();
+
+            match command {
+                "s" | "step" => self.mode = Mode::Step,
+                "n" | "next" => self.mode = Mode::Next(depth),
+                "o" | "out" => self.mode = Mode::Out(depth),
+                "c" | "continue" => self.mode = Mode::Continue,
+                "b" | "break" => match self.parse_breakpoint(&args) {
+                    Ok(breakpoint) => {
+                        eprintln!("Breakpoint set at {}:{}", breakpoint.0, breakpoint.1);
+                        self.breakpoints.insert(breakpoint);
+                    }
+                    Err(e) => eprintln!("{}", e),
+                },
+                "d" | "delete" if args.is_empty() => self.breakpoints.clear(),
+                "d" | "delete" => match self.parse_breakpoint(&args) {
+                    Ok(breakpoint) => {
+                        if !self.breakpoints.remove(&breakpoint) {
+                            eprintln!("No breakpoint at {}:{}", breakpoint.0, breakpoint.1);
+                        }
+                    }
+                    Err(e) => eprintln!("{}", e),
+                },
+                "i" | "info" => {
+                    for (file, line) in &self.breakpoints {
+                        eprintln!("{}:{}", file, line);
+                    }
+                }
+                "l" | "list" => match args.first().map(|line| line.parse::<u32>()) {
+                    Some(Ok(line)) => self.list(line),
+                    Some(Err(_)) => eprintln!("Invalid line number: {}", args[0]),
+                    None => self.list(self.line),
+                },
+                "p" | "print" => {
+                    for name in &args {
+                        let name = name.trim_start_matches('$');
+                        match scope.lookup_value(name) {
+                            Some(value) => eprintln!("${} = {}", name, value),
+                            None => eprintln!("${} is not defined", name),
+                        }
+                    }
+                }
+                "set" => Self::set_var(scope, &args),
+                "v" | "vars" => Self::print_vars(scope),
+                "q" | "quit" => std::process::exit(1),
+                "h" | "help" => eprintln!("{}", HELP),
+                _ => eprintln!("Unknown debugger command: {}, type h for help", command),
+            }
+
+            if matches!(
+                command,
+                "s" | "step" | "n" | "next" | "o" | "out" | "c" | "continue"
+            ) {
+                break;
+            }
+        }
+    }
+
+    /// Parse [FILE:]LINE, the file defaults to the current one.
+    fn parse_breakpoint(&self, args: &[&str]) -> Result<(String, u32), String> {
+        let spec = match args {
+            [spec] => spec,
+            _ => return Err("Expecting breakpoint location: [FILE:]LINE".to_string()),
+        };
+        let (file, line) = match spec.rsplit_once(':') {
+            Some((file, line)) => (file.to_string(), line),
+            None => match &self.file {
+                Some(file) => (file.to_string(), *spec),
+                None => return Err("Expecting file name in breakpoint location".to_string()),
+            },
+        };
+        match line.parse::<u32>() {
+            Ok(line) if line > 0 => Ok((file, line)),
+            _ => Err(format!("Invalid line number: {}", line)),
+        }
+    }
+
+    /// Check for a breakpoint at the current line; the breakpoint file
+    /// may be a trailing part of the path, e.g. script.my for scripts/script.my
+    fn is_breakpoint(&self) -> bool {
+        match &self.file {
+            Some(file) => self
+                .breakpoints
+                .iter()
+                .any(|(bp_file, line)| *line == self.line && Path::new(&**file).ends_with(bp_file)),
+            None => false,
+        }
+    }
+
+    /// Get the lines of the current file, reading the file the first time.
+    fn source(&mut self) -> Option<&Vec<String>> {
+        let file = self.file.as_ref()?;
+        if !self.sources.contains_key(file) {
+            let text = fs::read_to_string(&**file).ok()?;
+            self.sources
+                .insert(Arc::clone(file), text.lines().map(String::from).collect());
+        }
+        self.sources.get(file)
+    }
+
+    fn source_line(&mut self, line: u32) -> Option<String> {
+        self.source()?.get(line as usize - 1).cloned()
+    }
+
+    fn statement_line(&mut self, loc: &Location) -> u32 {
+        match self.source() {
+            Some(lines) => loc.statement_line(lines),
+            None => loc.line,
+        }
+    }
+
+    fn list(&mut self, center: u32) {
+        let current = self.line;
+        let breakpoints: Vec<u32> = self
+            .breakpoints
+            .iter()
+            .filter(|(file, _)| {
+                self.file
+                    .as_ref()
+                    .is_some_and(|f| Path::new(&**f).ends_with(file))
+            })
+            .map(|(_, line)| *line)
+            .collect();
+
+        match self.source() {
+            Some(lines) => {
+                let first = center.saturating_sub(5).max(1);
+                for (n, text) in lines.iter().enumerate().skip(first as usize - 1).take(11) {
+                    let n = n as u32 + 1;
+                    let marker = if n == current { ">" } else { " " };
+                    let bp = if breakpoints.contains(&n) { "*" } else { " " };
+                    eprintln!("{}{}{:4}  {}", marker, bp, n, text);
+                }
+            }
+            None => eprintln!("No source file"),
+        }
+    }
+
+    fn set_var(scope: &Arc<Scope>, args: &[&str]) {
+        let (name, value) = match args {
+            [name, "=", value @ ..] | [name, value @ ..] if !value.is_empty() => {
+                (name.trim_start_matches('$'), value.join(" "))
+            }
+            _ => return eprintln!("Usage: set NAME VALUE"),
+        };
+        let value = Value::from(value.as_str());
+
+        match scope.lookup(name) {
+            Some(var) => {
+                _ = var.assign(value);
+            }
+            None => scope.insert(name.to_string(), value),
+        }
+    }
+
+    /// Print the variables visible in the current scope, except for the
+    /// global scope, which holds the environment variables.
+    fn print_vars(scope: &Arc<Scope>) {
+        let mut vars = BTreeMap::new();
+        let mut current = Some(scope);
+
+        while let Some(scope) = current.filter(|scope| scope.parent.is_some()) {
+            for (name, var) in scope.vars().iter() {
+                vars.entry(name.to_string())
+                    .or_insert_with(|| var.value().to_string());
+            }
+            current = scope.parent.as_ref();
+        }
+        for (name, value) in vars {
+            eprintln!("${} = {}", name, value);
+        }
+    }
+}
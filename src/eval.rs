@@ -1,26 +1,41 @@
-use crate::cmds::{get_command, Exec, ShellCommand};
+use crate::cmds::{get_command, register_command, Exec, ShellCommand};
+use crate::debugger;
+use crate::funcs::{self, BuiltinFunction};
+use crate::job::{self, Exit, Process};
+use crate::modules::{self, Module};
+use crate::profiler;
 use crate::prompt::{confirm, Answer};
-use crate::scope::Scope;
-use crate::utils::{self, copy_vars_to_command_env, executable};
+use crate::scope::{Scope, Variable};
+use crate::stdio::{self, Terminal};
+use crate::symlnk::SymLink;
+use crate::traps;
+use crate::utils;
 use colored::*;
-use gag::{BufferRedirect, Gag, Redirect};
+use gag::Redirect;
 use glob::glob;
+use os_pipe::{PipeReader, PipeWriter};
+use parking_lot::RwLock;
 use regex::Regex;
+use std::any::Any;
 use std::borrow::Cow;
-use std::cell::RefCell;
+use std::cell::Cell;
 use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::{self, Debug};
 use std::fs::{File, OpenOptions};
-use std::io::{self, ErrorKind, IsTerminal, Read, Write};
+use std::io::{self, ErrorKind, Read, Write};
 use std::iter::Peekable;
+use std::ops::Range;
 use std::path::Path;
-use std::process::{Command as StdCommand, Stdio};
-use std::rc::Rc;
+use std::process::Stdio;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
+use std::thread;
+use std::time::Instant;
 
-pub const KEYWORDS: [&str; 8] = [
-    "BREAK", "CONTINUE", "ELSE", "FOR", "IF", "IN", "QUIT", "WHILE",
+pub const KEYWORDS: [&str; 21] = [
+    "BREAK", "CASE", "CATCH", "CONST", "CONTINUE", "DEF", "DEFER", "ELSE", "EXPORT", "FINALLY",
+    "FOR", "IF", "IMPORT", "IN", "LOCAL", "MATCH", "QUIT", "READONLY", "RETURN", "TRY", "WHILE",
 ];
 
 const ASSIGN_STATUS_ERROR: &str = "Assignment of command status to variable is not allowed.
@@ -32,31 +47,49 @@ const ERR_ADD_NUM_STATUS: &str = "Cannot add number and command status";
 const ERR_ADD_STATUS: &str = "Cannot add to command status";
 const ERR_CMP_NUM_STR: &str = "Cannot compare number to string";
 const ERR_CMP_STR_NUM: &str = "Cannot compare string to number";
+const ERR_CMP_LIST: &str = "Lists and maps can only be compared for equality";
+const ERR_ADD_LIST_STATUS: &str = "Cannot add command status to list";
+const ERR_ADD_NUM_MAP: &str = "Cannot add number and map";
+const ERR_ADD_MAP: &str = "Cannot add to map, use: $map[key] = value";
 const ERR_MUL_NUM_STR: &str = "Cannot multiply number by string";
 const ERR_MUL_STR_NUM: &str = "Cannot multiply string by number";
 const ERR_MUL_STR_STR: &str = "Cannot multiply strings";
 const ERR_MUL_STATUS: &str = "Cannot multiply command statuses";
+const ERR_MUL_LIST: &str = "Cannot multiply lists or maps";
 const ERR_SUB_NUM_STR: &str = "Cannot subtract string from number";
 const ERR_SUB_NUM_STATUS: &str = "Cannot subtract command status from number";
 const ERR_SUB_STR_NUM: &str = "Cannot subtract number from string";
 const ERR_SUB_STR_STATUS: &str = "Cannot subtract command status from string";
 const ERR_SUB_STATUS: &str = "Cannot subtract from command status";
+const ERR_SUB_LIST: &str = "Cannot subtract lists or maps";
 const ERR_POW_STR_EXP: &str = "Exponent cannot be a string";
 const ERR_POW_STATUS_EXP: &str = "Exponent cannot be a command status";
+const ERR_POW_LIST_EXP: &str = "Exponent cannot be a list or map";
 const ERR_POW_INVALID_BASE: &str = "Invalid base type";
 
 const NULL_REDIRECT: &str = "NULL";
 
+/// Guard against runaway recursion in user-defined functions.
+const MAX_CALL_DEPTH: usize = 200;
+
+/// Stack size of the threads that evaluate pipeline stages and background jobs, as for the
+/// main thread (the default for spawned threads is too small for MAX_CALL_DEPTH nested calls).
+const THREAD_STACK_SIZE: usize = 8 * 1024 * 1024;
+
 #[derive(Clone, Debug, PartialEq)]
 enum Op {
     And,
     Append,
     Assign,
+    Background,
     Div,
     Equals,
+    Export,
     Gt,
     Gte,
+    In,
     IntDiv,
+    Local,
     Minus,
     Mod,
     Mul,
@@ -68,6 +101,10 @@ enum Op {
     Pipe,
     Plus,
     Power,
+    Read,
+    Readonly,
+    HereString,
+    Return,
     Write,
 }
 
@@ -77,11 +114,15 @@ impl fmt::Display for Op {
             Op::And => write!(f, "&&"),
             Op::Append => write!(f, "=>>"),
             Op::Assign => write!(f, "="),
+            Op::Background => write!(f, "&"),
             Op::Div => write!(f, "/"),
             Op::Equals => write!(f, "=="),
+            Op::Export => write!(f, "export"),
             Op::Gt => write!(f, ">"),
             Op::Gte => write!(f, ">="),
+            Op::In => write!(f, "in"),
             Op::IntDiv => write!(f, "//"),
+            Op::Local => write!(f, "local"),
             Op::Minus => write!(f, "-"),
             Op::Mod => write!(f, "%"),
             Op::Mul => write!(f, "*"),
@@ -93,6 +134,10 @@ impl fmt::Display for Op {
             Op::Pipe => write!(f, "|"),
             Op::Plus => write!(f, "+"),
             Op::Power => write!(f, "^"),
+            Op::Readonly => write!(f, "readonly"),
+            Op::Return => write!(f, "return"),
+            Op::HereString => write!(f, "<<<"),
+            Op::Read => write!(f, "=<"),
             Op::Write => write!(f, "=>"),
         }
     }
@@ -110,16 +155,27 @@ impl Op {
         match &self {
             // Give logical ops same (lowest) priority as assignment so that parentheses are not
             // needed in: ```a == b || b = c``` i.e. we don't need to write ```a == b || (b = c)```
-            Op::Assign | Op::Pipe | Op::Or | Op::And => Priority::VeryLow,
+            Op::Assign
+            | Op::Background
+            | Op::Pipe
+            | Op::Or
+            | Op::And
+            | Op::Return
+            | Op::Local
+            | Op::Readonly
+            | Op::Export => Priority::VeryLow,
             Op::Append
             | Op::Gt
             | Op::Gte
+            | Op::In
             | Op::Lt
             | Op::Lte
             | Op::Not
             | Op::NotEquals
             | Op::Minus
             | Op::Plus
+            | Op::HereString
+            | Op::Read
             | Op::Write => Priority::Low,
             _ => Priority::High,
         }
@@ -128,6 +184,10 @@ impl Op {
     fn is_unary_ok(&self) -> bool {
         return matches!(&self, Op::Minus | Op::Not);
     }
+
+    fn is_declaration(&self) -> bool {
+        matches!(&self, Op::Local | Op::Readonly | Op::Export)
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -135,6 +195,7 @@ struct Text {
     value: Arc<String>,
     quoted: bool,
     raw: bool,
+    range: bool, // Unquoted {START..END[..STEP]} in FOR arguments
 }
 
 impl Text {
@@ -143,6 +204,14 @@ impl Text {
             value: Arc::new(value),
             quoted,
             raw,
+            range: false,
+        }
+    }
+
+    fn range(value: String) -> Self {
+        Self {
+            range: true,
+            ..Self::new(value, false, false)
         }
     }
 
@@ -151,13 +220,23 @@ impl Text {
     }
 }
 
+impl fmt::Display for Text {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.quoted {
+            if self.raw {
+                write!(f, "r\"({})\"", &self.value)
+            } else {
+                write!(f, "\"{}\"", &self.value.escape_default())
+            }
+        } else {
+            write!(f, "{}", &self.value)
+        }
+    }
+}
+
 impl From<String> for Token {
     fn from(value: String) -> Self {
-        Token::Literal(Text {
-            value: Arc::new(value),
-            quoted: false,
-            raw: false,
-        })
+        Token::Literal(Text::new(value, false, false))
     }
 }
 
@@ -169,6 +248,11 @@ enum Token {
     Operator(Op),
     LeftParen,
     RightParen,
+    LeftBracket,
+    RightBracket,
+    Key(Text),     // Map key, followed by colon
+    HereDoc(Text), // Here-document text, see Parser::here_doc
+    Colon,
     Semicolon,
 }
 
@@ -216,8 +300,32 @@ impl Location {
         self.col = 0;
     }
 
+    /// Statement locations are where the previous expression ended; find the line
+    /// in the source where the statement starts, skipping separators and comments.
+    pub fn statement_line(&self, lines: &[String]) -> u32 {
+        // Columns start at 1 on the first line, and at 0 on the following lines.
+        let mut skip = if self.line == 1 {
+            self.col.saturating_sub(1)
+        } else {
+            self.col
+        } as usize;
+
+        for (n, text) in lines.iter().enumerate().skip(self.line as usize - 1) {
+            for c in text.chars().skip(skip) {
+                if c == '#' {
+                    break;
+                }
+                if !c.is_whitespace() && !";()".contains(c) {
+                    return n as u32 + 1;
+                }
+            }
+            skip = 0;
+        }
+        self.line
+    }
+
     /// Format error message with location.
-    pub fn error<T: IsTerminal>(&self, scope: &Arc<Scope>, message: &str, output: &T) -> String {
+    pub fn error<T: Terminal>(&self, scope: &Arc<Scope>, message: &str, output: &T) -> String {
         if scope.use_colors(output) {
             let msgs: Vec<&str> = message.split('\n').collect();
             let mut err_msg = String::new();
@@ -256,6 +364,18 @@ macro_rules! derive_has_location {
     };
 }
 
+/// The parser binds expressions to scopes; evaluate them in the scope that stands in for the
+/// bound one on the current thread (see Scope::resolve), e.g. a copy, in pipeline stages.
+macro_rules! derive_scope {
+    ($type:ty) => {
+        impl $type {
+            fn scope(&self) -> Arc<Scope> {
+                self.scope.resolve()
+            }
+        }
+    };
+}
+
 /// Status of command execution.
 ///
 /// The idea is to not fail immediatelly, but propagate to either an IF expression
@@ -264,6 +384,7 @@ macro_rules! derive_has_location {
 #[derive(Clone, Debug, PartialEq)]
 pub struct Status {
     cmd: String,
+    args: Vec<String>, // Expanded arguments of the command
     neg: bool,
     loc: Location,
     err: Option<EvalError>,
@@ -275,6 +396,7 @@ impl Status {
     fn new(cmd: String, result: EvalResult<Value>, loc: &Location) -> Box<Self> {
         Box::new(Self {
             cmd,
+            args: Vec::new(),
             neg: false,
             loc: loc.clone(),
             err: result.err(),
@@ -284,7 +406,7 @@ impl Status {
     fn as_bool(&self, scope: &Arc<Scope>) -> bool {
         let mut result = true;
         if let Some(e) = &self.err {
-            Self::append_line_to(scope, "__errors", format!("{}: {}", self.cmd, &e.message));
+            self.log_error(scope, e);
             result = false;
         }
 
@@ -308,7 +430,9 @@ impl Status {
                 }
 
                 if let Some(e) = &status.err {
-                    return Err(e.clone());
+                    let mut e = e.clone();
+                    e.command.get_or_insert_with(|| status.cmd.clone());
+                    return Err(e);
                 } else {
                     result
                 }
@@ -317,15 +441,21 @@ impl Status {
         }
     }
 
-    fn append_line_to(scope: &Arc<Scope>, var_name: &str, info: String) {
-        match &scope.lookup_local(var_name) {
-            Some(v) => {
-                v.assign(Value::new_str(format!("{}\n{}", v.value().as_str(), info)));
-            }
-            _ => {
-                scope.insert(var_name.to_string(), Value::new_str(info));
-            }
-        }
+    /// Append an entry with the error details to the $__errors list in the local scope;
+    /// the list is hoisted to the enclosing scopes when the status is evaluated as boolean.
+    fn log_error(&self, scope: &Arc<Scope>, err: &EvalError) {
+        let mut entry = err.to_map(&self.cmd);
+        entry.insert(
+            "args".to_string(),
+            Value::new_list(self.args.iter().cloned().map(Value::new_str).collect()),
+        );
+
+        let mut errors = match scope.lookup_local("__errors").map(|v| v.value().clone()) {
+            Some(Value::List(list)) => list.to_vec(),
+            _ => Vec::new(),
+        };
+        errors.push(Value::new_map(entry));
+        scope.insert("__errors".to_string(), Value::new_list(errors));
     }
 
     pub fn err(&mut self) -> Option<EvalError> {
@@ -349,6 +479,8 @@ pub enum Value {
     Real(f64),
     Str(Arc<String>),
     Stat(Box<Status>),
+    List(Arc<Vec<Value>>),
+    Map(Arc<BTreeMap<String, Value>>),
 }
 
 impl Default for Value {
@@ -372,6 +504,31 @@ impl fmt::Display for Value {
             Value::Stat(s) => {
                 write!(f, "{}", s)
             }
+            Value::List(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    item.fmt_item(f)?;
+                }
+                write!(f, "]")
+            }
+            Value::Map(map) => {
+                if map.is_empty() {
+                    return write!(f, "[:]");
+                }
+                write!(f, "[")?;
+                for (i, (key, value)) in map.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    fmt_quoted(key, f)?;
+                    write!(f, ": ")?;
+                    value.fmt_item(f)?;
+                }
+                write!(f, "]")
+            }
         }
     }
 }
@@ -424,14 +581,45 @@ impl TryFrom<Value> for f64 {
     }
 }
 
+/// Format string inside a list or map, quote it if it would not read back as one single element.
+fn fmt_quoted(s: &str, f: &mut fmt::Formatter) -> fmt::Result {
+    if s.is_empty() || s.contains(|c: char| c.is_whitespace() || "\"[],:".contains(c)) {
+        write!(f, "\"{}\"", s.escape_default())
+    } else {
+        write!(f, "{}", s)
+    }
+}
+
 impl Value {
     pub fn as_str(&self) -> Cow<'_, str> {
         match self {
-            Value::Int(_) | Value::Real(_) | Value::Stat(_) => Cow::Owned(self.to_string()),
+            Value::Int(_) | Value::Real(_) | Value::Stat(_) | Value::List(_) | Value::Map(_) => {
+                Cow::Owned(self.to_string())
+            }
             Value::Str(s) => Cow::Borrowed(s.as_str()),
         }
     }
 
+    /// Format list element or map value.
+    fn fmt_item(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Str(s) => fmt_quoted(s, f),
+            _ => write!(f, "{}", self),
+        }
+    }
+
+    pub fn is_map(&self) -> bool {
+        matches!(self, Value::Map(_))
+    }
+
+    pub fn new_map(map: BTreeMap<String, Value>) -> Self {
+        Value::Map(Arc::new(map))
+    }
+
+    pub fn new_list(items: Vec<Value>) -> Self {
+        Value::List(Arc::new(items))
+    }
+
     pub fn new_str(value: String) -> Self {
         Value::Str(Arc::new(value))
     }
@@ -442,7 +630,9 @@ impl Value {
 
     pub fn to_rc_string(&self) -> Arc<String> {
         match self {
-            Value::Int(_) | Value::Real(_) | Value::Stat(_) => Arc::new(self.to_string()),
+            Value::Int(_) | Value::Real(_) | Value::Stat(_) | Value::List(_) | Value::Map(_) => {
+                Arc::new(self.to_string())
+            }
             Value::Str(s) => Arc::clone(&s),
         }
     }
@@ -452,29 +642,65 @@ impl Value {
 enum Jump {
     Break(Value),
     Continue(Value),
+    Return(Value),
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct EvalError {
     pub loc: Location,
     pub message: String,
+    command: Option<String>, // The failed command, if the error originated from a command status
+    exit: Option<Box<Exit>>, // How the failed command ended
     jump: Option<Jump>,
 }
 
 impl EvalError {
-    fn new<S: AsRef<str>>(loc: Location, message: S) -> Self {
+    pub fn new<S: AsRef<str>>(loc: Location, message: S) -> Self {
         Self {
             loc,
             message: message.as_ref().to_string(),
+            command: None,
+            exit: None,
             jump: None,
         }
     }
 
+    /// Make a map with the error details: message, command (defaults to the given
+    /// command text), exit_code, signal, file, line and col.
+    pub fn to_map(&self, command: &str) -> BTreeMap<String, Value> {
+        // Failed external commands carry their exit; everything else is a generic failure.
+        let exit = self.exit.as_deref().copied().unwrap_or(Exit::FAILURE);
+
+        let command = self.command.as_deref().unwrap_or(command);
+        let file = self.loc.file.as_ref().map(|f| f.to_string());
+
+        let mut map = BTreeMap::new();
+        map.insert("message".to_string(), Value::new_str(self.message.clone()));
+        map.insert("command".to_string(), Value::new_str(command.to_string()));
+        map.insert("exit_code".to_string(), Value::Int(exit.code));
+        map.insert(
+            "signal".to_string(),
+            Value::new_str(exit.signal_name().unwrap_or_default()),
+        );
+        map.insert("file".to_string(), Value::new_str(file.unwrap_or_default()));
+        map.insert("line".to_string(), Value::Int(self.loc.line as _));
+        map.insert("col".to_string(), Value::Int(self.loc.col as _));
+        map
+    }
+
     /// Show error details, with colors.
     pub fn show(&self, scope: &Arc<Scope>, input: &str) {
         let stderr = std::io::stderr();
         eprintln!("{}", self.loc.error(scope, &self.message, &stderr));
 
+        // The error may be in another file than the input, e.g. in an imported module.
+        let source = self
+            .loc
+            .file
+            .as_ref()
+            .and_then(|f| std::fs::read_to_string(&**f).ok());
+        let input = source.as_deref().unwrap_or(input);
+
         let (line, col) = (self.loc.line as usize, self.loc.col as usize);
 
         // Retrieve and trim the line with the error
@@ -509,7 +735,7 @@ fn error<S: HasLocation, R>(source: &S, message: &str) -> EvalResult<R> {
 
 /// Non-terminal AST node.
 trait ExprNode {
-    fn add_child(&mut self, child: &Rc<Expression>) -> EvalResult;
+    fn add_child(&mut self, child: &Arc<Expression>) -> EvalResult;
 }
 
 struct Parser<I: Iterator<Item = char>> {
@@ -519,19 +745,26 @@ struct Parser<I: Iterator<Item = char>> {
     comment: bool,
     escaped: bool,
     in_quotes: bool,
-    expect_else_expr: bool,
-    empty: Rc<Expression>,
-    current_expr: Rc<Expression>,
+    else_stack: Vec<usize>, // Depths of the expression stack where IF branches expect ELSE bodies
+    empty: Arc<Expression>,
+    current_expr: Arc<Expression>,
     scope: Arc<Scope>,
-    expr_stack: Vec<Rc<Expression>>,
+    expr_stack: Vec<Arc<Expression>>,
     scope_stack: Vec<Arc<Scope>>,
-    group: Rc<Expression>,
-    group_stack: Vec<Rc<Expression>>,
-    globbed_tokens: Vec<String>,
+    group: Arc<Expression>,
+    group_stack: Vec<Arc<Expression>>,
+    group_base: usize, // Depth of the expression stack at the start of the current group
+    base_stack: Vec<usize>,
+    globbed_tokens: Vec<Token>, // Pending tokens, from globbing and brace expansion
     text: String,
     quoted: bool,
     raw: bool,
     glob: bool,
+    offset: usize, // Offset into the input of the next character
+    functions: HashMap<String, ShellCommand>, // Functions defined (but not yet evaluated) in this input
+    module: Option<Arc<Module>>,              // Module being imported, see modules.rs
+    token_start: Option<usize>, // Offset into the source where the current token starts
+    tokens: Option<Vec<(Token, Range<usize>)>>, // Tokens and their source ranges, recorded for formatting
 }
 
 impl<I: Iterator<Item = char>> HasLocation for Parser<I> {
@@ -589,12 +822,154 @@ fn globbed_token(value: String) -> Token {
     Token::Literal(Text::new(value, false, true))
 }
 
+/// Numeric range {START..END[..STEP]}, counting down if END is less than START.
+#[derive(Debug)]
+struct NumRange {
+    next: Option<i64>,
+    end: i64,
+    step: i64,
+}
+
+impl NumRange {
+    fn parse(s: &str) -> Option<Self> {
+        let spec = s.strip_prefix('{')?.strip_suffix('}')?;
+        let (start, end, step) = parse_sequence(spec, |s| s.parse::<i64>().ok())?;
+        Some(Self::new(start, end, step))
+    }
+
+    fn new(start: i64, end: i64, step: i64) -> Self {
+        let step = if start <= end { step } else { -step };
+        Self {
+            next: Some(start),
+            end,
+            step,
+        }
+    }
+}
+
+impl Iterator for NumRange {
+    type Item = i64;
+
+    fn next(&mut self) -> Option<i64> {
+        let n = self.next?;
+        if (self.step > 0 && n > self.end) || (self.step < 0 && n < self.end) {
+            return None;
+        }
+        self.next = n.checked_add(self.step);
+        Some(n)
+    }
+}
+
+/// Parse START..END[..STEP], with the bounds converted by the given function.
+fn parse_sequence<T>(spec: &str, bound: impl Fn(&str) -> Option<T>) -> Option<(T, T, i64)> {
+    let parts = spec.split("..").collect::<Vec<_>>();
+    let step = match parts.len() {
+        2 => 1,
+        3 => parts[2].parse::<i64>().ok()?.checked_abs()?,
+        _ => return None,
+    };
+    if step == 0 {
+        return None;
+    }
+    Some((bound(parts[0])?, bound(parts[1])?, step))
+}
+
+/// Expand the alternatives of a brace expression, e.g. {a,b,c}, {1..10..2}, {a..e},
+/// or return None if the text between the braces is not an expression.
+fn brace_alternatives(spec: &str) -> Option<Vec<String>> {
+    if let Some((start, end, step)) = parse_sequence(spec, |s| s.parse::<i64>().ok()) {
+        return Some(
+            NumRange::new(start, end, step)
+                .map(|n| n.to_string())
+                .collect(),
+        );
+    }
+
+    let single_char = |s: &str| {
+        let mut chars = s.chars();
+        chars
+            .next()
+            .filter(|c| c.is_ascii_alphabetic() && chars.next().is_none())
+    };
+    if let Some((start, end, step)) = parse_sequence(spec, single_char) {
+        return Some(
+            NumRange::new(start as i64, end as i64, step)
+                .filter_map(|n| char::from_u32(n as u32))
+                .map(String::from)
+                .collect(),
+        );
+    }
+
+    // Split at the top level commas
+    let mut alternatives = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (i, c) in spec.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            ',' if depth == 0 => {
+                alternatives.push(spec[start..i].to_string());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if alternatives.is_empty() {
+        return None;
+    }
+    alternatives.push(spec[start..].to_string());
+    Some(alternatives)
+}
+
+/// Brace expansion: file{1,2}.txt -> file1.txt file2.txt
+/// The braces of variables ${...} are left as they are, and so is text with command substitutions $(...)
+fn expand_braces(s: &str) -> Vec<String> {
+    if !s.contains('{') || s.contains("$(") {
+        return vec![s.to_string()];
+    }
+
+    let bytes = s.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'{' {
+            i += 1;
+            continue;
+        }
+        // Find the matching right brace
+        let mut depth = 0;
+        let close = s[i..].char_indices().find_map(|(j, c)| {
+            match c {
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                _ => {}
+            }
+            (depth == 0).then_some(i + j)
+        });
+        let Some(close) = close else { break };
+
+        if i > 0 && bytes[i - 1] == b'$' {
+            i = close + 1; // Skip variables
+            continue;
+        }
+        if let Some(alternatives) = brace_alternatives(&s[i + 1..close]) {
+            let (prefix, suffix) = (&s[..i], &s[close + 1..]);
+            return alternatives
+                .iter()
+                .flat_map(|alt| expand_braces(&format!("{}{}{}", prefix, alt, suffix)))
+                .collect();
+        }
+        i += 1;
+    }
+    vec![s.to_string()]
+}
+
 impl<T> Parser<T>
 where
     T: Iterator<Item = char>,
 {
     fn new(input: T, scope: &Arc<Scope>, file: Option<Arc<String>>) -> Self {
-        let empty = Rc::new(Expression::Empty);
+        let empty = Arc::new(Expression::Empty);
         let loc = Location::with_file(file);
 
         Self {
@@ -604,27 +979,45 @@ where
             comment: false,
             escaped: false,
             in_quotes: false,
-            expect_else_expr: false,
-            empty: Rc::clone(&empty),
-            current_expr: Rc::clone(&empty),
+            else_stack: Vec::new(),
+            empty: Arc::clone(&empty),
+            current_expr: Arc::clone(&empty),
             scope: Arc::clone(&scope),
             expr_stack: Vec::new(),
             scope_stack: Vec::new(),
             group: new_group(&loc, &scope),
             group_stack: Vec::new(),
+            group_base: 0,
+            base_stack: Vec::new(),
             globbed_tokens: Vec::new(),
             text: String::new(),
             quoted: false,
             raw: false,
             glob: true,
+            offset: 0,
+            functions: HashMap::new(),
+            module: None,
+            token_start: None,
+            tokens: None,
         }
     }
 
-    fn empty(&self) -> Rc<Expression> {
-        Rc::clone(&self.empty)
+    fn empty(&self) -> Arc<Expression> {
+        Arc::clone(&self.empty)
+    }
+
+    /// Check if the current token has an unclosed ${...} parameter expansion.
+    fn in_braces(&self) -> bool {
+        self.text
+            .rfind("${")
+            .is_some_and(|i| !self.text[i..].contains('}'))
     }
 
     fn is_delimiter(&self, tok: &str, c: char) -> bool {
+        if self.in_braces() {
+            // Operators inside of parameter expansions, e.g. ${NAME%.txt}, ${NAME:-default}
+            return "\n\r;".contains(c);
+        }
         // Forward slashes and dashes need special handling, since they occur in
         // paths and command line options; it is unreasonable to require quotes.
 
@@ -632,24 +1025,75 @@ where
         if "/-+*".contains(c) {
             if tok.is_empty() {
                 return !self.group.is_args()
+                    && !self.group.is_list()
                     && !self.current_expr.is_cmd()
                     && !self.current_expr.is_empty();
             }
+            if tok.contains("$(") {
+                // Do not run command substitutions while tokenizing.
+                return false;
+            }
             match parse_value(tok, &self.loc, &self.scope) {
                 Ok(Value::Int(_)) | Ok(Value::Real(_)) => true,
                 _ => false,
             }
-        } else if c == '#' && self.text == "$" {
-            false // Special case for $# variable (holding number of command line arguments)
+        } else if c == '#' && (self.text == "$" || self.text.ends_with("${")) {
+            // Special case for $# variable (holding number of command line arguments),
+            // and for ${#NAME} (the length of a variable).
+            false
+        } else if c == ']' || c == ',' {
+            self.group.is_list()
         } else {
             const DELIMITERS: &str = " \t\n\r()+=;|&<>#^";
             DELIMITERS.contains(c)
         }
     }
 
+    fn is_for_args(&self) -> bool {
+        self.group.is_args() && self.expr_stack.last().is_some_and(|e| e.is_for())
+    }
+
+    fn is_case_args(&self) -> bool {
+        self.group.is_args() && self.expr_stack.last().is_some_and(|e| e.is_case())
+    }
+
+    fn is_case_allowed(&self) -> bool {
+        self.current_expr.is_empty() && self.expr_stack.last().is_some_and(|e| e.is_match())
+    }
+
+    /// IN is a keyword in FOR expressions and in expressions such as if (x in $list) ...,
+    /// but not in the arguments of commands and FOR loops, e.g. echo logged in as root.
+    fn is_in_allowed(&self) -> bool {
+        self.current_expr.is_for()
+            || !(self.group.is_args()
+                && self
+                    .expr_stack
+                    .last()
+                    .is_some_and(|e| e.is_cmd() || e.is_for()))
+    }
+
+    /// LOCAL, READONLY (CONST), EXPORT, IMPORT and DEFER are keywords only at the start of
+    /// statements, e.g. echo local and x = local are fine.
+    fn is_declaration_allowed(&self) -> bool {
+        self.current_expr.is_empty()
+            && self.expr_stack.len() <= self.group_base
+            && !self.group.is_args()
+            && !self.group.is_list()
+    }
+
+    /// Square brackets start list literals, except in command arguments,
+    /// where they are taken literally (as in glob patterns).
+    fn is_list_allowed(&self) -> bool {
+        !(self.group.is_args() && self.expr_stack.last().is_some_and(|e| e.is_cmd()))
+    }
+
     fn next(&mut self) -> Option<char> {
         self.loc.col += 1;
-        self.chars.next()
+        let next = self.chars.next();
+        if let Some(c) = next {
+            self.offset += c.len_utf8();
+        }
+        next
     }
 
     fn glob_literal(&mut self) -> EvalResult<Token> {
@@ -659,7 +1103,13 @@ where
         if self.glob && !self.quoted {
             let upper = self.text.to_uppercase();
             for &keyword in &KEYWORDS {
-                if keyword == upper {
+                // CASE is a keyword only at the start of MATCH arms, e.g. echo case is fine.
+                if keyword == upper
+                    && (keyword != "CASE" || self.is_case_allowed())
+                    && (keyword != "IN" || self.is_in_allowed())
+                    && (!(is_declaration_keyword(keyword) || matches!(keyword, "IMPORT" | "DEFER"))
+                        || self.is_declaration_allowed())
+                {
                     return Ok(Token::Keyword(upper));
                 }
             }
@@ -670,20 +1120,40 @@ where
                 }
             }
 
-            match glob(&self.text) {
-                Ok(paths) => {
-                    self.globbed_tokens = paths
-                        .filter_map(Result::ok)
-                        .map(|p| p.to_string_lossy().into_owned())
-                        .collect();
+            // CASE patterns are matched against the subject of MATCH, not expanded to file names.
+            if self.is_case_args() {
+                return Ok(Token::Literal(Text::new(
+                    self.text.clone(),
+                    false,
+                    self.raw,
+                )));
+            }
 
-                    if !self.globbed_tokens.is_empty() {
-                        let value = self.globbed_tokens.remove(0);
-                        return Ok(globbed_token(value));
+            // Numeric ranges in FOR arguments are iterated lazily, see ForExpr.
+            if self.is_for_args() && NumRange::parse(&self.text).is_some() {
+                return Ok(Token::Literal(Text::range(self.text.clone())));
+            }
+            let words = expand_braces(&self.text);
+
+            for word in words {
+                match glob(&word) {
+                    Ok(paths) => {
+                        let count = self.globbed_tokens.len();
+                        self.globbed_tokens.extend(
+                            paths
+                                .filter_map(Result::ok)
+                                .map(|p| globbed_token(p.to_string_lossy().into_owned())),
+                        );
+                        if self.globbed_tokens.len() > count {
+                            continue;
+                        }
                     }
+                    Err(_) => {} // Ignore glob errors and treat as literal
                 }
-                Err(_) => {} // Ignore glob errors and treat as literal
+                self.globbed_tokens
+                    .push(Token::Literal(Text::new(word, false, self.raw)));
             }
+            return Ok(self.globbed_tokens.remove(0));
         }
         Ok(Token::Literal(Text::new(
             self.text.clone(),
@@ -745,12 +1215,144 @@ where
         ))
     }
 
+    /// Copy a command substitution $(...) into the text of the current token, up to the
+    /// matching right parenthesis; the command is parsed and evaluated when the token is
+    /// expanded (see parse_value).
+    fn substitution(&mut self) -> EvalResult {
+        let mut depth = 0;
+        let mut quotes = false;
+        let mut escaped = false;
+
+        while let Some(c) = self.next() {
+            self.text.push(c);
+
+            if c == '\n' {
+                self.loc.next_line();
+            } else if escaped {
+                escaped = false;
+            } else if quotes {
+                match c {
+                    '\\' => escaped = true,
+                    '"' => quotes = false,
+                    _ => {}
+                }
+            } else {
+                match c {
+                    '"' => quotes = true,
+                    '(' => depth += 1,
+                    ')' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            return Ok(());
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        error(
+            self,
+            "Expecting closing parenthesis after command substitution",
+        )
+    }
+
+    /// Tokenize a here-string operator, <<< (followed by an expression), or a here-document:
+    /// <<MARKER at the end of a line, then the lines of text up to the line that starts with
+    /// MARKER. Parsing resumes after the closing marker, e.g.:
+    /// ```
+    /// cat <<END
+    /// Hello $USER
+    /// END | grep Hello;
+    /// ```
+    /// Variables in the text are not expanded if the marker is in single quotes: <<'END'
+    fn here_doc(&mut self) -> EvalResult<Token> {
+        if self.chars.peek() == Some(&'<') {
+            self.next();
+            return Ok(Token::Operator(Op::HereString));
+        }
+
+        let is_marker_char = |c: &char| c.is_alphanumeric() || *c == '_';
+
+        let raw = self.chars.peek() == Some(&'\'');
+        if raw {
+            self.next();
+        }
+        let mut marker = String::new();
+        while let Some(c) = self.chars.next_if(is_marker_char) {
+            self.loc.col += 1;
+            self.offset += c.len_utf8();
+            marker.push(c);
+        }
+        if raw && self.next() != Some('\'') {
+            return error(self, "Expecting closing quote after here-document marker");
+        }
+        if marker.is_empty() {
+            return error(self, "Expecting here-document marker");
+        }
+
+        // The text starts on the next line.
+        while let Some(c) = self.next() {
+            if c == '\n' {
+                self.loc.next_line();
+                break;
+            }
+            if !c.is_whitespace() {
+                return error(self, "Expecting new line after here-document marker");
+            }
+        }
+
+        let mut text = String::new();
+        loop {
+            // Skip indentation, and match the closing marker.
+            let mut line = String::new();
+            while let Some(c) = self.chars.next_if(|c| *c != '\n' && c.is_whitespace()) {
+                self.loc.col += 1;
+                self.offset += c.len_utf8();
+                line.push(c);
+            }
+            let mut matched = 0;
+            for expected in marker.chars() {
+                if self.chars.peek() != Some(&expected) {
+                    break;
+                }
+                line.push(expected);
+                self.next();
+                matched += 1;
+            }
+            if matched == marker.chars().count() && !self.chars.peek().is_some_and(is_marker_char) {
+                break;
+            }
+
+            // Not the closing marker, copy the rest of the line.
+            loop {
+                match self.next() {
+                    Some('\n') => {
+                        self.loc.next_line();
+                        line.push('\n');
+                        break;
+                    }
+                    Some(c) => line.push(c),
+                    None => {
+                        return error(
+                            self,
+                            &format!("Expecting here-document end marker: {}", marker),
+                        )
+                    }
+                }
+            }
+            text.push_str(&line);
+        }
+
+        Ok(Token::HereDoc(Text::new(text, true, raw)))
+    }
+
     #[rustfmt::skip]
     pub fn next_token(&mut self) -> EvalResult<Token> {
 
+        self.token_start = None;
+
         if !self.globbed_tokens.is_empty() {
-            let value = self.globbed_tokens.remove(0);
-            return Ok(globbed_token(value));
+            return Ok(self.globbed_tokens.remove(0));
         }
 
         let mut tok = Token::End;
@@ -775,15 +1377,36 @@ where
                 self.next();
                 continue;
             }
+            if self.token_start.is_none() && !c.is_whitespace() && c != '#' && !(c == ',' && self.group.is_list()) {
+                self.token_start = Some(self.offset);
+            }
+            if !self.in_quotes && self.in_braces() && !self.is_delimiter(&self.text, c) {
+                self.text.push(c);
+                self.next();
+                continue;
+            }
             match c {
                 '#' => { self.comment = true; self.next(); }
-                '%' => token!(self, tok, Token::Operator(Op::Mod)),
+                '%' => {
+                    check_text!(self, tok);
+                    self.next();
+                    // Job numbers in command arguments, e.g. kill %1
+                    if !self.is_list_allowed() && self.chars.peek().is_some_and(|c| c.is_ascii_digit()) {
+                        self.text.push(c);
+                    } else {
+                        tok = Token::Operator(Op::Mod);
+                    }
+                }
                 '(' => token!(self, tok, Token::LeftParen),
                 ')' => token!(self, tok, Token::RightParen),
                 ';' => token!(self, tok, Token::Semicolon),
+                '[' if self.text.is_empty() && self.is_list_allowed() => token!(self, tok, Token::LeftBracket),
+                ']' if self.group.is_list() => token!(self, tok, Token::RightBracket),
+                ',' if self.group.is_list() => { check_text!(self, tok); self.next(); } // Same as whitespace
+                ':' if self.group.is_list() && self.text.is_empty() => token!(self, tok, Token::Colon),
                 '+' => token!(self, tok, Token::Operator(Op::Plus)),
                 '^' => token!(self, tok, Token::Operator(Op::Power)),
-                '&' => token!(self, tok, '&', Token::Operator(Op::And)),
+                '&' => token!(self, tok, '&', Token::Operator(Op::Background), Token::Operator(Op::And)),
                 '|' => token!(self, tok, '|', Token::Operator(Op::Pipe), Token::Operator(Op::Or)),
                 '!' => token!(self, tok, '=', Token::Operator(Op::Not), Token::Operator(Op::NotEquals)),
                 '*' => {
@@ -795,7 +1418,15 @@ where
                     }
                     self.next();
                 }
-                '<' => token!(self, tok, '=', Token::Operator(Op::Lt), Token::Operator(Op::Lte)),
+                '<' => {
+                    check_text!(self, tok);
+                    self.next();
+                    match self.chars.peek() {
+                        Some('=') => { self.next(); tok = Token::Operator(Op::Lte); }
+                        Some('<') => { self.next(); tok = self.here_doc()?; }
+                        _ => tok = Token::Operator(Op::Lt),
+                    }
+                }
                 '>' => token!(self, tok, '=', Token::Operator(Op::Gt), Token::Operator(Op::Gte)),
                 '=' => {
                     check_text!(self, tok);
@@ -818,6 +1449,11 @@ where
                             tok = Token::Operator(Op::Write);
                             continue;
                         }
+                        if next_c == '<' {
+                            self.next();
+                            tok = Token::Operator(Op::Read);
+                            continue;
+                        }
                         tok = Token::Operator(Op::Assign);
                     } else {
                         // Handle trailing equals
@@ -852,6 +1488,8 @@ where
                         continue;
                     }
 
+                    let mut key = false;
+
                     while let Some(&next_c) = self.chars.peek() {
                         if self.escaped {
                             match next_c {
@@ -905,6 +1543,18 @@ where
                                 }
                             }
                             self.text.push(next_c);
+                        } else if next_c == '(' && !self.raw && self.text.ends_with('$') {
+                            self.substitution()?;
+                        } else if next_c == ':' && !self.in_quotes && self.group.is_list() {
+                            self.next();
+                            // Colon followed by whitespace (or end of list) ends a map key.
+                            match self.chars.peek() {
+                                Some(&c) if !c.is_whitespace() && c != ']' && c != ',' => self.text.push(':'),
+                                _ => {
+                                    key = true;
+                                    break;
+                                }
+                            }
                         } else {
                             if self.in_quotes || !self.is_delimiter(&self.text, next_c) {
                                 self.text.push(next_c);
@@ -915,8 +1565,11 @@ where
                         }
                     }
 
-                    if !self.text.is_empty() || self.quoted {
-                        assert!(self.text != "-" && self.text != "/");
+                    if key {
+                        tok = Token::Key(Text::new(self.text.clone(), self.quoted, self.raw));
+                        self.text.clear();
+                    } else if !self.text.is_empty() || self.quoted {
+                        assert!(self.quoted || (self.text != "-" && self.text != "/"));
 
                         tok = self.glob_literal()?;
                         self.text.clear();
@@ -944,22 +1597,23 @@ where
     }
 
     /// Add an expression to the AST.
-    fn add_expr(&mut self, expr: &Rc<Expression>) -> EvalResult {
+    fn add_expr(&mut self, expr: &Arc<Expression>) -> EvalResult {
         assert!(!expr.is_empty());
 
         self.prev_loc = self.loc();
 
-        if self.expect_else_expr {
+        // Add the ELSE body to the branch, but not the expressions inside of the body.
+        if self.else_stack.last() == Some(&self.expr_stack.len()) {
             self.current_expr = self.expr_stack.pop().unwrap();
-            self.expect_else_expr = false;
+            self.else_stack.pop();
         }
 
         let ref current = *self.current_expr;
 
         if current.is_complete() {
-            if let Expression::Args(a) = &*self.group {
-                a.borrow_mut().add_child(&self.current_expr)?;
-                self.current_expr = Rc::clone(&expr);
+            if let Expression::Args(g) | Expression::List(g) = &*self.group {
+                g.write().add_child(&self.current_expr)?;
+                self.current_expr = Arc::clone(&expr);
                 return Ok(());
             } else {
                 let current_expr_str = current.to_string();
@@ -979,28 +1633,35 @@ where
         }
 
         match current {
-            Expression::Args(e) => e.borrow_mut().add_child(expr),
-            Expression::Bin(e) => e.borrow_mut().add_child(expr),
-            Expression::Branch(e) => e.borrow_mut().add_child(expr),
-            Expression::Cmd(e) => e.borrow_mut().add_child(expr),
+            Expression::Args(e) => e.write().add_child(expr),
+            Expression::Bin(e) => e.write().add_child(expr),
+            Expression::Branch(e) => e.write().add_child(expr),
+            Expression::Call(e) => e.write().add_child(expr),
+            Expression::Case(e) => e.write().add_child(expr),
+            Expression::Cmd(e) => e.write().add_child(expr),
+            Expression::Defer(e) => e.write().add_child(expr),
             Expression::Empty => {
-                self.current_expr = Rc::clone(expr);
+                self.current_expr = Arc::clone(expr);
                 Ok(())
             }
-            Expression::For(e) => e.borrow_mut().add_child(expr),
-            Expression::Group(e) => e.borrow_mut().add_child(expr),
+            Expression::For(e) => e.write().add_child(expr),
+            Expression::Func(e) => e.write().add_child(expr),
+            Expression::Group(e) => e.write().add_child(expr),
             Expression::Leaf(_) => error(self, "Unexpected expression after literal"),
-            Expression::Loop(e) => e.borrow_mut().add_child(expr),
+            Expression::List(e) => e.write().add_child(expr),
+            Expression::Loop(e) => e.write().add_child(expr),
+            Expression::Match(e) => e.write().add_child(expr),
+            Expression::Try(e) => e.write().add_child(expr),
         }
     }
 
-    fn close_group(group: &Rc<Expression>) {
+    fn close_group(group: &Arc<Expression>) {
         match &**group {
             Expression::Args(g) => {
-                g.borrow_mut().closed = true;
+                g.write().closed = true;
             }
-            Expression::Group(g) => {
-                g.borrow_mut().closed = true;
+            Expression::Group(g) | Expression::List(g) => {
+                g.write().closed = true;
             }
             _ => {
                 dbg!(&group);
@@ -1015,10 +1676,15 @@ where
             // expression, pop it; add current expression to it; then make it the
             // new current expression.
 
+            // Do not pop past the start of the current group, e.g. in $x * (1 + 2)
+            if self.expr_stack.len() <= self.group_base {
+                break;
+            }
+
             // If not at the end of a statement, do not pop the stack past VeryLow priority ops.
 
             if stack_top.is_bin() && (end_statement || stack_top.priority() > Priority::VeryLow) {
-                let expr = Rc::clone(&self.current_expr);
+                let expr = Arc::clone(&self.current_expr);
                 self.current_expr = self.expr_stack.pop().unwrap();
 
                 if !expr.is_empty() {
@@ -1039,25 +1705,26 @@ where
         }
 
         // Handle the use case of erasing variables, e.g. $VAR = ;
+        // and of returning from functions without a value.
         if self.current_expr.is_empty() {
             if let Some(top) = self.expr_stack.last() {
-                if top.is_assignment() {
+                if top.is_assignment() || top.is_return() || top.is_declaration() {
                     self.current_expr = self.expr_stack.pop().unwrap();
                 }
             }
         }
-        let group = Rc::clone(&self.group);
+        let group = Arc::clone(&self.group);
 
         if let Expression::Args(g) = &*group {
             self.pop_binary_ops(true)?;
             if !self.current_expr.is_empty() {
-                g.borrow_mut().add_child(&self.current_expr)?;
+                g.write().add_child(&self.current_expr)?;
             }
             self.pop_group()?;
         } else if !self.current_expr.is_empty() {
-            if let Expression::Group(g) = &*group {
+            if let Expression::Group(g) | Expression::List(g) = &*group {
                 self.pop_binary_ops(true)?;
-                g.borrow_mut().add_child(&self.current_expr)?;
+                g.write().add_child(&self.current_expr)?;
             } else {
                 panic!("Unexpected group error");
             }
@@ -1092,17 +1759,22 @@ where
             // Create new scope and make it current
             self.scope = Scope::with_parent_and_hooks(Some(current_scope), None);
             // Start a new group
-            self.group_stack.push(Rc::clone(&self.group));
+            self.group_stack.push(Arc::clone(&self.group));
+            self.base_stack.push(self.group_base);
+            self.group_base = self.expr_stack.len() + 1;
 
             if group == Group::Args {
                 self.group = new_args(&self.prev_loc, &self.scope);
                 self.prev_loc = self.loc();
+            } else if group == Group::List {
+                self.group = new_list(&self.prev_loc, &self.scope);
+                self.prev_loc = self.loc();
             } else {
                 self.group = new_group(&self.prev_loc, &self.scope);
                 self.prev_loc = self.loc();
             }
         }
-        self.expr_stack.push(Rc::clone(&self.current_expr));
+        self.expr_stack.push(Arc::clone(&self.current_expr));
         self.clear_current();
 
         Ok(())
@@ -1119,9 +1791,10 @@ where
         }
 
         Self::close_group(&self.group);
-        let group = Rc::clone(&self.group);
+        let group = Arc::clone(&self.group);
 
         self.group = self.group_stack.pop().unwrap(); // Restore group
+        self.group_base = self.base_stack.pop().unwrap();
         self.scope = self.scope_stack.pop().unwrap(); // Restore scope
 
         // Add the group itself to the expression previously saved on the stack
@@ -1133,23 +1806,142 @@ where
         Ok(())
     }
 
-    fn parse(&mut self, quit: &mut bool) -> EvalResult<Rc<Expression>> {
+    /// Record the token and its source range, when formatting.
+    fn record_token(&mut self, tok: &Token) {
+        if let Some(tokens) = &mut self.tokens {
+            let end = self.offset;
+            tokens.push((tok.clone(), self.token_start.unwrap_or(end)..end));
+        }
+    }
+
+    /// IMPORT NAME: load the module at parse time, so that its functions (NAME.FUNCTION)
+    /// can be called in the rest of the input; the module is evaluated by a command.
+    fn import(&mut self) -> EvalResult {
+        let loc = self.prev_loc.clone();
+        let tok = self.next_token()?;
+        self.record_token(&tok);
+
+        let name = match tok {
+            Token::Literal(text) if !text.value.is_empty() => text.value(),
+            _ => return error(self, "Expecting module name after IMPORT"),
+        };
+        let (cmd, functions) =
+            modules::import(&name, &self.scope, self.loc.file.as_ref(), &self.loc)?;
+
+        for func in functions {
+            self.functions.insert(func.name().clone(), func);
+        }
+
+        let args = new_args(&self.loc, &self.scope);
+        if let Expression::Args(group) = &*args {
+            group
+                .write()
+                .content
+                .push(Arc::new(Expression::Leaf(Arc::new(Literal {
+                    text: Text::new(name, false, false),
+                    loc: self.loc.clone(),
+                    scope: Arc::clone(&self.scope),
+                }))));
+        }
+        let expr = Arc::new(Expression::Cmd(RwLock::new(Command {
+            cmd,
+            args,
+            loc,
+            scope: Arc::clone(&self.scope),
+        })));
+        self.add_expr(&expr)
+    }
+
+    fn parse(&mut self, quit: &mut bool) -> EvalResult<Arc<Expression>> {
         loop {
             let tok = self.next_token()?;
+            self.record_token(&tok);
+
             match &tok {
                 Token::End => {
                     break;
                 }
                 Token::LeftParen => {
+                    let current = Arc::clone(&self.current_expr);
+                    if let Expression::Func(f) = &*current {
+                        if f.read().params.is_empty() {
+                            // Function parameters are parsed as an argument list.
+                            self.prev_loc = self.loc();
+                            self.push(Group::Args)?;
+                            continue;
+                        }
+                    }
                     self.push(Group::Block)?;
                 }
                 Token::RightParen => {
                     if self.group_stack.is_empty() {
                         return error(self, "Unmatched right parenthesis");
                     }
+                    if self.is_call_args() {
+                        self.end_call()?;
+                        continue;
+                    }
+                    if self.group.is_list() {
+                        return error(self, "Expecting closing bracket");
+                    }
+                    if self.group.is_args() && self.expr_stack.last().is_some_and(|e| e.is_func()) {
+                        // End of function parameters list
+                        self.add_current_expr_to_group()?;
+                        continue;
+                    }
                     self.pop()?;
                 }
+                Token::LeftBracket => {
+                    self.push(Group::List)?;
+                }
+                Token::RightBracket => {
+                    if self.is_call_args() {
+                        return error(
+                            self,
+                            "Expecting closing parenthesis after function arguments",
+                        );
+                    }
+                    self.add_current_expr_to_group()?;
+                    if let Expression::List(g) = &*self.group {
+                        if g.read().keys.len() > g.read().content.len() {
+                            return error(self, "Missing map value");
+                        }
+                    }
+                    self.pop_group()?;
+                }
+                Token::Key(key) => {
+                    self.add_current_expr_to_group()?;
+                    self.clear_current();
+
+                    if let Expression::List(g) = &*self.group {
+                        let mut g = g.write();
+                        if g.keys.len() != g.content.len() {
+                            let message = if g.kind == Group::Map {
+                                "Missing map value"
+                            } else {
+                                "Unexpected map key in list"
+                            };
+                            return error(self, message);
+                        }
+                        g.kind = Group::Map;
+                        g.keys.push(key.clone());
+                    }
+                }
+                Token::Colon => {
+                    // Empty map: [:]
+                    match &*self.group {
+                        Expression::List(g)
+                            if g.read().content.is_empty() && self.current_expr.is_empty() =>
+                        {
+                            g.write().kind = Group::Map;
+                        }
+                        _ => return error(self, "Unexpected colon"),
+                    }
+                }
                 Token::Semicolon => {
+                    if self.group.is_list() {
+                        return error(self, "Expecting closing bracket");
+                    }
                     self.finalize_groups()?;
 
                     // Semicolons end both statements and FOR argument lists.
@@ -1168,7 +1960,7 @@ where
                         break;
                     }
                     if word == "IF" {
-                        let expr = Rc::new(Expression::Branch(RefCell::new(BranchExpr {
+                        let expr = Arc::new(Expression::Branch(RwLock::new(BranchExpr {
                             cond: self.empty(),
                             if_branch: self.empty(),
                             else_branch: self.empty(),
@@ -1179,28 +1971,31 @@ where
                         self.add_expr(&expr)?;
                     } else if word == "IN" {
                         if let Expression::For(f) = &*self.current_expr {
-                            if f.borrow().var.is_empty() {
+                            if f.read().vars.is_empty() {
                                 return error(self, "Expecting identifier in FOR expression");
                             }
                             self.prev_loc = self.loc();
                         } else {
-                            return error(self, "IN without FOR");
+                            // Outside of FOR, IN tests for membership, e.g. if (x in $list) ...
+                            self.add_operator(&Op::In)?;
+                            continue;
                         }
                         self.push(Group::Args)?; // args will be added to ForExpr when finalized
                     } else if word == "ELSE" {
                         if let Expression::Branch(b) = &*self.current_expr {
-                            if !b.borrow_mut().is_else_expected() {
+                            if !b.write().is_else_expected() {
                                 return error(self, "Conditional expression or IF branch missing");
                             }
                             self.prev_loc = self.loc();
-                            self.expect_else_expr = true;
                             self.push(Group::None)?;
+                            self.else_stack.push(self.expr_stack.len());
                         } else {
                             return error(self, "ELSE without IF");
                         }
                     } else if word == "FOR" {
-                        let expr = Rc::new(Expression::For(RefCell::new(ForExpr {
-                            var: String::default(),
+                        let expr = Arc::new(Expression::For(RwLock::new(ForExpr {
+                            vars: Vec::new(),
+                            lines: false,
                             args: self.empty(),
                             body: self.empty(),
                             loc: self.prev_loc.clone(),
@@ -1209,32 +2004,153 @@ where
                         self.add_expr(&expr)?;
                         self.current_expr = expr;
                     } else if word == "WHILE" {
-                        let expr = Rc::new(Expression::Loop(RefCell::new(LoopExpr {
+                        let expr = Arc::new(Expression::Loop(RwLock::new(LoopExpr {
                             cond: self.empty(),
                             body: self.empty(),
                             loc: self.prev_loc.clone(),
                             scope: Arc::clone(&self.scope),
                         })));
                         self.add_expr(&expr)?;
-                    } else if word == "BREAK" || word == "CONTINUE" {
-                        let expr = Rc::new(Expression::Leaf(Rc::new(Literal {
-                            text: Text::new(word.to_owned(), false, false),
+                    } else if word == "DEF" {
+                        let expr = Arc::new(Expression::Func(RwLock::new(FuncExpr {
+                            func: None,
+                            params: self.empty(),
+                            body: self.empty(),
+                            module: self.module.clone(),
                             loc: self.prev_loc.clone(),
-                            scope: Arc::clone(&self.scope),
                         })));
                         self.add_expr(&expr)?;
-                    }
-                }
-                Token::Literal(text) => {
-                    // Excluding quoted strings here seems wrong, e.g.:
-                    // "C:\\Program Files\\BraveSoftware\\Brave-Browser\\Application\\brave.exe"
-                    if /* !text.quoted && */ !self.group.is_args() {
-                        if let Some(cmd) = get_command(&text.value) {
-                            let expr = Rc::new(Expression::Cmd(RefCell::new(Command {
-                                cmd,
-                                args: self.empty(),
-                                loc: self.prev_loc.clone(),
-                                scope: Arc::clone(&self.scope),
+                        self.current_expr = expr;
+                    } else if word == "RETURN" {
+                        if !self.current_expr.is_empty() {
+                            return error(self, "Unexpected RETURN, missing semicolon?");
+                        }
+                        // RETURN is a unary operator, with an optional operand.
+                        let expr = Arc::new(Expression::Bin(RwLock::new(BinExpr {
+                            op: Op::Return,
+                            lhs: self.empty(),
+                            rhs: self.empty(),
+                            loc: self.prev_loc.clone(),
+                            scope: Arc::clone(&self.scope),
+                        })));
+                        self.prev_loc = self.loc();
+                        self.expr_stack.push(expr);
+                    } else if word == "IMPORT" {
+                        self.import()?;
+                    } else if is_declaration_keyword(word) {
+                        // Declarations are unary operators, like RETURN; the operand
+                        // is an assignment (NAME = VALUE), or a variable name.
+                        let op = match word.as_str() {
+                            "LOCAL" => Op::Local,
+                            "EXPORT" => Op::Export,
+                            _ => Op::Readonly,
+                        };
+                        let expr = Arc::new(Expression::Bin(RwLock::new(BinExpr {
+                            op,
+                            lhs: self.empty(),
+                            rhs: self.empty(),
+                            loc: self.prev_loc.clone(),
+                            scope: Arc::clone(&self.scope),
+                        })));
+                        self.prev_loc = self.loc();
+                        self.expr_stack.push(expr);
+                    } else if word == "BREAK" || word == "CONTINUE" {
+                        let expr = Arc::new(Expression::Leaf(Arc::new(Literal {
+                            text: Text::new(word.to_owned(), false, false),
+                            loc: self.prev_loc.clone(),
+                            scope: Arc::clone(&self.scope),
+                        })));
+                        self.add_expr(&expr)?;
+                    } else if word == "MATCH" {
+                        let expr = Arc::new(Expression::Match(RwLock::new(MatchExpr {
+                            subject: self.empty(),
+                            body: self.empty(),
+                            loc: self.prev_loc.clone(),
+                        })));
+                        self.add_expr(&expr)?;
+                    } else if word == "CASE" {
+                        let expr = Arc::new(Expression::Case(RwLock::new(CaseExpr {
+                            args: self.empty(),
+                            loc: self.prev_loc.clone(),
+                            scope: Arc::clone(&self.scope),
+                        })));
+                        self.add_expr(&expr)?;
+
+                        self.current_expr = expr;
+                        self.push(Group::Args)?; // patterns and body are added to CASE when finalized
+                    } else if word == "TRY" {
+                        let expr = Arc::new(Expression::Try(RwLock::new(TryExpr {
+                            body: self.empty(),
+                            var: String::default(),
+                            catch_body: self.empty(),
+                            finally_body: self.empty(),
+                            expect_catch: false, // becomes true once "catch" keyword is seen
+                            expect_finally: false,
+                            loc: self.prev_loc.clone(),
+                            scope: Arc::clone(&self.scope),
+                        })));
+                        self.add_expr(&expr)?;
+                    } else if word == "DEFER" {
+                        let expr = Arc::new(Expression::Defer(RwLock::new(DeferExpr {
+                            body: self.empty(),
+                            loc: self.prev_loc.clone(),
+                        })));
+                        self.add_expr(&expr)?;
+                    } else if word == "CATCH" || word == "FINALLY" {
+                        if let Expression::Try(t) = &*self.current_expr {
+                            t.write()
+                                .expect_clause(word)
+                                .map_err(|e| EvalError::new(self.loc(), e))?;
+                            self.prev_loc = self.loc();
+                        } else {
+                            return error(self, &format!("{} without TRY", word));
+                        }
+                    }
+                }
+                Token::Literal(text) => {
+                    let current = Arc::clone(&self.current_expr);
+                    if let Expression::Func(f) = &*current {
+                        if f.read().func.is_none() {
+                            // Make the function known to the rest of the input being parsed, so that
+                            // it can be called (from its own body, too) before the definition is evaluated.
+                            let expr = Arc::new(Expression::Leaf(Arc::new(Literal {
+                                text: text.clone(),
+                                loc: self.prev_loc.clone(),
+                                scope: Arc::clone(&self.scope),
+                            })));
+                            self.add_expr(&expr)?;
+
+                            if let Some(func) = &f.read().func {
+                                let name = text.value();
+                                let cmd = ShellCommand::new(name.clone(), Arc::clone(func) as _);
+                                self.functions.insert(name, cmd);
+                            }
+                            continue;
+                        }
+                    }
+                    // Built-in function call, e.g. upper($x); no whitespace before the parenthesis.
+                    if !text.quoted && self.chars.peek() == Some(&'(') {
+                        if let Some(func) = funcs::get_function(&text.value) {
+                            self.begin_call(func)?;
+                            continue;
+                        }
+                    }
+                    if !self.group.is_args() && !self.group.is_list() {
+                        let cmd = match self.functions.get(text.value.as_str()) {
+                            Some(func) => Some(func.clone()),
+                            // Functions of the module (called from function bodies) come first.
+                            None => self
+                                .module
+                                .as_ref()
+                                .and_then(|m| get_command(&format!("{}.{}", m.name, text.value)))
+                                .or_else(|| get_command(&text.value)),
+                        };
+                        if let Some(cmd) = cmd {
+                            let expr = Arc::new(Expression::Cmd(RwLock::new(Command {
+                                cmd,
+                                args: self.empty(),
+                                loc: self.prev_loc.clone(),
+                                scope: Arc::clone(&self.scope),
                             })));
                             self.add_expr(&expr)?;
 
@@ -1245,7 +2161,7 @@ where
                         }
                     }
                     // Identifiers and literals.
-                    let expr = Rc::new(Expression::Leaf(Rc::new(Literal {
+                    let expr = Arc::new(Expression::Leaf(Arc::new(Literal {
                         text: text.clone(),
                         loc: self.prev_loc.clone(),
                         scope: Arc::clone(&self.scope),
@@ -1254,33 +2170,22 @@ where
                         self.add_expr(&expr)?;
                     }
                 }
+                Token::Operator(Op::Background) => {
+                    self.add_background()?;
+                }
                 Token::Operator(op) => {
-                    let is_low_priority = op.priority() <= Priority::Low;
-
-                    if is_low_priority {
-                        if self.group.is_args() {
-                            // Finish the arguments of the left hand-side expression
-                            self.add_current_expr_to_group()?;
-                        }
-                        self.pop_binary_ops(false)?;
-                    }
+                    self.add_operator(op)?;
+                }
+                Token::HereDoc(text) => {
+                    // Feed the text to the standard input of the preceding expression.
+                    self.add_operator(&Op::HereString)?;
 
-                    let expr = Rc::new(Expression::Bin(RefCell::new(BinExpr {
-                        op: op.clone(),
-                        lhs: Rc::clone(&self.current_expr),
-                        rhs: self.empty(),
+                    let expr = Arc::new(Expression::Leaf(Arc::new(Literal {
+                        text: text.clone(),
                         loc: self.prev_loc.clone(),
                         scope: Arc::clone(&self.scope),
                     })));
-
-                    self.prev_loc = self.loc();
-
-                    if is_low_priority {
-                        self.expr_stack.push(Rc::clone(&expr));
-                        self.clear_current();
-                    } else {
-                        self.current_expr = expr;
-                    }
+                    self.add_expr(&expr)?;
                 }
             }
         }
@@ -1288,11 +2193,105 @@ where
         self.finalize_parse()
     }
 
-    fn finalize_parse(&mut self) -> EvalResult<Rc<Expression>> {
+    fn add_operator(&mut self, op: &Op) -> EvalResult {
+        let is_low_priority = op.priority() <= Priority::Low;
+
+        if is_low_priority {
+            if self.group.is_list() {
+                return error(self, "Expressions in lists must be enclosed in parentheses");
+            }
+            if self.group.is_args() {
+                // Finish the arguments of the left hand-side expression
+                self.add_current_expr_to_group()?;
+            }
+            self.pop_binary_ops(false)?;
+        }
+
+        let expr = Arc::new(Expression::Bin(RwLock::new(BinExpr {
+            op: op.clone(),
+            lhs: Arc::clone(&self.current_expr),
+            rhs: self.empty(),
+            loc: self.prev_loc.clone(),
+            scope: Arc::clone(&self.scope),
+        })));
+
+        self.prev_loc = self.loc();
+
+        if is_low_priority {
+            self.expr_stack.push(Arc::clone(&expr));
+            self.clear_current();
+        } else {
+            self.current_expr = expr;
+        }
+        Ok(())
+    }
+
+    /// Start parsing the arguments of a built-in function call.
+    fn begin_call(&mut self, func: &'static BuiltinFunction) -> EvalResult {
+        let expr = Arc::new(Expression::Call(RwLock::new(CallExpr {
+            func,
+            args: self.empty(),
+            loc: self.prev_loc.clone(),
+        })));
+        self.next(); // Consume the opening parenthesis
+
+        // Save the current expression, the call is added to it once the arguments are parsed.
+        self.expr_stack.push(Arc::clone(&self.current_expr));
+        self.current_expr = expr;
+
+        // Parse the arguments as a list (separated by whitespace or commas), closed by ')'
+        self.push(Group::List)
+    }
+
+    fn end_call(&mut self) -> EvalResult {
+        self.add_current_expr_to_group()?;
+        self.pop_group()?; // The arguments are added to the call, which becomes current
+
+        let call = Arc::clone(&self.current_expr);
+        self.current_expr = self.expr_stack.pop().unwrap();
+        self.add_expr(&call)
+    }
+
+    fn is_call_args(&self) -> bool {
+        self.group.is_list() && self.expr_stack.last().is_some_and(|e| e.is_call())
+    }
+
+    /// A trailing & runs the statement in the background, and ends the statement (like a semicolon).
+    fn add_background(&mut self) -> EvalResult {
+        if self.group.is_list() {
+            return error(self, "Expressions in lists must be enclosed in parentheses");
+        }
+        if self.group.is_args() {
+            // Finish the arguments of the command
+            self.add_current_expr_to_group()?;
+        }
+        self.pop_binary_ops(true)?;
+
+        if self.current_expr.is_empty() {
+            return error(self, "Expecting expression to run in the background");
+        }
+
+        self.current_expr = Arc::new(Expression::Bin(RwLock::new(BinExpr {
+            op: Op::Background,
+            lhs: Arc::clone(&self.current_expr),
+            rhs: self.empty(),
+            loc: self.prev_loc.clone(),
+            scope: Arc::clone(&self.scope),
+        })));
+
+        self.finalize_groups()?;
+        self.clear_current();
+        Ok(())
+    }
+
+    fn finalize_parse(&mut self) -> EvalResult<Arc<Expression>> {
+        if self.group.is_list() {
+            return error(self, "Expecting closing bracket");
+        }
         self.finalize_groups()?;
 
         if !self.expr_stack.is_empty() {
-            let msg = if self.expect_else_expr {
+            let msg = if !self.else_stack.is_empty() {
                 "Dangling ELSE"
             } else {
                 my_dbg!(&self.expr_stack);
@@ -1303,10 +2302,10 @@ where
         assert!(self.group_stack.is_empty()); // because the expr_stack is empty
 
         Self::close_group(&self.group);
-        Ok(Rc::clone(&self.group))
+        Ok(Arc::clone(&self.group))
     }
 
-    fn rewrite_pipeline(&mut self, expr: &Rc<Expression>) -> EvalResult<bool> {
+    fn rewrite_pipeline(&mut self, expr: &Arc<Expression>) -> EvalResult<bool> {
         assert!(self.current_expr.is_empty());
 
         let mut head = self.empty();
@@ -1315,17 +2314,17 @@ where
         while let Some(top) = self.expr_stack.last().cloned() {
             if top.is_pipe() {
                 if !head.is_empty() {
-                    self.current_expr = Rc::clone(&top);
+                    self.current_expr = Arc::clone(&top);
                     self.add_expr(&head)?;
                 }
                 if tail.is_empty() {
                     if let Expression::Bin(b) = &*top {
-                        assert!(b.borrow().op == Op::Pipe);
-                        tail = Rc::clone(&b.borrow().lhs);
-                        head = Rc::clone(&tail);
+                        assert!(b.read().op == Op::Pipe);
+                        tail = Arc::clone(&b.read().lhs);
+                        head = Arc::clone(&tail);
                     }
                 } else {
-                    head = Rc::clone(&top);
+                    head = Arc::clone(&top);
                 }
                 self.expr_stack.pop();
             } else {
@@ -1336,10 +2335,10 @@ where
         if head.is_empty() {
             Ok(false)
         } else {
-            self.current_expr = Rc::new(Expression::Bin(RefCell::new(BinExpr {
+            self.current_expr = Arc::new(Expression::Bin(RwLock::new(BinExpr {
                 op: Op::Pipe,
-                lhs: Rc::clone(&head),
-                rhs: Rc::clone(&expr),
+                lhs: Arc::clone(&head),
+                rhs: Arc::clone(&expr),
                 loc: expr.loc(),
                 scope: Arc::clone(&self.scope),
             })));
@@ -1377,86 +2376,543 @@ where
 /// "${NAME/(\\w+) (\\w+)/\\2, \\1}"   -> "Doe, John"
 /// "${GREETING/(Hello), (World)!/\\2 says \\1}" -> "World says Hello"
 /// ```
+///
+/// Default values, substrings, prefix and suffix removal, case conversion:
+/// ```
+/// "${UNDEFINED:-$NAME}"     -> "John Doe"
+/// "${NAME:0:4}"             -> "John"
+/// "${NAME^^}"               -> "JOHN DOE"
+/// ```
+/// (see expand_param for the complete list of operators)
+///
+/// Command substitution:
+/// ```
+/// "$(echo $NAME | wc -w)"   -> "2"
+/// ```
 fn parse_value(s: &str, loc: &Location, scope: &Arc<Scope>) -> EvalResult<Value> {
-    let re = Regex::new(r"\$\{([^}]+)\}|\$([a-zA-Z0-9_$@#][a-zA-Z0-9_]*)")
+    let re = Regex::new(r"\$\{([^}]+)\}|\$([a-zA-Z0-9_$@#][a-zA-Z0-9_]*)((?:\[[^\]]*\])*)")
         .map_err(|e| EvalError::new(loc.clone(), e.to_string()))?;
 
-    let result = re.replace_all(s, |caps: &regex::Captures| {
-        let var_expr = caps
-            .get(1)
-            .or_else(|| caps.get(2))
-            .map(|m| m.as_str())
-            .unwrap_or("");
-
-        let parts: Vec<&str> = var_expr.splitn(3, '/').collect();
-        let var_name = parts[0];
-
-        match scope.lookup(var_name) {
-            Some(var) => {
-                let mut value = var.value().to_string();
-
-                if parts.len() == 3 {
-                    let search = parts[1];
-                    // Recursively expand variables in the replacement pattern.
-                    let replace = parse_value(parts[2], loc, scope)
-                        .unwrap_or(Value::default())
-                        .to_string();
-
-                    if let Ok(re) = Regex::new(search) {
-                        // Implement bash-like substitution with capture groups
-                        value = re
-                            .replace_all(&value, |caps: &regex::Captures| {
-                                let mut result = replace.to_string();
-                                for (i, cap) in caps.iter().enumerate().skip(1) {
-                                    if let Some(m) = cap {
-                                        result = result.replace(&format!("\\{}", i), m.as_str());
-                                    }
-                                }
-                                result
-                            })
-                            .into_owned();
-                    }
+    let mut result = String::new();
+    let mut pos = 0;
+
+    loop {
+        // Variables are expanded in the text up to the next command substitution, if any;
+        // the output of the command is inserted as is.
+        let substitution = find_substitution(&s[pos..]).map(|(i, j)| (pos + i, pos + j));
+        let end = substitution.map_or(s.len(), |(i, _)| i);
+        let mut last = pos;
+
+        for caps in re.captures_iter(&s[pos..end]) {
+            let m = caps.get(0).unwrap();
+            result.push_str(&s[last..pos + m.start()]);
+            last = pos + m.end();
+
+            match expand_var(&caps, loc, scope)? {
+                // A lone variable evaluates to its value, so that lists and maps are not flattened into strings.
+                value @ (Value::List(_) | Value::Map(_)) if m.range() == (0..s.len()) => {
+                    return Ok(value)
                 }
+                value => result.push_str(&value.as_str()),
+            }
+        }
+        result.push_str(&s[last..end]);
 
-                value
+        match substitution {
+            Some((start, end)) => {
+                result.push_str(&substitute(&s[start + 2..end - 1], loc, scope)?);
+                pos = end;
             }
-            None => format!("${}", var_name),
+            None => break,
         }
-    });
+    }
 
     result
         .parse::<Value>()
         .map_err(|e| EvalError::new(loc.clone(), e.to_string()))
 }
 
+/// Find the first command substitution $(...) in a string, return the range of bytes
+/// from the dollar sign to the matching right parenthesis (inclusive).
+fn find_substitution(s: &str) -> Option<(usize, usize)> {
+    let start = s.find("$(")?;
+    let mut depth = 0;
+    let mut quotes = false;
+    let mut escaped = false;
+
+    for (i, c) in s[start + 1..].char_indices() {
+        if escaped {
+            escaped = false;
+        } else if quotes {
+            match c {
+                '\\' => escaped = true,
+                '"' => quotes = false,
+                _ => {}
+            }
+        } else {
+            match c {
+                '"' => quotes = true,
+                '(' => depth += 1,
+                ')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some((start, start + 1 + i + 1));
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    None
+}
+
+/// Evaluate a command substitution, in a child scope, and return its standard output trimmed
+/// of whitespace, as when piping into a variable. Builtin commands, functions and expressions
+/// run in-process.
+/// Errors are reported at their location inside of the substitution.
+/// ```
+/// echo "Today is $(date)";
+/// for f in $(ls /tmp); (echo $f);
+/// ```
+fn substitute(input: &str, loc: &Location, scope: &Arc<Scope>) -> EvalResult<String> {
+    let scope = Scope::with_parent_and_hooks(Some(scope.clone()), None);
+    let mut parser = Parser::new(input.chars(), &scope, loc.file.clone());
+    parser.loc = loc.clone();
+    parser.prev_loc = loc.clone();
+
+    let mut quit = false;
+    let expr = parser.parse(&mut quit)?;
+
+    let (mut reader, writer) = os_pipe::pipe()
+        .map_err(|e| EvalError::new(loc.clone(), format!("Failed to create pipe: {}", e)))?;
+
+    let capture = thread::spawn(move || {
+        let mut buffer = Vec::new();
+        reader.read_to_end(&mut buffer).map(|_| buffer)
+    });
+
+    let redirect = stdio::redirect_stdout(writer);
+
+    let result = expr.eval();
+
+    // Expressions such as $(1 + 2) substitute their value.
+    if let Ok(val) = &result {
+        if !matches!(val, Value::Stat(_)) {
+            _ = my_println!("{}", &val);
+        }
+    }
+
+    // Close the write end of the pipe, so that the reader thread sees the end of the output.
+    drop(redirect);
+
+    let output = capture
+        .join()
+        .map_err(|e| EvalError::new(loc.clone(), format!("Thread panicked: {:?}", e)))?
+        .map_err(|e| EvalError::new(loc.clone(), format!("Failed to read output: {}", e)))?;
+
+    Status::check_result(result, false)?;
+
+    let output = String::from_utf8(output).map_err(|e| {
+        EvalError::new(
+            loc.clone(),
+            format!("Failed to convert command output from UTF8: {}", e),
+        )
+    })?;
+
+    Ok(output.trim().to_string())
+}
+
+/// Expand one variable reference matched in parse_value.
+///
+/// Besides the substitutions documented above, lists support indexing and slicing:
+/// ```
+/// "$x[0]"     -> first element
+/// "$x[-1]"    -> last element
+/// "$x[1:3]"   -> list of the second and third elements
+/// "${#x}"     -> number of elements (number of characters if x is a string)
+/// ```
+/// and maps support lookup by key:
+/// ```
+/// "$m[host]"  -> value of the "host" entry
+/// "${#m}"     -> number of entries
+/// ```
+fn expand_var(caps: &regex::Captures, loc: &Location, scope: &Arc<Scope>) -> EvalResult<Value> {
+    let var_expr = caps
+        .get(1)
+        .or_else(|| caps.get(2))
+        .map(|m| m.as_str())
+        .unwrap_or("");
+
+    if caps.get(1).is_some() {
+        if let Some(value) = expand_param(var_expr, loc, scope)? {
+            return Ok(value);
+        }
+    }
+
+    let parts: Vec<&str> = var_expr.splitn(3, '/').collect();
+    let (var_name, mut subscript) = match caps.get(3) {
+        Some(m) => (parts[0], m.as_str()),
+        None => split_subscript(parts[0]),
+    };
+
+    if caps.get(1).is_some() && parts.len() == 1 && subscript.is_empty() {
+        if let Some(name) = var_name.strip_prefix('#').filter(|name| !name.is_empty()) {
+            if let Some(var) = scope.lookup(name) {
+                let len = match &*var.value() {
+                    Value::List(items) => items.len(),
+                    Value::Map(map) => map.len(),
+                    value => value.as_str().chars().count(),
+                };
+                return Ok(Value::Int(len as _));
+            }
+        }
+    }
+
+    let mut value = match scope.lookup(var_name) {
+        Some(var) => var.value().clone(),
+        None => return Ok(Value::new_str(format!("${}{}", var_name, subscript))),
+    };
+
+    while let Some((index, rest)) = subscript.strip_prefix('[').and_then(|s| s.split_once(']')) {
+        value = match value {
+            Value::List(items) => index_list(&items, index, loc, scope)?,
+            Value::Map(map) => {
+                let key = parse_value(index.trim(), loc, scope)?.to_string();
+                match map.get(&key) {
+                    Some(value) => value.clone(),
+                    None => {
+                        return Err(EvalError::new(
+                            loc.clone(),
+                            format!("Key not found: {}", key),
+                        ))
+                    }
+                }
+            }
+            // Not a list, keep the brackets as they are (they may be part of a glob pattern).
+            _ => break,
+        };
+        subscript = rest;
+    }
+    if !subscript.is_empty() {
+        value = Value::new_str(format!("{}{}", value, subscript));
+    }
+
+    if parts.len() == 3 {
+        let search = parts[1];
+        // Recursively expand variables in the replacement pattern.
+        let replace = parse_value(parts[2], loc, scope)
+            .unwrap_or(Value::default())
+            .to_string();
+
+        if let Ok(re) = Regex::new(search) {
+            // Implement bash-like substitution with capture groups
+            value = Value::new_str(
+                re.replace_all(&value.as_str(), |caps: &regex::Captures| {
+                    let mut result = replace.to_string();
+                    for (i, cap) in caps.iter().enumerate().skip(1) {
+                        if let Some(m) = cap {
+                            result = result.replace(&format!("\\{}", i), m.as_str());
+                        }
+                    }
+                    result
+                })
+                .into_owned(),
+            );
+        }
+    }
+
+    Ok(value)
+}
+
+/// Expand the bash-style operators of ${NAME<OP>...}, return None if there is no operator.
+///
+/// ```
+/// "${X:-word}"       -> X if set and not empty, otherwise word
+/// "${X:+word}"       -> word if X is set and not empty, otherwise empty string
+/// "${X:?message}"    -> X if set and not empty, otherwise error
+/// "${X:2}"           -> substring from the third character to the end
+/// "${X:2:3}"         -> three characters starting at the third (negative offsets count from the end)
+/// "${X#pattern}"     -> remove shortest prefix matching glob pattern (## removes the longest)
+/// "${X%pattern}"     -> remove shortest suffix matching glob pattern (%% removes the longest)
+/// "${X^}", "${X^^}"  -> first character, or all characters to uppercase
+/// "${X,}", "${X,,}"  -> first character, or all characters to lowercase
+/// ```
+fn expand_param(expr: &str, loc: &Location, scope: &Arc<Scope>) -> EvalResult<Option<Value>> {
+    let len = expr
+        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .unwrap_or(expr.len());
+    let (name, op) = expr.split_at(len);
+    if name.is_empty() || op.is_empty() {
+        return Ok(None);
+    }
+
+    let value = scope.lookup(name).map(|var| var.value().clone());
+    let is_set = value.as_ref().is_some_and(|v| !v.as_str().is_empty());
+    let text = value
+        .as_ref()
+        .map_or(String::default(), |v| v.as_str().to_string());
+
+    let result = if let Some(word) = op.strip_prefix(":-") {
+        match value {
+            Some(value) if is_set => value,
+            _ => parse_value(word, loc, scope)?,
+        }
+    } else if let Some(word) = op.strip_prefix(":+") {
+        if is_set {
+            parse_value(word, loc, scope)?
+        } else {
+            Value::default()
+        }
+    } else if let Some(message) = op.strip_prefix(":?") {
+        match value {
+            Some(value) if is_set => value,
+            _ => {
+                let message = if message.is_empty() {
+                    "parameter not set".to_string()
+                } else {
+                    parse_value(message, loc, scope)?.to_string()
+                };
+                return Err(EvalError::new(
+                    loc.clone(),
+                    format!("{}: {}", name, message),
+                ));
+            }
+        }
+    } else if let Some(range) = op.strip_prefix(':') {
+        Value::new_str(substring(&text, range, loc, scope)?)
+    } else if let Some(pattern) = op.strip_prefix("##") {
+        Value::new_str(remove_match(&text, pattern, true, true, loc, scope)?)
+    } else if let Some(pattern) = op.strip_prefix('#') {
+        Value::new_str(remove_match(&text, pattern, true, false, loc, scope)?)
+    } else if let Some(pattern) = op.strip_prefix("%%") {
+        Value::new_str(remove_match(&text, pattern, false, true, loc, scope)?)
+    } else if let Some(pattern) = op.strip_prefix('%') {
+        Value::new_str(remove_match(&text, pattern, false, false, loc, scope)?)
+    } else {
+        match op {
+            "^" => Value::new_str(convert_first(&text, |c| c.to_uppercase().collect())),
+            "^^" => Value::new_str(text.to_uppercase()),
+            "," => Value::new_str(convert_first(&text, |c| c.to_lowercase().collect())),
+            ",," => Value::new_str(text.to_lowercase()),
+            _ => return Ok(None),
+        }
+    };
+
+    Ok(Some(result))
+}
+
+/// Extract substring given OFFSET[:LENGTH], negative values count from the end.
+fn substring(s: &str, range: &str, loc: &Location, scope: &Arc<Scope>) -> EvalResult<String> {
+    let parse_int = |arg: &str| match parse_value(arg.trim(), loc, scope)? {
+        Value::Int(i) => Ok(i),
+        _ => Err(EvalError::new(
+            loc.clone(),
+            format!("Invalid substring offset or length: {}", arg.trim()),
+        )),
+    };
+
+    let chars = s.chars().collect::<Vec<_>>();
+    let count = chars.len() as i64;
+
+    let (offset, length) = match range.split_once(':') {
+        Some((offset, length)) => (offset, Some(length)),
+        None => (range, None),
+    };
+    let mut start = parse_int(offset)?;
+    if start < 0 {
+        start += count;
+    }
+    let start = start.clamp(0, count);
+
+    let end = match length {
+        Some(length) => match parse_int(length)? {
+            n if n < 0 => count + n,
+            n => start + n,
+        },
+        None => count,
+    };
+    let end = end.clamp(start, count);
+
+    Ok(chars[start as usize..end as usize].iter().collect())
+}
+
+/// Remove the shortest (or longest) prefix or suffix matching a glob pattern.
+fn remove_match(
+    s: &str,
+    pattern: &str,
+    prefix: bool,
+    longest: bool,
+    loc: &Location,
+    scope: &Arc<Scope>,
+) -> EvalResult<String> {
+    let pattern = parse_value(pattern, loc, scope)?.to_string();
+    let pattern = glob::Pattern::new(&pattern)
+        .map_err(|e| EvalError::new(loc.clone(), format!("Invalid pattern: {}", e)))?;
+
+    let mut bounds = s.char_indices().map(|(i, _)| i).collect::<Vec<_>>();
+    bounds.push(s.len());
+
+    // Prefix candidates are s[..i], suffix candidates are s[i..]; try them in
+    // order from the shortest to the longest, or the reverse.
+    if prefix == longest {
+        bounds.reverse();
+    }
+    for i in bounds {
+        let (head, tail) = s.split_at(i);
+        if prefix && pattern.matches(head) {
+            return Ok(tail.to_string());
+        }
+        if !prefix && pattern.matches(tail) {
+            return Ok(head.to_string());
+        }
+    }
+    Ok(s.to_string())
+}
+
+/// Apply case conversion to the first character of a string.
+fn convert_first(s: &str, convert: fn(char) -> String) -> String {
+    let mut chars = s.chars();
+    match chars.next() {
+        Some(c) => convert(c) + chars.as_str(),
+        None => String::default(),
+    }
+}
+
+/// Split NAME[INDEX] into NAME and [INDEX]
+fn split_subscript(s: &str) -> (&str, &str) {
+    match s.find('[') {
+        Some(i) if s.ends_with(']') => s.split_at(i),
+        _ => (s, ""),
+    }
+}
+
+/// Index or slice a list: [i], [-i] counts from the end, [start:end] with optional bounds.
+fn index_list(
+    items: &[Value],
+    index: &str,
+    loc: &Location,
+    scope: &Arc<Scope>,
+) -> EvalResult<Value> {
+    let len = items.len() as i64;
+
+    let position = |s: &str| -> EvalResult<Option<i64>> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Ok(None);
+        }
+        match parse_value(s, loc, scope)? {
+            Value::Int(i) if i < 0 => Ok(Some(i + len)),
+            Value::Int(i) => Ok(Some(i)),
+            _ => Err(EvalError::new(
+                loc.clone(),
+                format!("Invalid list index: {}", s),
+            )),
+        }
+    };
+
+    if let Some((start, end)) = index.split_once(':') {
+        let start = position(start)?.unwrap_or(0).clamp(0, len) as usize;
+        let end = position(end)?.unwrap_or(len).clamp(0, len) as usize;
+
+        return Ok(Value::new_list(items[start..end.max(start)].to_vec()));
+    }
+
+    match position(index)? {
+        Some(i) if (0..len).contains(&i) => Ok(items[i as usize].clone()),
+        Some(_) => Err(EvalError::new(
+            loc.clone(),
+            format!("List index out of range: {}", index),
+        )),
+        None => Err(EvalError::new(loc.clone(), "Expecting list index")),
+    }
+}
+
 #[derive(Debug)]
 enum Expression {
     Empty,
-    Args(RefCell<GroupExpr>),
-    Bin(RefCell<BinExpr>),
-    Cmd(RefCell<Command>),
-    Branch(RefCell<BranchExpr>),
-    For(RefCell<ForExpr>),
-    Group(RefCell<GroupExpr>),
-    Leaf(Rc<Literal>), // Values and identifiers
-    Loop(RefCell<LoopExpr>),
+    Args(RwLock<GroupExpr>),
+    Bin(RwLock<BinExpr>),
+    Call(RwLock<CallExpr>),
+    Cmd(RwLock<Command>),
+    Defer(RwLock<DeferExpr>),
+    Branch(RwLock<BranchExpr>),
+    Case(RwLock<CaseExpr>),
+    For(RwLock<ForExpr>),
+    Func(RwLock<FuncExpr>),
+    Group(RwLock<GroupExpr>),
+    Leaf(Arc<Literal>), // Values and identifiers
+    List(RwLock<GroupExpr>),
+    Loop(RwLock<LoopExpr>),
+    Match(RwLock<MatchExpr>),
+    Try(RwLock<TryExpr>),
 }
 
 impl Expression {
+    /// Return the (non-empty) subexpressions, in source order.
+    fn children(&self) -> Vec<Arc<Expression>> {
+        let children = match self {
+            Expression::Empty | Expression::Leaf(_) => vec![],
+            Expression::Args(g) | Expression::Group(g) | Expression::List(g) => {
+                g.read().content.clone()
+            }
+            Expression::Bin(b) => {
+                let b = b.read();
+                vec![Arc::clone(&b.lhs), Arc::clone(&b.rhs)]
+            }
+            Expression::Branch(b) => {
+                let b = b.read();
+                vec![
+                    Arc::clone(&b.cond),
+                    Arc::clone(&b.if_branch),
+                    Arc::clone(&b.else_branch),
+                ]
+            }
+            Expression::Call(c) => vec![Arc::clone(&c.read().args)],
+            Expression::Case(c) => vec![Arc::clone(&c.read().args)],
+            Expression::Cmd(c) => vec![Arc::clone(&c.read().args)],
+            Expression::Defer(d) => vec![Arc::clone(&d.read().body)],
+            Expression::For(f) => {
+                let f = f.read();
+                vec![Arc::clone(&f.args), Arc::clone(&f.body)]
+            }
+            Expression::Func(f) => {
+                let f = f.read();
+                vec![Arc::clone(&f.params), Arc::clone(&f.body)]
+            }
+            Expression::Loop(l) => {
+                let l = l.read();
+                vec![Arc::clone(&l.cond), Arc::clone(&l.body)]
+            }
+            Expression::Match(m) => {
+                let m = m.read();
+                vec![Arc::clone(&m.subject), Arc::clone(&m.body)]
+            }
+            Expression::Try(t) => {
+                let t = t.read();
+                vec![
+                    Arc::clone(&t.body),
+                    Arc::clone(&t.catch_body),
+                    Arc::clone(&t.finally_body),
+                ]
+            }
+        };
+        children.into_iter().filter(|e| !e.is_empty()).collect()
+    }
+
     fn is_args(&self) -> bool {
         matches!(self, Expression::Args(_))
     }
 
     fn is_no_args(&self) -> bool {
         if let Expression::Args(g) = self {
-            return g.borrow().content.is_empty();
+            return g.read().content.is_empty();
         }
         false
     }
 
     fn is_assignment(&self) -> bool {
         if let Expression::Bin(bin_expr) = &self {
-            return bin_expr.borrow().op == Op::Assign;
+            return bin_expr.read().op == Op::Assign;
         }
         false
     }
@@ -1469,16 +2925,36 @@ impl Expression {
         matches!(self, Expression::Cmd(_))
     }
 
-    fn is_for(&self) -> bool {
-        matches!(self, Expression::For(_))
+    fn is_match(&self) -> bool {
+        matches!(self, Expression::Match(_))
     }
 
-    fn is_empty(&self) -> bool {
-        matches!(self, Expression::Empty)
+    fn is_call(&self) -> bool {
+        matches!(self, Expression::Call(_))
     }
 
-    fn is_group(&self) -> bool {
-        matches!(self, Expression::Group(_))
+    fn is_case(&self) -> bool {
+        matches!(self, Expression::Case(_))
+    }
+
+    fn is_for(&self) -> bool {
+        matches!(self, Expression::For(_))
+    }
+
+    fn is_func(&self) -> bool {
+        matches!(self, Expression::Func(_))
+    }
+
+    fn is_empty(&self) -> bool {
+        matches!(self, Expression::Empty)
+    }
+
+    fn is_group(&self) -> bool {
+        matches!(self, Expression::Group(_))
+    }
+
+    fn is_list(&self) -> bool {
+        matches!(self, Expression::List(_))
     }
 
     fn is_number(&self) -> bool {
@@ -1491,9 +2967,33 @@ impl Expression {
         }
     }
 
+    /// Return the command if the expression runs an external program.
+    fn as_external(&self) -> Option<&RwLock<Command>> {
+        match self {
+            Expression::Cmd(cmd) if cmd.read().cmd.is_external() => Some(cmd),
+            _ => None,
+        }
+    }
+
     fn is_pipe(&self) -> bool {
         if let Expression::Bin(b) = self {
-            b.borrow().op == Op::Pipe
+            b.read().op == Op::Pipe
+        } else {
+            false
+        }
+    }
+
+    fn is_return(&self) -> bool {
+        if let Expression::Bin(b) = self {
+            b.read().op == Op::Return
+        } else {
+            false
+        }
+    }
+
+    fn is_declaration(&self) -> bool {
+        if let Expression::Bin(b) = self {
+            b.read().op.is_declaration()
         } else {
             false
         }
@@ -1501,21 +3001,37 @@ impl Expression {
     /// Is the expression completely constructed (parsed)?
     fn is_complete(&self) -> bool {
         match self {
-            Expression::Args(group) => group.borrow().closed,
-            Expression::Bin(bin_expr) => !&bin_expr.borrow().rhs.is_empty(),
+            Expression::Args(group) => group.read().closed,
+            Expression::Bin(bin_expr) => !&bin_expr.read().rhs.is_empty(),
             Expression::Branch(branch) => {
-                let b = branch.borrow();
+                let b = branch.read();
                 if b.expect_else && b.else_branch.is_empty() {
                     return false;
                 }
                 !&b.if_branch.is_empty()
             }
-            Expression::Cmd(cmd) => !&cmd.borrow().args.is_empty(),
+            Expression::Call(call) => !&call.read().args.is_empty(),
+            Expression::Case(case) => !&case.read().args.is_empty(),
+            Expression::Cmd(cmd) => !&cmd.read().args.is_empty(),
+            Expression::Defer(defer) => !&defer.read().body.is_empty(),
             Expression::Empty => false,
-            Expression::For(for_expr) => !&for_expr.borrow().body.is_empty(),
-            Expression::Group(group) => group.borrow().closed,
+            Expression::For(for_expr) => !&for_expr.read().body.is_empty(),
+            Expression::Func(func) => !&func.read().body.is_empty(),
+            Expression::Group(group) => group.read().closed,
             Expression::Leaf(_) => true,
-            Expression::Loop(loop_expr) => !&loop_expr.borrow().body.is_empty(),
+            Expression::List(group) => group.read().closed,
+            Expression::Loop(loop_expr) => !&loop_expr.read().body.is_empty(),
+            Expression::Match(match_expr) => !&match_expr.read().body.is_empty(),
+            Expression::Try(try_expr) => {
+                let t = try_expr.read();
+                if t.expect_catch && t.catch_body.is_empty() {
+                    return false;
+                }
+                if t.expect_finally && t.finally_body.is_empty() {
+                    return false;
+                }
+                !&t.body.is_empty()
+            }
         }
     }
 
@@ -1527,14 +3043,50 @@ impl Expression {
         scope: &Arc<Scope>,
         read_stdin_if_dash: bool,
     ) -> EvalResult<Vec<String>> {
+        Ok(self
+            .eval_args(scope, read_stdin_if_dash, false, true, None)?
+            .iter()
+            .map(|v| v.to_string())
+            .collect())
+    }
+
+    /// Evaluate arguments into values. Unquoted strings are split at whitespace
+    /// (or into non-empty lines, if split_lines is true), unquoted lists are expanded
+    /// into their elements (without being split) if expand_lists is true, maps are passed
+    /// as they are. The positions of numeric ranges written in FOR arguments are collected
+    /// in ranges.
+    fn eval_args(
+        &self,
+        scope: &Arc<Scope>,
+        read_stdin_if_dash: bool,
+        split_lines: bool,
+        expand_lists: bool,
+        mut ranges: Option<&mut Vec<usize>>,
+    ) -> EvalResult<Vec<Value>> {
+        let split = |s: &str| -> Vec<Value> {
+            let words: Box<dyn Iterator<Item = &str>> = if split_lines {
+                Box::new(s.lines().filter(|line| !line.trim().is_empty()))
+            } else {
+                Box::new(s.split_ascii_whitespace())
+            };
+            words.map(|s| Value::new_str(s.to_string())).collect()
+        };
+
         match &self {
             Expression::Args(args) => {
-                let mut tokens = Vec::new();
+                let mut values = Vec::new();
 
-                for expr in &args.borrow().content {
+                for expr in &args.read().content {
                     let quoted = if let Expression::Leaf(lit) = &**expr {
+                        if lit.text.range {
+                            if let Some(ranges) = ranges.as_deref_mut() {
+                                ranges.push(values.len());
+                            }
+                            values.push(Value::new_str(lit.text.value()));
+                            continue;
+                        }
                         if lit.text.raw {
-                            tokens.push(lit.text.value());
+                            values.push(Value::new_str(lit.text.value()));
                             continue;
                         }
                         lit.text.quoted
@@ -1546,26 +3098,33 @@ impl Expression {
                     let val = Status::check_result(expr.eval(), true)?;
 
                     if quoted {
-                        tokens.push(val.to_string());
+                        values.push(Value::new_str(val.to_string()));
+                    } else if let Value::List(items) = &val {
+                        if expand_lists {
+                            values.extend(items.iter().cloned());
+                        } else {
+                            values.push(val);
+                        }
+                    } else if val.is_map() {
+                        values.push(val);
                     } else {
-                        // If not quoted, split at ASCII whitespace
-                        tokens.extend(val.to_string().split_ascii_whitespace().map(String::from));
+                        // If not quoted, split at ASCII whitespace (or into lines)
+                        values.extend(split(&val.to_string()));
                     }
                 }
 
                 // Read from stdin if args consist of one single dash, allowing arguments to be piped
                 // into FOR commands e.g. ```find . ".*\\.rs" | for file in -; (echo $file);```
-                if read_stdin_if_dash && tokens.len() == 1 && tokens[0] == "-" {
+                if read_stdin_if_dash && values.len() == 1 && values[0].as_str() == "-" {
                     scope.show_eof_hint();
                     let mut buffer = String::new();
-                    io::stdin()
-                        .lock()
+                    stdio::stdin()
                         .read_to_string(&mut buffer)
                         .map_err(|e| EvalError::new(self.loc(), e.to_string()))?;
-                    tokens = buffer.split_ascii_whitespace().map(String::from).collect();
+                    values = split(&buffer);
                 }
 
-                Ok(tokens)
+                Ok(values)
             }
             _ => error(self, "Expecting argument list"),
         }
@@ -1573,15 +3132,22 @@ impl Expression {
 
     fn priority(&self) -> Priority {
         match self {
-            Expression::Bin(bin_expr) => bin_expr.borrow().op.priority(),
+            Expression::Bin(bin_expr) => bin_expr.read().op.priority(),
             Expression::Args(_)
             | Expression::Branch(_)
+            | Expression::Call(_)
+            | Expression::Case(_)
             | Expression::Cmd(_)
+            | Expression::Defer(_)
             | Expression::Empty
             | Expression::For(_)
+            | Expression::Func(_)
             | Expression::Group(_)
             | Expression::Leaf(_)
-            | Expression::Loop(_) => Priority::High,
+            | Expression::List(_)
+            | Expression::Loop(_)
+            | Expression::Match(_)
+            | Expression::Try(_) => Priority::High,
         }
     }
 }
@@ -1589,15 +3155,22 @@ impl Expression {
 impl fmt::Display for Expression {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Expression::Args(group) => write!(f, "{}", group.borrow()),
-            Expression::Bin(bin_expr) => write!(f, "{}", bin_expr.borrow()),
-            Expression::Branch(branch) => write!(f, "{}", branch.borrow()),
-            Expression::Cmd(cmd) => write!(f, "{}", cmd.borrow()),
+            Expression::Args(group) => write!(f, "{}", group.read()),
+            Expression::Bin(bin_expr) => write!(f, "{}", bin_expr.read()),
+            Expression::Branch(branch) => write!(f, "{}", branch.read()),
+            Expression::Call(call) => write!(f, "{}", call.read()),
+            Expression::Case(case) => write!(f, "{}", case.read()),
+            Expression::Cmd(cmd) => write!(f, "{}", cmd.read()),
+            Expression::Defer(defer) => write!(f, "{}", defer.read()),
             Expression::Empty => write!(f, ""),
-            Expression::For(for_expr) => write!(f, "{}", for_expr.borrow()),
-            Expression::Group(group) => write!(f, "{}", group.borrow()),
+            Expression::For(for_expr) => write!(f, "{}", for_expr.read()),
+            Expression::Func(func) => write!(f, "{}", func.read()),
+            Expression::Group(group) => write!(f, "{}", group.read()),
             Expression::Leaf(literal) => write!(f, "{}", literal),
-            Expression::Loop(loop_expr) => write!(f, "{}", loop_expr.borrow()),
+            Expression::List(group) => write!(f, "{}", group.read()),
+            Expression::Loop(loop_expr) => write!(f, "{}", loop_expr.read()),
+            Expression::Match(match_expr) => write!(f, "{}", match_expr.read()),
+            Expression::Try(try_expr) => write!(f, "{}", try_expr.read()),
         }
     }
 }
@@ -1605,15 +3178,22 @@ impl fmt::Display for Expression {
 impl HasLocation for Expression {
     fn loc(&self) -> Location {
         match self {
-            Expression::Args(group) => group.borrow().loc(),
-            Expression::Bin(bin_expr) => bin_expr.borrow().loc(),
-            Expression::Branch(branch) => branch.borrow().loc(),
-            Expression::Cmd(cmd) => cmd.borrow().loc(),
+            Expression::Args(group) => group.read().loc(),
+            Expression::Bin(bin_expr) => bin_expr.read().loc(),
+            Expression::Branch(branch) => branch.read().loc(),
+            Expression::Call(call) => call.read().loc(),
+            Expression::Case(case) => case.read().loc(),
+            Expression::Cmd(cmd) => cmd.read().loc(),
+            Expression::Defer(defer) => defer.read().loc(),
             Expression::Empty => panic!("Empty expression"),
-            Expression::For(for_expr) => for_expr.borrow().loc(),
-            Expression::Group(group) => group.borrow().loc(),
+            Expression::For(for_expr) => for_expr.read().loc(),
+            Expression::Func(func) => func.read().loc(),
+            Expression::Group(group) => group.read().loc(),
             Expression::Leaf(literal) => literal.loc(),
-            Expression::Loop(loop_expr) => loop_expr.borrow().loc(),
+            Expression::List(group) => group.read().loc(),
+            Expression::Loop(loop_expr) => loop_expr.read().loc(),
+            Expression::Match(match_expr) => match_expr.read().loc(),
+            Expression::Try(try_expr) => try_expr.read().loc(),
         }
     }
 }
@@ -1621,19 +3201,20 @@ impl HasLocation for Expression {
 #[derive(Debug)]
 struct BinExpr {
     op: Op,
-    lhs: Rc<Expression>,
-    rhs: Rc<Expression>,
+    lhs: Arc<Expression>,
+    rhs: Arc<Expression>,
     loc: Location,
     scope: Arc<Scope>, // Scope needed for assignment op.
 }
 
 derive_has_location!(BinExpr);
+derive_scope!(BinExpr);
 
 impl ExprNode for BinExpr {
     /// Add right hand-side child expression.
-    fn add_child(&mut self, child: &Rc<Expression>) -> EvalResult {
+    fn add_child(&mut self, child: &Arc<Expression>) -> EvalResult {
         if self.rhs.is_empty() {
-            self.rhs = Rc::clone(child);
+            self.rhs = Arc::clone(child);
             Ok(())
         } else {
             error(&**child, "Unexpected expression, missing a semicolon?")
@@ -1643,6 +3224,12 @@ impl ExprNode for BinExpr {
 
 impl fmt::Display for BinExpr {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.op == Op::Return || self.op.is_declaration() {
+            return write!(f, "{} {}", self.op, self.rhs);
+        }
+        if self.op == Op::Background {
+            return write!(f, "{} {}", self.lhs, self.op);
+        }
         write!(f, "{} {} {}", self.lhs, self.op, self.rhs)
     }
 }
@@ -1667,6 +3254,7 @@ macro_rules! div_match {
             }
             Value::Str(s) => Ok(Value::new_str(format!("{}/{}", $i, s.as_str()))),
             Value::Stat(_) => error($self, "Cannot divide by command status"),
+            Value::List(_) | Value::Map(_) => error($self, "Cannot divide by list or map"),
         }
     };
 }
@@ -1688,6 +3276,42 @@ fn starts_with_special(s: &str) -> bool {
     s.starts_with(|c: char| c.is_ascii_digit() || matches!(c, '{' | '}' | '[' | ']'))
 }
 
+fn is_declaration_keyword(word: &str) -> bool {
+    matches!(word, "LOCAL" | "READONLY" | "CONST" | "EXPORT")
+}
+
+/// The name of the variable in a declaration, which may also be the name of a command, e.g. local w
+fn declared_name(expr: &Expression) -> Option<String> {
+    match expr {
+        Expression::Leaf(lit) => Some(lit.text.value.trim_start_matches('$').to_string()),
+        Expression::Cmd(cmd) => match &*cmd.read().args {
+            Expression::Args(args) if args.read().content.is_empty() => {
+                Some(cmd.read().cmd.name().to_string())
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Assign a variable in the given scope: variables that are read-only cannot be
+/// assigned, and assigning to the name of an exported variable (e.g. PATH = ...)
+/// keeps it exported, whether the new variable shadows it or replaces it.
+fn assign_var(scope: &Arc<Scope>, name: &Arc<String>, value: Value) -> Result<(), String> {
+    let exported = match scope.lookup(name) {
+        Some(var) if var.is_readonly() => {
+            return Err(format!("Cannot assign read-only variable: ${}", name));
+        }
+        Some(var) => var.is_exported(),
+        None => false,
+    };
+    scope.insert_value(name, value);
+    if exported {
+        scope.lookup_local(name).unwrap().set_exported();
+    }
+    Ok(())
+}
+
 impl BinExpr {
     fn eval_and(&self) -> EvalResult<Value> {
         let mut status = false;
@@ -1698,7 +3322,7 @@ impl BinExpr {
                 return Ok(lhs_val);
             }
         }
-        let mut all = value_as_bool(&*self.lhs, &lhs_val, &self.scope)?;
+        let mut all = value_as_bool(&*self.lhs, &lhs_val, &self.scope())?;
 
         if all {
             let rhs_val = self.rhs.eval()?;
@@ -1711,7 +3335,7 @@ impl BinExpr {
                 status = false;
             }
 
-            all &= value_as_bool(&*self.rhs, &rhs_val, &self.scope)?;
+            all &= value_as_bool(&*self.rhs, &rhs_val, &self.scope())?;
         }
 
         let result = Ok(Value::Int(all as _));
@@ -1738,14 +3362,14 @@ impl BinExpr {
             status = true;
         }
 
-        let mut any = value_as_bool(&*self.lhs, &lhs_val, &self.scope)?;
+        let mut any = value_as_bool(&*self.lhs, &lhs_val, &self.scope())?;
 
         if !any {
             let rhs_val = self.rhs.eval()?;
             if let Value::Stat(_) = &rhs_val {
                 return Ok(rhs_val); // Return delayed Status
             }
-            any = value_as_bool(&*self.rhs, &rhs_val, &self.scope)?;
+            any = value_as_bool(&*self.rhs, &rhs_val, &self.scope())?;
         }
 
         let result = Ok(Value::Int(any as _));
@@ -1776,21 +3400,86 @@ impl BinExpr {
             let var_name = &lit.text.value;
 
             if var_name.starts_with('$') {
+                let (name, subscript) = split_subscript(&var_name[1..]);
                 // Assigning to an already-defined variable, as in: $i = $i + 1?
-                if let Some(var) = lit.scope.lookup(&var_name[1..]) {
+                if let Some(var) = lit.scope().lookup(name) {
+                    if var.is_readonly() {
+                        return error(
+                            self,
+                            &format!("Cannot assign read-only variable: ${}", name),
+                        );
+                    }
+                    if !subscript.is_empty() {
+                        return self.assign_item(&var, subscript, rhs);
+                    }
                     return Ok(var.assign(rhs).clone());
                 } else {
-                    return error(self, &format!("Variable not found: {}", var_name));
+                    return error(self, &format!("Variable not found: ${}", name));
                 }
             } else if !starts_with_special(&var_name) {
                 // Create new variable in the current scope
-                self.scope.insert_value(var_name, rhs.clone());
+                assign_var(&self.scope(), var_name, rhs.clone())
+                    .map_err(|e| EvalError::new(self.loc(), e))?;
                 return Ok(rhs);
             }
         }
         error(self, "Identifier expected on left hand-side of assignment")
     }
 
+    /// Assign to list element or map entry, e.g. $x[0] = 1; $m[key] = value; $m[a][b] = value
+    fn assign_item(&self, var: &Variable, subscript: &str, rhs: Value) -> EvalResult<Value> {
+        let mut value = var.value().clone();
+        _ = var.assign(Value::default()); // Release the reference held by the variable, avoid copying.
+
+        let result = self.set_item(&mut value, subscript, rhs.clone());
+        _ = var.assign(value);
+
+        result.map(|_| rhs)
+    }
+
+    fn set_item(&self, container: &mut Value, subscript: &str, rhs: Value) -> EvalResult {
+        let (index, rest) = match subscript.strip_prefix('[').and_then(|s| s.split_once(']')) {
+            Some(parts) => parts,
+            None => return error(self, &format!("Invalid subscript: {}", subscript)),
+        };
+        let key = parse_value(index.trim(), &self.loc, &self.scope())?;
+
+        let item = match container {
+            Value::List(items) => {
+                let items = Arc::make_mut(items);
+                let i = match key {
+                    Value::Int(i) if i < 0 => i + items.len() as i64,
+                    Value::Int(i) => i,
+                    _ => return error(self, &format!("Invalid list index: {}", index)),
+                };
+                match usize::try_from(i).ok().and_then(|i| items.get_mut(i)) {
+                    Some(item) => item,
+                    None => return error(self, &format!("List index out of range: {}", index)),
+                }
+            }
+            Value::Map(map) => {
+                let map = Arc::make_mut(map);
+                let key = key.to_string();
+                if rest.is_empty() {
+                    map.insert(key, rhs);
+                    return Ok(());
+                }
+                match map.get_mut(&key) {
+                    Some(item) => item,
+                    None => return error(self, &format!("Key not found: {}", key)),
+                }
+            }
+            _ => return error(self, "Subscript assignment requires a list or map"),
+        };
+
+        if rest.is_empty() {
+            *item = rhs;
+            Ok(())
+        } else {
+            self.set_item(item, rest, rhs)
+        }
+    }
+
     fn eval_cmp_status(&self) -> EvalResult<Value> {
         let message = if self.op == Op::Gt {
             "Command status does not support '>', did you mean redirect '=>' ?"
@@ -1819,6 +3508,12 @@ impl BinExpr {
             (Int(_) | Real(_), Str(_)) => error(self, ERR_CMP_NUM_STR),
             (Str(_), Int(_) | Real(_)) => error(self, ERR_CMP_STR_NUM),
             (Stat(_), _) | (_, Stat(_)) => self.eval_cmp_status(),
+            (lhs @ (List(_) | Map(_)), rhs @ (List(_) | Map(_)))
+                if matches!(self.op, Op::Equals | Op::NotEquals) =>
+            {
+                Ok(Int((lhs != rhs) as _))
+            }
+            (List(_) | Map(_), _) | (_, List(_) | Map(_)) => error(self, ERR_CMP_LIST),
         }
     }
 
@@ -1839,11 +3534,29 @@ impl BinExpr {
                 }
                 Value::Str(s2) => Ok(Value::new_str(format!("{}/{}", s1.as_str(), s2.as_str()))),
                 Value::Stat(_) => error(self, "Cannot divide by command status"),
+                Value::List(_) | Value::Map(_) => error(self, "Cannot divide by list or map"),
             },
             Value::Stat(_) => error(self, "Cannot divide command status"),
+            Value::List(_) | Value::Map(_) => error(self, "Cannot divide list or map"),
         }
     }
 
+    /// Membership test: element of list, key of map, or substring.
+    fn eval_in(&self, lhs: Value, rhs: Value) -> EvalResult<Value> {
+        let found = match &rhs {
+            Value::List(items) => items.contains(&lhs),
+            Value::Map(map) => map.contains_key(lhs.as_str().as_ref()),
+            Value::Str(s) => s.contains(lhs.as_str().as_ref()),
+            _ => {
+                return error(
+                    self,
+                    "Expecting list, map or string on the right hand-side of IN",
+                )
+            }
+        };
+        Ok(Value::Int(found as _))
+    }
+
     fn eval_int_div(&self, lhs: Value, rhs: Value) -> EvalResult<Value> {
         if let (Value::Int(i), Value::Int(j)) = (lhs, rhs) {
             Ok(Value::Int(i / j))
@@ -1867,7 +3580,7 @@ impl BinExpr {
                 self,
                 &format!(
                     "Cannot subtract strings, {} is not a recognized command",
-                    if self.scope.use_colors(&io::stderr()) {
+                    if self.scope().use_colors(&io::stderr()) {
                         self.lhs.to_string().bright_cyan()
                     } else {
                         self.lhs.to_string().normal()
@@ -1876,6 +3589,7 @@ impl BinExpr {
             ),
             (Str(_), Stat(_)) => error(self, ERR_SUB_STR_STATUS),
             (Stat(_), _) => error(self, ERR_SUB_STATUS),
+            (List(_) | Map(_), _) | (_, List(_) | Map(_)) => error(self, ERR_SUB_LIST),
         }
     }
     fn eval_mod(&self, lhs: Value, rhs: Value) -> EvalResult<Value> {
@@ -1898,6 +3612,7 @@ impl BinExpr {
             (Str(_), Int(_) | Real(_)) => error(self, ERR_MUL_STR_NUM),
             (Str(_), Str(_)) => error(self, ERR_MUL_STR_STR),
             (Stat(_), _) | (_, Stat(_)) => error(self, ERR_MUL_STATUS),
+            (List(_) | Map(_), _) | (_, List(_) | Map(_)) => error(self, ERR_MUL_LIST),
         }
     }
 
@@ -1912,43 +3627,39 @@ impl BinExpr {
             (Real(i), Real(j)) => Ok(Real(i.powf(j))),
             (Int(_) | Real(_), Str(_)) => error(self, ERR_POW_STR_EXP),
             (Int(_) | Real(_), Stat(_)) => error(self, ERR_POW_STATUS_EXP),
-            (Str(_), _) | (Stat(_), _) => error(self, ERR_POW_INVALID_BASE),
+            (Int(_) | Real(_), List(_) | Map(_)) => error(self, ERR_POW_LIST_EXP),
+            (Str(_) | Stat(_) | List(_) | Map(_), _) => error(self, ERR_POW_INVALID_BASE),
         }
     }
 
-    /// Evaluate expr and redirect output into a String
-    fn eval_redirect(&self, expr: &Rc<Expression>) -> EvalResult<(Value, String)> {
-        let mut redirect =
-            BufferRedirect::stdout().map_err(|e| EvalError::new(self.loc(), e.to_string()))?;
-
-        let result = expr.eval()?;
-
-        let mut str_buf = String::new();
-        redirect
-            .read_to_string(&mut str_buf)
-            .map_err(|e| EvalError::new(self.loc(), e.to_string()))?;
-
-        Ok((result, str_buf.to_string()))
-    }
-
-    /// Evaluate the exit code of a comand, and wrap result into Value::Stat (command status)
-    fn eval_exit_code(&self, cmd: String, status: &std::process::ExitStatus) -> EvalResult<Value> {
-        let exit_code = status.code().unwrap_or_else(|| -1);
-        my_dbg!(exit_code);
-
-        let result = if exit_code == 0 {
-            Ok(Value::success())
-        } else {
-            Err(EvalError::new(
-                self.loc(),
-                format!("{}: exited with code {}", cmd, exit_code),
-            ))
-        };
-
-        Ok(Value::Stat(Status::new(cmd, result, &self.loc)))
+    /// Flatten a pipeline expression, a | b | c, into its stages.
+    fn pipe_stages(expr: &Arc<Expression>, stages: &mut Vec<Arc<Expression>>) {
+        match &**expr {
+            Expression::Bin(b) if b.read().op == Op::Pipe => {
+                let b = b.read();
+                Self::pipe_stages(&b.lhs, stages);
+                Self::pipe_stages(&b.rhs, stages);
+            }
+            _ => stages.push(Arc::clone(expr)),
+        }
     }
 
-    /// Evaluate piping an expression into a variable (assign the output of an expression to a var.)
+    /// Evaluate pipe expression.
+    /// The pipeline is flattened into stages, connected with OS pipes, that run concurrently.
+    /// External commands are started first. The other stages (builtins, functions, aliases,
+    /// expressions) before the last are evaluated on threads, in copies of the scopes (see
+    /// Scope::copy_frames), so they see the functions and variables of the interpreter, but do
+    /// not change them (like the subshells of other shells). The last stage is evaluated on this
+    /// thread, with its standard input redirected. A stage that writes after the next one stopped
+    /// reading fails as if killed by SIGPIPE.
+    ///
+    /// The status of each stage is stored in the global $__pipestatus list: 0 if the stage
+    /// succeeded, or the error message otherwise.
+    ///
+    /// The result is the first error in the stages before the last (other than being killed by
+    /// SIGPIPE), or the result of the last stage.
+    ///
+    /// Piping into a variable assigns the output of the pipeline to the variable.
     /// Example:
     /// ```
     /// ls -al | x; echo $x
@@ -1957,160 +3668,260 @@ impl BinExpr {
     /// ```
     /// x = `ls -al`; echo $x
     /// ```
+    /// Piping into ```x[]``` assigns the output as a list of lines:
+    /// ```
+    /// ls | x[]; echo ${#x}
+    /// ```
     /// # NOTE: Changed in 0.20.0
     /// The returned result is of the left side of the pipe, so that:
     /// if (ls | result) ... or if (ls | cat | my_var) ... evaluates to true / false if the commands succeed / fail.
     ///
-    fn eval_pipe_to_var(
-        &self,
-        lhs: &Rc<Expression>,
-        rhs: &Rc<Expression>,
-    ) -> EvalResult<Option<Value>> {
-        // Piping into a literal? assign standard output capture to string variable.
-        if let Expression::Leaf(lit) = &**rhs {
-            // Special case: is the left hand-side expression a pipeline?
-            let (result, output) = if lhs.is_pipe() {
-                let program = executable().map_err(|e| EvalError::new(self.loc(), e))?;
-
-                // Get the left hand-side expression as a string
-                let lhs_str = lhs.to_string();
-
-                // Start an instance of the interpreter to evaluate the left hand-side of the pipe
-                // println!("Executing pipe LHS: {} -c {}", &program, &lhs_str);
-
-                let mut command = StdCommand::new(&program);
-                copy_vars_to_command_env(&mut command, &self.scope);
-
-                let mut child = command
-                    .arg("-c")
-                    .arg(&lhs_str)
-                    .stdout(Stdio::piped())
-                    .spawn()
-                    .map_err(|e| {
-                        EvalError::new(rhs.loc(), format!("Failed to spawn child process: {}", e))
-                    })?;
+    fn eval_pipe(&self) -> EvalResult<Value> {
+        let mut stages = Vec::new();
+        Self::pipe_stages(&self.lhs, &mut stages);
+        Self::pipe_stages(&self.rhs, &mut stages);
 
-                let mut buffer = Vec::new();
-                if let Some(mut stdout) = child.stdout.take() {
-                    stdout.read_to_end(&mut buffer).map_err(|e| {
-                        EvalError::new(rhs.loc(), format!("Failed to read output: {}", e))
-                    })?;
-                }
+        if stages.iter().any(|stage| stage.is_empty()) {
+            return error(self, "Expecting pipe input");
+        }
+
+        // Piping into a literal? capture the standard output into a variable.
+        let var = match &*stages[stages.len() - 1] {
+            Expression::Leaf(lit) => Some(Arc::clone(lit)),
+            _ => None,
+        };
+        if var.is_some() {
+            stages.pop();
+        }
 
-                // Wait for the child process to complete
-                let exit_status = child.wait().map_err(|e| {
-                    EvalError::new(
-                        rhs.loc(),
-                        format!("Failed to wait for child process output: {}", e),
-                    )
-                })?;
+        let mut output = var.as_ref().map(|_| Vec::new());
+        let mut results = self.run_pipeline(&stages, output.as_mut())?;
 
-                (
-                    self.eval_exit_code(lhs_str, &exit_status)?,
-                    String::from_utf8(buffer).map_err(|e| {
-                        EvalError::new(
-                            rhs.loc(),
-                            format!("Failed to convert pipe output from UTF8: {}", e),
-                        )
-                    })?,
+        let status = results
+            .iter()
+            .map(|result| match result {
+                Ok(Value::Stat(status)) if status.is_err() => {
+                    Value::new_str(status.err.as_ref().unwrap().message.clone())
+                }
+                Ok(_) => Value::Int(0),
+                Err(e) => Value::new_str(e.message.clone()),
+            })
+            .collect();
+        self.scope()
+            .global()
+            .insert("__pipestatus".to_string(), Value::new_list(status));
+
+        if let (Some(lit), Some(output)) = (var, output) {
+            let output = String::from_utf8(output).map_err(|e| {
+                EvalError::new(
+                    lit.loc(),
+                    format!("Failed to convert pipe output from UTF8: {}", e),
+                )
+            })?;
+
+            // Piping into NAME[] captures the output as a list of lines.
+            let result = if let Some(name) = lit.text.value.strip_suffix("[]") {
+                let lines = output.lines().map(Value::from).collect();
+                assign_var(
+                    &self.scope(),
+                    &Arc::new(name.to_string()),
+                    Value::new_list(lines),
                 )
             } else {
-                // Base use case, left hand-side is not a pipe expression
-                self.eval_redirect(lhs)?
+                let value = Value::from_str(output.trim())?;
+                assign_var(&self.scope(), &lit.text.value, value)
             };
+            result.map_err(|e| EvalError::new(lit.loc(), e))?;
+        }
 
-            let value = Value::from_str(output.trim())?;
-            self.scope.insert_value(&lit.text.value, value.clone());
-
-            return Ok(Some(result));
+        let last = results.pop().unwrap();
+        // Stages killed by SIGPIPE, because a later stage stopped reading, did not fail.
+        for result in results.into_iter().filter(|r| !Self::is_broken_pipe(r)) {
+            Status::check_result(result, false)?;
         }
-        Ok(None)
+        last
     }
 
-    /// Evaluate pipe expression.
-    /// Start an instance of this interpreter, and pass it the expression on the right hand-side of the pipe
-    /// via -c <expr>. Redirect the standard output of to a pipe, and evaluate the left hand-side expression
-    /// with its output redirected. The pipe is connected to the input of the child process that evaluates the
-    /// right side expression.
-    fn eval_pipe(&self, lhs: &Rc<Expression>, rhs: &Rc<Expression>) -> EvalResult<Value> {
-        if lhs.is_empty() {
-            return error(self, "Expecting pipe input");
+    fn is_broken_pipe(result: &EvalResult<Value>) -> bool {
+        let err = match result {
+            Ok(Value::Stat(status)) => status.err.as_ref(),
+            Ok(_) => None,
+            Err(e) => Some(e),
+        };
+        err.and_then(|e| e.exit.as_deref())
+            .is_some_and(Exit::is_broken_pipe)
+    }
+
+    /// Run the stages of a pipeline, see eval_pipe. Return the result of each stage.
+    /// If an output buffer is given, the output of the last stage is captured into it.
+    fn run_pipeline(
+        &self,
+        stages: &[Arc<Expression>],
+        output: Option<&mut Vec<u8>>,
+    ) -> EvalResult<Vec<EvalResult<Value>>> {
+        let pipe_error =
+            |e: io::Error| EvalError::new(self.loc(), format!("Failed to create pipe: {}", e));
+
+        let count = stages.len();
+        let external: Vec<_> = stages.iter().map(|stage| stage.as_external()).collect();
+
+        let mut inputs: Vec<Option<PipeReader>> = (0..count).map(|_| None).collect();
+        let mut outputs: Vec<Option<PipeWriter>> = (0..count).map(|_| None).collect();
+
+        for i in 1..count {
+            let (reader, writer) = os_pipe::pipe().map_err(pipe_error)?;
+            outputs[i - 1] = Some(writer);
+            inputs[i] = Some(reader);
         }
 
-        if let Some(val) = self.eval_pipe_to_var(lhs, rhs)? {
-            return Ok(val);
+        // Collect the output of the last stage on a separate thread.
+        let capture = if output.is_some() {
+            let (mut reader, writer) = os_pipe::pipe().map_err(pipe_error)?;
+            outputs[count - 1] = Some(writer);
+
+            Some(thread::spawn(move || {
+                let mut buffer = Vec::new();
+                reader.read_to_end(&mut buffer).map(|_| buffer)
+            }))
+        } else {
+            None
+        };
+
+        let mut results: Vec<Option<EvalResult<Value>>> = (0..count).map(|_| None).collect();
+
+        // Start the external commands first, so that they run concurrently with the other stages.
+        let mut processes = Vec::new();
+        for i in 0..count {
+            if let Some(cmd) = external[i] {
+                // The first and last stages use the input and output of the thread.
+                let stdin = match inputs[i].take() {
+                    Some(reader) => Ok(reader.into()),
+                    None => stdio::child_stdin(),
+                };
+                let stdout = match outputs[i].take() {
+                    Some(writer) => Ok(writer.into()),
+                    None => stdio::child_stdout(),
+                };
+                let spawned = match (stdin, stdout) {
+                    (Ok(stdin), Ok(stdout)) => cmd.read().spawn(stdin, stdout),
+                    (Err(e), _) | (_, Err(e)) => {
+                        Err(EvalError::new(cmd.read().loc(), e.to_string()))
+                    }
+                };
+                match spawned {
+                    Ok(process) => processes.push((i, process)),
+                    Err(e) => results[i] = Some(Err(e)),
+                }
+            }
         }
 
-        // Create a pipe
-        let (reader, writer) = os_pipe::pipe()
-            .map_err(|e| EvalError::new(self.loc(), format!("Failed to create pipe: {}", e)))?;
+        // Evaluate the other stages before the last on threads, each in its own copy of the
+        // scopes, so that assignments in one stage do not race with the others; the last stage
+        // is evaluated on this thread, in the scopes of the interpreter.
+        thread::scope(|s| {
+            let mut threads = Vec::new();
+
+            for i in 0..count - 1 {
+                if external[i].is_some() {
+                    continue;
+                }
+                let stage = &stages[i];
+                let (input, output) = (inputs[i].take(), outputs[i].take());
+
+                // The first stage reads the input of this thread.
+                let stdin = if i == 0 {
+                    stdio::try_clone_stdin()
+                } else {
+                    Ok(None)
+                };
+                let frames = self.scope.copy_frames();
+                let job = job::CurrentJob::get();
+
+                let spawned = stdin.and_then(|stdin| {
+                    thread::Builder::new()
+                        .stack_size(THREAD_STACK_SIZE)
+                        .spawn_scoped(s, move || {
+                            let _frames = frames.enter();
+                            let _job = job.enter();
+                            let _stdin = stdin.map(stdio::redirect_stdin);
+                            self.eval_stage(stage, input, output)
+                        })
+                });
+                match spawned {
+                    Ok(thread) => threads.push((i, thread)),
+                    Err(e) => {
+                        let message = format!("Failed to start thread: {}", e);
+                        results[i] = Some(Err(EvalError::new(stage.loc(), message)));
+                    }
+                }
+            }
 
-        // Get our own program name
-        let program = executable().map_err(|e| EvalError::new(self.loc(), e))?;
+            if external[count - 1].is_none() {
+                let (input, output) = (inputs[count - 1].take(), outputs[count - 1].take());
+                results[count - 1] = Some(self.eval_stage(&stages[count - 1], input, output));
+            }
 
-        // Get the right-hand side expression as a string
-        let rhs_str = rhs.to_string();
+            for (i, thread) in threads {
+                results[i] = Some(thread.join().unwrap_or_else(|e| {
+                    Err(EvalError::new(
+                        stages[i].loc(),
+                        format!("Thread panicked: {:?}", e),
+                    ))
+                }));
+            }
+        });
 
-        // Start a copy of the running program with the arguments "-c" rhs_str
-        // to evaluate the right hand-side of the pipe expression
-        let mut command = StdCommand::new(&program);
+        for (i, mut process) in processes {
+            results[i] = Some(Ok(external[i].unwrap().read().wait(&mut process)));
+        }
 
-        // Send variables over the environment to the child process.
-        copy_vars_to_command_env(&mut command, &self.scope);
+        if let (Some(thread), Some(output)) = (capture, output) {
+            *output = thread
+                .join()
+                .map_err(|e| EvalError::new(self.loc(), format!("Thread panicked: {:?}", e)))?
+                .map_err(|e| EvalError::new(self.loc(), format!("Failed to read output: {}", e)))?;
+        }
 
-        let child = command
-            .arg("-c")
-            .arg(&rhs_str)
-            .stdin(Stdio::from(reader))
-            .stdout(Stdio::inherit())
-            .spawn()
-            .map_err(|e| {
-                EvalError::new(rhs.loc(), format!("Failed to spawn child process: {}", e))
-            })?;
+        Ok(results.into_iter().map(Option::unwrap).collect())
+    }
 
-        // Drop the command to avoid deadlocks, see https://docs.rs/os_pipe/latest/os_pipe/index.html
-        drop(command);
+    /// Evaluate a pipeline stage, with its standard input and output redirected.
+    fn eval_stage(
+        &self,
+        stage: &Arc<Expression>,
+        input: Option<PipeReader>,
+        output: Option<PipeWriter>,
+    ) -> EvalResult<Value> {
+        let _stdin = input.map(stdio::redirect_stdin);
+
+        let Some(output) = output else {
+            return stage.eval();
+        };
 
-        // Redirect stdout to the pipe
-        let redirect = Redirect::stdout(writer)
-            .map_err(|e| EvalError::new(self.loc(), format!("Failed to redirect stdout: {}", e)))?;
+        // Dropping the redirect at the end closes the write end of the pipe.
+        let _stdout = stdio::redirect_stdout(output);
 
-        // Left-side evaluation's stdout goes into the pipe.
-        let lhs_result = Status::check_result(lhs.eval(), false);
+        let result = stage.eval();
 
         // Ensure expressions such as ```$HOME | cat ``` work.
-        if let Ok(val) = &lhs_result {
+        if let Ok(val) = &result {
             if !matches!(val, Value::Stat(_)) {
-                my_println!("{}", &val).map_err(|e| EvalError::new(self.loc(), e.to_string()))?;
+                _ = my_println!("{}", &val);
             }
         }
 
-        // Drop the redirect to close the write end of the pipe
-        drop(redirect);
-
-        // Flush any unread stdout buffer content to the null device,
-        // in case the child process exited without consuming it all.
-        {
-            _ = Gag::stdout().and_then(|_| std::io::stdout().flush());
+        // The next stage stopped reading: report it like a process killed by SIGPIPE.
+        if stdio::is_broken_pipe() {
+            let mut err = EvalError::new(stage.loc(), format!("{}: {}", stage, Exit::BROKEN_PIPE));
+            err.exit = Some(Box::new(Exit::BROKEN_PIPE));
+            return Err(err);
         }
-
-        // Get the output and exit code of the child process.
-        let rhs_result = match child.wait_with_output() {
-            Ok(output) => {
-                // Print the output of the right-hand side expression.
-                print!("{}", String::from_utf8_lossy(&output.stdout));
-                self.eval_exit_code(rhs_str, &output.status)
-            }
-            Err(panic_info) => Err(EvalError::new(
-                rhs.loc(),
-                format!("Thread panicked: {:?}", panic_info),
-            )),
-        };
-
-        lhs_result.and_then(|_| rhs_result)
+        result
     }
 
     /// Evaluate binary plus expression.
+    /// Adding a list to a list concatenates, adding an item to a list appends (or prepends).
+    /// Adding two maps merges them, the right hand-side map wins on key collisions.
     fn eval_plus(&self, lhs: Value, rhs: Value) -> EvalResult<Value> {
         match lhs {
             Value::Int(i) => match rhs {
@@ -2118,16 +3929,195 @@ impl BinExpr {
                 Value::Real(j) => Ok(Value::Real(i as f64 + j)),
                 Value::Str(ref s) => Ok(Value::new_str(format!("{}{}", i, s.as_str()))),
                 Value::Stat(_) => error(self, ERR_ADD_NUM_STATUS),
+                Value::List(items) => Ok(Value::new_list([&[lhs], items.as_slice()].concat())),
+                Value::Map(_) => error(self, ERR_ADD_NUM_MAP),
             },
             Value::Real(i) => match rhs {
                 Value::Int(j) => Ok(Value::Real(i + j as f64)),
                 Value::Real(j) => Ok(Value::Real(i + j)),
                 Value::Str(ref s) => Ok(Value::new_str(format!("{}{}", i, s.as_str()))),
                 Value::Stat(_) => error(self, ERR_ADD_NUM_STATUS),
+                Value::List(items) => Ok(Value::new_list([&[lhs], items.as_slice()].concat())),
+                Value::Map(_) => error(self, ERR_ADD_NUM_MAP),
+            },
+            Value::Str(ref s) => match rhs {
+                Value::List(items) => Ok(Value::new_list([&[lhs], items.as_slice()].concat())),
+                _ => Ok(Value::new_str(format!("{}{}", s.as_str(), rhs.as_str()))),
             },
-            Value::Str(s) => Ok(Value::new_str(format!("{}{}", s.as_str(), rhs.as_str()))),
             Value::Stat(_) => error(self, ERR_ADD_STATUS),
+            Value::List(items) => match rhs {
+                Value::List(other) => Ok(Value::new_list([items.as_slice(), &other].concat())),
+                Value::Stat(_) => error(self, ERR_ADD_LIST_STATUS),
+                _ => Ok(Value::new_list([items.as_slice(), &[rhs]].concat())),
+            },
+            Value::Map(map) => match rhs {
+                Value::Map(other) => {
+                    let mut map = Arc::unwrap_or_clone(map);
+                    map.extend(other.iter().map(|(k, v)| (k.clone(), v.clone())));
+                    Ok(Value::new_map(map))
+                }
+                _ => error(self, ERR_ADD_MAP),
+            },
+        }
+    }
+
+    /// Evaluate the (optional) operand of RETURN, and jump out of the function.
+    fn eval_return(&self) -> EvalResult<Value> {
+        let value = if self.rhs.is_empty() {
+            Value::success()
+        } else {
+            self.rhs.eval()?
+        };
+
+        Err(EvalError {
+            loc: self.loc(),
+            message: "RETURN outside function".to_string(),
+            command: None,
+            exit: None,
+            jump: Some(Jump::Return(value)),
+        })
+    }
+
+    /// Evaluate LOCAL, READONLY (CONST) and EXPORT declarations, of the form: NAME = VALUE, or NAME.
+    fn eval_declaration(&self) -> EvalResult<Value> {
+        // export "NAME = VALUE" evaluates the string in the global scope, like eval --export
+        if let Expression::Leaf(lit) = &*self.rhs {
+            if self.op == Op::Export && lit.text.quoted {
+                let text = self.rhs.eval()?.to_string();
+                let eval = get_command("eval").unwrap();
+                eval.exec("eval", &vec!["--export".to_string(), text], &self.scope())
+                    .map_err(|e| EvalError::new(self.loc(), e))?;
+                return Ok(Value::success());
+            }
+        }
+
+        let (name, value) = match &*self.rhs {
+            Expression::Bin(b) if b.read().op == Op::Assign => match declared_name(&b.read().lhs) {
+                Some(name) => (name, Some(Arc::clone(&b.read().rhs))),
+                None => return error(self, "Identifier expected on left hand-side of assignment"),
+            },
+            expr => match declared_name(expr) {
+                Some(name) => (name, None),
+                None => {
+                    let op = self.op.to_string().to_uppercase();
+                    return error(
+                        self,
+                        &format!("Expecting NAME = VALUE, or NAME after {}", op),
+                    );
+                }
+            },
+        };
+        let name = Arc::new(name);
+        if name.is_empty() || starts_with_special(&name) {
+            return error(self, &format!("Invalid variable name: {}", name));
+        }
+
+        let value = match value {
+            Some(expr) => match expr.eval()? {
+                Value::Stat(stat) => {
+                    return error(
+                        self,
+                        &format!("{} {} | {};", ASSIGN_STATUS_ERROR, stat.cmd, name),
+                    );
+                }
+                value => Some(value),
+            },
+            None => None,
+        };
+
+        let scope = self.scope();
+        match (&self.op, value) {
+            // A new variable in the current scope, which shadows any variable of the same name in
+            // the enclosing scopes, i.e. subsequent $NAME = VALUE assignments do not change them.
+            (Op::Local, value) => {
+                if scope
+                    .lookup_local(&name)
+                    .is_some_and(|var| var.is_readonly())
+                {
+                    return error(
+                        self,
+                        &format!("Cannot assign read-only variable: ${}", name),
+                    );
+                }
+                let exported = scope.lookup(&name).is_some_and(|var| var.is_exported());
+                let value = value.unwrap_or_else(|| Value::new_str(String::default()));
+                scope.insert_value(&name, value.clone());
+                if exported {
+                    scope.lookup_local(&name).unwrap().set_exported();
+                }
+                Ok(value)
+            }
+            (op, Some(value)) => {
+                assign_var(&scope, &name, value.clone())
+                    .map_err(|e| EvalError::new(self.loc(), e))?;
+                let var = scope.lookup_local(&name).unwrap();
+                if *op == Op::Export {
+                    var.set_exported();
+                } else {
+                    var.set_readonly();
+                }
+                Ok(value)
+            }
+            // Change the attributes of an existing variable, in the current or in an enclosing scope.
+            (op, None) => match scope.lookup(&name) {
+                Some(var) => {
+                    if *op == Op::Export {
+                        var.set_exported();
+                    } else {
+                        var.set_readonly();
+                    }
+                    let value = var.value().clone();
+                    Ok(value)
+                }
+                None => error(self, &format!("Variable not found: ${}", name)),
+            },
+        }
+    }
+
+    /// Start the left hand-side expression in the background, on a thread, in a copy of the
+    /// scopes (see Scope::copy_frames): the job sees all the functions and variables of the
+    /// interpreter, but does not change them. Its standard input is detached.
+    /// The job is added to the job table, see the jobs, fg, bg, wait and kill commands.
+    fn eval_background(&self) -> EvalResult<Value> {
+        let text = self.lhs.to_string();
+        let expr = Arc::clone(&self.lhs);
+        let frames = self.scope.copy_frames();
+
+        let stdio = stdio::null().and_then(|stdin| Ok((stdin, stdio::try_clone_stdout()?)));
+        let started = stdio.and_then(|(stdin, stdout)| {
+            job::start_background(text.clone(), THREAD_STACK_SIZE, move || {
+                let _frames = frames.enter();
+                let _stdin = stdio::redirect_stdin(stdin);
+                let _stdout = stdio::redirect_stdout(stdout);
+
+                match Status::check_result(expr.eval(), false) {
+                    Ok(_) => Exit::default(),
+                    Err(e) => {
+                        // Jobs terminated with kill end quietly, as if killed by the signal.
+                        if !Scope::is_interrupted() {
+                            eprintln!("{}", e);
+                        }
+                        e.exit.as_deref().copied().unwrap_or(Exit::FAILURE)
+                    }
+                }
+            })
+        });
+
+        let id = started.map_err(|e| {
+            EvalError::new(self.loc(), format!("Failed to start background job: {}", e))
+        })?;
+
+        if matches!(
+            self.scope().lookup_value("__interactive"),
+            Some(Value::Int(1))
+        ) {
+            eprintln!("[{}]", id);
         }
+        Ok(Value::Stat(Status::new(
+            text,
+            Ok(Value::success()),
+            &self.loc,
+        )))
     }
 
     /// Lookup and erase the variable named by the left hand-side expression
@@ -2136,7 +4126,17 @@ impl BinExpr {
             let var_name = &lit.text.value;
 
             if var_name.starts_with('$') {
-                if let Some(var) = lit.scope.erase(&var_name[1..]) {
+                if lit
+                    .scope()
+                    .lookup(&var_name[1..])
+                    .is_some_and(|var| var.is_readonly())
+                {
+                    return error(
+                        self,
+                        &format!("Cannot erase read-only variable: {}", var_name),
+                    );
+                }
+                if let Some(var) = lit.scope().erase(&var_name[1..]) {
                     return Ok(var.value().clone()); // Return the erased value
                 } else {
                     return error(self, &format!("Variable not found: {}", var_name));
@@ -2146,13 +4146,62 @@ impl BinExpr {
         error(self, "Variable expected on left hand-side of assignment")
     }
 
+    /// Evaluate here-strings and here-documents: the text on the right hand-side is written
+    /// to the standard input of the left hand-side expression (from a thread, via a pipe).
+    fn eval_here_string(&self) -> EvalResult<Value> {
+        let mut text = match &*self.rhs {
+            // Here-documents with quoted markers are not expanded.
+            Expression::Leaf(lit) if lit.text.raw => lit.text.value(),
+            _ => self.rhs.eval()?.to_string(),
+        };
+        if !text.ends_with('\n') {
+            text.push('\n');
+        }
+
+        let (reader, mut writer) = os_pipe::pipe()
+            .map_err(|e| EvalError::new(self.loc(), format!("Failed to create pipe: {}", e)))?;
+
+        thread::spawn(move || _ = writer.write_all(text.as_bytes()));
+
+        // Redirect stdin to the pipe
+        let _redirect = stdio::redirect_stdin(reader);
+
+        // Evaluate left hand-side expression
+        self.lhs.eval()
+    }
+
+    /// Evaluate input redirection: ```sort =< file.txt```
+    /// The file is connected to the standard input of the left hand-side expression,
+    /// and is read by builtin commands as well as by external programs.
+    fn eval_read(&self) -> EvalResult<Value> {
+        let filename = self.rhs.eval()?.to_string();
+
+        let path = Path::new(&filename);
+        let file = path
+            .dereference()
+            .and_then(|path| File::open(&path))
+            .map_err(|e| {
+                EvalError::new(
+                    self.rhs.loc(),
+                    format!("Failed to open {}: {}", self.scope().err_str(&filename), e),
+                )
+            })?;
+
+        // Redirect stdin to the file
+        let _redirect = stdio::redirect_stdin(file);
+
+        // Evaluate left hand-side expression
+        self.lhs.eval()
+    }
+
     /// Redirect standard output to file, and evaluate the left hand-side expression.
     fn eval_write(&self, append: bool) -> EvalResult<Value> {
         let filename = self.rhs.eval()?.to_string();
         if filename == NULL_REDIRECT {
             // Silence off stdout
-            let _redirect = Gag::stdout()
+            let null = stdio::null()
                 .map_err(|e| EvalError::new(self.loc(), format!("Failed to gag output: {}", e)))?;
+            let _redirect = stdio::redirect_stdout(null);
             // Evaluate left hand-side expression
             self.lhs.eval()
         } else {
@@ -2161,7 +4210,7 @@ impl BinExpr {
             if Path::new(&filename).exists()
                 && confirm(
                     format!("{} exists, confirm {}", filename, operation),
-                    &self.scope,
+                    &self.scope(),
                     false,
                 )
                 .map_err(|e| EvalError::new(self.loc(), e.to_string()))?
@@ -2181,16 +4230,14 @@ impl BinExpr {
                             self.loc(),
                             format!(
                                 "Failed to open {}: {}",
-                                self.scope.err_str(&filename),
+                                self.scope().err_str(&filename),
                                 e.to_string()
                             ),
                         )
                     })?;
 
                 // Redirect stdout to the file
-                let _redirect = Redirect::stdout(file).map_err(|e| {
-                    EvalError::new(self.loc(), format!("Failed to redirect stdout: {}", e))
-                })?;
+                let _redirect = stdio::redirect_stdout(file);
 
                 // Evaluate left hand-side expression
                 self.lhs.eval()
@@ -2207,14 +4254,20 @@ macro_rules! eval_bin {
 
 impl Eval for BinExpr {
     fn eval(&self) -> EvalResult<Value> {
-        if self.rhs.is_empty() {
+        if self.op == Op::Return {
+            self.eval_return()
+        } else if self.op.is_declaration() {
+            self.eval_declaration()
+        } else if self.op == Op::Background {
+            self.eval_background()
+        } else if self.rhs.is_empty() {
             if self.op == Op::Assign {
                 return self.eval_erase(); // Assign empty, erase variable
             }
             error(self, "Expecting right hand-side operand")
         } else if self.lhs.is_empty() {
             if self.op.is_unary_ok() {
-                eval_unary(self, &self.op, self.rhs.eval()?, &self.scope)
+                eval_unary(self, &self.op, self.rhs.eval()?, &self.scope())
             } else {
                 error(self, "Expecting left hand-side operand")
             }
@@ -2223,11 +4276,14 @@ impl Eval for BinExpr {
                 Op::And => self.eval_and(),
                 Op::Append => self.eval_write(true),
                 Op::Assign => self.eval_assign(),
+                Op::Background => self.eval_background(),
                 Op::Div => eval_bin!(self, eval_div),
                 Op::Gt => eval_bin!(self, eval_gt),
                 Op::Gte => eval_bin!(self, eval_gte),
+                Op::In => eval_bin!(self, eval_in),
                 Op::IntDiv => eval_bin!(self, eval_int_div),
                 Op::Equals => eval_bin!(self, eval_equals),
+                Op::Export | Op::Local | Op::Readonly => self.eval_declaration(),
                 Op::Lt => eval_bin!(self, eval_lt),
                 Op::Lte => eval_bin!(self, eval_lte),
                 Op::Minus => eval_bin!(self, eval_minus),
@@ -2236,9 +4292,12 @@ impl Eval for BinExpr {
                 Op::Not => error(self, "Unexpected logical negation operator"),
                 Op::NotEquals => eval_bin!(self, eval_not_equals),
                 Op::Or => self.eval_or(),
-                Op::Pipe => self.eval_pipe(&self.lhs, &self.rhs),
+                Op::Pipe => self.eval_pipe(),
                 Op::Plus => eval_bin!(self, eval_plus),
                 Op::Power => eval_bin!(self, eval_power),
+                Op::Return => self.eval_return(),
+                Op::HereString => self.eval_here_string(),
+                Op::Read => self.eval_read(),
                 Op::Write => self.eval_write(false),
             }
         }
@@ -2250,6 +4309,8 @@ enum Group {
     None,
     Args,
     Block,
+    List,
+    Map,
 }
 
 #[derive(Debug)]
@@ -2257,7 +4318,8 @@ struct GroupExpr {
     kind: Group,
     closed: bool,
     scope: Arc<Scope>,
-    content: Vec<Rc<Expression>>,
+    content: Vec<Arc<Expression>>,
+    keys: Vec<Text>, // Map keys
     loc: Location,
 }
 
@@ -2267,6 +4329,7 @@ impl GroupExpr {
             kind: Group::Args,
             scope: Arc::clone(&scope),
             content: Vec::new(),
+            keys: Vec::new(),
             loc: loc.clone(),
             closed: false,
         }
@@ -2276,27 +4339,90 @@ impl GroupExpr {
         Self {
             kind: Group::Block,
             content: Vec::new(),
+            keys: Vec::new(),
             loc: loc.clone(),
             scope: Arc::clone(&scope),
             closed: false,
         }
     }
-}
 
-derive_has_location!(GroupExpr);
+    fn new_list(loc: &Location, scope: &Arc<Scope>) -> Self {
+        Self {
+            kind: Group::List,
+            content: Vec::new(),
+            keys: Vec::new(),
+            loc: loc.clone(),
+            scope: Arc::clone(scope),
+            closed: false,
+        }
+    }
+
+    /// Evaluate the elements of a list literal, e.g. [a "b c" $x (1 + 2)],
+    /// or of a map literal, e.g. [host: localhost, port: 8080]
+    fn eval_list(&self) -> EvalResult<Value> {
+        if self.kind == Group::Map {
+            let mut map = BTreeMap::new();
+
+            for (key, e) in self.keys.iter().zip(&self.content) {
+                let key = if key.raw {
+                    key.value()
+                } else {
+                    parse_value(&key.value, &self.loc, &self.scope())?.to_string()
+                };
+                map.insert(key, Self::eval_item(e)?);
+            }
+            return Ok(Value::new_map(map));
+        }
+
+        let items = self
+            .content
+            .iter()
+            .map(Self::eval_item)
+            .collect::<EvalResult<Vec<_>>>()?;
+
+        Ok(Value::new_list(items))
+    }
+
+    fn eval_item(expr: &Arc<Expression>) -> EvalResult<Value> {
+        if let Expression::Leaf(lit) = &**expr {
+            if lit.text.raw {
+                return Ok(Value::new_str(lit.text.value()));
+            }
+        }
+        Status::check_result(expr.eval(), true)
+    }
+}
+
+derive_has_location!(GroupExpr);
+derive_scope!(GroupExpr);
 
 impl Eval for GroupExpr {
     fn eval(&self) -> EvalResult<Value> {
-        self.scope.clear();
+        self.scope().clear();
 
         let mut result = Ok(Value::success());
+        let mut deferred = Vec::new();
 
         for e in &self.content {
             // Check the previous result for unhandled command errors
             result = Status::check_result(result, false);
 
             if result.is_ok() {
+                if let Expression::Defer(d) = &**e {
+                    if self.kind == Group::Block {
+                        deferred.push(Arc::clone(&d.read().body));
+                        continue;
+                    }
+                }
+                if self.kind == Group::Block && debugger::is_active() {
+                    let depth = COMMAND_DEPTH.with(|depth| depth.get());
+                    debugger::before_statement(&e.loc(), &**e, &self.scope(), depth);
+                }
+                let start = (self.kind == Group::Block && profiler::is_active()).then(Instant::now);
                 let temp = e.eval();
+                if let Some(start) = start {
+                    profiler::record_statement(&e.loc(), &**e, start.elapsed());
+                }
 
                 if let Ok(Value::Str(word)) = &temp {
                     // BREAK and CONTINUE are "caught" by eval_iteration,
@@ -2306,6 +4432,8 @@ impl Eval for GroupExpr {
                         result = Err(EvalError {
                             loc: e.loc(),
                             message: "BREAK outside loop".to_string(),
+                            command: None,
+                            exit: None,
                             jump: Some(Jump::Break(result.unwrap())),
                         });
                         break;
@@ -2313,6 +4441,8 @@ impl Eval for GroupExpr {
                         result = Err(EvalError {
                             loc: e.loc(),
                             message: "CONTINUE outside loop".to_string(),
+                            command: None,
+                            exit: None,
                             jump: Some(Jump::Continue(result.unwrap())),
                         });
                         // It may seem counter-intuitive to break here instead of continue-ing;
@@ -2328,6 +4458,8 @@ impl Eval for GroupExpr {
                             result = Err(EvalError {
                                 loc: e.loc(),
                                 message: err.message,
+                                command: None,
+                                exit: None,
                                 jump: Some(Jump::Break(result.unwrap())),
                             });
                             break;
@@ -2336,11 +4468,13 @@ impl Eval for GroupExpr {
                             result = Err(EvalError {
                                 loc: e.loc(),
                                 message: err.message,
+                                command: None,
+                                exit: None,
                                 jump: Some(Jump::Continue(result.unwrap())),
                             });
                             break;
                         }
-                        None => {
+                        Some(Jump::Return(_)) | None => {
                             result = Err(err);
                             break;
                         }
@@ -2351,18 +4485,45 @@ impl Eval for GroupExpr {
             }
         }
 
+        if !deferred.is_empty() {
+            Self::run_deferred(&deferred, &mut result);
+        }
+
         result // Return the last evaluation
     }
 }
 
+impl GroupExpr {
+    /// Run the DEFER bodies of the block, most recent first, whether the block succeeded or not
+    /// (and also on Ctrl+C). As with FINALLY, an error in a DEFER body takes precedence.
+    fn run_deferred(deferred: &[Arc<Expression>], result: &mut EvalResult<Value>) {
+        let mut deferred_err = None;
+
+        traps::uninterrupted(|| {
+            for body in deferred.iter().rev() {
+                if let Err(e) = Status::check_result(body.eval(), false) {
+                    deferred_err.get_or_insert(e);
+                }
+            }
+        });
+
+        if let Some(e) = deferred_err {
+            *result = Err(e);
+        }
+    }
+}
+
 impl ExprNode for GroupExpr {
-    fn add_child(&mut self, child: &Rc<Expression>) -> EvalResult {
-        self.content.push(Rc::clone(child));
+    fn add_child(&mut self, child: &Arc<Expression>) -> EvalResult {
+        if self.kind == Group::Map && self.content.len() >= self.keys.len() {
+            return error(&**child, "Expecting map key");
+        }
+        self.content.push(Arc::clone(child));
         Ok(())
     }
 }
 
-fn join_expr(expressions: &[Rc<Expression>], separator: &str) -> String {
+fn join_expr(expressions: &[Arc<Expression>], separator: &str) -> String {
     expressions
         .iter()
         .map(|expr| expr.to_string())
@@ -2374,6 +4535,16 @@ impl fmt::Display for GroupExpr {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         if self.kind == Group::Args {
             write!(f, "{}", join_expr(&self.content, " "))
+        } else if self.kind == Group::List {
+            write!(f, "[{}]", join_expr(&self.content, " "))
+        } else if self.kind == Group::Map {
+            if self.content.is_empty() {
+                return write!(f, "[:]");
+            }
+            let entries: Vec<_> = (self.keys.iter().zip(&self.content))
+                .map(|(key, value)| format!("{}: {}", key, value))
+                .collect();
+            write!(f, "[{}]", entries.join(" "))
         } else {
             write!(f, "( {} )", join_expr(&self.content, "; "))
         }
@@ -2383,12 +4554,13 @@ impl fmt::Display for GroupExpr {
 #[derive(Debug)]
 struct Command {
     cmd: ShellCommand,
-    args: Rc<Expression>,
+    args: Arc<Expression>,
     loc: Location,
     scope: Arc<Scope>,
 }
 
 derive_has_location!(Command);
+derive_scope!(Command);
 
 macro_rules! handle_redir_error {
     ($redir:expr, $loc:expr) => {
@@ -2407,19 +4579,25 @@ macro_rules! handle_redir_error {
 /// __stdout = some/path/file.txt ls -al;
 /// __stdout = output.txt; __stderr = 1; ls -al c:\
 /// ```
+/// The standard output is redirected for the current thread only (see stdio.rs), and the
+/// standard error for the whole process.
 enum Redirection {
     #[allow(dead_code)]
-    File(Redirect<File>),
+    Stdout(stdio::StdoutRedirect),
     #[allow(dead_code)]
-    Stdout(Option<Redirect<std::io::Stdout>>),
-    #[allow(dead_code)]
-    Stderr(Option<Redirect<std::io::Stderr>>),
-    #[allow(dead_code)]
-    Null(Gag),
+    Stderr(Redirect<File>),
     None,
 }
 
 impl Redirection {
+    fn to_file(name: &str, file: File) -> io::Result<Self> {
+        if name == "__stdout" {
+            Ok(Redirection::Stdout(stdio::redirect_stdout(file)))
+        } else {
+            Ok(Redirection::Stderr(Redirect::stderr(file)?))
+        }
+    }
+
     fn with_scope(
         scope: &Arc<Scope>,
         name: &str,
@@ -2444,11 +4622,8 @@ impl Redirection {
         path: &String,
     ) -> Result<Self, String> {
         if path == NULL_REDIRECT {
-            if name == "__stdout" {
-                return Ok(Redirection::Null(Gag::stdout().map_err(|e| e.to_string())?));
-            } else {
-                return Ok(Redirection::Null(Gag::stderr().map_err(|e| e.to_string())?));
-            }
+            let null = stdio::null().map_err(|e| e.to_string())?;
+            return Self::to_file(name, null).map_err(|e| e.to_string());
         }
 
         if path == other || path == other_desc {
@@ -2462,13 +4637,14 @@ impl Redirection {
                 return Self::redirect(scope, name, other, other_desc, &other_path);
             }
 
-            if name == "__stdout" {
-                let redir = Redirect::stdout(io::stderr()).map_err(|e| e.to_string())?;
-                return Ok(Redirection::Stderr(Some(redir)));
+            let other_file = if name == "__stdout" {
+                stdio::try_clone_stderr()
             } else {
-                let redir = Redirect::stderr(io::stdout()).map_err(|e| e.to_string())?;
-                return Ok(Redirection::Stdout(Some(redir)));
-            }
+                stdio::try_clone_stdout()
+            };
+            return other_file
+                .and_then(|file| Self::to_file(name, file))
+                .map_err(|e| e.to_string());
         }
 
         if Path::new(&path).exists()
@@ -2498,12 +4674,7 @@ impl Redirection {
                 )
             })?;
 
-        let redir = if name == "__stdout" {
-            Redirect::stdout(file)
-        } else {
-            Redirect::stderr(file)
-        }
-        .map_err(|error| {
+        let redir = Self::to_file(name, file).map_err(|error| {
             format!(
                 "Failed to redirect {} to file {}: {}",
                 name,
@@ -2511,7 +4682,7 @@ impl Redirection {
                 error
             )
         })?;
-        return Ok(Redirection::File(redir));
+        return Ok(redir);
     }
 }
 
@@ -2520,13 +4691,13 @@ impl Command {
     /// an argument, if the error is related to one of the arguments, return the
     /// location of the corresponding expression.
     fn err_loc(&self) -> Location {
-        let mut index = self.scope.err_arg();
+        let mut index = self.scope().err_arg();
         if index > 0 {
             index -= 1;
             match &*self.args {
                 Expression::Args(a) => {
-                    if index < a.borrow().content.len() {
-                        return a.borrow().content[index].loc();
+                    if index < a.read().content.len() {
+                        return a.read().content[index].loc();
                     }
                 }
                 _ => {}
@@ -2541,36 +4712,186 @@ impl Eval for Command {
     fn eval(&self) -> EvalResult<Value> {
         // Redirect stdout if a $__stdout variable found in scope.
         // Values can be "2", "__stderr", "NULL", or a filename.
-        let redir_stdout = Redirection::with_scope(&self.scope, "__stdout", "__stderr", "2");
+        let redir_stdout = Redirection::with_scope(&self.scope(), "__stdout", "__stderr", "2");
         handle_redir_error!(&redir_stdout, self.loc());
 
         // Redirect stderr if a $__stderr variable found in scope.
         // Values can be "1", "__stdout", "NULL", or a filename.
-        let redir_stderr = Redirection::with_scope(&self.scope, "__stderr", "__stdout", "1");
+        let redir_stderr = Redirection::with_scope(&self.scope(), "__stderr", "__stdout", "1");
         handle_redir_error!(&redir_stderr, self.loc());
 
-        let args = self.args.tokenize_args(&self.scope, false)?;
+        // Functions get their arguments as values, unquoted lists are not expanded.
+        let expand_lists = self.cmd.as_function().is_none();
+        let values = self
+            .args
+            .eval_args(&self.scope(), false, false, expand_lists, None)?;
+        let args: Vec<_> = values.iter().map(Value::to_string).collect();
+
+        let tracing = is_tracing(&self.scope());
+        if tracing {
+            self.trace_start(&args);
+        }
+        let start = profiler::is_active().then(Instant::now);
+        COMMAND_DEPTH.with(|depth| depth.set(depth.get() + 1));
+        let result = self.run(&args, &values);
+        COMMAND_DEPTH.with(|depth| depth.set(depth.get() - 1));
+        if let Some(start) = start {
+            profiler::record_command(self.cmd.name(), start.elapsed());
+        }
+        if tracing {
+            self.trace_end(&result);
+        }
+        result
+    }
+}
+
+thread_local! {
+    // Depth of nested commands, e.g. functions, aliases, eval --source
+    static COMMAND_DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Check if execution tracing is enabled, with $__trace or the -x command line flag.
+fn is_tracing(scope: &Arc<Scope>) -> bool {
+    scope
+        .lookup("__trace")
+        .is_some_and(|var| !matches!(&*var.value().as_str(), "" | "0"))
+}
+
+impl Command {
+    fn run(&self, args: &Vec<String>, values: &[Value]) -> EvalResult<Value> {
+        // User-defined functions are evaluated in-process; their results (values or
+        // command statuses) are returned as they are, with errors at their own locations.
+        if let Some(func) = self.cmd.as_function() {
+            return func.call(values, &self.scope(), &self.loc);
+        }
 
         // Execute command
-        let result = self
+        job::clear_last_exit();
+        let mut result = self
             .cmd
-            .exec(&self.cmd.name(), &args, &self.scope)
+            .exec(&self.cmd.name(), args, &self.scope())
             .map_err(|e| EvalError::new(self.err_loc(), e));
+        self.record_exit(&mut result);
 
-        Ok(Value::Stat(Status::new(
-            self.to_string(),
-            result,
-            &self.loc,
-        )))
+        // Commands may hand back a status of their own, e.g. throw re-raising a caught error.
+        if let Ok(Value::Stat(_)) = &result {
+            return result;
+        }
+
+        let mut status = Status::new(self.to_string(), result, &self.loc);
+        status.args = args.clone();
+        Ok(Value::Stat(status))
+    }
+
+    /// Store how the command ended in $__status (the exit code), $__signal (the name of the
+    /// signal that terminated the process, if any) and $__core_dumped. Errors carry the exit
+    /// of the process that failed, if the command ran any.
+    fn record_exit(&self, result: &mut EvalResult<Value>) {
+        let failure = || {
+            job::last_exit()
+                .filter(|exit| !exit.success())
+                .unwrap_or(Exit::FAILURE)
+        };
+        let exit = match result {
+            Ok(Value::Stat(status)) => match &status.err {
+                Some(e) => e.exit.as_deref().copied().unwrap_or(Exit::FAILURE),
+                None => Exit::default(),
+            },
+            Ok(_) => Exit::default(),
+            Err(e) => **e.exit.get_or_insert_with(|| Box::new(failure())),
+        };
+
+        let scope = self.scope();
+        let global = scope.global();
+        global.insert("__status".to_string(), Value::Int(exit.code));
+        global.insert(
+            "__signal".to_string(),
+            Value::new_str(exit.signal_name().unwrap_or_default()),
+        );
+        global.insert(
+            "__core_dumped".to_string(),
+            Value::Int(exit.core_dumped as _),
+        );
+    }
+
+    /// Print the trace prefix: indentation by the depth of nested commands (e.g. eval --source,
+    /// aliases, functions), and the location of the command.
+    fn trace_prefix(&self) -> String {
+        let indent = "  ".repeat(COMMAND_DEPTH.with(|depth| depth.get()));
+        // The arguments start on the line of the command name, self.loc may be on the line above.
+        let loc = self.args.loc();
+        match &loc.file {
+            Some(file) => format!("{}+ {}:{}:", indent, file, loc.line),
+            None => format!("{}+ {}:", indent, loc.line),
+        }
+    }
+
+    /// Trace the command with its expanded arguments to stderr, before running it.
+    fn trace_start(&self, args: &[String]) {
+        let mut line = format!("{} {}", self.trace_prefix(), self.cmd.name());
+        for arg in args {
+            if arg.is_empty() || arg.contains(char::is_whitespace) {
+                line.push_str(&format!(" {:?}", arg));
+            } else {
+                line.push(' ');
+                line.push_str(arg);
+            }
+        }
+        eprintln!("{}", line);
+    }
+
+    /// Trace the resulting status (or value, for functions) to stderr, after running the command.
+    fn trace_end(&self, result: &EvalResult<Value>) {
+        let outcome = match result {
+            Ok(Value::Stat(status)) => match &status.err {
+                Some(e) => format!("failed: {}", e.message),
+                None => "ok".to_string(),
+            },
+            Ok(value) => value.to_string(),
+            Err(e) => format!("failed: {}", e.message),
+        };
+        eprintln!("{} {} => {}", self.trace_prefix(), self.cmd.name(), outcome);
+    }
+
+    /// Start an external command as a pipeline stage, without waiting for it to complete.
+    fn spawn(&self, stdin: Stdio, stdout: Stdio) -> EvalResult<Process> {
+        // The child process inherits the redirected standard error.
+        let redir_stderr = Redirection::with_scope(&self.scope(), "__stderr", "__stdout", "1");
+        handle_redir_error!(&redir_stderr, self.loc());
+
+        let args = self.args.tokenize_args(&self.scope(), false)?;
+
+        if is_tracing(&self.scope()) {
+            self.trace_start(&args);
+        }
+        self.cmd
+            .spawn(&args, &self.scope(), stdin, stdout)
+            .map_err(|e| EvalError::new(self.err_loc(), format!("{}: {}", self, e)))
+    }
+
+    /// Wait for a command started with spawn, and wrap its exit code into a command status.
+    fn wait(&self, process: &mut Process) -> Value {
+        job::clear_last_exit();
+        let mut result = process
+            .wait()
+            .map(|_| Value::success())
+            .map_err(|e| EvalError::new(self.err_loc(), format!("{}: {}", self, e)));
+        self.record_exit(&mut result);
+
+        let value = Value::Stat(Status::new(self.to_string(), result, &self.loc));
+        if is_tracing(&self.scope()) {
+            self.trace_end(&Ok(value.clone()));
+        }
+        value
     }
 }
 
 impl ExprNode for Command {
-    fn add_child(&mut self, child: &Rc<Expression>) -> EvalResult {
+    fn add_child(&mut self, child: &Arc<Expression>) -> EvalResult {
         if !child.is_args() {
             return Err(EvalError::new(child.loc(), "Expecting argument list"));
         }
-        self.args = Rc::clone(&child);
+        self.args = Arc::clone(&child);
         Ok(())
     }
 }
@@ -2586,15 +4907,16 @@ impl fmt::Display for Command {
 
 #[derive(Debug)]
 struct BranchExpr {
-    cond: Rc<Expression>,
-    if_branch: Rc<Expression>,
-    else_branch: Rc<Expression>,
+    cond: Arc<Expression>,
+    if_branch: Arc<Expression>,
+    else_branch: Arc<Expression>,
     expect_else: bool,
     loc: Location,
     scope: Arc<Scope>,
 }
 
 derive_has_location!(BranchExpr);
+derive_scope!(BranchExpr);
 
 impl BranchExpr {
     fn is_else_expected(&mut self) -> bool {
@@ -2628,6 +4950,9 @@ fn value_as_bool<L: HasLocation>(loc: &L, val: &Value, scope: &Arc<Scope>) -> Ev
             ));
         }
         Value::Stat(stat) => stat.as_bool(&scope),
+        Value::List(_) | Value::Map(_) => {
+            return error(loc, "Cannot evaluate list or map as boolean");
+        }
     };
 
     hoist(scope, "__errors");
@@ -2635,20 +4960,20 @@ fn value_as_bool<L: HasLocation>(loc: &L, val: &Value, scope: &Arc<Scope>) -> Ev
     Ok(result)
 }
 
-fn eval_as_bool(expr: &Rc<Expression>, scope: &Arc<Scope>) -> EvalResult<bool> {
+fn eval_as_bool(expr: &Arc<Expression>, scope: &Arc<Scope>) -> EvalResult<bool> {
     let value = expr.eval()?;
     value_as_bool(&**expr, &value, &scope)
 }
 
 impl ExprNode for BranchExpr {
-    fn add_child(&mut self, child: &Rc<Expression>) -> EvalResult {
+    fn add_child(&mut self, child: &Arc<Expression>) -> EvalResult {
         if self.cond.is_empty() {
-            self.cond = Rc::clone(child);
+            self.cond = Arc::clone(child);
         } else if self.if_branch.is_empty() {
             if !child.is_group() {
                 return error(&**child, "Parentheses are required around IF body");
             }
-            self.if_branch = Rc::clone(child);
+            self.if_branch = Arc::clone(child);
         } else if self.else_branch.is_empty() {
             if !self.expect_else {
                 return error(&**child, "Expecting ELSE keyword");
@@ -2656,7 +4981,7 @@ impl ExprNode for BranchExpr {
             if !child.is_group() {
                 return error(&**child, "Parentheses are required around ELSE body");
             }
-            self.else_branch = Rc::clone(child);
+            self.else_branch = Arc::clone(child);
         } else {
             return error(
                 &**child,
@@ -2674,7 +4999,7 @@ impl Eval for BranchExpr {
         } else if self.if_branch.is_empty() {
             return error(self, "Expecting IF block");
         }
-        if eval_as_bool(&self.cond, &self.scope)? {
+        if eval_as_bool(&self.cond, &self.scope())? {
             self.if_branch.eval()
         } else if self.else_branch.is_empty() {
             Ok(Value::success())
@@ -2703,41 +5028,45 @@ struct Literal {
 }
 
 derive_has_location!(Literal);
+derive_scope!(Literal);
 
 impl Eval for Literal {
     fn eval(&self) -> EvalResult<Value> {
-        parse_value(&self.text.value, &self.loc, &self.scope)
+        match parse_value(&self.text.value, &self.loc, &self.scope())? {
+            // Quoted lists and maps are strings, e.g. "$x"
+            value @ (Value::List(_) | Value::Map(_)) if self.text.quoted => {
+                Ok(Value::new_str(value.to_string()))
+            }
+            value => Ok(value),
+        }
     }
 }
 
 impl fmt::Display for Literal {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        if self.text.quoted {
-            if self.text.raw {
-                write!(f, "r\"({})\"", &self.text.value)
-            } else {
-                write!(f, "\"{}\"", &self.text.value.escape_default())
-            }
-        } else {
-            write!(f, "{}", &self.text.value)
-        }
+        write!(f, "{}", self.text)
     }
 }
 
 #[derive(Debug)]
 struct LoopExpr {
-    cond: Rc<Expression>,
-    body: Rc<Expression>,
+    cond: Arc<Expression>,
+    body: Arc<Expression>,
     loc: Location,
     scope: Arc<Scope>,
 }
 
 derive_has_location!(LoopExpr);
+derive_scope!(LoopExpr);
 
 macro_rules! eval_iteration {
     ($self:expr, $result:ident) => {{
         if Scope::is_interrupted() {
-            eprintln!("^C");
+            // Loops writing into a pipe that is no longer read, or killed in
+            // the background, stop silently.
+            if !stdio::is_broken_pipe() && !job::is_background() {
+                eprintln!("^C");
+            }
             break;
         }
 
@@ -2754,7 +5083,7 @@ macro_rules! eval_iteration {
                 Some(Jump::Continue(v)) => {
                     $result = Ok(v.clone());
                 }
-                None => {
+                Some(Jump::Return(_)) | None => {
                     break;
                 }
             }
@@ -2771,7 +5100,7 @@ impl Eval for LoopExpr {
         }
         let mut result = Ok(Value::success());
         loop {
-            if !eval_as_bool(&self.cond, &self.scope)? {
+            if !eval_as_bool(&self.cond, &self.scope())? {
                 break;
             }
             eval_iteration!(self, result);
@@ -2780,94 +5109,825 @@ impl Eval for LoopExpr {
     }
 }
 
-impl ExprNode for LoopExpr {
-    fn add_child(&mut self, child: &Rc<Expression>) -> EvalResult {
-        if self.cond.is_empty() {
-            self.cond = Rc::clone(child);
-        } else if self.body.is_empty() {
-            if !child.is_group() {
-                return error(&**child, "Parentheses are required around WHILE body");
+impl ExprNode for LoopExpr {
+    fn add_child(&mut self, child: &Arc<Expression>) -> EvalResult {
+        if self.cond.is_empty() {
+            self.cond = Arc::clone(child);
+        } else if self.body.is_empty() {
+            if !child.is_group() {
+                return error(&**child, "Parentheses are required around WHILE body");
+            }
+            self.body = Arc::clone(&child);
+        } else {
+            return error(&**child, "WHILE already has a body");
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for LoopExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "while {} {}", self.cond, self.body)
+    }
+}
+
+/// FOR loop: for VAR[, VAR...] in ARGS; (BODY)
+/// With more than one variable, each iteration binds the elements of a list
+/// (or the key and value of a map entry), or else consumes one arg per variable.
+/// With VAR[], unquoted args and stdin are split into lines instead of words.
+#[derive(Debug)]
+struct ForExpr {
+    vars: Vec<String>,
+    lines: bool,
+    args: Arc<Expression>,
+    body: Arc<Expression>,
+    loc: Location,
+    scope: Arc<Scope>,
+}
+
+derive_has_location!(ForExpr);
+derive_scope!(ForExpr);
+
+impl Eval for ForExpr {
+    fn eval(&self) -> EvalResult<Value> {
+        if self.vars.is_empty() {
+            return error(self, "Expecting FOR variable");
+        }
+        if self.args.is_empty() || self.args.is_no_args() {
+            return error(self, "Expecting FOR arguments");
+        }
+        if self.body.is_empty() {
+            return error(self, "Expecting FOR body");
+        }
+
+        let mut result = Ok(Value::success());
+
+        // Iterate over the keys of maps (or key, value pairs if there are multiple
+        // variables), and lazily over numeric ranges {START..END[..STEP]}
+        let count = self.vars.len();
+        let mut ranges = Vec::new();
+        let values =
+            self.args
+                .eval_args(&self.scope(), true, self.lines, true, Some(&mut ranges))?;
+        let mut args = values.into_iter().enumerate().flat_map(
+            |(i, arg)| -> Box<dyn Iterator<Item = Value>> {
+                match arg {
+                    Value::Map(map) if count > 1 => Box::new(
+                        map.iter()
+                            .map(|(k, v)| {
+                                Value::new_list(vec![Value::new_str(k.clone()), v.clone()])
+                            })
+                            .collect::<Vec<_>>()
+                            .into_iter(),
+                    ),
+                    Value::Map(map) => {
+                        let keys = map.keys().map(|k| Value::new_str(k.clone()));
+                        Box::new(keys.collect::<Vec<_>>().into_iter())
+                    }
+                    Value::Str(ref s) if ranges.contains(&i) => match NumRange::parse(s) {
+                        Some(range) => Box::new(range.map(Value::Int)),
+                        None => Box::new(std::iter::once(arg)),
+                    },
+                    _ => Box::new(std::iter::once(arg)),
+                }
+            },
+        );
+        while let Some(arg) = args.next() {
+            let values = match arg {
+                Value::List(items) if count > 1 => items.to_vec(),
+                _ if count > 1 => std::iter::once(arg)
+                    .chain(args.by_ref().take(count - 1))
+                    .collect(),
+                _ => vec![arg],
+            };
+            // Bind variables, missing values are bound to empty strings.
+            for (i, var) in self.vars.iter().enumerate() {
+                let value = match values.get(i) {
+                    Some(Value::Str(s)) => s.parse::<Value>()?,
+                    Some(value) => value.clone(), // List elements keep their values
+                    None => Value::default(),
+                };
+                self.scope().insert(var.clone(), value);
+            }
+
+            eval_iteration!(self, result);
+        }
+
+        result
+    }
+}
+
+impl ExprNode for ForExpr {
+    fn add_child(&mut self, child: &Arc<Expression>) -> EvalResult {
+        if self.args.is_empty() && !child.is_args() {
+            // Variables, until the argument list that follows IN, e.g. for i, j in ...
+            if let Expression::Leaf(lit) = &**child {
+                let text = lit.text.value();
+                for name in text.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+                    if self.lines {
+                        return error(self, "Expecting IN after FOR variable[]");
+                    }
+                    if let Some(name) = name.strip_suffix("[]") {
+                        if !self.vars.is_empty() {
+                            return error(self, "Line iteration requires one single FOR variable");
+                        }
+                        self.lines = true;
+                        self.vars.push(name.to_string());
+                    } else {
+                        self.vars.push(name.to_string());
+                    }
+                }
+                return Ok(());
+            }
+            return error(self, "Expecting identifier in FOR expression");
+        } else if self.args.is_empty() {
+            if child.is_args() {
+                self.args = Arc::clone(&child);
+            } else {
+                return error(self, "Expecting argument list");
+            }
+        } else if self.body.is_empty() {
+            if !child.is_group() {
+                return error(&**child, "Parentheses are required around FOR body");
+            }
+            self.body = Arc::clone(&child);
+        } else {
+            return error(self, "FOR already has a body");
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ForExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let vars = self.vars.join(", ");
+        let lines = if self.lines { "[]" } else { "" };
+        write!(f, "for {}{} in {}; {}", vars, lines, self.args, self.body)
+    }
+}
+
+/// Function definition: def NAME (PARAMS) (BODY)
+#[derive(Debug)]
+struct FuncExpr {
+    func: Option<Arc<Function>>,
+    params: Arc<Expression>,
+    body: Arc<Expression>,
+    module: Option<Arc<Module>>,
+    loc: Location,
+}
+
+derive_has_location!(FuncExpr);
+
+impl FuncExpr {
+    /// Define the function, once the body is parsed; each call evaluates the parsed body in
+    /// new scopes, see Function::call.
+    fn define(&self) -> EvalResult {
+        let (Some(func), Expression::Group(body)) = (&self.func, &*self.body) else {
+            return Ok(());
+        };
+        let params = match &*self.params {
+            Expression::Args(args) => args.read().content.iter().map(|p| p.to_string()).collect(),
+            _ => Vec::new(),
+        };
+        let def = FuncDef {
+            params,
+            body: Arc::clone(&self.body),
+            scope: Arc::clone(&body.read().scope),
+            loc: self.body.loc(),
+        };
+        if func.def.set(def).is_err() {
+            return error(self, "Function is already defined");
+        }
+        Ok(())
+    }
+}
+
+impl Eval for FuncExpr {
+    fn eval(&self) -> EvalResult<Value> {
+        match &self.func {
+            Some(func) if func.def.get().is_some() => {
+                register_command(ShellCommand::new(
+                    func.qualified_name(),
+                    Arc::clone(func) as _,
+                ));
+
+                Ok(Value::Stat(Status::new(
+                    format!("def {}", func.name),
+                    Ok(Value::success()),
+                    &self.loc,
+                )))
+            }
+            _ => error(self, "Expecting function body"),
+        }
+    }
+}
+
+impl ExprNode for FuncExpr {
+    fn add_child(&mut self, child: &Arc<Expression>) -> EvalResult {
+        if self.func.is_none() {
+            if let Expression::Leaf(lit) = &**child {
+                let name = lit.text.value();
+                if !lit.text.quoted && !name.starts_with('$') && !starts_with_special(&name) {
+                    self.func = Some(Arc::new(Function::new(name, self.module.clone())));
+                    return Ok(());
+                }
+            }
+            return error(&**child, "Expecting function name");
+        } else if self.params.is_empty() {
+            if let Expression::Args(args) = &**child {
+                for param in &args.read().content {
+                    match &**param {
+                        Expression::Leaf(lit)
+                            if !lit.text.quoted
+                                && !lit.text.value.starts_with('$')
+                                && !starts_with_special(&lit.text.value) => {}
+                        _ => return error(&**param, "Expecting parameter name"),
+                    }
+                }
+                self.params = Arc::clone(child);
+            } else {
+                return error(&**child, "Expecting parameters list");
+            }
+        } else if self.body.is_empty() {
+            if !child.is_group() {
+                return error(&**child, "Parentheses are required around function body");
+            }
+            self.body = Arc::clone(child);
+            self.define()?;
+        } else {
+            return error(
+                &**child,
+                "Unexpected expression after function body, missing semicolon?",
+            );
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for FuncExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self
+            .func
+            .as_ref()
+            .map(|func| func.name.as_str())
+            .unwrap_or_default();
+        write!(f, "def {} ({}) {}", name, self.params, self.body)
+    }
+}
+
+#[derive(Debug)]
+struct FuncDef {
+    params: Vec<String>,
+    body: Arc<Expression>,
+    scope: Arc<Scope>, // The scope that the parser bound the body to
+    loc: Location,
+}
+
+thread_local! {
+    static CALL_DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+/// User-defined function, registered as a command when its definition is evaluated.
+#[derive(Debug)]
+pub struct Function {
+    name: String,
+    def: OnceLock<FuncDef>,
+    module: Option<Arc<Module>>, // Imported module that defines the function
+}
+
+impl Function {
+    fn new(name: String, module: Option<Arc<Module>>) -> Self {
+        Self {
+            name,
+            def: OnceLock::new(),
+            module,
+        }
+    }
+
+    /// The name the function is registered with, e.g. util.greet for functions of modules.
+    fn qualified_name(&self) -> String {
+        match &self.module {
+            Some(module) => format!("{}.{}", module.name, self.name),
+            None => self.name.clone(),
+        }
+    }
+
+    /// Bind the arguments to the parameters in a new scope, and evaluate the body. The body
+    /// is evaluated in new scopes (see Scope::replace_with), so that recursive calls do not
+    /// share variables.
+    fn call(&self, args: &[Value], scope: &Arc<Scope>, loc: &Location) -> EvalResult<Value> {
+        let def = match self.def.get() {
+            Some(def) => def,
+            None => return Err(EvalError::new(loc.clone(), "Expecting function body")),
+        };
+
+        if def.params.len() > args.len() {
+            return Err(EvalError::new(
+                loc.clone(),
+                format!(
+                    "{}: missing argument: {}",
+                    self.name,
+                    def.params[args.len()]
+                ),
+            ));
+        }
+
+        let depth = CALL_DEPTH.with(|depth| depth.replace(depth.get() + 1));
+        if depth >= MAX_CALL_DEPTH {
+            CALL_DEPTH.with(|depth| depth.set(depth.get() - 1));
+            return Err(EvalError::new(
+                loc.clone(),
+                format!("{}: maximum call depth exceeded", self.name),
+            ));
+        }
+
+        let func_scope = Scope::with_parent_and_hooks(Some(Arc::clone(scope)), None);
+        // Functions of modules see the (current values of the) module-level variables.
+        if let Some(module) = &self.module {
+            for (name, var) in module.scope.vars().iter() {
+                if !name.is_special_var() {
+                    func_scope.vars_mut().insert(name.clone(), var.clone());
+                }
+            }
+        }
+        for (param, arg) in def.params.iter().zip(args) {
+            // Words are parsed, e.g. as numbers; lists and maps are bound as they are.
+            let value = match arg {
+                Value::Str(s) => s.parse::<Value>()?,
+                _ => arg.clone(),
+            };
+            func_scope.insert(param.clone(), value);
+        }
+        // Extra arguments are accessible via $@ and $#
+        let words: Vec<_> = args.iter().map(Value::to_string).collect();
+        func_scope.insert("@".to_string(), Value::new_str(words.join(" ")));
+        func_scope.insert("#".to_string(), Value::Int(args.len() as _));
+
+        let body_scope = Scope::with_parent_and_hooks(Some(func_scope), None);
+        let result = {
+            let _call = def.scope.replace_with(&body_scope);
+            def.body.eval()
+        };
+
+        CALL_DEPTH.with(|depth| depth.set(depth.get() - 1));
+
+        match result {
+            Err(EvalError {
+                jump: Some(Jump::Return(value)),
+                ..
+            }) => Ok(value),
+            // Do not let BREAK and CONTINUE escape into loops in the caller.
+            Err(err) if err.jump.is_some() => Err(EvalError::new(err.loc, err.message)),
+            _ => result,
+        }
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let params = self
+            .def
+            .get()
+            .map(|def| def.params.join(" "))
+            .unwrap_or_default();
+        write!(f, "def {} ({})", self.name, params)
+    }
+}
+
+impl Exec for Function {
+    fn as_any(&self) -> Option<&dyn Any> {
+        Some(self)
+    }
+
+    fn exec(&self, _name: &str, args: &Vec<String>, scope: &Arc<Scope>) -> Result<Value, String> {
+        let loc = self
+            .def
+            .get()
+            .map(|def| def.loc.clone())
+            .unwrap_or(Location::new(0, 0));
+        let args: Vec<_> = args.iter().map(|arg| Value::new_str(arg.clone())).collect();
+
+        Status::check_result(self.call(&args, scope, &loc), false).map_err(|e| e.to_string())
+    }
+}
+
+/// Error handling: try (BODY) catch VAR (HANDLER) finally (CLEANUP)
+#[derive(Debug)]
+struct TryExpr {
+    body: Arc<Expression>,
+    var: String,
+    catch_body: Arc<Expression>,
+    finally_body: Arc<Expression>,
+    expect_catch: bool,
+    expect_finally: bool,
+    loc: Location,
+    scope: Arc<Scope>,
+}
+
+derive_has_location!(TryExpr);
+derive_scope!(TryExpr);
+
+impl TryExpr {
+    /// Called by the parser when seeing the CATCH or FINALLY keyword.
+    fn expect_clause(&mut self, keyword: &str) -> Result<(), String> {
+        if self.body.is_empty() {
+            return Err(format!("Expecting TRY block before {}", keyword));
+        }
+        if self.expect_finally {
+            return Err(format!("Unexpected {} after FINALLY", keyword));
+        }
+        if keyword == "CATCH" {
+            if self.expect_catch {
+                return Err("TRY already has a CATCH block".to_string());
+            }
+            self.expect_catch = true;
+        } else {
+            if self.expect_catch && self.catch_body.is_empty() {
+                return Err("Expecting CATCH block".to_string());
+            }
+            self.expect_finally = true;
+        }
+        Ok(())
+    }
+
+    /// Make a map with the error details, to be bound to the CATCH variable.
+    fn error_object(&self, err: &EvalError) -> Value {
+        Value::new_map(err.to_map(""))
+    }
+}
+
+impl ExprNode for TryExpr {
+    fn add_child(&mut self, child: &Arc<Expression>) -> EvalResult {
+        if self.body.is_empty() {
+            if !child.is_group() {
+                return error(&**child, "Parentheses are required around TRY body");
+            }
+            self.body = Arc::clone(child);
+        } else if self.expect_finally {
+            if !self.finally_body.is_empty() {
+                return error(
+                    &**child,
+                    "Unexpected expression after FINALLY body, missing semicolon?",
+                );
+            }
+            if !child.is_group() {
+                return error(&**child, "Parentheses are required around FINALLY body");
+            }
+            self.finally_body = Arc::clone(child);
+        } else if self.expect_catch {
+            if !self.catch_body.is_empty() {
+                return error(
+                    &**child,
+                    "Unexpected expression after CATCH body, missing semicolon?",
+                );
+            }
+            // The error variable is optional: catch (...)
+            if let Expression::Leaf(lit) = &**child {
+                if self.var.is_empty() {
+                    self.var = lit.text.value();
+                    return Ok(());
+                }
+            }
+            if !child.is_group() {
+                return error(&**child, "Parentheses are required around CATCH body");
+            }
+            self.catch_body = Arc::clone(child);
+        } else {
+            return error(&**child, "Expecting CATCH or FINALLY keyword");
+        }
+        Ok(())
+    }
+}
+
+impl Eval for TryExpr {
+    fn eval(&self) -> EvalResult<Value> {
+        if self.body.is_empty() {
+            return error(self, "Expecting TRY block");
+        } else if self.catch_body.is_empty() && self.finally_body.is_empty() {
+            return error(self, "Expecting CATCH or FINALLY block");
+        }
+
+        let mut result = Status::check_result(self.body.eval(), false);
+
+        if let Err(e) = &result {
+            // BREAK, CONTINUE and RETURN pass through, and so do interruptions (Ctrl+C).
+            if e.jump.is_none() && !self.catch_body.is_empty() && !Scope::is_interrupted() {
+                if !self.var.is_empty() {
+                    self.scope().insert(self.var.clone(), self.error_object(e));
+                }
+                // Keep the original error, for THROW without arguments to re-raise it.
+                let cmd = e.command.clone().unwrap_or_default();
+                let status = Status::new(cmd, Err(e.clone()), &e.loc);
+                self.scope()
+                    .insert("__caught".to_string(), Value::Stat(status));
+
+                result = Status::check_result(self.catch_body.eval(), false);
+
+                self.scope().erase("__caught");
+            }
+        }
+
+        if !self.finally_body.is_empty() {
+            Status::check_result(self.finally_body.eval(), false)?;
+        }
+        result
+    }
+}
+
+impl fmt::Display for TryExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "try {}", self.body)?;
+
+        if !self.catch_body.is_empty() {
+            if self.var.is_empty() {
+                write!(f, " catch {}", self.catch_body)?;
+            } else {
+                write!(f, " catch {} {}", self.var, self.catch_body)?;
+            }
+        }
+        if !self.finally_body.is_empty() {
+            write!(f, " finally {}", self.finally_body)?;
+        }
+        Ok(())
+    }
+}
+
+/// Cleanup at the end of the enclosing block: defer (BODY)
+///
+/// The body runs when the block finishes, whether it succeeded or not, see GroupExpr::eval.
+#[derive(Debug)]
+struct DeferExpr {
+    body: Arc<Expression>,
+    loc: Location,
+}
+
+derive_has_location!(DeferExpr);
+
+impl ExprNode for DeferExpr {
+    fn add_child(&mut self, child: &Arc<Expression>) -> EvalResult {
+        if !self.body.is_empty() {
+            return error(
+                &**child,
+                "Unexpected expression after DEFER body, missing semicolon?",
+            );
+        }
+        if !child.is_group() {
+            return error(&**child, "Parentheses are required around DEFER body");
+        }
+        self.body = Arc::clone(child);
+        Ok(())
+    }
+}
+
+impl Eval for DeferExpr {
+    fn eval(&self) -> EvalResult<Value> {
+        // Statements of blocks are handled by GroupExpr::eval, anything else is misplaced.
+        error(self, "DEFER outside of block")
+    }
+}
+
+impl fmt::Display for DeferExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "defer {}", self.body)
+    }
+}
+
+/// Built-in function call: NAME(ARGS)
+#[derive(Debug)]
+struct CallExpr {
+    func: &'static BuiltinFunction,
+    args: Arc<Expression>,
+    loc: Location,
+}
+
+derive_has_location!(CallExpr);
+
+impl ExprNode for CallExpr {
+    fn add_child(&mut self, child: &Arc<Expression>) -> EvalResult {
+        if !self.args.is_empty() || !matches!(**child, Expression::List(_)) {
+            return error(&**child, "Unexpected expression after function call");
+        }
+        self.args = Arc::clone(child);
+        Ok(())
+    }
+}
+
+impl Eval for CallExpr {
+    fn eval(&self) -> EvalResult<Value> {
+        let args = match &*self.args {
+            Expression::List(group) => group.read().eval_list()?,
+            _ => return error(self, "Expecting function arguments"),
+        };
+        let args = match &args {
+            Value::List(items) => items.as_slice(),
+            _ => return error(self, "Expecting function arguments, not a map"),
+        };
+        self.func
+            .call(args)
+            .map_err(|e| EvalError::new(self.loc(), format!("{}: {}", self.func.name, e)))
+    }
+}
+
+impl fmt::Display for CallExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}(", self.func.name)?;
+        if let Expression::List(group) = &*self.args {
+            for (i, arg) in group.read().content.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}", arg)?;
+            }
+        }
+        write!(f, ")")
+    }
+}
+
+/// match SUBJECT (case PATTERN... (BODY); ...)
+#[derive(Debug)]
+struct MatchExpr {
+    subject: Arc<Expression>,
+    body: Arc<Expression>,
+    loc: Location,
+}
+
+derive_has_location!(MatchExpr);
+
+impl ExprNode for MatchExpr {
+    fn add_child(&mut self, child: &Arc<Expression>) -> EvalResult {
+        if self.subject.is_empty() {
+            self.subject = Arc::clone(child);
+        } else if self.body.is_empty() {
+            if let Expression::Group(group) = &**child {
+                if let Some(arm) = group.read().content.iter().find(|e| !e.is_case()) {
+                    return error(&**arm, "Expecting CASE in MATCH body");
+                }
+            } else {
+                return error(&**child, "Parentheses are required around MATCH body");
+            }
+            self.body = Arc::clone(child);
+        } else {
+            return error(
+                &**child,
+                "Unexpected expression after MATCH body, missing semicolon?",
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Eval for MatchExpr {
+    fn eval(&self) -> EvalResult<Value> {
+        if self.subject.is_empty() {
+            return error(self, "Expecting MATCH subject");
+        }
+        let subject = match Status::check_result(self.subject.eval(), true)? {
+            Value::Stat(_) => return error(&*self.subject, "Cannot match command status"),
+            value => value.to_string(),
+        };
+
+        if let Expression::Group(group) = &*self.body {
+            let group = group.read();
+            // Start with a clean slate, the variables bound to regex captures go in this scope.
+            group.scope().clear();
+
+            for arm in &group.content {
+                if let Expression::Case(case) = &**arm {
+                    let case = case.read();
+                    if case.matches(&subject)? {
+                        return case.body().eval();
+                    }
+                }
             }
-            self.body = Rc::clone(&child);
         } else {
-            return error(&**child, "WHILE already has a body");
+            return error(self, "Expecting MATCH body");
         }
-        Ok(())
+        Ok(Value::success())
     }
 }
 
-impl fmt::Display for LoopExpr {
+impl fmt::Display for MatchExpr {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "while {} {}", self.cond, self.body)
+        write!(f, "match {} {}", self.subject, self.body)
     }
 }
 
+/// A MATCH arm. The patterns and the body are parsed as an argument list,
+/// the last argument being the body.
 #[derive(Debug)]
-struct ForExpr {
-    var: String,
-    args: Rc<Expression>,
-    body: Rc<Expression>,
+struct CaseExpr {
+    args: Arc<Expression>,
     loc: Location,
     scope: Arc<Scope>,
 }
 
-derive_has_location!(ForExpr);
+derive_has_location!(CaseExpr);
+derive_scope!(CaseExpr);
 
-impl Eval for ForExpr {
-    fn eval(&self) -> EvalResult<Value> {
-        if self.var.is_empty() {
-            return error(self, "Expecting FOR variable");
-        }
-        if self.args.is_empty() || self.args.is_no_args() {
-            return error(self, "Expecting FOR arguments");
-        }
-        if self.body.is_empty() {
-            return error(self, "Expecting FOR body");
+impl CaseExpr {
+    fn patterns(&self) -> Vec<Arc<Expression>> {
+        match &*self.args {
+            Expression::Args(args) => {
+                let args = args.read();
+                args.content[..args.content.len() - 1].to_vec()
+            }
+            _ => vec![],
         }
+    }
 
-        let mut result = Ok(Value::success());
+    fn body(&self) -> Arc<Expression> {
+        match &*self.args {
+            Expression::Args(args) => Arc::clone(args.read().content.last().unwrap()),
+            _ => panic!("Unexpected CASE arguments"),
+        }
+    }
 
-        let args = self.args.tokenize_args(&self.scope, true)?;
-        for arg in &args {
-            // Bind variable to arg. TODO: experiment with binding multiple vars for i, j in $args
-            self.scope.insert(self.var.clone(), arg.parse::<Value>()?);
+    /// Match the subject against the patterns: raw strings are regular expressions,
+    /// an unquoted underscore matches anything, everything else is a glob pattern.
+    fn matches(&self, subject: &str) -> EvalResult<bool> {
+        for pattern in self.patterns() {
+            if let Expression::Leaf(lit) = &*pattern {
+                if lit.text.raw {
+                    let re = Regex::new(&lit.text.value).map_err(|e| {
+                        EvalError::new(lit.loc(), format!("Invalid regular expression: {}", e))
+                    })?;
+                    if let Some(captures) = re.captures(subject) {
+                        self.bind_captures(&re, &captures)?;
+                        return Ok(true);
+                    }
+                    continue;
+                } else if !lit.text.quoted && lit.text.value.as_str() == "_" {
+                    return Ok(true);
+                }
+            }
 
-            eval_iteration!(self, result);
+            let value = match Status::check_result(pattern.eval(), true)? {
+                Value::Stat(_) => return error(&*pattern, "Cannot match command status"),
+                value => value.to_string(),
+            };
+            let is_match = match glob::Pattern::new(&value) {
+                Ok(glob_pattern) => glob_pattern.matches(subject),
+                Err(_) => value == subject,
+            };
+            if is_match {
+                return Ok(true);
+            }
         }
+        Ok(false)
+    }
 
-        result
+    /// Bind named captures to variables, and all captures to $__match (as a list).
+    fn bind_captures(&self, re: &Regex, captures: &regex::Captures) -> EvalResult {
+        for name in re.capture_names().flatten() {
+            if let Some(m) = captures.name(name) {
+                self.scope()
+                    .insert(name.to_string(), m.as_str().parse::<Value>()?);
+            }
+        }
+        let items = captures
+            .iter()
+            .map(|m| {
+                m.map_or(Ok(Value::new_str(String::default())), |m| {
+                    m.as_str().parse()
+                })
+            })
+            .collect::<EvalResult<Vec<_>>>()?;
+
+        self.scope()
+            .insert("__match".to_string(), Value::new_list(items));
+        Ok(())
     }
 }
 
-impl ExprNode for ForExpr {
-    fn add_child(&mut self, child: &Rc<Expression>) -> EvalResult {
-        if self.var.is_empty() {
-            if let Expression::Leaf(lit) = &**child {
-                self.var = lit.text.value();
-                return Ok(());
-            }
-            return error(self, "Expecting identifier in FOR expression");
-        } else if self.args.is_empty() {
-            if child.is_args() {
-                self.args = Rc::clone(&child);
-            } else {
-                return error(self, "Expecting argument list");
-            }
-        } else if self.body.is_empty() {
-            if !child.is_group() {
-                return error(&**child, "Parentheses are required around FOR body");
-            }
-            self.body = Rc::clone(&child);
-        } else {
-            return error(self, "FOR already has a body");
+impl ExprNode for CaseExpr {
+    fn add_child(&mut self, child: &Arc<Expression>) -> EvalResult {
+        if !self.args.is_empty() {
+            return error(self, "CASE already has a body");
         }
+        match &**child {
+            Expression::Args(args) => match args.read().content.as_slice() {
+                [] | [_] => return error(self, "Expecting CASE pattern and body"),
+                [.., body] if !body.is_group() => {
+                    return error(&**body, "Parentheses are required around CASE body");
+                }
+                _ => {}
+            },
+            _ => return error(self, "Expecting argument list"),
+        }
+        self.args = Arc::clone(child);
         Ok(())
     }
 }
 
-impl fmt::Display for ForExpr {
+impl Eval for CaseExpr {
+    fn eval(&self) -> EvalResult<Value> {
+        error(self, "CASE outside MATCH")
+    }
+}
+
+impl fmt::Display for CaseExpr {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "for {} in {}; {}", &self.var, self.args, self.body)
+        write!(f, "case {}", self.args)
     }
 }
 
@@ -2883,6 +5943,9 @@ fn eval_unary<T: HasLocation>(
             Value::Real(r) => Ok(Value::Real(-r)),
             Value::Str(s) => Ok(Value::new_str(format!("-{}", s))),
             Value::Stat(_) => error(loc, "Unary minus not supported for command status"),
+            Value::List(_) | Value::Map(_) => {
+                error(loc, "Unary minus not supported for lists and maps")
+            }
         },
         Op::Not => {
             if let Value::Stat(mut s) = val {
@@ -2900,17 +5963,24 @@ fn eval_unary<T: HasLocation>(
 impl Eval for Expression {
     fn eval(&self) -> EvalResult<Value> {
         match &self {
-            Expression::Args(g) => g.borrow().eval(),
-            Expression::Bin(b) => b.borrow().eval(),
-            Expression::Branch(b) => b.borrow().eval(),
-            Expression::Cmd(c) => c.borrow().eval(),
+            Expression::Args(g) => g.read().eval(),
+            Expression::Bin(b) => b.read().eval(),
+            Expression::Branch(b) => b.read().eval(),
+            Expression::Call(c) => c.read().eval(),
+            Expression::Case(c) => c.read().eval(),
+            Expression::Cmd(c) => c.read().eval(),
+            Expression::Defer(d) => d.read().eval(),
             Expression::Empty => {
                 panic!("Empty expression");
             }
-            Expression::For(f) => f.borrow().eval(),
-            Expression::Group(g) => g.borrow().eval(),
+            Expression::For(f) => f.read().eval(),
+            Expression::Func(f) => f.read().eval(),
+            Expression::Group(g) => g.read().eval(),
             Expression::Leaf(lit) => lit.eval(),
-            Expression::Loop(l) => l.borrow().eval(),
+            Expression::List(g) => g.read().eval_list(),
+            Expression::Loop(l) => l.read().eval(),
+            Expression::Match(m) => m.read().eval(),
+            Expression::Try(t) => t.read().eval(),
         }
     }
 }
@@ -2918,26 +5988,34 @@ impl Eval for Expression {
 pub struct Interp {
     scope: Arc<Scope>,
     file: Option<Arc<String>>,
+    module: Option<Arc<Module>>,
     pub quit: bool,
 }
 
-fn new_args(loc: &Location, scope: &Arc<Scope>) -> Rc<Expression> {
-    Rc::new(Expression::Args(RefCell::new(GroupExpr::new_args(
+fn new_args(loc: &Location, scope: &Arc<Scope>) -> Arc<Expression> {
+    Arc::new(Expression::Args(RwLock::new(GroupExpr::new_args(
         loc, &scope,
     ))))
 }
 
-fn new_group(loc: &Location, scope: &Arc<Scope>) -> Rc<Expression> {
-    Rc::new(Expression::Group(RefCell::new(GroupExpr::new_group(
+fn new_group(loc: &Location, scope: &Arc<Scope>) -> Arc<Expression> {
+    Arc::new(Expression::Group(RwLock::new(GroupExpr::new_group(
         loc, &scope,
     ))))
 }
 
+fn new_list(loc: &Location, scope: &Arc<Scope>) -> Arc<Expression> {
+    Arc::new(Expression::List(RwLock::new(GroupExpr::new_list(
+        loc, scope,
+    ))))
+}
+
 impl Interp {
     pub fn new(scope: Arc<Scope>) -> Self {
         Self {
             scope,
             file: None,
+            module: None,
             quit: false,
         }
     }
@@ -2946,6 +6024,7 @@ impl Interp {
         Self {
             scope: Scope::with_env_vars(),
             file: None,
+            module: None,
             quit: false,
         }
     }
@@ -2971,7 +6050,11 @@ impl Interp {
         Status::check_result(result, false)
     }
 
-    fn parse(&mut self, input: &str, eval_scope: Option<Arc<Scope>>) -> EvalResult<Rc<Expression>> {
+    fn parse(
+        &mut self,
+        input: &str,
+        eval_scope: Option<Arc<Scope>>,
+    ) -> EvalResult<Arc<Expression>> {
         let scope = {
             if let Some(scope) = eval_scope {
                 scope
@@ -2983,6 +6066,7 @@ impl Interp {
         };
 
         let mut parser = Parser::new(input.chars(), &scope, self.file.clone());
+        parser.module = self.module.clone();
 
         parser.parse(&mut self.quit)
     }
@@ -3003,6 +6087,118 @@ impl Interp {
         self.file.clone()
     }
 
+    pub fn set_module(&mut self, module: Option<Arc<Module>>) {
+        self.module = module;
+    }
+
+    /// Parse the source of the module being imported (without evaluating it),
+    /// and return the functions it defines, registered as MODULE.FUNCTION
+    pub fn module_functions(&self, input: &str) -> EvalResult<Vec<ShellCommand>> {
+        let module = match &self.module {
+            Some(module) => module,
+            None => return Ok(Vec::new()),
+        };
+        let mut parser = Parser::new(input.chars(), &module.scope, self.file.clone());
+        parser.module = Some(Arc::clone(module));
+        parser.parse(&mut false)?;
+
+        // Skip the functions of the modules imported by this one.
+        Ok(parser
+            .functions
+            .into_values()
+            .filter_map(|cmd| {
+                let func = cmd.as_function()?;
+                let own = func.module.as_ref().is_some_and(|m| Arc::ptr_eq(m, module));
+                own.then(|| cmd.with_name(func.qualified_name()))
+            })
+            .collect())
+    }
+
+    /// Parse the input without evaluating it, and run static checks (the --check mode).
+    /// Return the syntax errors and the warnings, ordered by location.
+    pub fn check(&self, input: &str) -> Vec<EvalError> {
+        let scope = Scope::with_parent_and_hooks(Some(self.scope.clone()), None);
+        let mut linter = Linter::new(input);
+        let mut errors = Vec::new();
+        let (mut line, mut offset) = (1, 0);
+
+        let parse = |line: u32, text: &str| {
+            let mut parser = Parser::new(text.chars(), &scope, self.file.clone());
+            parser.loc.line = line;
+            parser.prev_loc.line = line;
+            parser.parse(&mut false)
+        };
+
+        // Lines that are not indented, and likely start a new statement: (line number, offset).
+        let mut start = 0;
+        let statements: Vec<_> = input
+            .split_inclusive('\n')
+            .zip(1u32..)
+            .filter_map(|(text, n)| {
+                let result = text
+                    .starts_with(|c: char| !c.is_whitespace() && !")#".contains(c))
+                    .then_some((n, start));
+                start += text.len();
+                result
+            })
+            .collect();
+
+        loop {
+            match parse(line, &input[offset..]) {
+                Ok(ast) => {
+                    linter.check(&ast);
+                    break;
+                }
+                Err(e) => {
+                    let err_line = e.loc.line;
+                    errors.push(e);
+
+                    // Lint the longest part before the error that parses.
+                    let prefix = statements
+                        .iter()
+                        .rev()
+                        .filter(|(n, _)| *n > line && *n <= err_line)
+                        .find_map(|(_, end)| parse(line, &input[offset..*end]).ok());
+                    if let Some(ast) = prefix {
+                        linter.check(&ast);
+                    }
+
+                    // Resume at the next statement after the error.
+                    let next = statements.iter().find(|(n, _)| *n > err_line).copied();
+                    match next {
+                        Some((n, start)) => (line, offset) = (n, start),
+                        None => break,
+                    }
+                }
+            }
+        }
+
+        errors.extend(linter.finish(&scope));
+        errors.sort_by_key(|e| (e.loc.line, e.loc.col));
+        errors
+    }
+
+    /// Parse the input, and re-emit it with consistent indentation and spacing (the --fmt mode).
+    /// The result is parsed again, to make sure that formatting did not change its meaning.
+    pub fn format(&self, input: &str) -> EvalResult<String> {
+        let scope = Scope::with_parent_and_hooks(Some(self.scope.clone()), None);
+        let mut parser = Parser::new(input.chars(), &scope, self.file.clone());
+        parser.tokens = Some(Vec::new());
+        let expected = parser.parse(&mut false)?.to_string();
+
+        let output = Formatter::new(input).format(&parser.tokens.unwrap_or_default());
+
+        let scope = Scope::with_parent_and_hooks(Some(self.scope.clone()), None);
+        let mut parser = Parser::new(output.chars(), &scope, self.file.clone());
+        match parser.parse(&mut false) {
+            Ok(ast) if ast.to_string() == expected => Ok(output),
+            _ => Err(EvalError::new(
+                Location::with_file(self.file.clone()),
+                "Formatting changes the meaning of the script, leaving it as is",
+            )),
+        }
+    }
+
     pub fn parse_tail(&self, input: &str) -> Option<(Location, String)> {
         let scope = Scope::with_parent_and_hooks(Some(self.scope.clone()), None);
         let mut parser = Parser::new(input.chars(), &scope, None);
@@ -3021,17 +6217,17 @@ impl Interp {
 
 /// Walk an expression tree and descend right, return expression on the right side.
 /// Used by the command line auto-completion to parse more intelligently than just space-split.
-fn walk_right(expr: &Rc<Expression>) -> Option<Rc<Expression>> {
+fn walk_right(expr: &Arc<Expression>) -> Option<Arc<Expression>> {
     match &**expr {
-        Expression::Args(g) => return g.borrow().content.last().and_then(|e| walk_right(e)),
+        Expression::Args(g) => return g.read().content.last().and_then(|e| walk_right(e)),
         Expression::Bin(b) => {
-            let rhs = &b.borrow().rhs;
+            let rhs = &b.read().rhs;
             if !rhs.is_empty() {
                 return walk_right(rhs);
             }
         }
         Expression::Branch(b) => {
-            let b = b.borrow();
+            let b = b.read();
             if !b.else_branch.is_empty() {
                 return walk_right(&b.else_branch);
             } else if !b.if_branch.is_empty() {
@@ -3040,13 +6236,31 @@ fn walk_right(expr: &Rc<Expression>) -> Option<Rc<Expression>> {
                 return walk_right(&b.cond);
             }
         }
+        Expression::Call(c) => {
+            let args = &c.read().args;
+            if !args.is_empty() {
+                return walk_right(args);
+            }
+        }
+        Expression::Case(c) => {
+            let args = &c.read().args;
+            if !args.is_empty() {
+                return walk_right(args);
+            }
+        }
         Expression::Cmd(_) => {
             // Return the partially parsed command, do not walk down the argument expression(s).
             // For auto-completion purposes it is more helpful to return "git cl" than just "cl"
         }
+        Expression::Defer(d) => {
+            let body = &d.read().body;
+            if !body.is_empty() {
+                return walk_right(body);
+            }
+        }
         Expression::Empty => return None,
         Expression::For(f) => {
-            let f = f.borrow();
+            let f = f.read();
             if !f.body.is_empty() {
                 return walk_right(&f.body);
             }
@@ -3055,14 +6269,24 @@ fn walk_right(expr: &Rc<Expression>) -> Option<Rc<Expression>> {
                 return walk_right(&f.args);
             }
         }
+        Expression::Func(f) => {
+            let f = f.read();
+            if !f.body.is_empty() {
+                return walk_right(&f.body);
+            }
+            if !f.params.is_empty() {
+                return walk_right(&f.params);
+            }
+        }
         Expression::Group(g) => {
-            return g.borrow().content.last().and_then(|e| walk_right(e));
+            return g.read().content.last().and_then(|e| walk_right(e));
         }
         Expression::Leaf(_) => {
             return Some(expr.clone());
         }
+        Expression::List(g) => return g.read().content.last().and_then(walk_right),
         Expression::Loop(l) => {
-            let loop_expr = l.borrow();
+            let loop_expr = l.read();
             if !loop_expr.body.is_empty() {
                 return walk_right(&loop_expr.body);
             }
@@ -3070,6 +6294,472 @@ fn walk_right(expr: &Rc<Expression>) -> Option<Rc<Expression>> {
                 return walk_right(&loop_expr.cond);
             }
         }
+        Expression::Match(m) => {
+            let m = m.read();
+            if !m.body.is_empty() {
+                return walk_right(&m.body);
+            }
+            if !m.subject.is_empty() {
+                return walk_right(&m.subject);
+            }
+        }
+        Expression::Try(t) => {
+            let t = t.read();
+            for block in [&t.finally_body, &t.catch_body, &t.body] {
+                if !block.is_empty() {
+                    return walk_right(block);
+                }
+            }
+        }
     }
     return Some(expr.clone());
 }
+
+/// Static checks of parsed scripts, see Interp::check
+struct Linter<'a> {
+    input: &'a str,
+    assigned: HashSet<String>,
+    functions: HashSet<String>,
+    used: Vec<(String, Location)>,
+    statuses: Vec<(String, String, Location)>, // Variable, command, location of the assignment
+    words: Vec<(String, Location)>,            // Bare words in place of commands
+    warnings: Vec<EvalError>,
+    var_re: Regex,
+    arith_re: Regex,
+}
+
+impl<'a> Linter<'a> {
+    fn new(input: &'a str) -> Self {
+        Self {
+            input,
+            assigned: HashSet::new(),
+            functions: HashSet::new(),
+            used: Vec::new(),
+            statuses: Vec::new(),
+            words: Vec::new(),
+            warnings: Vec::new(),
+            // Variable references; ${NAME:-...}, ${NAME:+...} and ${NAME:?...} handle unset variables.
+            var_re: Regex::new(r"\$(?:\{#?)?([a-zA-Z_][a-zA-Z0-9_]*)(:[-+?])?").unwrap(),
+            // Multiplication (or division by a number) without spaces: $x*$y, $x*2, $x/2
+            arith_re: Regex::new(r"^\$\{?\w+\}?(\*(\$\{?\w+\}?|[0-9.]+)|/[0-9.]+)$").unwrap(),
+        }
+    }
+
+    /// Check a parsed script, or a part of it that parses when the script has syntax errors.
+    fn check(&mut self, ast: &Arc<Expression>) {
+        let (defined, count) = (self.functions.clone(), self.words.len());
+        match &**ast {
+            Expression::Group(g) => self.check_statements(&g.read(), true),
+            _ => self.walk(ast),
+        }
+        // Functions defined in earlier parts are not known to the parser of this part.
+        let mut i = 0;
+        self.words.retain(|(word, _)| {
+            i += 1;
+            i <= count || !defined.contains(word)
+        });
+    }
+
+    /// Check the statements in a group. The last statement in a block is the value
+    /// of the block, unless the block is the script itself (top).
+    fn check_statements(&mut self, group: &GroupExpr, top: bool) {
+        let count = group.content.len();
+        for (i, stmt) in group.content.iter().enumerate() {
+            if top || i + 1 < count || stmt.is_bin() {
+                if let Some((word, loc)) = Self::leading_word(stmt) {
+                    let loc = self.locate(&loc, &word);
+                    self.words.push((word, loc));
+                }
+            }
+            self.walk(stmt);
+        }
+    }
+
+    /// Unrecognized commands are parsed as literals: NAME, or NAME -FLAG...
+    fn leading_word(expr: &Expression) -> Option<(String, Location)> {
+        match expr {
+            Expression::Leaf(lit) => {
+                let text = &lit.text;
+                let word = !text.quoted
+                    && !text.raw
+                    && text
+                        .value
+                        .starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                    && text
+                        .value
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || "_-.".contains(c));
+                word.then(|| (text.value(), lit.loc.clone()))
+            }
+            Expression::Bin(b) if b.read().op == Op::Minus => Self::leading_word(&b.read().lhs),
+            _ => None,
+        }
+    }
+
+    /// The location of an expression is where the previous expression ends;
+    /// search the source text, to point to where the expression actually starts.
+    fn locate(&self, loc: &Location, text: &str) -> Location {
+        let text = text.to_ascii_lowercase();
+        let first = loc.line.max(1) as usize;
+
+        for (line_num, line) in self.input.lines().enumerate().skip(first - 1) {
+            let line = line.to_ascii_lowercase();
+            let skip = if line_num + 1 == first {
+                (loc.col as usize).saturating_sub(1)
+            } else {
+                0
+            };
+            let start = line.char_indices().nth(skip).map_or(line.len(), |(i, _)| i);
+            if let Some(pos) = line[start..].find(&text) {
+                return Location {
+                    line: line_num as u32 + 1,
+                    col: line[..start + pos].chars().count() as u32 + 1,
+                    file: loc.file.clone(),
+                };
+            }
+        }
+        loc.clone()
+    }
+
+    fn assign(&mut self, name: &str) {
+        let name = name.trim_start_matches('$');
+        let name = name.split('[').next().unwrap_or(name);
+        self.assigned.insert(name.to_string());
+    }
+
+    fn walk(&mut self, expr: &Arc<Expression>) {
+        match &**expr {
+            Expression::Leaf(lit) => {
+                if lit.text.raw {
+                    return;
+                }
+                for caps in self.var_re.captures_iter(&lit.text.value) {
+                    if caps.get(2).is_none() {
+                        let loc = self.locate(&lit.loc, &caps[0]);
+                        self.used.push((caps[1].to_string(), loc));
+                    }
+                }
+                if !lit.text.quoted && self.arith_re.is_match(&lit.text.value) {
+                    self.warnings.push(EvalError::new(
+                        self.locate(&lit.loc, &lit.text.value),
+                        format!(
+                            "{} is not evaluated as arithmetic, use spaces around the operator",
+                            lit.text.value
+                        ),
+                    ));
+                }
+                return;
+            }
+            Expression::Bin(b) => {
+                let b = b.read();
+                if let (Op::Assign, Expression::Leaf(lit)) = (&b.op, &*b.lhs) {
+                    self.assign(&lit.text.value);
+                    // The status of a command (or of a block ending with a command)
+                    let cmd = match &*b.rhs {
+                        Expression::Cmd(cmd) => Some(cmd.read().cmd.name().clone()),
+                        Expression::Group(g) => match g.read().content.last().map(|e| &**e) {
+                            Some(Expression::Cmd(cmd)) => Some(cmd.read().cmd.name().clone()),
+                            _ => None,
+                        },
+                        _ => None,
+                    };
+                    if let Some(cmd) = cmd {
+                        let var = lit.text.value.trim_start_matches('$').to_string();
+                        let loc = self.locate(&lit.loc, &lit.text.value);
+                        self.statuses.push((var, cmd, loc));
+                    }
+                    self.walk(&b.rhs);
+                    return;
+                }
+                if b.op.is_declaration() {
+                    // Declarations without value (e.g. local NAME), and of variables
+                    // named like commands (e.g. local w = 1) are not walked as assignments.
+                    let lhs = match &*b.rhs {
+                        Expression::Bin(assign) if assign.read().op == Op::Assign => {
+                            Arc::clone(&assign.read().lhs)
+                        }
+                        _ => Arc::clone(&b.rhs),
+                    };
+                    if let Some(name) = declared_name(&lhs) {
+                        self.assign(&name);
+                    }
+                }
+                if let (Op::Pipe, Expression::Leaf(lit)) = (&b.op, &*b.rhs) {
+                    // Capture output into variable: ... | NAME, or ... | NAME[]
+                    self.assign(lit.text.value.trim_end_matches("[]"));
+                    self.walk(&b.lhs);
+                    return;
+                }
+            }
+            Expression::Case(c) => {
+                // Named regex captures bind variables
+                for pattern in c.read().patterns() {
+                    if let Expression::Leaf(lit) = &*pattern {
+                        if lit.text.raw {
+                            if let Ok(re) = Regex::new(&lit.text.value) {
+                                for name in re.capture_names().flatten() {
+                                    self.assign(name);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            Expression::Cmd(cmd) if cmd.read().cmd.name() == "eval" => {
+                // Variables assigned by the evaluated text, e.g. eval --export "NAME = VALUE"
+                let re = Regex::new(r"(?:^|;)\s*\$?([a-zA-Z_][a-zA-Z0-9_]*)\s*=[^=]").unwrap();
+                for arg in cmd.read().args.children() {
+                    if let Expression::Leaf(lit) = &*arg {
+                        for caps in re.captures_iter(&lit.text.value) {
+                            self.assign(&caps[1]);
+                        }
+                    }
+                }
+            }
+            Expression::Cmd(cmd) if cmd.read().cmd.name() == "import" => {
+                // Variables of the module, e.g. ${util.x} (matched as $util)
+                for arg in cmd.read().args.children() {
+                    if let Expression::Leaf(lit) = &*arg {
+                        if let Some(name) = Path::new(lit.text.value.as_str()).file_stem() {
+                            self.assign(&name.to_string_lossy());
+                        }
+                    }
+                }
+            }
+            Expression::For(f) => {
+                for var in &f.read().vars {
+                    self.assign(var);
+                }
+            }
+            Expression::Func(f) => {
+                let f = f.read();
+                if let Some(func) = &f.func {
+                    self.functions.insert(func.name.clone());
+                }
+                for param in f.params.children() {
+                    if let Expression::Leaf(lit) = &*param {
+                        for name in lit.text.value.split(',').filter(|s| !s.is_empty()) {
+                            self.assign(name.trim());
+                        }
+                    }
+                }
+                self.walk(&f.body);
+                return;
+            }
+            Expression::Group(g) => {
+                self.check_statements(&g.read(), false);
+                return;
+            }
+            Expression::Loop(l) => {
+                let l = l.read();
+                if l.body.is_empty() {
+                    self.warnings.push(EvalError::new(
+                        self.locate(&l.loc, "while"),
+                        "WHILE body is missing, or not enclosed in parentheses",
+                    ));
+                }
+            }
+            Expression::Try(t) => {
+                let t = t.read();
+                if !t.var.is_empty() {
+                    self.assign(&t.var);
+                }
+            }
+            _ => {}
+        }
+        for child in expr.children() {
+            self.walk(&child);
+        }
+    }
+
+    fn finish(self, scope: &Arc<Scope>) -> Vec<EvalError> {
+        let mut warnings = self.warnings;
+
+        for (word, loc) in self.words {
+            let message = if self.functions.contains(&word) {
+                format!("Function {} is called before it is defined", word)
+            } else {
+                format!("Unknown command: {}", word)
+            };
+            warnings.push(EvalError::new(loc, message));
+        }
+
+        let mut reported = HashSet::new();
+        for (name, loc) in &self.used {
+            if self.assigned.contains(name)
+                || name.starts_with("__")
+                || scope.lookup(name).is_some()
+                || !reported.insert(name)
+            {
+                continue;
+            }
+            warnings.push(EvalError::new(
+                loc.clone(),
+                format!("Variable ${} is used, but never assigned", name),
+            ));
+        }
+
+        for (var, cmd, loc) in self.statuses {
+            if !self.used.iter().any(|(name, _)| *name == var) {
+                warnings.push(EvalError::new(
+                    loc,
+                    format!(
+                        "Status of {} is assigned to ${}, but never checked",
+                        cmd, var
+                    ),
+                ));
+            }
+        }
+        warnings
+    }
+}
+
+/// Indentation of nested groups, see Formatter
+const INDENT: &str = "    ";
+
+/// What was last emitted on the current line, to decide the spacing before the next token.
+#[derive(Clone, Copy, PartialEq)]
+enum Emitted {
+    Open,     // Opening parenthesis or bracket
+    Close,    // Closing parenthesis or bracket
+    Text,     // Literal, here-document
+    Unary,    // Unary minus or negation
+    Operator, // Binary operator
+    Other,    // Keyword, semicolon, comment...
+}
+
+/// Re-emits the tokens recorded by the parser, see Interp::format.
+/// Comments, quoted and raw strings, here-documents and the words that the parser expands
+/// (globs, braces, ~) are taken as written from the source, line breaks are kept (collapsing
+/// consecutive blank lines), and the indentation follows the nesting of parentheses.
+struct Formatter<'a> {
+    input: &'a str,
+    lines: Vec<String>,
+    line: String,
+    indent: usize,   // Indentation level of the current line
+    depth: usize,    // Nesting level of parentheses and brackets
+    newlines: usize, // Line breaks since the last emitted text
+    last: Emitted,
+}
+
+impl<'a> Formatter<'a> {
+    fn new(input: &'a str) -> Self {
+        Self {
+            input,
+            lines: Vec::new(),
+            line: String::new(),
+            indent: 0,
+            depth: 0,
+            newlines: 0,
+            last: Emitted::Other,
+        }
+    }
+
+    fn format(mut self, tokens: &[(Token, Range<usize>)]) -> String {
+        let mut end = 0;
+        for (tok, range) in tokens {
+            // Skip the end of input, and the tokens expanded from the same source word.
+            if range.is_empty() {
+                continue;
+            }
+            let adjacent = range.start == end;
+            self.gap(&self.input[end..range.start]);
+            self.token(tok, self.input[range.clone()].trim_end(), adjacent);
+            end = range.end;
+        }
+        self.gap(&self.input[end..]);
+        self.newline();
+
+        while self.lines.last().is_some_and(|line| line.is_empty()) {
+            self.lines.pop();
+        }
+        let mut output = self.lines.join("\n");
+        output.push('\n');
+        output
+    }
+
+    /// Emit what the tokenizer skipped between two tokens: comments, and the commas in lists;
+    /// also, the opening parenthesis of function calls, which is consumed by the parser.
+    fn gap(&mut self, gap: &str) {
+        for (i, part) in gap.split('\n').enumerate() {
+            if i > 0 {
+                self.newlines += 1;
+            }
+            let (code, comment) = part.split_at(part.find('#').unwrap_or(part.len()));
+
+            for c in code.chars().filter(|c| !c.is_whitespace()) {
+                if c == '(' {
+                    self.emit("(", false);
+                    self.depth += 1;
+                    self.last = Emitted::Open;
+                } else {
+                    self.emit(&c.to_string(), c != ',');
+                    self.last = Emitted::Other;
+                }
+            }
+            if !comment.is_empty() {
+                self.emit(comment.trim_end(), true);
+                self.last = Emitted::Other;
+            }
+        }
+    }
+
+    fn token(&mut self, tok: &Token, text: &str, adjacent: bool) {
+        let space = match tok {
+            Token::Semicolon => self.last == Emitted::Operator, // e.g. $x = ;
+            Token::RightParen | Token::RightBracket => false,
+            _ if matches!(self.last, Emitted::Open | Emitted::Unary) => false,
+            // Keep words together as written, e.g. upper(...) is a function call, upper (...) is not.
+            Token::Literal(_) | Token::LeftParen | Token::LeftBracket => {
+                !adjacent || self.last != Emitted::Text
+            }
+            _ => true,
+        };
+        if matches!(tok, Token::RightParen | Token::RightBracket) {
+            self.depth = self.depth.saturating_sub(1);
+        }
+        match tok {
+            // Keywords are not case sensitive, use lowercase consistently.
+            Token::Keyword(word) => self.emit(&word.to_lowercase(), space),
+            _ => self.emit(text, space),
+        }
+        self.last = match tok {
+            Token::LeftParen | Token::LeftBracket => {
+                self.depth += 1;
+                Emitted::Open
+            }
+            Token::RightParen | Token::RightBracket => Emitted::Close,
+            Token::Literal(_) | Token::HereDoc(_) => Emitted::Text,
+            Token::Operator(Op::Not) => Emitted::Unary,
+            Token::Operator(Op::Minus) if !matches!(self.last, Emitted::Text | Emitted::Close) => {
+                Emitted::Unary
+            }
+            Token::Operator(_) => Emitted::Operator,
+            _ => Emitted::Other,
+        };
+    }
+
+    fn emit(&mut self, text: &str, space: bool) {
+        if self.newlines > 0 {
+            self.newline();
+        }
+        if self.line.is_empty() {
+            self.indent = self.depth;
+        } else if space {
+            self.line.push(' ');
+        }
+        self.line.push_str(text);
+    }
+
+    /// End the current line, keeping at most one blank line.
+    fn newline(&mut self) {
+        if !self.line.is_empty() {
+            self.lines.push(INDENT.repeat(self.indent) + &self.line);
+            self.line.clear();
+        }
+        if self.newlines > 1 && self.lines.last().is_some_and(|line| !line.is_empty()) {
+            self.lines.push(String::new());
+        }
+        self.newlines = 0;
+    }
+}
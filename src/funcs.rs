@@ -0,0 +1,273 @@
+//! Library of built-in functions, called with: NAME(ARG, ...)
+//! The functions are pure: they operate on values directly, without running commands.
+
+use crate::eval::Value;
+use std::path::Path;
+
+type FuncResult = Result<Value, String>;
+
+#[derive(Debug)]
+pub struct BuiltinFunction {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub help: &'static str,
+    min_args: usize,
+    max_args: usize,
+    func: fn(&[Value]) -> FuncResult,
+}
+
+impl BuiltinFunction {
+    pub fn call(&self, args: &[Value]) -> FuncResult {
+        if args.len() < self.min_args || args.len() > self.max_args {
+            return Err(format!(
+                "Invalid number of arguments, usage: {}",
+                self.usage
+            ));
+        }
+        (self.func)(args)
+    }
+}
+
+macro_rules! function {
+    ($name:literal, $usage:literal, $help:literal, $min:expr, $max:expr, $func:expr) => {
+        BuiltinFunction {
+            name: $name,
+            usage: $usage,
+            help: $help,
+            min_args: $min,
+            max_args: $max,
+            func: $func,
+        }
+    };
+}
+
+const ANY: usize = usize::MAX;
+
+#[rustfmt::skip]
+static FUNCTIONS: &[BuiltinFunction] = &[
+    // Strings
+    function!("upper", "upper(STRING)", "Convert to uppercase", 1, 1, |a| Ok(str_value(a[0].as_str().to_uppercase()))),
+    function!("lower", "lower(STRING)", "Convert to lowercase", 1, 1, |a| Ok(str_value(a[0].as_str().to_lowercase()))),
+    function!("trim", "trim(STRING)", "Remove leading and trailing whitespace", 1, 1, |a| Ok(str_value(a[0].as_str().trim().to_string()))),
+    function!("len", "len(VALUE)", "Number of characters in a string, or of items in a list or map", 1, 1, len),
+    function!("substr", "substr(STRING, START, [LENGTH])", "Extract substring, negative START counts from the end", 2, 3, substr),
+    function!("split", "split(STRING, [DELIMITER])", "Split into a list, at whitespace by default", 1, 2, split),
+    function!("join", "join(LIST, [DELIMITER])", "Join list items into a string, with spaces by default", 1, 2, join),
+    function!("replace", "replace(STRING, FROM, TO)", "Replace all occurrences of FROM with TO", 3, 3, |a| Ok(str_value(a[0].as_str().replace(&*a[1].as_str(), &a[2].as_str())))),
+    function!("enumerate", "enumerate(LIST)", "List of [INDEX, ITEM] pairs, e.g. for i, x in enumerate($list)", 1, 1, enumerate),
+    function!("contains", "contains(VALUE, ITEM)", "Test if a string contains a substring, a list an item, or a map a key", 2, 2, contains),
+    function!("starts_with", "starts_with(STRING, PREFIX)", "Test if a string starts with prefix", 2, 2, |a| Ok(bool_value(a[0].as_str().starts_with(&*a[1].as_str())))),
+    function!("ends_with", "ends_with(STRING, SUFFIX)", "Test if a string ends with suffix", 2, 2, |a| Ok(bool_value(a[0].as_str().ends_with(&*a[1].as_str())))),
+    // Paths
+    function!("basename", "basename(PATH)", "File name component of a path", 1, 1, |a| path_component(&a[0], |p| p.file_name())),
+    function!("dirname", "dirname(PATH)", "Parent directory of a path", 1, 1, dirname),
+    function!("ext", "ext(PATH)", "File extension, without the dot", 1, 1, |a| path_component(&a[0], |p| p.extension())),
+    function!("stem", "stem(PATH)", "File name without extension", 1, 1, |a| path_component(&a[0], |p| p.file_stem())),
+    // Math
+    function!("abs", "abs(NUMBER)", "Absolute value", 1, 1, abs),
+    function!("sqrt", "sqrt(NUMBER)", "Square root", 1, 1, |a| Ok(Value::Real(number(&a[0])?.sqrt()))),
+    function!("round", "round(NUMBER, [DIGITS])", "Round to the nearest integer, or to a number of decimal digits", 1, 2, round),
+    function!("floor", "floor(NUMBER)", "Largest integer less than or equal to the number", 1, 1, |a| int_value(number(&a[0])?.floor())),
+    function!("ceil", "ceil(NUMBER)", "Smallest integer greater than or equal to the number", 1, 1, |a| int_value(number(&a[0])?.ceil())),
+    function!("min", "min(NUMBER, ...)", "Smallest of the arguments, or of the items of a list", 1, ANY, |a| min_max(a, |x, y| x < y)),
+    function!("max", "max(NUMBER, ...)", "Largest of the arguments, or of the items of a list", 1, ANY, |a| min_max(a, |x, y| x > y)),
+    // Type conversions
+    function!("int", "int(VALUE)", "Convert to integer, truncating real numbers", 1, 1, int),
+    function!("real", "real(VALUE)", "Convert to real number", 1, 1, |a| Ok(Value::Real(number(&a[0])?))),
+    function!("str", "str(VALUE)", "Convert to string", 1, 1, |a| Ok(str_value(a[0].to_string()))),
+    function!("type", "type(VALUE)", "Type of value: int, real, str, list, map", 1, 1, |a| Ok(Value::from(type_name(&a[0])))),
+];
+
+/// Look up built-in function by name.
+pub fn get_function(name: &str) -> Option<&'static BuiltinFunction> {
+    FUNCTIONS.iter().find(|f| f.name == name)
+}
+
+pub fn functions() -> impl Iterator<Item = &'static BuiltinFunction> {
+    FUNCTIONS.iter()
+}
+
+fn str_value(s: String) -> Value {
+    Value::new_str(s)
+}
+
+fn bool_value(b: bool) -> Value {
+    Value::Int(b as _)
+}
+
+fn int_value(f: f64) -> FuncResult {
+    if f.is_finite() && f.abs() < i64::MAX as f64 {
+        Ok(Value::Int(f as i64))
+    } else {
+        Ok(Value::Real(f))
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Int(_) => "int",
+        Value::Real(_) => "real",
+        Value::Str(_) => "str",
+        Value::Stat(_) => "status",
+        Value::List(_) => "list",
+        Value::Map(_) => "map",
+    }
+}
+
+fn number(value: &Value) -> Result<f64, String> {
+    match value {
+        Value::Int(i) => Ok(*i as f64),
+        Value::Real(r) => Ok(*r),
+        _ => value
+            .as_str()
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| format!("Expecting number, got {}: {}", type_name(value), value)),
+    }
+}
+
+fn len(args: &[Value]) -> FuncResult {
+    let len = match &args[0] {
+        Value::List(items) => items.len(),
+        Value::Map(map) => map.len(),
+        value => value.as_str().chars().count(),
+    };
+    Ok(Value::Int(len as _))
+}
+
+fn substr(args: &[Value]) -> FuncResult {
+    let chars = args[0].as_str().chars().collect::<Vec<_>>();
+    let count = chars.len() as i64;
+
+    let mut start = number(&args[1])? as i64;
+    if start < 0 {
+        start += count;
+    }
+    let start = start.clamp(0, count);
+
+    let end = match args.get(2) {
+        Some(len) => (start + (number(len)? as i64).max(0)).min(count),
+        None => count,
+    };
+    Ok(str_value(
+        chars[start as usize..end as usize].iter().collect(),
+    ))
+}
+
+fn split(args: &[Value]) -> FuncResult {
+    let s = args[0].as_str();
+    let items: Vec<Value> = match args.get(1) {
+        Some(delim) if !delim.as_str().is_empty() => {
+            s.split(&*delim.as_str()).map(Value::from).collect()
+        }
+        _ => s.split_whitespace().map(Value::from).collect(),
+    };
+    Ok(Value::new_list(items))
+}
+
+fn join(args: &[Value]) -> FuncResult {
+    let delim = args.get(1).map_or(" ".into(), |d| d.as_str());
+    match &args[0] {
+        Value::List(items) => {
+            let items = items.iter().map(|item| item.as_str()).collect::<Vec<_>>();
+            Ok(str_value(items.join(&delim)))
+        }
+        value => Err(format!(
+            "Expecting list, got {}: {}",
+            type_name(value),
+            value
+        )),
+    }
+}
+
+fn enumerate(args: &[Value]) -> FuncResult {
+    match &args[0] {
+        Value::List(items) => Ok(Value::new_list(
+            items
+                .iter()
+                .enumerate()
+                .map(|(i, item)| Value::new_list(vec![Value::Int(i as _), item.clone()]))
+                .collect(),
+        )),
+        value => Err(format!(
+            "Expecting list, got {}: {}",
+            type_name(value),
+            value
+        )),
+    }
+}
+
+fn contains(args: &[Value]) -> FuncResult {
+    let item = args[1].as_str();
+    let result = match &args[0] {
+        Value::List(items) => items.iter().any(|v| v.as_str() == item),
+        Value::Map(map) => map.contains_key(&*item),
+        value => value.as_str().contains(&*item),
+    };
+    Ok(bool_value(result))
+}
+
+fn path_component(value: &Value, component: fn(&Path) -> Option<&std::ffi::OsStr>) -> FuncResult {
+    let path = value.as_str();
+    let result =
+        component(Path::new(&*path)).map_or(String::default(), |s| s.to_string_lossy().to_string());
+    Ok(str_value(result))
+}
+
+fn dirname(args: &[Value]) -> FuncResult {
+    let path = args[0].as_str();
+    let result = match Path::new(&*path).parent() {
+        Some(parent) if parent.as_os_str().is_empty() => ".".to_string(),
+        Some(parent) => parent.to_string_lossy().to_string(),
+        None => path.to_string(), // Root
+    };
+    Ok(str_value(result))
+}
+
+fn abs(args: &[Value]) -> FuncResult {
+    match &args[0] {
+        Value::Int(i) => Ok(Value::Int(i.abs())),
+        value => Ok(Value::Real(number(value)?.abs())),
+    }
+}
+
+fn round(args: &[Value]) -> FuncResult {
+    let n = number(&args[0])?;
+    match args.get(1) {
+        Some(digits) => {
+            let scale = 10f64.powi(number(digits)? as i32);
+            Ok(Value::Real((n * scale).round() / scale))
+        }
+        None => int_value(n.round()),
+    }
+}
+
+fn min_max(args: &[Value], pick: fn(f64, f64) -> bool) -> FuncResult {
+    let items = match args {
+        [Value::List(items)] => items.as_slice(),
+        _ => args,
+    };
+    let mut result: Option<(&Value, f64)> = None;
+    for item in items {
+        let n = number(item)?;
+        if result.is_none_or(|(_, best)| pick(n, best)) {
+            result = Some((item, n));
+        }
+    }
+    result
+        .map(|(item, _)| item.clone())
+        .ok_or_else(|| "Empty list".to_string())
+}
+
+fn int(args: &[Value]) -> FuncResult {
+    match &args[0] {
+        Value::Int(i) => Ok(Value::Int(*i)),
+        value => {
+            let s = value.as_str();
+            match s.trim().parse::<i64>() {
+                Ok(i) => Ok(Value::Int(i)),
+                Err(_) => int_value(number(value)?.trunc()),
+            }
+        }
+    }
+}
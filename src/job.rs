@@ -1,7 +1,12 @@
 use crate::scope::Scope;
-use std::io;
+use std::cell::{Cell, RefCell};
 use std::path::Path;
-use std::process::Command;
+use std::process::{Command, ExitStatus};
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, LazyLock, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use std::{fmt, io};
 
 /// Execute commands as part of a Job. Experimental.
 /// Just a simple std::process::Command wrapper for non-Windows targets.
@@ -21,6 +26,14 @@ impl<'a> Job<'a> {
         self.inner.run()
     }
 
+    /// Start the job without waiting for it to complete (used by pipelines,
+    /// where all external stages run concurrently). Not supported for elevated jobs.
+    pub fn spawn(&mut self) -> io::Result<Process> {
+        Ok(Process {
+            inner: self.inner.spawn()?,
+        })
+    }
+
     pub fn command_mut(&mut self) -> Option<&mut Command> {
         self.inner.command_mut()
     }
@@ -35,25 +48,576 @@ impl<'a> Job<'a> {
     }
 }
 
-fn check_exit_code(code: i64) -> io::Result<()> {
-    if code != 0 {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            format!("exit code: {} (0x{:X})", code, code),
-        ));
+/// A running process, started with Job::spawn.
+pub struct Process {
+    inner: imp::Process,
+}
+
+impl Process {
+    /// Wait for the process to complete and check its exit code.
+    pub fn wait(&mut self) -> io::Result<()> {
+        self.inner.wait()
+    }
+}
+
+/// Signals that the kill command sends to processes; see imp::kill for what is supported
+/// on each platform.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Signal {
+    Hangup,
+    Interrupt,
+    Kill,
+    Terminate,
+    Stop,
+    Continue,
+    Other(i32),
+}
+
+impl FromStr for Signal {
+    type Err = String;
+
+    /// Parse signal names with or without the SIG prefix (HUP, SIGINT), and signal numbers.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.to_uppercase();
+        match name.strip_prefix("SIG").unwrap_or(&name) {
+            "HUP" | "1" => Ok(Signal::Hangup),
+            "INT" | "2" => Ok(Signal::Interrupt),
+            "KILL" | "9" => Ok(Signal::Kill),
+            "TERM" | "15" => Ok(Signal::Terminate),
+            "STOP" => Ok(Signal::Stop),
+            "CONT" => Ok(Signal::Continue),
+            other => other
+                .parse::<i32>()
+                .map(Signal::Other)
+                .map_err(|_| format!("Invalid signal: {}", s)),
+        }
+    }
+}
+
+/// Send a signal to a process.
+pub fn kill(pid: u32, signal: Signal) -> io::Result<()> {
+    imp::kill(pid, false, signal)
+}
+
+/// The state of a background job, shared by the job table and the thread that evaluates
+/// the job (see start_background).
+#[derive(Default)]
+struct JobState {
+    control: Mutex<JobControl>,
+    resumed: Condvar,
+}
+
+#[derive(Default)]
+struct JobControl {
+    stopped: bool,
+    // The signal that terminated the job, if any.
+    terminated: Option<Signal>,
+    // The processes of the external commands that the job is running.
+    processes: Vec<u32>,
+}
+
+impl JobState {
+    fn control(&self) -> MutexGuard<'_, JobControl> {
+        self.control.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Stop, resume or terminate the job: the thread stops at the next check for
+    /// interruptions (see is_background_interrupted), and the signal is forwarded
+    /// to the processes that the job is running.
+    fn signal(&self, signal: Signal) {
+        let mut control = self.control();
+        match signal {
+            Signal::Stop => control.stopped = true,
+            Signal::Continue => control.stopped = false,
+            _ => {
+                control.terminated.get_or_insert(signal);
+            }
+        }
+        self.resumed.notify_all();
+
+        for &pid in &control.processes {
+            // The process may have just exited.
+            _ = imp::kill(pid, true, signal);
+        }
+    }
+}
+
+thread_local! {
+    // The job that the thread evaluates in the background, if any.
+    static CURRENT_JOB: RefCell<Option<Arc<JobState>>> = const { RefCell::new(None) };
+}
+
+/// The background job of the current thread, if any, for the threads that evaluate parts of it
+/// (e.g. pipeline stages).
+pub struct CurrentJob(Option<Arc<JobState>>);
+
+impl CurrentJob {
+    pub fn get() -> Self {
+        Self(CURRENT_JOB.with_borrow(Option::clone))
+    }
+
+    /// Make the job the background job of the current thread, until the guard is dropped.
+    pub fn enter(self) -> CurrentJobGuard {
+        CurrentJobGuard {
+            previous: CURRENT_JOB.replace(self.0),
+        }
+    }
+}
+
+pub struct CurrentJobGuard {
+    previous: Option<Arc<JobState>>,
+}
+
+impl Drop for CurrentJobGuard {
+    fn drop(&mut self) {
+        CURRENT_JOB.set(self.previous.take());
+    }
+}
+
+/// True if the current thread evaluates a background job.
+pub fn is_background() -> bool {
+    CURRENT_JOB.with_borrow(Option::is_some)
+}
+
+/// If the current thread evaluates a background job, wait while the job is stopped, and return
+/// whether the job was terminated (with kill); None otherwise. Background jobs do not get the
+/// Ctrl+C meant for the foreground, see Scope::is_interrupted.
+pub fn is_background_interrupted() -> Option<bool> {
+    CURRENT_JOB.with_borrow(|job| {
+        job.as_ref().map(|job| {
+            let mut control = job.control();
+            while control.stopped && control.terminated.is_none() {
+                control = job.resumed.wait(control).unwrap_or_else(|e| e.into_inner());
+            }
+            control.terminated.is_some()
+        })
+    })
+}
+
+/// Keeps track of a process that a background job runs, until dropped, so that signals
+/// sent to the job reach the process.
+struct BackgroundProcess {
+    job: Option<Arc<JobState>>,
+    pid: u32,
+}
+
+impl BackgroundProcess {
+    fn new(pid: u32) -> Self {
+        let CurrentJob(job) = CurrentJob::get();
+        if let Some(job) = &job {
+            let mut control = job.control();
+            // The job may have been terminated while starting the process.
+            if let Some(signal) = control.terminated {
+                _ = imp::kill(pid, true, signal);
+            }
+            control.processes.push(pid);
+        }
+        Self { job, pid }
+    }
+}
+
+impl Drop for BackgroundProcess {
+    fn drop(&mut self) {
+        if let Some(job) = &self.job {
+            job.control().processes.retain(|&pid| pid != self.pid);
+        }
+    }
+}
+
+/// Start the processes of background jobs in their own process group, so that
+/// they do not get the Ctrl+C meant for the foreground command.
+fn prepare_command(command: &mut Command) {
+    if is_background() {
+        imp::new_process_group(command);
+    }
+}
+
+/// An expression evaluated in the background with the & operator.
+struct BackgroundJob {
+    id: usize,
+    command: String,
+    state: Arc<JobState>,
+    thread: Option<JoinHandle<Exit>>,
+    status: Option<Exit>,
+}
+
+impl BackgroundJob {
+    /// Check if the job has completed, without blocking.
+    fn poll(&mut self) {
+        if self.thread.as_ref().is_some_and(JoinHandle::is_finished) {
+            let thread = self.thread.take().unwrap();
+            self.status = Some(thread.join().unwrap_or(Exit::FAILURE));
+        }
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.state.control().stopped
+    }
+
+    fn state(&self) -> String {
+        match &self.status {
+            Some(exit) => match exit.signal_name() {
+                Some(name) => format!("Killed ({})", name),
+                None if exit.success() => "Done".to_string(),
+                None => format!("Exit {}", exit.code),
+            },
+            None if self.is_stopped() => "Stopped".to_string(),
+            None => "Running".to_string(),
+        }
+    }
+
+    /// The result of a completed job, as reported by fg and wait.
+    fn result(&self) -> Result<(), String> {
+        if let Some(exit) = self.status {
+            set_last_exit(exit);
+        }
+        match &self.status {
+            Some(exit) if !exit.success() => Err(format!("{}: {}", self.command, self.state())),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for BackgroundJob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {:<12} {} &", self.id, self.state(), self.command)
+    }
+}
+
+/// The job table, see start_background.
+static BACKGROUND_JOBS: LazyLock<Mutex<Vec<BackgroundJob>>> =
+    LazyLock::new(|| Mutex::new(Vec::new()));
+
+fn background_jobs() -> MutexGuard<'static, Vec<BackgroundJob>> {
+    BACKGROUND_JOBS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Find a job by number, or the most recently started job if no number is given.
+fn find_job(jobs: &mut [BackgroundJob], id: Option<usize>) -> Result<&mut BackgroundJob, String> {
+    match id {
+        Some(id) => jobs
+            .iter_mut()
+            .find(|job| job.id == id)
+            .ok_or_else(|| format!("%{}: no such job", id)),
+        None => jobs
+            .iter_mut()
+            .max_by_key(|job| job.id)
+            .ok_or_else(|| "No current job".to_string()),
+    }
+}
+
+/// Evaluate an expression in the background, on a thread with the given stack size, and add it
+/// to the job table. The function evaluates the expression, and returns how it ended; when the
+/// job is terminated (see kill), it ends as if killed by the signal. Return the job number.
+pub fn start_background<F>(text: String, stack_size: usize, f: F) -> io::Result<usize>
+where
+    F: FnOnce() -> Exit + Send + 'static,
+{
+    let state = Arc::new(JobState::default());
+    let job = Arc::clone(&state);
+
+    let thread = thread::Builder::new()
+        .stack_size(stack_size)
+        .spawn(move || {
+            let exit = {
+                let _job = CurrentJob(Some(Arc::clone(&job))).enter();
+                f()
+            };
+            job.control().terminated.map_or(exit, imp::exit_of_signal)
+        })?;
+
+    let mut jobs = background_jobs();
+    let id = jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+
+    jobs.push(BackgroundJob {
+        id,
+        command: text,
+        state,
+        thread: Some(thread),
+        status: None,
+    });
+    Ok(id)
+}
+
+/// Return the numbers of the jobs in the job table.
+pub fn background_job_ids() -> Vec<usize> {
+    background_jobs().iter().map(|job| job.id).collect()
+}
+
+/// List the background jobs; completed jobs are listed one last time, then forgotten.
+pub fn list_background_jobs() -> Vec<String> {
+    let mut jobs = background_jobs();
+    let list = jobs
+        .iter_mut()
+        .map(|job| {
+            job.poll();
+            job.to_string()
+        })
+        .collect();
+
+    jobs.retain(|job| job.status.is_none());
+    list
+}
+
+/// Remove the background jobs that have completed, and return their descriptions.
+/// The interactive shell prints them before the next prompt.
+pub fn completed_background_jobs() -> Vec<String> {
+    let mut completed = Vec::new();
+
+    background_jobs().retain_mut(|job| {
+        job.poll();
+        if job.status.is_some() {
+            completed.push(job.to_string());
+            false
+        } else {
+            true
+        }
+    });
+    completed
+}
+
+/// Send a signal to a background job, see JobState::signal.
+pub fn signal_background_job(id: Option<usize>, signal: Signal) -> Result<(), String> {
+    let mut jobs = background_jobs();
+    let job = find_job(&mut jobs, id)?;
+
+    job.state.signal(signal);
+    Ok(())
+}
+
+/// Resume a stopped background job, and return its description.
+pub fn resume_background_job(id: Option<usize>) -> Result<String, String> {
+    let mut jobs = background_jobs();
+    let job = find_job(&mut jobs, id)?;
+
+    if !job.is_stopped() {
+        return Err(format!("%{}: job is already running", job.id));
+    }
+    job.state.signal(Signal::Continue);
+
+    Ok(job.to_string())
+}
+
+/// Wait for a background job to complete, remove it from the job table and return its result.
+/// Ctrl+C stops waiting; when the job was brought to the foreground (fg), the job is interrupted.
+pub fn wait_background_job(id: Option<usize>, foreground: bool) -> Result<(), String> {
+    let id = {
+        let mut jobs = background_jobs();
+        let job = find_job(&mut jobs, id)?;
+
+        if foreground {
+            if job.is_stopped() {
+                job.state.signal(Signal::Continue);
+            }
+            eprintln!("{}", job.command);
+        }
+        job.id
+    };
+
+    let mut interrupted = false;
+    loop {
+        {
+            let mut jobs = background_jobs();
+            let job = find_job(&mut jobs, Some(id))?;
+
+            job.poll();
+            if job.status.is_some() {
+                let result = job.result();
+                jobs.retain(|job| job.id != id);
+                return result;
+            }
+
+            if Scope::is_interrupted() && !interrupted {
+                if !foreground {
+                    return Err(format!("%{}: wait interrupted", id));
+                }
+                interrupted = true;
+                job.state.signal(Signal::Interrupt);
+            }
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// How a process ended: with an exit code, or (on Unix) killed by a signal, in
+/// which case the code is 128 + the signal number, as in other shells.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Exit {
+    pub code: i64,
+    pub signal: Option<i32>,
+    pub core_dumped: bool,
+}
+
+impl Exit {
+    /// Generic failure, of commands that do not run processes.
+    pub const FAILURE: Exit = Exit {
+        code: 1,
+        signal: None,
+        core_dumped: false,
+    };
+
+    pub fn success(&self) -> bool {
+        self.code == 0 && self.signal.is_none()
+    }
+
+    /// The name of the signal that terminated the process, e.g. SIGKILL
+    pub fn signal_name(&self) -> Option<String> {
+        self.signal.map(imp::signal_name)
+    }
+
+    /// Killed by SIGPIPE, writing to a pipe that the reader closed, e.g. yes | head;
+    /// in-process pipeline stages stop the same way.
+    pub const BROKEN_PIPE: Exit = Exit {
+        code: 128 + imp::SIGPIPE as i64,
+        signal: Some(imp::SIGPIPE),
+        core_dumped: false,
+    };
+
+    /// Killed by SIGPIPE, see BROKEN_PIPE.
+    pub fn is_broken_pipe(&self) -> bool {
+        self.signal.is_some_and(imp::is_sigpipe)
+    }
+}
+
+impl fmt::Display for Exit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.signal_name() {
+            Some(name) if self.core_dumped => write!(f, "terminated by {} (core dumped)", name),
+            Some(name) => write!(f, "terminated by {}", name),
+            None => write!(f, "exit code: {} (0x{:X})", self.code, self.code),
+        }
+    }
+}
+
+thread_local! {
+    static LAST_EXIT: Cell<Option<Exit>> = const { Cell::new(None) };
+}
+
+/// Forget the exit of the last process waited for by this thread, before running a command.
+pub fn clear_last_exit() {
+    LAST_EXIT.with(|exit| exit.set(None));
+}
+
+/// How the last process waited for by this thread (since clear_last_exit) ended.
+pub fn last_exit() -> Option<Exit> {
+    LAST_EXIT.with(|exit| exit.get())
+}
+
+fn set_last_exit(exit: Exit) {
+    LAST_EXIT.with(|last| last.set(Some(exit)));
+}
+
+/// Record how the process ended, and turn failures into errors.
+fn check_exit(exit: Exit) -> io::Result<()> {
+    set_last_exit(exit);
+
+    if !exit.success() {
+        return Err(io::Error::new(io::ErrorKind::Other, exit.to_string()));
     }
     Ok(())
 }
 
+#[cfg(windows)]
+fn check_exit_code(code: i64) -> io::Result<()> {
+    check_exit(Exit {
+        code,
+        ..Default::default()
+    })
+}
+
 #[cfg(not(windows))]
 mod imp {
     use super::*;
 
     fn check_exit_status(status: std::process::ExitStatus) -> io::Result<()> {
-        if let Some(code) = status.code() {
-            check_exit_code(code as _)
+        check_exit(exit_of(&status))
+    }
+
+    pub fn exit_of(status: &ExitStatus) -> Exit {
+        use std::os::unix::process::ExitStatusExt;
+
+        match (status.code(), status.signal()) {
+            (Some(code), _) => Exit {
+                code: code as _,
+                ..Default::default()
+            },
+            (None, Some(signal)) => Exit {
+                code: 128 + signal as i64,
+                signal: Some(signal),
+                core_dumped: status.core_dumped(),
+            },
+            (None, None) => Exit::default(),
+        }
+    }
+
+    pub fn signal_name(signal: i32) -> String {
+        match nix::sys::signal::Signal::try_from(signal) {
+            Ok(signal) => signal.to_string(),
+            Err(_) => format!("signal {}", signal),
+        }
+    }
+
+    pub const SIGPIPE: i32 = nix::libc::SIGPIPE;
+
+    pub fn is_sigpipe(signal: i32) -> bool {
+        signal == SIGPIPE
+    }
+
+    fn to_nix_signal(signal: Signal) -> nix::Result<nix::sys::signal::Signal> {
+        use nix::sys::signal::Signal as Sig;
+
+        match signal {
+            Signal::Hangup => Ok(Sig::SIGHUP),
+            Signal::Interrupt => Ok(Sig::SIGINT),
+            Signal::Kill => Ok(Sig::SIGKILL),
+            Signal::Terminate => Ok(Sig::SIGTERM),
+            Signal::Stop => Ok(Sig::SIGSTOP),
+            Signal::Continue => Ok(Sig::SIGCONT),
+            Signal::Other(n) => Sig::try_from(n),
+        }
+    }
+
+    /// How a background job terminated by the signal ended, as if the signal killed a process.
+    pub fn exit_of_signal(signal: Signal) -> Exit {
+        let signal = to_nix_signal(signal).map_or(nix::libc::SIGTERM, |signal| signal as i32);
+        Exit {
+            code: 128 + signal as i64,
+            signal: Some(signal),
+            core_dumped: false,
+        }
+    }
+
+    /// Start background jobs in their own process group, so that they
+    /// do not get the Ctrl+C meant for the foreground command.
+    pub fn new_process_group(command: &mut Command) {
+        use std::os::unix::process::CommandExt;
+        command.process_group(0);
+    }
+
+    /// Send a signal to a process, or to the process group that it leads.
+    pub fn kill(pid: u32, group: bool, signal: Signal) -> io::Result<()> {
+        use nix::sys::signal;
+        use nix::unistd::Pid;
+
+        let signal = to_nix_signal(signal)?;
+        let pid = Pid::from_raw(pid as _);
+
+        if group {
+            signal::killpg(pid, signal)?;
         } else {
-            Ok(())
+            signal::kill(pid, signal)?;
+        }
+        Ok(())
+    }
+
+    pub struct Process {
+        child: std::process::Child,
+        _background: BackgroundProcess,
+    }
+
+    impl Process {
+        pub fn wait(&mut self) -> io::Result<()> {
+            check_exit_status(self.child.wait()?)
         }
     }
 
@@ -73,10 +637,21 @@ mod imp {
         }
 
         pub fn run(&mut self) -> io::Result<()> {
+            prepare_command(&mut self.cmd);
             let mut child = self.cmd.spawn()?;
+            let _background = BackgroundProcess::new(child.id());
             check_exit_status(child.wait()?)
         }
 
+        pub fn spawn(&mut self) -> io::Result<Process> {
+            prepare_command(&mut self.cmd);
+            let child = self.cmd.spawn()?;
+            Ok(Process {
+                _background: BackgroundProcess::new(child.id()),
+                child,
+            })
+        }
+
         pub fn command_mut(&mut self) -> Option<&mut Command> {
             Some(&mut self.cmd)
         }
@@ -389,11 +964,71 @@ mod imp {
         Ok(job)
     }
 
+    /// How a background job terminated with kill ended, as if its process was terminated.
+    pub fn exit_of_signal(_signal: Signal) -> Exit {
+        Exit::FAILURE
+    }
+
+    /// There are no signals on Windows, but in-process pipeline stages that write to a closed
+    /// pipe are reported as terminated by SIGPIPE, see Exit::BROKEN_PIPE.
+    pub const SIGPIPE: i32 = 13;
+
+    /// Processes are not terminated by signals on Windows.
+    pub fn signal_name(signal: i32) -> String {
+        if signal == SIGPIPE {
+            "SIGPIPE".to_string()
+        } else {
+            format!("signal {}", signal)
+        }
+    }
+
+    pub fn is_sigpipe(signal: i32) -> bool {
+        signal == SIGPIPE
+    }
+
+    /// Start background jobs in their own process group, so that they
+    /// do not get the Ctrl+C meant for the foreground command.
+    /// The processes are created suspended, see Job::new.
+    pub fn new_process_group(command: &mut Command) {
+        command.creation_flags(CREATE_SUSPENDED.0 | CREATE_NEW_PROCESS_GROUP.0);
+    }
+
+    /// Terminate a process; processes started by the interpreter in a background job are
+    /// associated with job objects, and terminate when their parent does.
+    pub fn kill(pid: u32, _group: bool, signal: Signal) -> io::Result<()> {
+        match signal {
+            Signal::Hangup | Signal::Interrupt | Signal::Kill | Signal::Terminate => unsafe {
+                let process = to_owned(OpenProcess(PROCESS_TERMINATE, false, pid)?);
+                TerminateProcess(HANDLE(process.as_raw_handle()), 1)?;
+                Ok(())
+            },
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{:?} is not supported on Windows", signal),
+            )),
+        }
+    }
+
     const EXIT_CODE_EXEMPT: [&str; 2] = [
         "\\windows\\explorer.exe",
         "\\windows\\system32\\control.exe",
     ];
 
+    pub struct Process {
+        child: std::process::Child,
+        _job: OwnedHandle, // Keep the job object alive, it kills the process on close.
+        _background: BackgroundProcess,
+    }
+
+    impl Process {
+        pub fn wait(&mut self) -> io::Result<()> {
+            match self.child.wait()?.code() {
+                Some(code) => check_exit_code(code as _),
+                None => Ok(()),
+            }
+        }
+    }
+
     pub struct Job<'a> {
         cmd: Option<Command>,
         path: &'a Path,
@@ -497,6 +1132,32 @@ mod imp {
             }
         }
 
+        /// Spawn the command process, associate it with a job object and return without waiting.
+        pub fn spawn(&mut self) -> io::Result<Process> {
+            let command = self.command_mut().ok_or_else(|| {
+                io::Error::new(io::ErrorKind::Unsupported, "Cannot spawn elevated command")
+            })?;
+            prepare_command(command);
+            let child = command.spawn()?;
+
+            let handle = HANDLE(child.as_raw_handle());
+            let job = match add_process_to_job(self.scope, child.id(), handle) {
+                Ok(job) => job,
+                Err(e) => {
+                    unsafe {
+                        _ = TerminateProcess(handle, 42);
+                    }
+                    return Err(e);
+                }
+            };
+
+            Ok(Process {
+                _background: BackgroundProcess::new(child.id()),
+                child,
+                _job: job,
+            })
+        }
+
         /// Spawn command process and associate it with a job object.
         /// The process is created suspended and add_proccess_to_job resumes it on success.
         /// Return the exit code.
@@ -511,7 +1172,9 @@ mod imp {
             );
 
             let command = self.command_mut().expect("No command");
+            prepare_command(command);
             let mut child = command.spawn()?;
+            let _background = BackgroundProcess::new(child.id());
 
             let handle = HANDLE(child.as_raw_handle());
 
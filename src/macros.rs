@@ -9,6 +9,25 @@ macro_rules! my_dbg {
     };
 }
 
+/// Write to the standard output of the current thread (see stdio.rs), so that the output of
+/// builtin commands evaluated in pipeline stages and background jobs goes where it is redirected.
+/// Shadows std::print! in the modules declared after this one.
+macro_rules! print {
+    ($($arg:tt)*) => {
+        $crate::stdio::print(format_args!($($arg)*))
+    };
+}
+
+/// Shadows std::println!, see print! above.
+macro_rules! println {
+    () => {
+        $crate::stdio::print(format_args!("\n"))
+    };
+    ($($arg:tt)*) => {
+        $crate::stdio::print(format_args!("{}\n", format_args!($($arg)*)))
+    };
+}
+
 /// Write to stdout without panic.
 /// More robust than built-in when redirect stdout to pipe.
 #[macro_export]
@@ -21,19 +40,16 @@ macro_rules! my_println {
         use std::io::{ErrorKind, Write};
 
         // Create a formatted string
-        let output = format!($($arg)*);
+        let output = format!("{}\n", format_args!($($arg)*));
 
         // Attempt to write to stdout, ignoring broken pipe errors.
-        let mut stdout = std::io::stdout().lock();
-        match stdout
-            .write_all(output.as_bytes())
-            .and_then(|_| stdout.write_all(b"\n")) {
-                Ok(_) => Ok(()),
-                Err(e) => match e.kind() {
-                    ErrorKind::BrokenPipe => Ok(()),
-                    _ => Err(e.to_string()),
-                },
-            }
+        match $crate::stdio::stdout().write_all(output.as_bytes()) {
+            Ok(_) => Ok(()),
+            Err(e) => match e.kind() {
+                ErrorKind::BrokenPipe => Ok(()),
+                _ => Err(e.to_string()),
+            },
+        }
     }};
 }
 
@@ -48,7 +64,7 @@ macro_rules! my_print {
         let output = format!($($arg)*);
 
         // Attempt to write to stdout, ignoring broken pipe errors.
-        match std::io::stdout().lock().write_all(output.as_bytes()) {
+        match $crate::stdio::stdout().write_all(output.as_bytes()) {
             Ok(_) => Ok(()),
             Err(e) => match e.kind() {
                 ErrorKind::BrokenPipe => Ok(()),
@@ -27,14 +27,20 @@ mod macros;
 
 mod cmds;
 mod completions;
+mod debugger;
 mod eval;
+mod funcs;
 mod hooks;
 mod job;
+mod modules;
+mod profiler;
 mod prompt;
 mod scope;
+mod stdio;
 mod symlnk;
 mod testcmds;
 mod testeval;
+mod traps;
 mod utils;
 
 #[derive(Helper, Hinter, Validator)]
@@ -386,6 +392,10 @@ struct Shell {
     source: Option<Box<dyn BufRead>>,
     interactive: bool,
     wait: bool,
+    check: bool, // Check the script for errors without running it
+    format: bool, // Print the script with consistent formatting, without running it
+    debug: bool,  // Run the script in the debugger
+    profiling: bool, // Report the time spent per statement and per command, at exit
     interp: Interp,
     home_dir: Option<PathBuf>,
     history_path: Option<PathBuf>,
@@ -424,6 +434,10 @@ impl Shell {
             source: None,
             interactive: true,
             wait: false,
+            check: false,
+            format: false,
+            debug: false,
+            profiling: false,
             interp,
             home_dir: None,
             history_path: None,
@@ -567,6 +581,11 @@ impl Shell {
 
             // Run interactive read-evaluate loop
             while !self.interp.quit {
+                // Report the background jobs that completed since the last prompt.
+                for job in job::completed_background_jobs() {
+                    eprintln!("{}", job);
+                }
+
                 let prompt = self.prompt_builder.prompt();
 
                 // Hack around peculiarity in Rustyline, where a prompt that contains color ANSI codes
@@ -610,6 +629,16 @@ impl Shell {
             // Evaluate a script file
             let mut script: String = String::new();
             match reader.read_to_string(&mut script) {
+                Ok(_) if self.check => {
+                    self.check(&script);
+                }
+                Ok(_) if self.format => {
+                    self.format(&script);
+                }
+                Ok(_) if self.debug => {
+                    self.start_debugger()?;
+                    self.eval(&script);
+                }
                 Ok(_) => {
                     self.eval(&script);
                 }
@@ -696,22 +725,86 @@ impl Shell {
             Value::Int(self.interactive as _),
         );
 
-        match self.interp.eval(input, Some(Arc::clone(&scope))) {
+        let failed = match self.interp.eval(input, Some(Arc::clone(&scope))) {
             Ok(value) => {
                 // Did the expression eval result in running a command? Check for errors.
                 if let Value::Stat(mut status) = value {
-                    if let Some(e) = status.err() {
-                        e.show(&scope, input);
+                    match status.err() {
+                        Some(e) => {
+                            e.show(&scope, input);
+                            true
+                        }
+                        None => false,
+                    }
+                } else {
+                    if self.interactive {
+                        self.show_result(&scope, &input.trim(), &value);
                     }
-                } else if self.interactive {
-                    self.show_result(&scope, &input.trim(), &value);
+                    false
                 }
             }
             Err(e) => {
                 e.show(&scope, input);
-                if !self.interactive && !self.wait {
-                    std::process::exit(500);
+                true
+            }
+        };
+
+        if Scope::is_interrupted() {
+            traps::run(traps::Condition::Interrupt);
+        } else if failed {
+            traps::run(traps::Condition::Error);
+        }
+
+        // Stop the script, and let the parent process know that it failed.
+        if failed && !self.interactive && !self.wait {
+            traps::run(traps::Condition::Exit);
+            profiler::report();
+            std::process::exit(500);
+        }
+    }
+
+    /// Parse the script and report syntax errors and warnings, without running it.
+    fn check(&mut self, input: &str) {
+        let scope = self.interp.global_scope();
+        let errors = self.interp.check(input);
+        for e in &errors {
+            e.show(&scope, input);
+        }
+        if !errors.is_empty() {
+            eprintln!("{} problem(s) found", errors.len());
+            std::process::exit(1);
+        }
+    }
+
+    /// Set up a line editor for the debugger prompt, with the same configuration
+    /// and completion helper as the interactive mode.
+    fn start_debugger(&mut self) -> Result<(), String> {
+        let mut rl = CmdLineEditor::with_config(self.edit_config)
+            .map_err(|e| format!("Failed to create editor: {}", e))?;
+
+        let scope = self.interp.global_scope();
+        rl.set_helper(Some(CmdLineHelper::new(scope, None)));
+
+        debugger::start(Box::new(move |prompt| {
+            rl.helper_mut().unwrap().set_prompt(prompt);
+            match rl.readline(prompt) {
+                Ok(line) => {
+                    _ = rl.add_history_entry(line.as_str());
+                    Some(line)
                 }
+                Err(_) => None,
+            }
+        }));
+        Ok(())
+    }
+
+    /// Print the script with consistent indentation and spacing, without running it.
+    fn format(&mut self, input: &str) {
+        match self.interp.format(input) {
+            Ok(output) => print!("{}", output),
+            Err(e) => {
+                e.show(&self.interp.global_scope(), input);
+                std::process::exit(1);
             }
         }
     }
@@ -738,8 +831,24 @@ fn create_shell() -> Result<Shell, String> {
     let args: Vec<String> = env::args().collect();
     for (i, arg) in args.iter().enumerate().skip(1) {
         if arg.starts_with("-") {
-            if arg == "-c" || arg == "-k" {
-                if !shell.interactive {
+            if arg == "--check" {
+                shell.check = true;
+                shell.interactive = false;
+            } else if arg == "--fmt" {
+                shell.format = true;
+                shell.interactive = false;
+            } else if arg == "--debug" {
+                shell.debug = true;
+                shell.interactive = false;
+            } else if arg == "--profile" {
+                shell.profiling = true;
+            } else if arg == "-x" {
+                shell
+                    .interp
+                    .global_scope()
+                    .insert("__trace".to_string(), eval::Value::Int(1));
+            } else if arg == "-c" || arg == "-k" {
+                if shell.interp.file().is_some() {
                     Err("Cannot specify -c command and scripts at the same time")?;
                 }
                 shell.source = Some(Box::new(Cursor::new(format!(
@@ -762,6 +871,14 @@ fn create_shell() -> Result<Shell, String> {
             shell.source = Some(Box::new(BufReader::new(file)));
             shell.interactive = false;
             shell.interp.set_file(Some(Arc::new(arg.to_owned())));
+
+            // The arguments that follow belong to the script, see argparse.
+            let script_args = args[i + 1..].iter().map(|arg| Value::from(arg.as_str()));
+            shell
+                .interp
+                .global_scope()
+                .insert("__args".to_string(), Value::new_list(script_args.collect()));
+            break;
         }
     }
 
@@ -828,12 +945,17 @@ fn main() -> Result<(), ()> {
             eprint!("Command line error: {}.", e);
         }
         Ok(shell) => {
+            if shell.profiling {
+                profiler::start();
+            }
             match &shell.eval_input() {
                 Err(e) => {
                     eprintln!("{}", e);
                 }
                 Ok(_) => {}
             }
+            traps::run(traps::Condition::Exit);
+            profiler::report();
 
             if shell.wait {
                 prompt::read_input("\nPress Enter to continue... ").unwrap_or(String::default());
@@ -0,0 +1,224 @@
+use crate::cmds::{Exec, ShellCommand};
+use crate::eval::{EvalError, EvalResult, Interp, Location, Value};
+use crate::scope::Scope;
+use directories::UserDirs;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+///
+/// Module loaded with: import NAME
+///
+/// The functions that a module defines are registered as NAME.FUNCTION commands, and its
+/// top-level variables are copied to the global scope as NAME.VARIABLE, e.g. ${util.x}.
+/// Modules are resolved along the search path ($SHMYPATH, then ~/.shmy/lib), or relative
+/// to the importing script, if the name is a path, e.g. import ./lib/util
+///
+/// Imports are resolved when the importing script is parsed, so that the functions of the
+/// module can be called in the rest of the script; the module is evaluated (once) when the
+/// import statement runs.
+///
+pub struct Module {
+    pub name: String,      // Namespace of the functions and variables
+    pub scope: Arc<Scope>, // Module-level variables
+    path: Arc<String>,
+    source: String,
+    loaded: AtomicBool,
+}
+
+impl fmt::Debug for Module {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Module({})", self.path)
+    }
+}
+
+#[derive(Clone)]
+struct Import {
+    module: Arc<Module>,
+    functions: Vec<ShellCommand>,
+}
+
+thread_local! {
+    static MODULES: RefCell<HashMap<PathBuf, Import>> = RefCell::new(HashMap::new());
+    static LOADING: RefCell<Vec<PathBuf>> = const { RefCell::new(Vec::new()) }; // Modules being parsed
+}
+
+/// Resolve and parse the module (the first time it is imported); return the command that
+/// evaluates the module, and the functions defined by the module, named NAME.FUNCTION
+pub fn import(
+    name: &str,
+    scope: &Arc<Scope>,
+    importer: Option<&Arc<String>>,
+    loc: &Location,
+) -> EvalResult<(ShellCommand, Vec<ShellCommand>)> {
+    let path = resolve(name, scope, importer).map_err(|e| EvalError::new(loc.clone(), e))?;
+    let key = path.canonicalize().unwrap_or_else(|_| path.clone());
+
+    let import = match MODULES.with(|modules| modules.borrow().get(&key).cloned()) {
+        Some(import) => import,
+        None => {
+            let import = parse(&path, &key, scope, loc)?;
+            MODULES.with(|modules| modules.borrow_mut().insert(key, import.clone()));
+            import
+        }
+    };
+
+    let cmd = ShellCommand::new("import".to_string(), Arc::new(import.clone()));
+    Ok((cmd, import.functions))
+}
+
+fn parse(path: &Path, key: &PathBuf, scope: &Arc<Scope>, loc: &Location) -> EvalResult<Import> {
+    if let Some(start) = LOADING.with(|loading| loading.borrow().iter().position(|p| p == key)) {
+        let cycle = LOADING.with(|loading| {
+            loading.borrow()[start..]
+                .iter()
+                .chain([key])
+                .map(|p| display_name(p))
+                .collect::<Vec<_>>()
+                .join(" -> ")
+        });
+        return Err(EvalError::new(
+            loc.clone(),
+            format!("Import cycle: {}", cycle),
+        ));
+    }
+
+    let source = fs::read_to_string(path).map_err(|e| {
+        EvalError::new(
+            loc.clone(),
+            format!("Failed to read module {}: {}", path.display(), e),
+        )
+    })?;
+    let name = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+
+    let global = global_scope(scope);
+    let module = Arc::new(Module {
+        name,
+        scope: Scope::with_parent_and_hooks(Some(Arc::clone(&global)), None),
+        path: Arc::new(path.display().to_string()),
+        source,
+        loaded: AtomicBool::new(false),
+    });
+
+    let mut interp = Interp::new(global);
+    interp.set_file(Some(Arc::clone(&module.path)));
+    interp.set_module(Some(Arc::clone(&module)));
+
+    LOADING.with(|loading| loading.borrow_mut().push(key.clone()));
+    let result = interp.module_functions(&module.source);
+    LOADING.with(|loading| loading.borrow_mut().pop());
+
+    Ok(Import {
+        module,
+        functions: result?,
+    })
+}
+
+fn display_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+fn global_scope(scope: &Arc<Scope>) -> Arc<Scope> {
+    let mut scope = scope;
+    while let Some(parent) = &scope.parent {
+        scope = parent;
+    }
+    Arc::clone(scope)
+}
+
+/// Directories where modules are looked up: the $SHMYPATH list, then ~/.shmy/lib
+fn search_path(scope: &Arc<Scope>) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+
+    if let Some(var) = scope.lookup("SHMYPATH") {
+        dirs.extend(env::split_paths(&*var.value().as_str()));
+    }
+
+    let home = match scope.lookup("HOME") {
+        Some(var) => Some(PathBuf::from(&*var.value().as_str())),
+        None => UserDirs::new().map(|dirs| dirs.home_dir().to_path_buf()),
+    };
+    if let Some(home) = home {
+        dirs.push(home.join(".shmy").join("lib"));
+    }
+    dirs
+}
+
+/// Resolve the module name to a file; the .my extension is optional. Names with
+/// more than one path component are relative to the directory of the importing script.
+fn resolve(
+    name: &str,
+    scope: &Arc<Scope>,
+    importer: Option<&Arc<String>>,
+) -> Result<PathBuf, String> {
+    let mut file = PathBuf::from(name);
+    if file.extension().is_none() {
+        file.set_extension("my");
+    }
+
+    if file.is_absolute() || file.components().count() > 1 {
+        let dir = importer
+            .and_then(|path| Path::new(&**path).parent())
+            .unwrap_or(Path::new(""));
+        let path: PathBuf = dir
+            .join(file)
+            .components()
+            .filter(|c| *c != Component::CurDir)
+            .collect();
+        if path.is_file() {
+            return Ok(path);
+        }
+    } else {
+        for dir in search_path(scope) {
+            let path = dir.join(&file);
+            if path.is_file() {
+                return Ok(path);
+            }
+        }
+    }
+    Err(format!("Module not found: {}", name))
+}
+
+impl Exec for Import {
+    /// Evaluate the module the first time the import statement runs.
+    fn exec(&self, _name: &str, _args: &Vec<String>, scope: &Arc<Scope>) -> Result<Value, String> {
+        let module = &self.module;
+        if module.loaded.swap(true, Ordering::Relaxed) {
+            return Ok(Value::success());
+        }
+
+        let global = global_scope(scope);
+        let mut interp = Interp::new(Arc::clone(&global));
+        interp.set_file(Some(Arc::clone(&module.path)));
+        interp.set_module(Some(Arc::clone(module)));
+
+        match interp.eval(&module.source, Some(Arc::clone(&module.scope))) {
+            Err(e) => {
+                e.show(scope, &module.source);
+                return Err(format!("Error importing module '{}'", module.name));
+            }
+            Ok(Value::Stat(status)) if status.is_err() => {
+                return Err(status.clone().err().unwrap().to_string());
+            }
+            Ok(_) => {}
+        }
+
+        for (key, var) in module.scope.vars().iter() {
+            if !key.is_special_var() {
+                let name = format!("{}.{}", module.name, key.as_str());
+                global.insert(name, var.value().clone());
+            }
+        }
+        Ok(Value::success())
+    }
+}
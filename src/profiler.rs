@@ -0,0 +1,140 @@
+use crate::eval::Location;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::Duration;
+
+#[derive(Default)]
+struct Entry {
+    calls: u64,
+    total: Duration,
+    text: String, // Statement text
+}
+
+///
+/// Per-statement and per-command timings, enabled with the --profile command line flag.
+///
+/// Statements are identified by their location, commands (including functions and
+/// aliases) by their name. The times are inclusive: a loop statement includes the time
+/// spent in the statements of its body, a function includes the commands it runs.
+///
+#[derive(Default)]
+struct Profile {
+    statements: HashMap<(Option<Arc<String>>, u32, u32), Entry>,
+    commands: HashMap<String, Entry>,
+}
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+// Pipeline stages run on their own threads, hence the mutex.
+static PROFILE: LazyLock<Mutex<Option<Profile>>> = LazyLock::new(|| Mutex::new(None));
+
+pub fn start() {
+    *PROFILE.lock().unwrap() = Some(Profile::default());
+    ACTIVE.store(true, SeqCst);
+}
+
+pub fn is_active() -> bool {
+    ACTIVE.load(SeqCst)
+}
+
+pub fn record_statement(loc: &Location, statement: &dyn fmt::Display, elapsed: Duration) {
+    if let Some(profile) = PROFILE.lock().unwrap().as_mut() {
+        let key = (loc.file.clone(), loc.line, loc.col);
+        let entry = profile.statements.entry(key).or_insert_with(|| Entry {
+            text: statement.to_string(),
+            ..Default::default()
+        });
+        entry.calls += 1;
+        entry.total += elapsed;
+    }
+}
+
+pub fn record_command(name: &str, elapsed: Duration) {
+    if let Some(profile) = PROFILE.lock().unwrap().as_mut() {
+        let entry = profile.commands.entry(name.to_string()).or_default();
+        entry.calls += 1;
+        entry.total += elapsed;
+    }
+}
+
+/// Print the report to stderr, sorted by total time; subsequent calls do nothing.
+pub fn report() {
+    let profile = match PROFILE.lock().unwrap().take() {
+        Some(profile) => profile,
+        None => return,
+    };
+    ACTIVE.store(false, SeqCst);
+
+    // Source files, for finding the lines where statements start.
+    let mut sources: HashMap<Arc<String>, Vec<String>> = HashMap::new();
+
+    let mut statements = profile
+        .statements
+        .into_iter()
+        .map(|((file, line, col), entry)| {
+            let loc = Location {
+                line,
+                col,
+                file: file.clone(),
+            };
+            let lines = file.as_ref().and_then(|file| {
+                if !sources.contains_key(file) {
+                    let text = fs::read_to_string(&**file).ok()?;
+                    sources.insert(Arc::clone(file), text.lines().map(String::from).collect());
+                }
+                sources.get(file)
+            });
+            let line = lines.map_or(line, |lines| loc.statement_line(lines));
+            let where_ = match &file {
+                Some(file) => format!("{}:{}", file, line),
+                None => line.to_string(),
+            };
+            (where_, entry)
+        })
+        .collect::<Vec<_>>();
+    statements.sort_by_key(|(_, entry)| Reverse(entry.total));
+
+    let mut commands = profile.commands.into_iter().collect::<Vec<_>>();
+    commands.sort_by_key(|(_, entry)| Reverse(entry.total));
+
+    eprintln!("\nTime per statement:");
+    eprintln!("{:>12} {:>8}  Location", "Total", "Calls");
+    for (where_, entry) in &statements {
+        eprintln!(
+            "{:>12} {:>8}  {}  {}",
+            format_duration(entry.total),
+            entry.calls,
+            where_,
+            truncate(&entry.text, 60)
+        );
+    }
+
+    eprintln!("\nTime per command:");
+    eprintln!("{:>12} {:>8}  Command", "Total", "Calls");
+    for (name, entry) in &commands {
+        eprintln!(
+            "{:>12} {:>8}  {}",
+            format_duration(entry.total),
+            entry.calls,
+            name
+        );
+    }
+}
+
+fn format_duration(duration: Duration) -> String {
+    format!("{:.6}s", duration.as_secs_f64())
+}
+
+/// Keep the report lines short: show the first line of the statement, up to max chars.
+fn truncate(text: &str, max: usize) -> String {
+    let line = text.lines().next().unwrap_or_default();
+    if line.chars().count() > max || line.len() < text.trim_end().len() {
+        format!("{}...", line.chars().take(max).collect::<String>())
+    } else {
+        line.to_string()
+    }
+}
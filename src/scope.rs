@@ -1,49 +1,80 @@
 use crate::hooks::Hooks;
+use crate::job;
+use crate::stdio::{self, Terminal};
 use crate::{eval::Value, utils::executable};
 use colored::*;
-use std::cell::{Ref, RefCell, RefMut};
+use parking_lot::{MappedRwLockReadGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::cell::RefCell;
 use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::env;
 use std::fmt::{self, Debug};
 use std::hash::{Hash, Hasher};
-use std::io::IsTerminal;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering::Relaxed};
 use std::sync::Arc;
 
-#[derive(Clone, Debug)]
+#[derive(Debug)]
 pub struct Variable {
-    val: RefCell<Value>,
+    val: RwLock<Value>,
+    readonly: AtomicBool, // Declared with READONLY (or CONST), cannot be assigned
+    exported: AtomicBool, // Passed to the environment of child processes
 }
 
 impl Variable {
     pub fn new(val: Value) -> Self {
         Self {
-            val: RefCell::new(val),
+            val: RwLock::new(val),
+            readonly: AtomicBool::new(false),
+            exported: AtomicBool::new(false),
         }
     }
 
-    pub fn assign(&self, val: Value) -> Ref<Value> {
-        *self.val.borrow_mut() = val;
-        self.val.borrow()
+    pub fn assign(&self, val: Value) -> RwLockReadGuard<Value> {
+        *self.val.write() = val;
+        self.val.read()
     }
 
-    pub fn value(&self) -> Ref<Value> {
-        Ref::map(self.val.borrow(), |v| v)
+    pub fn value(&self) -> RwLockReadGuard<Value> {
+        self.val.read()
+    }
+
+    pub fn is_readonly(&self) -> bool {
+        self.readonly.load(Relaxed)
+    }
+
+    pub fn set_readonly(&self) {
+        self.readonly.store(true, Relaxed);
+    }
+
+    pub fn is_exported(&self) -> bool {
+        self.exported.load(Relaxed)
+    }
+
+    pub fn set_exported(&self) {
+        self.exported.store(true, Relaxed);
+    }
+}
+
+impl Clone for Variable {
+    fn clone(&self) -> Self {
+        Self {
+            val: RwLock::new(self.value().clone()),
+            readonly: AtomicBool::new(self.is_readonly()),
+            exported: AtomicBool::new(self.is_exported()),
+        }
     }
 }
 
 impl From<&str> for Variable {
     fn from(value: &str) -> Self {
-        Variable {
-            val: RefCell::new(value.parse::<Value>().unwrap()),
-        }
+        Variable::new(value.parse::<Value>().unwrap())
     }
 }
 
 impl fmt::Display for Variable {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.val.borrow())
+        write!(f, "{}", self.val.read())
     }
 }
 
@@ -125,45 +156,45 @@ pub trait Namespace {
     fn clear(&self);
     fn keys<F: Fn(&Ident) -> bool>(&self, pred: F) -> Vec<String>;
     fn insert(&self, ident: &Ident, val: Value) -> Option<Variable>;
-    fn lookup(&self, ident: &Ident) -> Option<Ref<Variable>>;
+    fn lookup(&self, ident: &Ident) -> Option<MappedRwLockReadGuard<Variable>>;
     fn remove(&self, ident: &Ident) -> Option<Variable>;
 }
 
 struct VarTable {
-    vars: RefCell<HashMap<Ident, Variable>>,
+    vars: RwLock<HashMap<Ident, Variable>>,
 }
 
 impl VarTable {
     fn new() -> Self {
         Self {
-            vars: RefCell::new(HashMap::new()),
+            vars: RwLock::new(HashMap::new()),
         }
     }
 
     fn with_vars(vars: HashMap<Ident, Variable>) -> Self {
         Self {
-            vars: RefCell::new(vars),
+            vars: RwLock::new(vars),
         }
     }
 
-    fn inner(&self) -> Ref<HashMap<Ident, Variable>> {
-        Ref::map(self.vars.borrow(), |vars| vars)
+    fn inner(&self) -> RwLockReadGuard<HashMap<Ident, Variable>> {
+        self.vars.read()
     }
 
-    fn inner_mut(&self) -> RefMut<HashMap<Ident, Variable>> {
-        RefMut::map(self.vars.borrow_mut(), |vars| vars)
+    fn inner_mut(&self) -> RwLockWriteGuard<HashMap<Ident, Variable>> {
+        self.vars.write()
     }
 }
 
 impl Namespace for VarTable {
     fn clear(&self) {
-        self.vars.borrow_mut().clear();
+        self.vars.write().clear();
     }
 
     /// Filter keys (identifiers) by predicate
     fn keys<F: Fn(&Ident) -> bool>(&self, pred: F) -> Vec<String> {
         self.vars
-            .borrow()
+            .read()
             .iter()
             .filter_map(|(k, _)| {
                 if pred(k) {
@@ -175,25 +206,30 @@ impl Namespace for VarTable {
             .collect()
     }
 
+    /// Insert a new variable, or replace the value of an existing one, keeping its attributes.
     fn insert(&self, ident: &Ident, val: Value) -> Option<Variable> {
-        self.vars
-            .borrow_mut()
-            .insert(ident.clone(), Variable::new(val))
+        let var = Variable::new(val);
+        let mut vars = self.vars.write();
+        if let Some(old) = vars.get(ident) {
+            var.readonly.store(old.is_readonly(), Relaxed);
+            var.exported.store(old.is_exported(), Relaxed);
+        }
+        vars.insert(ident.clone(), var)
     }
 
-    fn lookup(&self, ident: &Ident) -> Option<Ref<Variable>> {
-        Ref::filter_map(self.vars.borrow(), |vars| vars.get(ident)).ok()
+    fn lookup(&self, ident: &Ident) -> Option<MappedRwLockReadGuard<Variable>> {
+        RwLockReadGuard::try_map(self.vars.read(), |vars| vars.get(ident)).ok()
     }
 
     fn remove(&self, ident: &Ident) -> Option<Variable> {
-        self.vars.borrow_mut().remove(ident)
+        self.vars.write().remove(ident)
     }
 }
 
 pub struct Scope {
     pub parent: Option<Arc<Scope>>,
     vars: VarTable,
-    err_arg: RefCell<usize>, // Index of argument with error.
+    err_arg: AtomicUsize, // Index of argument with error.
     pub hooks: Option<Arc<Hooks>>,
 }
 
@@ -213,7 +249,7 @@ impl Scope {
         Arc::new(Self {
             parent: None,
             vars: VarTable::new(),
-            err_arg: RefCell::default(),
+            err_arg: AtomicUsize::default(),
             hooks: None,
         })
     }
@@ -225,7 +261,7 @@ impl Scope {
         Arc::new(Self {
             parent,
             vars: VarTable::new(),
-            err_arg: RefCell::default(),
+            err_arg: AtomicUsize::default(),
             hooks,
         })
     }
@@ -233,28 +269,78 @@ impl Scope {
     pub fn with_env_vars() -> Arc<Scope> {
         env::set_var("SHELL", executable().unwrap_or("shmy".to_string()));
 
+        // Variables that come from the environment are passed on to child processes.
         let vars: HashMap<Ident, Variable> = env::vars()
-            .map(|(key, value)| (Ident::from(key), Variable::from(value.as_str())))
+            .map(|(key, value)| {
+                let var = Variable::from(value.as_str());
+                var.set_exported();
+                (Ident::from(key), var)
+            })
             .collect::<HashMap<_, _>>();
 
         Arc::new(Scope {
             parent: None,
             vars: VarTable::with_vars(vars),
-            err_arg: RefCell::default(),
+            err_arg: AtomicUsize::default(),
             hooks: None,
         })
     }
 
+    /// A copy of the variables of this scope, with the given parent.
+    fn copy(&self, parent: Option<Arc<Scope>>) -> Arc<Scope> {
+        Arc::new(Self {
+            parent,
+            vars: VarTable::with_vars(self.vars().clone()),
+            err_arg: AtomicUsize::new(self.err_arg.load(Relaxed)),
+            hooks: self.hooks.clone(),
+        })
+    }
+
+    /// The scope that stands in for this one on the current thread, see Frame.
+    pub fn resolve(self: &Arc<Self>) -> Arc<Scope> {
+        FRAMES.with_borrow_mut(|frames| Frame::resolve(frames, self))
+    }
+
+    /// Evaluate the expressions bound to this scope (or to its descendants) in the given scope
+    /// instead, until the guard is dropped, e.g. the body of a function, in the scope of a call.
+    pub fn replace_with(self: &Arc<Self>, replacement: &Arc<Scope>) -> CallGuard {
+        let mut frame = Frame {
+            call: true,
+            ..Default::default()
+        };
+        frame.insert(self, replacement);
+        FRAMES.with_borrow_mut(|frames| frames.push(frame));
+        CallGuard { _private: () }
+    }
+
+    /// Copy the scopes of the current thread, for evaluating expressions bound to this scope
+    /// (or to its descendants) on another thread. This scope and its ancestors are copied now,
+    /// other scopes when first used by the other thread.
+    pub fn copy_frames(self: &Arc<Self>) -> Frames {
+        let scope = self.resolve();
+        let mut frames = FRAMES.with_borrow(Vec::clone);
+        let mut frame = Frame::default();
+        frame.copy_ancestors(&scope);
+        frames.push(frame);
+        Frames(frames)
+    }
+
+    /// Ctrl+C was pressed, or the standard output of the thread is a pipe that the reader
+    /// closed (see stdio::is_broken_pipe): either way, commands and loops should stop.
+    /// Background jobs are interrupted by kill instead, see job::is_background_interrupted.
     pub fn is_interrupted() -> bool {
-        crate::INTERRUPT_EVENT
-            .try_lock()
-            .and_then(|guard| Ok(guard.is_set()))
-            .unwrap_or(false)
+        stdio::is_broken_pipe()
+            || job::is_background_interrupted().unwrap_or_else(|| {
+                crate::INTERRUPT_EVENT
+                    .try_lock()
+                    .and_then(|guard| Ok(guard.is_set()))
+                    .unwrap_or(false)
+            })
     }
 
     pub fn clear(&self) {
         self.vars.clear();
-        *self.err_arg.borrow_mut() = 0;
+        self.err_arg.store(0, Relaxed);
     }
 
     pub fn insert(&self, name: String, val: Value) {
@@ -265,11 +351,11 @@ impl Scope {
         self.vars.insert(&Ident(Arc::clone(name)), val);
     }
 
-    pub fn lookup(&self, name: &str) -> Option<Ref<Variable>> {
+    pub fn lookup(&self, name: &str) -> Option<MappedRwLockReadGuard<Variable>> {
         self.lookup_by_ident(&Ident::from(name))
     }
 
-    fn lookup_by_ident(&self, ident: &Ident) -> Option<Ref<Variable>> {
+    fn lookup_by_ident(&self, ident: &Ident) -> Option<MappedRwLockReadGuard<Variable>> {
         self.vars.lookup(ident).or_else(|| {
             self.parent
                 .as_ref()
@@ -277,7 +363,7 @@ impl Scope {
         })
     }
 
-    pub fn lookup_local(&self, name: &str) -> Option<Ref<Variable>> {
+    pub fn lookup_local(&self, name: &str) -> Option<MappedRwLockReadGuard<Variable>> {
         self.vars.lookup(&Ident::from(name))
     }
 
@@ -318,31 +404,31 @@ impl Scope {
         }
     }
 
-    pub fn vars(&self) -> Ref<HashMap<Ident, Variable>> {
+    pub fn vars(&self) -> RwLockReadGuard<HashMap<Ident, Variable>> {
         self.vars.inner()
     }
 
-    pub fn vars_mut(&self) -> RefMut<HashMap<Ident, Variable>> {
+    pub fn vars_mut(&self) -> RwLockWriteGuard<HashMap<Ident, Variable>> {
         self.vars.inner_mut()
     }
 
     /// Getter and setter for the index of the argument that caused an error.
     pub fn err_arg(&self) -> usize {
-        *self.err_arg.borrow()
+        self.err_arg.load(Relaxed)
     }
 
     pub fn set_err_arg(&self, index: usize) {
-        *self.err_arg.borrow_mut() = index + 1;
+        self.err_arg.store(index + 1, Relaxed);
     }
 
     /// The evaluation scope is passed to commands via the Exec trait;
     /// this is a convenient place to check for NO_COLOR.
     /// TODO: CLICOLOR, CLICOLOR_FORCE? See: https://bixense.com/clicolors/
-    pub fn use_colors<T: IsTerminal>(&self, out: &T) -> bool {
+    pub fn use_colors<T: Terminal>(&self, out: &T) -> bool {
         self.lookup("NO_COLOR").is_none() && out.is_terminal()
     }
 
-    pub fn color<T: IsTerminal>(&self, t: &str, c: Color, out: &T) -> ColoredString {
+    pub fn color<T: Terminal>(&self, t: &str, c: Color, out: &T) -> ColoredString {
         if self.use_colors(out) {
             t.color(c)
         } else {
@@ -374,7 +460,7 @@ impl Scope {
     /// for i in -; (ls $i)
     /// ```
     pub fn show_eof_hint(&self) {
-        if std::io::stdin().is_terminal() {
+        if stdio::stdin().is_terminal() {
             #[cfg(windows)]
             const MESSAGE: &str = "Press Ctrl-Z to end input";
             #[cfg(not(windows))]
@@ -387,3 +473,95 @@ impl Scope {
         }
     }
 }
+
+/// Scopes that stand in, on the current thread, for the scopes that the parser bound to
+/// expressions. Pipeline stages and background jobs are evaluated in copies of the scopes of the
+/// interpreter, so that (like subshells) their assignments are not seen by the interpreter.
+/// Each call of a function evaluates the body of the function in new scopes.
+#[derive(Clone, Default)]
+struct Frame {
+    // The replaced scopes (kept alive, so that their addresses are not reused) and their
+    // replacements, by the addresses of the replaced scopes.
+    scopes: HashMap<usize, (Arc<Scope>, Arc<Scope>)>,
+    call: bool,
+}
+
+impl Frame {
+    /// Resolve a scope through the frames, from the top: a call replaces the scopes of the
+    /// function body, a copy replaces the scope that the frames below resolve to.
+    fn resolve(frames: &mut [Frame], scope: &Arc<Scope>) -> Arc<Scope> {
+        match frames.split_last_mut() {
+            None => Arc::clone(scope),
+            Some((frame, below)) if frame.call => frame
+                .replace(scope)
+                .unwrap_or_else(|| Self::resolve(below, scope)),
+            Some((frame, below)) => {
+                let scope = Self::resolve(below, scope);
+                frame.replace(&scope).unwrap_or(scope)
+            }
+        }
+    }
+
+    fn insert(&mut self, scope: &Arc<Scope>, replacement: &Arc<Scope>) {
+        let key = Arc::as_ptr(scope) as usize;
+        self.scopes
+            .insert(key, (Arc::clone(scope), Arc::clone(replacement)));
+    }
+
+    fn copy_ancestors(&mut self, scope: &Arc<Scope>) -> Arc<Scope> {
+        let parent = scope.parent.as_ref().map(|parent| self.copy_ancestors(parent));
+        let copy = scope.copy(parent);
+        self.insert(scope, &copy);
+        copy
+    }
+
+    /// The replacement of a scope, or of a descendant of a replaced scope, which is copied
+    /// on first use, under the replacement of its parent.
+    fn replace(&mut self, scope: &Arc<Scope>) -> Option<Arc<Scope>> {
+        let key = Arc::as_ptr(scope) as usize;
+        if let Some((_, replacement)) = self.scopes.get(&key) {
+            return Some(Arc::clone(replacement));
+        }
+        let parent = self.replace(scope.parent.as_ref()?)?;
+        let copy = scope.copy(Some(parent));
+        self.insert(scope, &copy);
+        Some(copy)
+    }
+}
+
+thread_local! {
+    static FRAMES: RefCell<Vec<Frame>> = const { RefCell::new(Vec::new()) };
+}
+
+/// The frames of a thread, for another thread to evaluate in, see Scope::copy_frames.
+pub struct Frames(Vec<Frame>);
+
+impl Frames {
+    /// Evaluate in these frames on the current thread, until the guard is dropped.
+    pub fn enter(self) -> FramesGuard {
+        FramesGuard {
+            previous: FRAMES.replace(self.0),
+        }
+    }
+}
+
+/// Restores the frames of the thread from before a function call, see Scope::replace_with.
+pub struct CallGuard {
+    _private: (),
+}
+
+impl Drop for CallGuard {
+    fn drop(&mut self) {
+        FRAMES.with_borrow_mut(|frames| frames.pop());
+    }
+}
+
+pub struct FramesGuard {
+    previous: Vec<Frame>,
+}
+
+impl Drop for FramesGuard {
+    fn drop(&mut self) {
+        FRAMES.set(std::mem::take(&mut self.previous));
+    }
+}
@@ -0,0 +1,273 @@
+//! Standard input and output of the current thread.
+//!
+//! Pipeline stages, command substitutions and background jobs are evaluated on threads of the
+//! interpreter. Redirecting the standard input or output replaces it for the current thread only,
+//! until the returned guard is dropped; threads that did not redirect use those of the process.
+//! Builtin commands read with stdin() and write with stdout() (print! and println! are routed
+//! here by macros.rs), and external commands are connected to the same streams (see connect).
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, IsTerminal, Read, Write};
+use std::process::{Command, Stdio};
+
+#[cfg(unix)]
+use std::os::fd::{AsFd, OwnedFd as OwnedHandle};
+#[cfg(windows)]
+use std::os::windows::io::{AsHandle, OwnedHandle};
+
+#[cfg(unix)]
+const NULL_DEVICE: &str = "/dev/null";
+#[cfg(windows)]
+const NULL_DEVICE: &str = "NUL";
+
+thread_local! {
+    static STDIN: RefCell<Option<BufReader<File>>> = const { RefCell::new(None) };
+    static STDOUT: RefCell<Option<File>> = const { RefCell::new(None) };
+    // Set when writing to a pipe that the reader closed (e.g. yes | head), see is_broken_pipe.
+    static BROKEN_PIPE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Like IsTerminal (which cannot be implemented outside of std), for the streams of the thread.
+pub trait Terminal {
+    fn is_terminal(&self) -> bool;
+}
+
+impl Terminal for io::Stdout {
+    fn is_terminal(&self) -> bool {
+        IsTerminal::is_terminal(self)
+    }
+}
+
+impl Terminal for io::Stderr {
+    fn is_terminal(&self) -> bool {
+        IsTerminal::is_terminal(self)
+    }
+}
+
+/// Restores the previous standard input of the thread when dropped.
+pub struct StdinRedirect {
+    previous: Option<BufReader<File>>,
+}
+
+impl Drop for StdinRedirect {
+    fn drop(&mut self) {
+        STDIN.set(self.previous.take());
+    }
+}
+
+/// Redirect the standard input of the thread to a pipe or file.
+pub fn redirect_stdin(input: impl Into<OwnedHandle>) -> StdinRedirect {
+    let input = BufReader::new(File::from(input.into()));
+    StdinRedirect {
+        previous: STDIN.replace(Some(input)),
+    }
+}
+
+/// Restores the previous standard output of the thread when dropped, closing the redirected one.
+pub struct StdoutRedirect {
+    previous: Option<File>,
+    broken_pipe: bool,
+}
+
+impl Drop for StdoutRedirect {
+    fn drop(&mut self) {
+        STDOUT.set(self.previous.take());
+        BROKEN_PIPE.set(self.broken_pipe);
+    }
+}
+
+/// Redirect the standard output of the thread to a pipe or file.
+pub fn redirect_stdout(output: impl Into<OwnedHandle>) -> StdoutRedirect {
+    let output = File::from(output.into());
+    StdoutRedirect {
+        previous: STDOUT.replace(Some(output)),
+        broken_pipe: BROKEN_PIPE.replace(false),
+    }
+}
+
+/// Open the null device, for discarding output (or reading no input).
+pub fn null() -> io::Result<File> {
+    OpenOptions::new().read(true).write(true).open(NULL_DEVICE)
+}
+
+/// Duplicate the redirected standard input of the thread, if any.
+pub fn try_clone_stdin() -> io::Result<Option<File>> {
+    STDIN.with_borrow(|stdin| stdin.as_ref().map(|r| r.get_ref().try_clone()).transpose())
+}
+
+/// Duplicate the standard output of the thread (the redirected one, or that of the process).
+pub fn try_clone_stdout() -> io::Result<File> {
+    STDOUT.with_borrow(|stdout| match stdout {
+        Some(file) => file.try_clone(),
+        None => try_clone(&io::stdout()),
+    })
+}
+
+/// Duplicate the standard error of the process.
+pub fn try_clone_stderr() -> io::Result<File> {
+    try_clone(&io::stderr())
+}
+
+#[cfg(unix)]
+fn try_clone(stream: &impl AsFd) -> io::Result<File> {
+    Ok(stream.as_fd().try_clone_to_owned()?.into())
+}
+
+#[cfg(windows)]
+fn try_clone(stream: &impl AsHandle) -> io::Result<File> {
+    Ok(stream.as_handle().try_clone_to_owned()?.into())
+}
+
+/// The standard input for a child process: the input of the thread, or else inherited.
+/// Input that builtin commands have buffered but not consumed is not seen by the child.
+pub fn child_stdin() -> io::Result<Stdio> {
+    Ok(try_clone_stdin()?.map_or(Stdio::inherit(), Stdio::from))
+}
+
+/// The standard output for a child process: the output of the thread, or else inherited.
+pub fn child_stdout() -> io::Result<Stdio> {
+    STDOUT.with_borrow(|stdout| match stdout {
+        Some(file) => Ok(file.try_clone()?.into()),
+        None => Ok(Stdio::inherit()),
+    })
+}
+
+/// Connect the standard input and output of a child process to those of the thread.
+pub fn connect(command: &mut Command) -> io::Result<()> {
+    command.stdin(child_stdin()?).stdout(child_stdout()?);
+    Ok(())
+}
+
+/// True if the thread wrote to a pipe that the reader closed. Builtin commands and loops stop
+/// (see Scope::is_interrupted), the way processes are killed by SIGPIPE in other shells.
+pub fn is_broken_pipe() -> bool {
+    BROKEN_PIPE.get()
+}
+
+fn check_broken_pipe<T>(result: io::Result<T>) -> io::Result<T> {
+    if let Err(e) = &result {
+        if e.kind() == io::ErrorKind::BrokenPipe {
+            BROKEN_PIPE.set(true);
+        }
+    }
+    result
+}
+
+/// Implementation of the print! and println! macros, see macros.rs
+pub fn print(args: fmt::Arguments) {
+    let written = STDOUT.with_borrow(|stdout| {
+        stdout
+            .as_ref()
+            .map(|mut file| check_broken_pipe(file.write_all(fmt::format(args).as_bytes())))
+    });
+    match written {
+        None => std::print!("{}", args),
+        Some(Err(e)) if e.kind() != io::ErrorKind::BrokenPipe => {
+            panic!("failed printing to stdout: {}", e)
+        }
+        Some(_) => {}
+    }
+}
+
+/// Handle to the standard output of the thread.
+pub struct Stdout {
+    _private: (),
+}
+
+pub fn stdout() -> Stdout {
+    Stdout { _private: () }
+}
+
+impl Write for Stdout {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        STDOUT.with_borrow(|stdout| match stdout.as_ref() {
+            Some(mut file) => check_broken_pipe(file.write(buf)),
+            None => io::stdout().write(buf),
+        })
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        STDOUT.with_borrow(|stdout| match stdout.as_ref() {
+            Some(mut file) => check_broken_pipe(file.write_all(buf)),
+            None => io::stdout().lock().write_all(buf),
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        STDOUT.with_borrow(|stdout| match stdout {
+            Some(_) => Ok(()),
+            None => io::stdout().flush(),
+        })
+    }
+}
+
+impl Terminal for Stdout {
+    fn is_terminal(&self) -> bool {
+        STDOUT.with_borrow(|stdout| match stdout {
+            Some(file) => file.is_terminal(),
+            None => IsTerminal::is_terminal(&io::stdout()),
+        })
+    }
+}
+
+/// Handle to the standard input of the thread. The buffered reader of a redirected input
+/// is taken from the thread while the handle is alive, and put back when it is dropped.
+pub struct Stdin {
+    input: Option<Input>,
+}
+
+enum Input {
+    Redirected(BufReader<File>),
+    Process(io::StdinLock<'static>),
+}
+
+pub fn stdin() -> Stdin {
+    let input = match STDIN.take() {
+        Some(reader) => Input::Redirected(reader),
+        None => Input::Process(io::stdin().lock()),
+    };
+    Stdin { input: Some(input) }
+}
+
+impl Stdin {
+    fn reader(&mut self) -> &mut dyn BufRead {
+        match self.input.as_mut().expect("stdin") {
+            Input::Redirected(reader) => reader,
+            Input::Process(stdin) => stdin,
+        }
+    }
+}
+
+impl Drop for Stdin {
+    fn drop(&mut self) {
+        if let Some(Input::Redirected(reader)) = self.input.take() {
+            STDIN.set(Some(reader));
+        }
+    }
+}
+
+impl Read for Stdin {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader().read(buf)
+    }
+}
+
+impl BufRead for Stdin {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.reader().fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.reader().consume(amt)
+    }
+}
+
+impl Terminal for Stdin {
+    fn is_terminal(&self) -> bool {
+        match &self.input {
+            Some(Input::Redirected(reader)) => reader.get_ref().is_terminal(),
+            _ => io::stdin().is_terminal(),
+        }
+    }
+}
@@ -134,6 +134,18 @@ mod tests {
         assert_eval_ok!(&sort_command, Value::from("1\n2\n10"));
     }
 
+    #[test]
+    fn test_read_redirect() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = create_test_file(&temp_dir, "test_input.txt", "banana\napple\ncherry\n");
+
+        let sort_command = format!("sort =< {} | result; $result", file_path.display());
+        assert_eval_ok!(&sort_command, Value::from("apple\nbanana\ncherry"));
+
+        let grep_command = format!("grep an =< {} | result; $result", file_path.display());
+        assert_eval_ok!(&grep_command, Value::from("banana"));
+    }
+
     #[test]
     fn test_which_error() {
         assert_eval_err!("which if", "Expecting IF condition");
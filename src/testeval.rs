@@ -143,6 +143,14 @@ pub mod tests {
             "i = 1; if ($i < 0) (Apple) else (Orange)",
             Value::from("Orange")
         );
+        assert_eval_ok!(
+            "i = 1; if ($i < 0) () else (x = Orange; $x)",
+            Value::from("Orange")
+        );
+        assert_eval_ok!(
+            "i = 1; if ($i < 0) () else (if ($i > 5) () else (x = Lemon; $x))",
+            Value::from("Lemon")
+        );
     }
 
     #[test]
@@ -361,20 +369,57 @@ pub mod tests {
     fn test_arithmetic() {
         assert_eval_ok!("2+2", Value::Int(4));
         assert_eval_ok!("1 - 2 * 2 + 3", Value::Int(0));
+        assert_eval_ok!("x = 2; $x * (1 + 1)", Value::Int(4));
     }
 
     #[test]
     fn test_error() {
         assert_eval_ok!(
-            "if (echo Hello && cp x) () else ($__errors)",
+            "if (echo Hello && cp x) () else (errors | x; $x)",
             Value::from("cp x: Missing destination")
         );
         assert_eval_ok!(
-            "if (!(0 || cp -x || cp)) ($__errors)",
+            "if (!(0 || cp -x || cp)) (errors | x; $x)",
             Value::from("cp -x: Unknown flag: -x\ncp: Missing source and destination")
         );
     }
 
+    #[test]
+    fn test_errors_log() {
+        assert_eval_ok!(
+            "if (cp -x) () else ($__errors[0][command])",
+            Value::from("cp -x")
+        );
+        assert_eval_ok!(
+            "if (cp -x) () else ($__errors[0][args])",
+            Value::new_list(vec![Value::from("-x")])
+        );
+        assert_eval_ok!(
+            "if (cp -x) () else ($__errors[0][exit_code])",
+            Value::Int(1)
+        );
+        assert_eval_ok!("if (cp -x) () else ($__errors[0][col])", Value::Int(7));
+        assert_eval_ok!(
+            "if (cp -x || cp || basename .) () else (errors -n 1 -c cp | x; $x)",
+            Value::from("cp: Missing source and destination")
+        );
+        assert_eval_ok!(
+            "if (cp -x || cp || basename .) () else (errors -m \"^Missing\" | x; $x)",
+            Value::from("cp: Missing source and destination")
+        );
+        assert_eval_ok!(
+            "if (basename .) () else (errors --json | x; $x)",
+            Value::from(concat!(
+                r#"{"args":["."],"col":13,"command":"basename .","exit_code":1,"#,
+                r#""file":"","line":1,"message":"Failed to get file name","signal":""}"#
+            ))
+        );
+        assert_eval_ok!(
+            "if (cp) () else (errors --clear; if (defined __errors) (yes) else (no))",
+            Value::from("no")
+        );
+    }
+
     #[test]
     fn test_erase() {
         assert_eval_ok!("x = 123; $x = ", Value::Int(123));
@@ -384,7 +429,7 @@ pub mod tests {
     #[test]
     fn test_logical_or_error() {
         assert_eval_ok!(
-            "(basename . || echo $__errors) | x; $x",
+            "(basename . || errors) | x; $x",
             Value::from("basename .: Failed to get file name")
         );
     }
@@ -437,7 +482,7 @@ pub mod tests {
         assert_eval_err!("(0 || cp -x || cp)", "Missing source and destination");
 
         assert_eval_ok!(
-            "if (0 || cp -x || cp) (ok) else ($__errors)",
+            "if (0 || cp -x || cp) (ok) else (errors | x; $x)",
             Value::from("cp -x: Unknown flag: -x\ncp: Missing source and destination")
         );
     }
@@ -445,7 +490,7 @@ pub mod tests {
     #[test]
     fn test_negated_status() {
         assert_eval_ok!(
-            "if (!(0 || cp -x || cp)) ($__errors)",
+            "if (!(0 || cp -x || cp)) (errors | x; $x)",
             Value::from("cp -x: Unknown flag: -x\ncp: Missing source and destination")
         );
     }
@@ -460,6 +505,637 @@ pub mod tests {
         assert_eval_ok!("i = 2; echo hello | echo $i | x; $x", Value::Int(2));
     }
 
+    #[test]
+    fn test_pipe_in_process() {
+        // Functions defined in the current interpreter are visible to all pipeline stages.
+        assert_eval_ok!(
+            "def shout() (cat | s; $s + \"!\"); echo hey | shout | cat | x; $x",
+            Value::from("hey!")
+        );
+        // Values on the left hand-side are written into the pipe.
+        assert_eval_ok!("a = 42; $a | b; $b", Value::Int(42));
+    }
+
+    #[test]
+    fn test_pipe_status() {
+        assert_eval_ok!("echo a | cat | cat | x; ${#__pipestatus}", Value::Int(3));
+        assert_eval_ok!("echo a | cat | x; $__pipestatus[1]", Value::Int(0));
+        assert_eval_ok!(
+            "if (echo a | cp | x) (1) else ($__pipestatus[1])",
+            Value::from("Missing source and destination")
+        );
+        assert_eval_err!("echo a | cp | cat | x", "Missing source and destination");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_pipe_sigpipe() {
+        // Stages killed by SIGPIPE when a later stage stops reading do not fail the pipeline.
+        assert_eval_ok!(
+            "yes | head -l 2 | x; $x + \" after\"",
+            Value::from("y\ny after")
+        );
+        assert_eval_ok!(
+            "yes | head -l 1 | x; $__pipestatus[0]",
+            Value::from("yes: terminated by SIGPIPE")
+        );
+        // In-process stages run concurrently, and stop when the next stage stops reading.
+        assert_eval_ok!(
+            "cat /dev/urandom | head -l 1 | wc -l | x; $x",
+            Value::Int(1)
+        );
+        // Stages before the last do not change the variables of the interpreter.
+        assert_eval_ok!("n = 1; (n = 2; echo $n) | cat | x; $x + $n", Value::Int(3));
+    }
+
+    #[test]
+    fn test_here_doc() {
+        assert_eval_ok!(
+            "x = 1; cat <<END\nx is $x\nEND | y; $y",
+            Value::from("x is 1")
+        );
+        assert_eval_ok!(
+            "x = 1; cat <<'END'\nx is $x\nEND | y; $y",
+            Value::from("x is $x")
+        );
+        assert_eval_ok!("cat <<END\n  a\n  b\n  END | y[]; ${#y}", Value::Int(2));
+        assert_eval_err!(
+            "cat <<END\nabc\n",
+            "Expecting here-document end marker: END"
+        );
+        assert_eval_err!(
+            "cat <<END abc\nEND",
+            "Expecting new line after here-document marker"
+        );
+    }
+
+    #[test]
+    fn test_here_string() {
+        assert_eval_ok!("x = 2; cat <<< \"x is $x\" | y; $y", Value::from("x is 2"));
+        assert_eval_ok!("wc -l <<< abc | y; $y", Value::Int(1));
+    }
+
+    #[test]
+    fn test_substitution() {
+        assert_eval_ok!("echo a $(echo b c) d | x; $x", Value::from("a b c d"));
+        assert_eval_ok!("x = 2; \"x is $(echo $x)\"", Value::from("x is 2"));
+        assert_eval_ok!("$(1 + 2) * 2", Value::Int(6));
+        assert_eval_ok!("\"$(echo \"(a)\")$(echo $(echo b))\"", Value::from("(a)b"));
+        assert_eval_ok!("echo abc | x; $(echo $x | grep b)", Value::from("abc"));
+        assert_eval_ok!("$(x = 1); $x", Value::from("$x"));
+    }
+
+    #[test]
+    fn test_substitution_error() {
+        let err = eval("echo ok;\necho $(ls /no/such/dir) never").unwrap_err();
+        assert_eq!(err.loc.line, 2);
+        assert!(err.message.starts_with("/no/such/dir"));
+
+        assert_eval_err!(
+            "echo $(echo a",
+            "Expecting closing parenthesis after command substitution"
+        );
+    }
+
+    #[test]
+    fn test_background() {
+        assert_eval_cmd_ok!("echo hello &; wait");
+
+        let err = eval("ls /no/such/dir &; wait").unwrap_err();
+        assert!(err.message.starts_with("ls /no/such/dir: Exit"));
+
+        let jobs = eval("while (1) () &; jobs | x; kill %1; if (wait %1) () else (); $x");
+        assert!(matches!(jobs, Ok(Value::Str(s)) if s.contains("Running")));
+
+        assert_eval_err!("kill %100", "%100: no such job");
+        assert_eval_err!("fg", "No current job");
+
+        // Background jobs see the functions and the (non-exported) lists of the interpreter.
+        let path = std::env::temp_dir().join(format!("shmy_bg_{}.txt", std::process::id()));
+        assert_eval_ok!(
+            &format!(
+                "def bg_count (p) (echo ${{#l}} => $p); l = [1, 2, 3]; bg_count {0} &; wait; cat {0} | x; $x",
+                path.display()
+            ),
+            Value::Int(3)
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        // ... but do not change the variables of the interpreter.
+        assert_eval_ok!("n = 1; (n = 2; echo $n) &; wait; $n", Value::Int(1));
+
+        // Killing a job terminates the commands that it runs.
+        assert_eval_ok!(
+            "sleep 100 &; kill %1; if (wait %1) (0) else (1)",
+            Value::Int(1)
+        );
+    }
+
+    #[test]
+    fn test_try() {
+        assert_eval_ok!(
+            "try (throw oops) catch e ($e[message])",
+            Value::from("oops")
+        );
+        assert_eval_ok!(
+            "try (ls /no/such/dir) catch e ($e[command])",
+            Value::from("ls /no/such/dir")
+        );
+        assert_eval_ok!(
+            "try (\necho;\n  throw oops) catch e ($e[line])",
+            Value::Int(3)
+        );
+        assert_eval_ok!(
+            "try (\necho;\n  throw oops) catch e ($e[col])",
+            Value::Int(8)
+        );
+        assert_eval_ok!("try (throw oops) catch e ($e[exit_code])", Value::Int(1));
+        assert_eval_ok!(
+            "x = 0; try (try (throw oops) finally ($x = 1)) catch (); $x",
+            Value::Int(1)
+        );
+        assert_eval_ok!(
+            "x = 0; for i in 1 2 3; (try ($x = $i; break) catch ($x = 100)); $x",
+            Value::Int(1)
+        );
+
+        // Re-raise, preserving the original location.
+        let err = eval("try (throw oops) catch (throw)").unwrap_err();
+        assert_eq!(err.message, "oops");
+        assert_eq!(err.loc.col, 11);
+
+        assert_eval_err!(
+            "throw",
+            "Missing error message (no caught error to re-raise)"
+        );
+        assert_eval_err!("catch (x)", "CATCH without TRY");
+        assert_eval_err!("try (echo)", "Expecting CATCH or FINALLY block");
+    }
+
+    #[test]
+    fn test_defer() {
+        assert_eval_ok!(
+            "x = \"\"; (defer ($x = $x + a); defer ($x = $x + b); $x = $x + c); $x",
+            Value::from("cba")
+        );
+        assert_eval_ok!(
+            "x = 0; try ((defer ($x = 1); throw oops)) catch (); $x",
+            Value::Int(1)
+        );
+        assert_eval_ok!(
+            "x = 0; def defer_test () (defer ($x = 2); return 1); defer_test; $x",
+            Value::Int(2)
+        );
+        assert_eval_ok!(
+            "x = \"\"; for i in 1 2; (defer ($x = $x + $i); $x = $x + -); $x",
+            Value::from("-1-2")
+        );
+        // DEFER is a keyword only at the start of statements.
+        assert_eval_ok!("echo defer | x; $x", Value::from("defer"));
+
+        assert_eval_err!("(defer (throw cleanup); echo)", "cleanup");
+        assert_eval_err!("defer echo", "Parentheses are required around DEFER body");
+    }
+
+    #[test]
+    fn test_trap() {
+        use crate::traps::{self, Condition};
+
+        let path = std::env::temp_dir().join(format!("shmy_trap_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        assert_eval_cmd_ok!(&format!(
+            "NO_CONFIRM = 1; trap --clear; trap \"echo first =>> {0}\"; trap \"echo second =>> {0}\" EXIT ERR",
+            path.display()
+        ));
+        assert_eval_ok!(
+            "trap -l | x; $x",
+            Value::new_str(format!(
+                "EXIT ERR: echo second =>> {0}\nEXIT: echo first =>> {0}",
+                path.display()
+            ))
+        );
+        assert_eval_err!(
+            "trap echo HUP",
+            "Invalid trap condition: HUP (expecting EXIT, ERR or INT)"
+        );
+
+        traps::run(Condition::Error);
+        traps::run(Condition::Exit);
+        traps::run(Condition::Exit); // EXIT traps run once
+
+        let output = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(output, "second\nsecond\nfirst\n");
+        assert_eval_ok!(
+            "trap -l | x; $x",
+            Value::new_str(format!("ERR: echo second =>> {}", path.display()))
+        );
+
+        assert_eval_cmd_ok!("trap --clear");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_argparse() {
+        let spec =
+            "\"v,verbose\" \"color:true\" \"o,out=DIR:/tmp\" \"dry-run\" \"<src>\" \"[dst]\"";
+
+        assert_eval_ok!(
+            &format!(
+                "argparse {} -- a; \"$verbose $color $out $dry_run $src [$dst]\"",
+                spec
+            ),
+            Value::from("0 1 /tmp 0 a []")
+        );
+        assert_eval_ok!(
+            &format!(
+                "argparse {} -- -v --no-color --dry-run -o x a b; \"$verbose $color $out $dry_run $src $dst\"",
+                spec
+            ),
+            Value::from("1 0 x 1 a b")
+        );
+        assert_eval_ok!(
+            "argparse \"<first>\" \"[rest]...\" -- a -- -b c; $rest",
+            Value::new_list(vec![Value::from("-b"), Value::from("c")])
+        );
+        assert_eval_ok!(
+            "argparse \"n,count=N:10\" -- -n5; $count + 1",
+            Value::Int(6)
+        );
+
+        assert_eval_err!(
+            "argparse \"v,verbose\" -- -x",
+            "Unknown flag: -x\nUsage: script [OPTIONS]"
+        );
+        assert_eval_err!(
+            "argparse -n cmd \"<src>\" --",
+            "Missing argument: <src>\nUsage: cmd [OPTIONS] <src>"
+        );
+        assert_eval_err!(
+            "argparse \"[a]\" \"<b>\" --",
+            "Required argument <b> follows optional [a]"
+        );
+        assert_eval_err!(
+            "argparse \"v,verbose\" \"v,version\" --",
+            "Duplicate flag: v,version"
+        );
+        assert_eval_err!("argparse \"help\" --", "Duplicate flag: help");
+        assert_eval_err!("argparse \"=x\" --", "Invalid flag spec: =x");
+        assert_eval_err!(
+            "argparse \"<src>...\" --",
+            "Missing argument: <src>...\nUsage: script [OPTIONS] <src>..."
+        );
+
+        // Without --, scripts parse their arguments ($__args), not including the script itself.
+        let path = std::env::temp_dir().join(format!("shmy_argparse_{}.my", std::process::id()));
+        std::fs::write(
+            &path,
+            "argparse \"v,verbose\" \"<src>...\"; echo $verbose ${#src} $src[0]",
+        )
+        .unwrap();
+        assert_eval_ok!(
+            &format!("eval --source {} -v \"a b\" c | x; $x", path.display()),
+            Value::from("1 2 a b")
+        );
+        // The numbered arguments and $@ are unchanged: $0 is the script, and $@ includes it.
+        std::fs::write(&path, "echo $# $1 ${#__args} $__args[1] $@").unwrap();
+        assert_eval_ok!(
+            &format!("eval --source {} -v \"a b\" c | x; $x", path.display()),
+            Value::from(format!("3 -v 3 a b {} -v a b c", path.display()).as_str())
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_exit_status() {
+        assert_eval_ok!("echo; $__status", Value::Int(0));
+        assert_eval_ok!("if (cp) () else ($__status)", Value::Int(1));
+        assert_eval_ok!("if (cp) () else (echo; $__status)", Value::Int(0));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_exit_status_unix() {
+        assert_eval_ok!("if (sh -c \"exit 3\") () else ($__status)", Value::Int(3));
+        assert_eval_ok!(
+            "try (sh -c \"exit 3\") catch e ($e[exit_code])",
+            Value::Int(3)
+        );
+
+        // Processes killed by signals fail, with 128 + the signal number as the exit code.
+        assert_eval_ok!(
+            "if (sh -c r\"(kill -9 $$)\") () else ($__status)",
+            Value::Int(137)
+        );
+        assert_eval_ok!(
+            "if (sh -c r\"(kill -9 $$)\") () else ($__signal)",
+            Value::from("SIGKILL")
+        );
+        assert_eval_ok!(
+            "try (sh -c r\"(kill -TERM $$)\") catch e ($e[signal])",
+            Value::from("SIGTERM")
+        );
+        assert_eval_err!(
+            "sh -c r\"(kill -9 $$)\"",
+            "/usr/bin/sh -c kill -9 $$: terminated by SIGKILL"
+        );
+    }
+
+    #[test]
+    fn test_builtin_functions() {
+        assert_eval_ok!("upper(hello)", Value::from("HELLO"));
+        assert_eval_ok!("x = \" a b \"; trim($x)", Value::from("a b"));
+        assert_eval_ok!("substr(\"hello world\", -5, 3)", Value::from("wor"));
+        assert_eval_ok!("len(split(\"a:b:c\", \":\"))", Value::Int(3));
+        assert_eval_ok!("join([a b c], \"-\")", Value::from("a-b-c"));
+        assert_eval_ok!("ext(/tmp/archive.tar.gz)", Value::from("gz"));
+        assert_eval_ok!("stem(/tmp/main.rs)", Value::from("main"));
+        assert_eval_ok!("sqrt(16) + 1", Value::Real(5.0));
+        assert_eval_ok!("round(2.567, 2)", Value::Real(2.57));
+        assert_eval_ok!("round(2.5)", Value::Int(3));
+        assert_eval_ok!("max(3, 7, 2)", Value::Int(7));
+        assert_eval_ok!("int(\"42\") + 1", Value::Int(43));
+        assert_eval_ok!("type(1.5)", Value::from("real"));
+        assert_eval_ok!("x = 16; y = sqrt($x) / 8; $y", Value::Real(0.5));
+
+        assert_eval_err!(
+            "substr(abc)",
+            "substr: Invalid number of arguments, usage: substr(STRING, START, [LENGTH])"
+        );
+        assert_eval_err!("sqrt(abc)", "sqrt: Expecting number, got str: abc");
+        assert_eval_err!(
+            "upper(abc]",
+            "Expecting closing parenthesis after function arguments"
+        );
+    }
+
+    #[test]
+    fn test_param_expansion() {
+        assert_eval_ok!("${UNDEFINED_X:-fallback}", Value::from("fallback"));
+        assert_eval_ok!("x = 1; ${x:-2}", Value::Int(1));
+        assert_eval_ok!("x = \"\"; ${x:-empty}", Value::from("empty"));
+        assert_eval_ok!("d = 5; ${UNDEFINED_X:-$d}", Value::Int(5));
+        assert_eval_ok!("x = 1; ${x:+other}", Value::from("other"));
+        assert_eval_ok!("\"${UNDEFINED_X:+other}\"", Value::from(""));
+        assert_eval_ok!("x = abcdef; ${x:2}", Value::from("cdef"));
+        assert_eval_ok!("x = abcdef; ${x:1:3}", Value::from("bcd"));
+        assert_eval_ok!("x = abcdef; ${x: -2}", Value::from("ef"));
+        assert_eval_ok!("x = abcdef; ${x:0:-2}", Value::from("abcd"));
+        assert_eval_ok!("f = archive.tar.gz; ${f#*.}", Value::from("tar.gz"));
+        assert_eval_ok!("f = archive.tar.gz; ${f##*.}", Value::from("gz"));
+        assert_eval_ok!("f = archive.tar.gz; ${f%.*}", Value::from("archive.tar"));
+        assert_eval_ok!("f = archive.tar.gz; \"${f%%.*}\"", Value::from("archive"));
+        assert_eval_ok!("word = hello; ${word^}", Value::from("Hello"));
+        assert_eval_ok!("word = hello; ${word^^}", Value::from("HELLO"));
+        assert_eval_ok!("word = HELLO; ${word,}", Value::from("hELLO"));
+        assert_eval_ok!("word = HELLO; ${word,,}", Value::from("hello"));
+        assert_eval_ok!("x = 1; ${x:?missing}", Value::Int(1));
+
+        assert_eval_err!("${UNDEFINED_X:?}", "UNDEFINED_X: parameter not set");
+        assert_eval_err!("${UNDEFINED_X:?is required}", "UNDEFINED_X: is required");
+        assert_eval_err!("x = abc; ${x:z}", "Invalid substring offset or length: z");
+    }
+
+    #[test]
+    fn test_brace_expansion() {
+        assert_eval_ok!(
+            "s = \"\"; for item in file{1,2,3}.txt; ($s = $s + $item + \" \"); $s",
+            Value::from("file1.txt file2.txt file3.txt ")
+        );
+        assert_eval_ok!(
+            "s = \"\"; for item in {a..e..2} {x,y}{1,2}; ($s = $s + $item); $s",
+            Value::from("acex1x2y1y2")
+        );
+        assert_eval_ok!(
+            "s = \"\"; for item in {-1..1} {a,b{c,d}} {} \"{x,y}\"; ($s = $s + $item + \" \"); $s",
+            Value::from("-1 0 1 a bc bd {} {x,y} ")
+        );
+        assert_eval_ok!(
+            "x = 0; for i in {1..10}; ($x = $x + $i); $x",
+            Value::Int(55)
+        );
+        assert_eval_ok!(
+            "x = 0; for i in {10..1..3}; ($x = $x * 10 + $i); $x",
+            Value::Int(10741)
+        );
+        // Ranges in FOR arguments are iterated lazily
+        assert_eval_ok!(
+            "n = 0; for i in {1..1000000000000}; ($n = $i; if ($i == 3) (break)); $n",
+            Value::Int(3)
+        );
+        // Only ranges written unquoted in the FOR arguments, not strings that look like ranges
+        assert_eval_ok!(
+            "s = \"\"; r = \"{1..3}\"; for i in \"{4..5}\" $r; ($s = $s + $i + \" \"); $s",
+            Value::from("{4..5} {1..3} ")
+        );
+    }
+
+    #[test]
+    fn test_for_multiple_vars() {
+        assert_eval_ok!(
+            "s = \"\"; for a, b in 1 2 3 4 5; ($s = $s + $a + \"-\" + $b + \" \"); $s",
+            Value::from("1-2 3-4 5- ")
+        );
+        assert_eval_ok!(
+            "s = 0; for a,b,c in [[1 2 3] [4 5 6]]; ($s = $s + $a * $b * $c); $s",
+            Value::Int(126)
+        );
+        assert_eval_ok!(
+            "s = \"\"; m = [x: 1, y: 2]; for k, v in $m; ($s = $s + $k + $v); $s",
+            Value::from("x1y2")
+        );
+        assert_eval_ok!(
+            "s = \"\"; for i, item in enumerate([a b c]); ($s = $s + $i + $item); $s",
+            Value::from("0a1b2c")
+        );
+        assert_eval_ok!(
+            "n = 0; x = \"one two\\nthree four\\n\"; for line[] in $x; ($n = $n + 1); $n",
+            Value::Int(2)
+        );
+        assert_eval_ok!(
+            "s = \"\"; x = \"a b\\nc\"; for line[] in $x; ($s = $s + \"<$line>\"); $s",
+            Value::from("<a b><c>")
+        );
+        assert_eval_err!(
+            "for a[], b in 1 2; (echo $a)",
+            "Expecting IN after FOR variable[]"
+        );
+        assert_eval_err!("enumerate(abc)", "enumerate: Expecting list, got str: abc");
+    }
+
+    fn check(input: &str) -> Vec<String> {
+        Interp::with_env_vars()
+            .check(input)
+            .iter()
+            .map(|e| format!("{}:{} {}", e.loc.line, e.loc.col, e.message))
+            .collect()
+    }
+
+    #[test]
+    fn test_check() {
+        assert!(check("x = 2; echo $x; for i in 1 2; (echo $i)").is_empty());
+        assert!(check("def add (a, b) (return $a + $b); echo (add 1 2)").is_empty());
+        assert!(check("echo ${UNDEFINED_X:-none}; ls | out[]; echo $out").is_empty());
+
+        assert_eq!(
+            check("echo $UNDEFINED_X;\nx = 2;\n$x*$x"),
+            vec![
+                "1:6 Variable $UNDEFINED_X is used, but never assigned",
+                "3:1 $x*$x is not evaluated as arithmetic, use spaces around the operator",
+            ]
+        );
+        assert_eq!(
+            check("echo hi;\nNoSuchCommand;\nNoSuchCommand -x"),
+            vec![
+                "2:1 Unknown command: NoSuchCommand",
+                "3:1 Unknown command: NoSuchCommand",
+            ]
+        );
+        assert_eq!(
+            check("result = (ls);\nwhile (1);"),
+            vec![
+                "1:1 Status of ls is assigned to $result, but never checked",
+                "2:1 WHILE body is missing, or not enclosed in parentheses",
+            ]
+        );
+        // Syntax errors are all reported, parsing resumes at the next unindented line.
+        let errors = check("if (1) (echo]\n  echo\n  (\necho ok\n)\n");
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].starts_with("1:"));
+        assert!(errors[1].starts_with("5:"));
+
+        // Lint warnings are reported around syntax errors, before and after them.
+        let errors = check(
+            "def f () (echo);\necho $UNDEFINED_Y;\nNoSuchCommand;\nif (1) (echo]\nf;\nNoSuchCommand\n",
+        );
+        assert_eq!(errors.len(), 4);
+        assert_eq!(
+            errors[0],
+            "2:6 Variable $UNDEFINED_Y is used, but never assigned"
+        );
+        assert_eq!(errors[1], "3:1 Unknown command: NoSuchCommand");
+        assert!(errors[2].starts_with("4:"));
+        assert_eq!(errors[3], "6:1 Unknown command: NoSuchCommand");
+    }
+
+    #[test]
+    fn test_trace() {
+        // Tracing does not change results, nor errors.
+        assert_eval_ok!(
+            "__trace = 1; def inc (a) (return $a + 1); inc 41",
+            Value::Int(42)
+        );
+        assert_eval_ok!(
+            "__trace = 1; if (!(cd /no/such/dir)) (Failed)",
+            Value::from("Failed")
+        );
+        assert_eval_err!("__trace = 1; throw oops", "oops");
+        assert_eval_ok!("__trace = 0; echo off; 1", Value::Int(1));
+    }
+
+    #[test]
+    fn test_time() {
+        assert_eval_ok!(
+            "time -q r\"(for i in 1 2 3; (x = $i))\"; $__time_real >= 0 && $__time_user >= 0",
+            Value::Int(1)
+        );
+        assert_eval_ok!("time -q echo hello; $__time_sys >= 0", Value::Int(1));
+        assert_eval_err!(
+            "time -q cd /no/such/dir",
+            "1:3 Change dir to \"/no/such/dir\": No such file or directory (os error 2)"
+        );
+        assert_eval_err!("time -q", "Missing expression to time");
+    }
+
+    #[test]
+    fn test_debugger() {
+        // Stop at the first statement, step over it, then modify $x and continue.
+        let mut commands = vec!["c", "set x 41", "p x", "n"];
+        crate::debugger::start(Box::new(move |_| commands.pop().map(String::from)));
+
+        assert_eval_ok!("x = 1; $x + 1", Value::Int(42));
+    }
+
+    fn format(input: &str) -> String {
+        Interp::with_env_vars().format(input).unwrap()
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(
+            format("x=-1;y = $x +2*3 ;echo   $y"),
+            "x = -1; y = $x + 2 * 3; echo $y\n"
+        );
+        assert_eq!(
+            format("IF (!$x) (echo yes)ELSE(echo   no)"),
+            "if (!$x) (echo yes) else (echo no)\n"
+        );
+        assert_eq!(
+            format("l = [1,2,  3]; m=[a: 1, b: 2]; e = [:]; echo upper($l) (1+2)"),
+            "l = [1, 2, 3]; m = [a: 1, b: 2]; e = [:]; echo upper($l) (1 + 2)\n"
+        );
+        // Comments, strings, globs and here-documents are kept as written.
+        assert_eq!(
+            format("# comment\nfor f in *.rs; (\necho  \"a  b\" r\"(c ; d)\"  # more\n\n\n)"),
+            "# comment\nfor f in *.rs; (\n    echo \"a  b\" r\"(c ; d)\" # more\n\n)\n"
+        );
+        assert_eq!(
+            format("if (1) (\ncat <<END\n  x   y\n  END\n)"),
+            "if (1) (\n    cat <<END\n  x   y\n  END\n)\n"
+        );
+        let output = format("def f (a, b) (\n  return   $a +  $b\n  );\nf 1 2");
+        assert_eq!(output, "def f (a, b) (\n    return $a + $b\n);\nf 1 2\n");
+        assert_eq!(format(&output), output);
+
+        assert!(Interp::with_env_vars().format("echo (").is_err());
+    }
+
+    #[test]
+    fn test_match() {
+        assert_eval_ok!(
+            "match b (case a (1); case b c (2); case _ (3))",
+            Value::Int(2)
+        );
+        assert_eval_ok!(
+            "match x (case a (1); case b c (2); case _ (3))",
+            Value::Int(3)
+        );
+        assert_eval_ok!(
+            "match foo.rs (case \"*.txt\" (1); case \"*.rs\" (2))",
+            Value::Int(2)
+        );
+        assert_eval_ok!(
+            "match v1.2 (case r\"(^v(?<major>\\d+)\\.(\\d+)$)\" ($major * 10 + $__match[2]))",
+            Value::Int(12)
+        );
+        assert_eval_ok!("x = 5; match ($x + 1) (case 6 (Yes))", Value::from("Yes"));
+
+        // Unquoted patterns are not expanded to the names of the files that they match
+        // (the input is parsed, and globbed, after cd runs, by eval).
+        let dir = std::env::temp_dir().join(format!("shmy_match_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.txt"), "").unwrap();
+        let cwd = std::env::current_dir().unwrap();
+        assert_eval_ok!(
+            &format!(
+                "cd {}; eval \"match b.txt (case *.txt (echo txt); case _ (echo other))\" | x; cd {}; $x",
+                dir.display(),
+                cwd.display()
+            ),
+            Value::from("txt")
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eval_ok!("match 1 (case 2 (echo)) ", Value::success());
+        assert_eval_cmd_ok!("match 1 (case 1 (echo one)) && echo case");
+
+        assert_eval_err!("match 1 (echo)", "Expecting CASE in MATCH body");
+        assert_eval_err!("match 1 (case 1)", "Expecting CASE pattern and body");
+        assert_eval_err!(
+            "match 1 (case 1 one)",
+            "Parentheses are required around CASE body"
+        );
+    }
+
     #[test]
     fn test_hash_tag() {
         assert_eval_ok!("x = hey#world; $x", Value::from("hey"));
@@ -471,6 +1147,112 @@ pub mod tests {
         assert_eval_ok!("r\"(_;)( \" )\"", Value::from("_;)( \" "));
     }
 
+    #[test]
+    fn test_declarations() {
+        // $x = ... assigns the nearest existing variable, unless shadowed by a local one.
+        assert_eval_ok!("x = 1; ($x = 3); $x", Value::Int(3));
+        assert_eval_ok!("x = 1; (local x = 2; $x = 3); $x", Value::Int(1));
+        assert_eval_ok!("local y; $y", Value::from(""));
+        assert_eval_ok!("echo local; x = local; $x", Value::from("local"));
+
+        assert_eval_err!(
+            "readonly r = 1; $r = 2",
+            "Cannot assign read-only variable: $r"
+        );
+        assert_eval_err!(
+            "const c = 1; (c = 2)",
+            "Cannot assign read-only variable: $c"
+        );
+        assert_eval_ok!("const c = 1; (local c = 2; $c)", Value::Int(2));
+        assert_eval_err!(
+            "r = [1 2]; readonly r; $r[0] = 5",
+            "Cannot assign read-only variable: $r"
+        );
+        assert_eval_err!(
+            "readonly r = 1; $r =",
+            "Cannot erase read-only variable: $r"
+        );
+
+        // Only exported variables are passed to child processes.
+        assert_eval_ok!("DECL_A = 1; env | grep DECL_A | a; $a", Value::from(""));
+        assert_eval_ok!(
+            "export DECL_B = 2; env | grep DECL_B | b; $b",
+            Value::from("DECL_B=2")
+        );
+        assert_eval_ok!(
+            "DECL_C = 3; export DECL_C; env | grep DECL_C | c; $c",
+            Value::from("DECL_C=3")
+        );
+
+        // The string form evaluates in the global scope, like eval --export
+        assert_eval_ok!(
+            "(export \"DECL_D = 4\"); env | grep DECL_D | d; $d",
+            Value::from("DECL_D=4")
+        );
+
+        // NAME is not mistaken for the string form when a file of the same name exists
+        // (the input is parsed, and globbed, after cd runs, by eval).
+        let dir = std::env::temp_dir().join(format!("shmy_export_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("DECL_E"), "").unwrap();
+        let cwd = std::env::current_dir().unwrap();
+        assert_eval_ok!(
+            &format!(
+                "cd {}; eval \"DECL_E = 5; export DECL_E; env\" | grep DECL_E | e; cd {}; $e",
+                dir.display(),
+                cwd.display()
+            ),
+            Value::from("DECL_E=5")
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eval_err!("local", "Expecting NAME = VALUE, or NAME after LOCAL");
+        assert_eval_err!("export decl_none", "Variable not found: $decl_none");
+    }
+
+    #[test]
+    fn test_import() {
+        let dir = std::env::temp_dir().join(format!("shmy_import_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("strs.my"),
+            "SEP = \"-\";\ndef join2 (a b) (return $a + $SEP + $b);\ndef twice (s) (join2 $s $s)",
+        )
+        .unwrap();
+        std::fs::write(dir.join("cyc_a.my"), "import ./cyc_b").unwrap();
+        std::fs::write(dir.join("cyc_b.my"), "\nimport ./cyc_a").unwrap();
+
+        let path = |name: &str| dir.join(name).display().to_string();
+
+        // Variables of the module are copied to the global scope when the module is loaded.
+        assert_eval_ok!(
+            &format!("import {}; strs.join2 a ${{strs.SEP}}", path("strs")),
+            Value::from("a--")
+        );
+        // Functions of the module call each other by their unqualified names.
+        assert_eval_ok!(
+            &format!("import {}; strs.twice x", path("strs.my")),
+            Value::from("x-x")
+        );
+        assert_eval_ok!(
+            &format!("import {}; echo import | x; $x", path("strs")),
+            Value::from("import")
+        );
+
+        match eval(&format!("import {}", path("cyc_a"))) {
+            Err(e) => {
+                assert_eq!(e.message, "Import cycle: cyc_a.my -> cyc_b.my -> cyc_a.my");
+                assert_eq!(e.loc.file.as_deref(), Some(&path("cyc_b.my")));
+                assert_eq!(e.loc.line, 2);
+            }
+            Ok(_) => panic!("Expected import cycle error"),
+        }
+        assert_eval_err!("import no_such_module", "Module not found: no_such_module");
+        assert_eval_err!("import", "Expecting module name after IMPORT");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
     #[test]
     fn test_trailing_equals() {
         assert_eval_err!("FOO=", "Variable expected on left hand-side of assignment");
@@ -491,6 +1273,225 @@ pub mod tests {
         assert_eval_ok!("$FOO", Value::from("$FOO"));
     }
 
+    #[test]
+    fn test_def() {
+        assert_eval_ok!("def sum2 (a b) ($a + $b); sum2 2 3", Value::Int(5));
+        assert_eval_ok!("def count_args () ($#); count_args 1 2 3", Value::Int(3));
+        assert_eval_cmd_ok!("def greet (name) (echo Hello $name); greet World");
+        assert_eval_err!(
+            "def sum3 (a b c) ($a); sum3 1 2",
+            "sum3: missing argument: c"
+        );
+        assert_eval_err!("def (a) ()", "Expecting function name");
+        assert_eval_err!("def fails () (cp); fails", "Missing source and destination");
+        assert_eval_ok!("def fails () (cp); if (fails) (1) else (2)", Value::Int(2));
+        // Lists are passed as they are, not expanded into separate arguments.
+        assert_eval_ok!(
+            "def count_items (l) (${#l}); items = [a \"b c\" d]; count_items $items",
+            Value::Int(3)
+        );
+        assert_eval_ok!(
+            "def second (l n) ($l[1] + $n); items = [1 2 3]; second $items 10",
+            Value::Int(12)
+        );
+    }
+
+    #[test]
+    fn test_def_recursive() {
+        assert_eval_ok!(
+            "def fact (n) (if ($n <= 1) (return 1); return $n * (fact ($n - 1))); fact 10",
+            Value::Int(3628800)
+        );
+        assert_eval_ok!(
+            "def fib (n) (if ($n < 2) (return $n); (fib ($n - 1)) + (fib ($n - 2))); fib 10",
+            Value::Int(55)
+        );
+        // Each call has its own variables.
+        assert_eval_ok!(
+            "def depth (n) (x = $n; if ($n > 0) (depth ($n - 1)); $x); depth 3",
+            Value::Int(3)
+        );
+        assert_eval_err!(
+            "def forever () (forever); forever",
+            "forever: maximum call depth exceeded"
+        );
+    }
+
+    #[test]
+    fn test_return() {
+        assert_eval_ok!("def ret_early () (return; 1); ret_early", Value::success());
+        assert_eval_ok!(
+            "def lookup (x) (for i in 1 2 3; (if ($i == $x) (return $i * 10)); -1); lookup 2",
+            Value::Int(20)
+        );
+        assert_eval_ok!(
+            "def ret_else () (if (0) (echo a) else (return 2)); ret_else",
+            Value::Int(2)
+        );
+        assert_eval_err!("return 1", "RETURN outside function");
+        assert_eval_err!(
+            "for i in 1 2; (def brk () (break); brk)",
+            "BREAK outside loop"
+        );
+    }
+
+    #[test]
+    fn test_list() {
+        let abc = Value::new_list(vec![Value::from("a"), Value::from("b c"), Value::Int(3)]);
+        assert_eval_ok!("x = [a \"b c\" (1 + 2)]; $x", abc.clone());
+        assert_eval_ok!("[a, \"b c\", 3]", abc.clone());
+        assert_eval_ok!("x = [a \"b c\" 3]; \"$x\"", Value::from("[a \"b c\" 3]"));
+        assert_eval_ok!(
+            "x = [1 2]; x = $x + 3; $x",
+            Value::new_list(vec![Value::Int(1), Value::Int(2), Value::Int(3)])
+        );
+        assert_eval_ok!(
+            "x = 0 + [1]; $x",
+            Value::new_list(vec![Value::Int(0), Value::Int(1)])
+        );
+        assert_eval_ok!(
+            "[1] + [2]",
+            Value::new_list(vec![Value::Int(1), Value::Int(2)])
+        );
+        assert_eval_ok!("[1 2] == [1 2]", Value::Int(1));
+        assert_eval_ok!("[1 2] != [1 2 3]", Value::Int(1));
+        assert_eval_ok!("x = []; ${#x}", Value::Int(0));
+        assert_eval_err!(
+            "[1 2] < [3]",
+            "Lists and maps can only be compared for equality"
+        );
+        assert_eval_err!("[1 2] * 2", "Cannot multiply lists or maps");
+        assert_eval_err!(
+            "[1 + 2]",
+            "Expressions in lists must be enclosed in parentheses"
+        );
+        assert_eval_err!("[1 2", "Expecting closing bracket");
+        assert_eval_err!("if ([1]) (echo)", "Cannot evaluate list or map as boolean");
+    }
+
+    #[test]
+    fn test_list_index() {
+        assert_eval_ok!("x = [a b c]; ${#x}", Value::Int(3));
+        assert_eval_ok!("x = [a b c]; $x[1]", Value::from("b"));
+        assert_eval_ok!("x = [a b c]; $x[-1]", Value::from("c"));
+        assert_eval_ok!("x = [a b c]; i = 2; $x[$i]", Value::from("c"));
+        assert_eval_ok!(
+            "x = [a b c]; $x[1:3]",
+            Value::new_list(vec![Value::from("b"), Value::from("c")])
+        );
+        assert_eval_ok!(
+            "x = [a b c]; $x[:-2]",
+            Value::new_list(vec![Value::from("a")])
+        );
+        assert_eval_ok!("x = [[1 2] [3 4]]; $x[1][0]", Value::Int(3));
+        assert_eval_ok!("x = [a b c]; \"${x[0]}-$x[2]\"", Value::from("a-c"));
+        assert_eval_ok!("x = hello; \"$x[0]\"", Value::from("hello[0]"));
+        assert_eval_ok!("x = hello; ${#x}", Value::Int(5));
+        assert_eval_err!("x = [a b c]; $x[3]", "List index out of range: 3");
+        assert_eval_err!("x = [a b c]; $x[b]", "Invalid list index: b");
+    }
+
+    #[test]
+    fn test_list_for() {
+        assert_eval_ok!(
+            "x = [\"a b\" c]; n = 0; for i in $x; ($n = $n + 1); $n",
+            Value::Int(2)
+        );
+        assert_eval_ok!(
+            "y = []; for i in [1 [2 3]]; ($y = $y + [$i]); $y",
+            Value::new_list(vec![
+                Value::Int(1),
+                Value::new_list(vec![Value::Int(2), Value::Int(3)])
+            ])
+        );
+        assert_eval_ok!(
+            "echo a b c | x[]; $x",
+            Value::new_list(vec![Value::from("a b c")])
+        );
+        assert_eval_ok!("echo \"a\\nb\" | x[]; ${#x}", Value::Int(2));
+    }
+
+    #[test]
+    fn test_map() {
+        assert_eval_ok!(
+            "m = [host: localhost, port: 8080]; $m[host]",
+            Value::from("localhost")
+        );
+        assert_eval_ok!(
+            "m = [host: localhost, port: 8080]; $m[port]",
+            Value::Int(8080)
+        );
+        assert_eval_ok!("m = [\"a b\": (1 + 2)]; k = \"a b\"; $m[$k]", Value::Int(3));
+        assert_eval_ok!("m = [b: 2 a: 1]; \"$m\"", Value::from("[a: 1 b: 2]"));
+        assert_eval_ok!("m = [:]; \"$m\"", Value::from("[:]"));
+        assert_eval_ok!("m = [a: 1 b: 2]; ${#m}", Value::Int(2));
+        assert_eval_ok!("([a: 1] + [b: 2]) == [a: 1, b: 2]", Value::Int(1));
+        assert_eval_err!("m = [a: 1]; $m[b]", "Key not found: b");
+        assert_eval_err!("[a: 1 2]", "Expecting map key");
+        assert_eval_err!("[a: ]", "Missing map value");
+        assert_eval_err!("[1 a: 2]", "Unexpected map key in list");
+        assert_eval_err!("[a: 1] + 1", "Cannot add to map, use: $map[key] = value");
+    }
+
+    #[test]
+    fn test_map_assign() {
+        assert_eval_ok!(
+            "m = [a: 1]; $m[a] = 2; $m[b] = 3; \"$m\"",
+            Value::from("[a: 2 b: 3]")
+        );
+        assert_eval_ok!("m = [a: [b: 1]]; $m[a][b] = 2; $m[a][b]", Value::Int(2));
+        assert_eval_ok!("x = [1 2 3]; $x[-1] = 4; \"$x\"", Value::from("[1 2 4]"));
+        assert_eval_ok!("m = [a: 1]; n = $m; $m[a] = 2; $n[a]", Value::Int(1));
+        assert_eval_err!("x = [1]; $x[1] = 2", "List index out of range: 1");
+        assert_eval_err!(
+            "x = 1; $x[0] = 2",
+            "Subscript assignment requires a list or map"
+        );
+        assert_eval_err!("$undefined[0] = 1", "Variable not found: $undefined");
+    }
+
+    #[test]
+    fn test_map_for_and_in() {
+        assert_eval_ok!(
+            "m = [a: 1 b: 2 c: 3]; keys = []; for k in $m; ($keys = $keys + $k); \"$keys\"",
+            Value::from("[a b c]")
+        );
+        assert_eval_ok!("m = [a: 1]; a in $m", Value::Int(1));
+        assert_eval_ok!("m = [a: 1]; b in $m", Value::Int(0));
+        assert_eval_ok!("2 in [1 2 3]", Value::Int(1));
+        assert_eval_ok!("ell in hello", Value::Int(1));
+        assert_eval_ok!("if (x in [x y]) (1) else (2)", Value::Int(1));
+        assert_eval_ok!("x = (b in [a b]); $x", Value::Int(1));
+        assert_eval_ok!("echo (x in [x y]) | x; $x", Value::Int(1));
+        // The example in the README, and in the help.
+        assert_eval_ok!(
+            "cfg = [host: localhost, port: 8080]; $cfg[port] = 9090; \
+            (for key in $cfg; (echo \"$key = $cfg[$key]\")) | x; $x",
+            Value::from("host = localhost\nport = 9090")
+        );
+        assert_eval_err!(
+            "1 in 2",
+            "Expecting list, map or string on the right hand-side of IN"
+        );
+    }
+
+    #[test]
+    fn test_in_as_argument() {
+        assert_eval_ok!(
+            "echo logged in as root | x; $x",
+            Value::from("logged in as root")
+        );
+        assert_eval_ok!("echo log in now | x; $x", Value::from("log in now"));
+        assert_eval_ok!(
+            "echo in the middle | grep -n in | x; $x",
+            Value::from("1:in the middle")
+        );
+        assert_eval_ok!(
+            "words = []; for word in logged in; ($words = $words + $word); \"$words\"",
+            Value::from("[logged in]")
+        );
+    }
+
     #[test]
     fn test_escape_unicode() {
         assert_eval_ok!("\"\\u{1b}\"", Value::from("\x1b"));
@@ -0,0 +1,142 @@
+use crate::eval::{Interp, Value};
+use crate::scope::Scope;
+use crate::INTERRUPT_EVENT;
+use std::cell::RefCell;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+/// When a trap runs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Condition {
+    Exit,      // The script (or the interactive shell) exits
+    Error,     // An error is not handled, and bubbles up to the top level
+    Interrupt, // Ctrl+C
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_uppercase().as_str() {
+            "EXIT" => Ok(Condition::Exit),
+            "ERR" => Ok(Condition::Error),
+            "INT" => Ok(Condition::Interrupt),
+            _ => Err(format!(
+                "Invalid trap condition: {} (expecting EXIT, ERR or INT)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Condition::Exit => write!(f, "EXIT"),
+            Condition::Error => write!(f, "ERR"),
+            Condition::Interrupt => write!(f, "INT"),
+        }
+    }
+}
+
+///
+/// Expression registered with: trap EXPR [EXIT|ERR|INT]...
+///
+/// The expression is evaluated in a new scope nested in the scope where the trap was
+/// registered; traps run in LIFO order, so that cleanup happens in reverse order of setup.
+///
+struct Trap {
+    expr: String,
+    conditions: Vec<Condition>,
+    scope: Arc<Scope>,
+}
+
+thread_local! {
+    static TRAPS: RefCell<Vec<Trap>> = const { RefCell::new(Vec::new()) };
+}
+
+pub fn add(expr: String, conditions: Vec<Condition>, scope: &Arc<Scope>) {
+    TRAPS.with(|traps| {
+        traps.borrow_mut().push(Trap {
+            expr,
+            conditions,
+            scope: Arc::clone(scope),
+        })
+    });
+}
+
+pub fn clear() {
+    TRAPS.with(|traps| traps.borrow_mut().clear());
+}
+
+/// Describe the registered traps, most recent first (i.e. in the order they run).
+pub fn list() -> Vec<String> {
+    TRAPS.with(|traps| {
+        traps
+            .borrow()
+            .iter()
+            .rev()
+            .map(|trap| {
+                let conditions: Vec<String> =
+                    trap.conditions.iter().map(|c| c.to_string()).collect();
+                format!("{}: {}", conditions.join(" "), trap.expr)
+            })
+            .collect()
+    })
+}
+
+/// Run the traps registered for the condition, most recent first. EXIT traps run only once,
+/// the others stay registered (the interactive shell may report more than one error).
+pub fn run(condition: Condition) {
+    let traps: Vec<(String, Arc<Scope>)> = TRAPS.with(|traps| {
+        let mut traps = traps.borrow_mut();
+        let selected = traps
+            .iter()
+            .rev()
+            .filter(|trap| trap.conditions.contains(&condition))
+            .map(|trap| (trap.expr.clone(), Arc::clone(&trap.scope)))
+            .collect();
+
+        if condition == Condition::Exit {
+            traps.retain(|trap| trap.conditions != [Condition::Exit]);
+            traps.iter_mut().for_each(|trap| {
+                trap.conditions.retain(|c| *c != Condition::Exit);
+            });
+        }
+        selected
+    });
+
+    for (expr, scope) in traps {
+        uninterrupted(|| eval(&expr, &scope));
+    }
+}
+
+fn eval(expr: &str, scope: &Arc<Scope>) {
+    let mut interp = Interp::new(Arc::clone(scope));
+    let eval_scope = Scope::with_parent_and_hooks(Some(Arc::clone(scope)), None);
+
+    match interp.eval(expr, Some(eval_scope)) {
+        Err(e) => e.show(scope, expr),
+        Ok(Value::Stat(mut status)) => {
+            if let Some(e) = status.err() {
+                e.show(scope, expr);
+            }
+        }
+        Ok(_) => {}
+    }
+}
+
+/// Run cleanup code with the Ctrl+C flag cleared, so that the commands that it runs are not
+/// cut short; the flag is restored afterwards, so that the interruption still propagates.
+pub fn uninterrupted<T>(f: impl FnOnce() -> T) -> T {
+    let interrupted = INTERRUPT_EVENT.try_lock().is_ok_and(|event| event.is_set());
+    if interrupted {
+        _ = INTERRUPT_EVENT.try_lock().map(|mut event| event.clear());
+    }
+    let result = f();
+    if interrupted {
+        _ = INTERRUPT_EVENT.try_lock().map(|mut event| event.set());
+    }
+    result
+}
@@ -1,5 +1,6 @@
 /// Misc. collection of helper routines used by core interpreter and built-in cmds.
 use crate::scope::Scope;
+use std::collections::HashSet;
 use std::env;
 use std::fs;
 use std::io;
@@ -9,16 +10,23 @@ use std::sync::Arc;
 // Maximum length for displaying user account name (ls, ps)
 pub const MAX_USER_DISPLAY_LEN: usize = 16;
 
-/// Copy variables from the current scope outwards into the environment of the
-/// command to be executed, but do not carry over special redirect variables.
+/// Copy the exported variables visible from the current scope into the environment
+/// of the command to be executed, but do not carry over special redirect variables.
+/// Maps are not exported, environment variables are flat strings.
 pub fn copy_vars_to_command_env(command: &mut std::process::Command, scope: &Arc<Scope>) {
     // Override existing environment variables
     command.env_clear();
 
+    // Variables in inner scopes shadow the outer ones with the same name.
+    let mut seen = HashSet::new();
+
     let mut current_scope = Some(scope);
     while let Some(scope) = &current_scope {
         for (key, variable) in scope.vars().iter() {
-            if !key.is_special_var() {
+            if !seen.insert(key.clone()) || !variable.is_exported() {
+                continue;
+            }
+            if !key.is_special_var() && !variable.value().is_map() {
                 command.env(key.as_str(), variable.value().to_string());
             }
         }
@@ -26,13 +34,15 @@ pub fn copy_vars_to_command_env(command: &mut std::process::Command, scope: &Arc
     }
 }
 
-/// Clear the environment, and copy variables from scope into environment.
+/// Clear the environment, and copy exported variables (except for maps) from scope into environment.
 pub fn sync_env_vars(scope: &Scope) {
     // Remove each environment variable
     env::vars().for_each(|(key, _)| env::remove_var(key));
 
     for (key, var) in scope.vars().iter() {
-        env::set_var(key.as_str(), var.to_string());
+        if var.is_exported() && !var.value().is_map() {
+            env::set_var(key.as_str(), var.to_string());
+        }
     }
 }
 